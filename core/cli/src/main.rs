@@ -1,10 +1,28 @@
 use chrono::prelude::*;
-use clap::{Parser, Subcommand};
+use clap::{Parser, Subcommand, ValueEnum};
 use color_eyre::eyre::{eyre, Result};
-use oxygen_core::audio_clip::{AudioBackend, AudioClip};
-use oxygen_core::db::Db;
+use output::{Cell, OutputFormat, Table};
+use oxygen_core::audio_clip::AudioBackend;
+use oxygen_core::backup::{self, RestoreMode};
+use oxygen_core::db::{Db, ListQuery, SortKey};
+use oxygen_core::diarization::{self, DiarizationOptions};
+use oxygen_core::features::{self, Matrix, MelOptions, MfccOptions, StftOptions, Window};
+use oxygen_core::fsck::{self, FsckOptions};
+use oxygen_core::import::{self, ConflictPolicy, ImportOptions, ImportOutcome, ImportProgress};
+use oxygen_core::internal_encoding::EncodingProfile;
 use oxygen_core::language_processor::LanguageProcessor;
-use std::{ffi::OsStr, path::Path, sync::mpsc::channel};
+use oxygen_core::transcript::{self, TranscriptFormat};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::{
+    ffi::OsStr,
+    fs::File,
+    io::BufWriter,
+    path::{Path, PathBuf},
+    sync::mpsc::channel,
+};
+
+mod output;
 
 #[derive(Parser, Debug)]
 #[clap(name = "oxygen")]
@@ -15,6 +33,15 @@ struct Cli {
     #[clap(subcommand)]
     command: Commands,
 
+    /// The database to use, created if it does not exist. Defaults to oxygen.sqlite in the
+    /// user's data directory. Use a separate database for each journal you want to keep.
+    #[clap(global = true, long, env = "OXYGEN_DB")]
+    db: Option<PathBuf>,
+
+    /// How to print lists, like clips, transcripts and features.
+    #[clap(global = true, long, value_enum, default_value_t = OutputFormat::Table)]
+    output: OutputFormat,
+
     #[cfg(feature = "jack")]
     #[clap(global = true, long)]
     /// On Linux, use the jack backend instead of the alsa backend.
@@ -31,20 +58,83 @@ enum Commands {
         /// The name of the clip to record. If not specified, the current date and time will be
         /// used.
         name: Option<String>,
+        /// How to encode the clip.
+        #[clap(long, value_enum, default_value_t = ProfileKind::Voice)]
+        profile: ProfileKind,
+    },
+    /// List all clips, or the clips in a collection in its order.
+    List {
+        /// The collection to list.
+        #[clap(long)]
+        collection: Option<String>,
+        /// What to sort by. Defaults to the collection's order with --collection, or else the
+        /// date.
+        #[clap(long, value_enum)]
+        sort: Option<SortKind>,
+        /// Sort in descending order.
+        #[clap(long)]
+        desc: bool,
+        /// Only clips recorded on or after this date, like "2023-05-01", "2023-05-01 18:00:00"
+        /// or "2023-05-01T18:00:00+02:00".
+        #[clap(long, value_parser = parse_since)]
+        since: Option<DateTime<Utc>>,
+        /// Only clips recorded on or before this date, or before this time if one is given.
+        #[clap(long, value_parser = parse_until)]
+        until: Option<DateTime<Utc>>,
+        /// Only clips whose name contains this, ignoring case.
+        #[clap(long)]
+        name: Option<String>,
+        /// List at most this many clips.
+        #[clap(long)]
+        limit: Option<usize>,
+        /// Skip this many clips first.
+        #[clap(long, default_value_t = 0)]
+        offset: usize,
+        /// The same as --output json, which it predates.
+        #[clap(long, hide = true)]
+        json: bool,
     },
-    /// List all clips.
-    List {},
     /// Play the clip with the given name.
     #[clap(arg_required_else_help = true)]
     Play {
         /// The name of the clip to play.
         name: String,
     },
-    /// Prints a transcript of the clip.
+    /// Prints a transcript of the clip, or writes it as subtitles.
     #[clap(arg_required_else_help = true)]
     Transcribe {
         /// The name of the clip to transcribe.
         name: String,
+        /// The format of the transcript. Defaults to the extension of the --out file, or else
+        /// a list of segments printed with --output.
+        #[clap(long, value_enum)]
+        format: Option<TranscriptKind>,
+        /// Write the transcript to this file instead of printing it.
+        #[clap(short, long)]
+        out: Option<PathBuf>,
+        /// Only the segments in which this speaker talks, by name or number. The clip must
+        /// have been diarized.
+        #[clap(long)]
+        speaker: Option<String>,
+    },
+    /// Work out who speaks when in the clip with the given name, numbering the speakers in
+    /// the order they first speak. Names given to speakers before are forgotten.
+    #[clap(arg_required_else_help = true)]
+    Diarize {
+        /// The name of the clip to diarize.
+        name: String,
+        /// The number of speakers, if known. Otherwise it is estimated.
+        #[clap(long)]
+        speakers: Option<usize>,
+        /// How different voices must be to belong to different speakers, when the number of
+        /// speakers is not given. Lower values find more speakers.
+        #[clap(long, default_value_t = DiarizationOptions::default().threshold)]
+        threshold: f32,
+    },
+    /// List or name the speakers in a diarized clip.
+    Speaker {
+        #[clap(subcommand)]
+        command: SpeakerCommands,
     },
     /// Rename a clip with the given name.
     #[clap(arg_required_else_help = true)]
@@ -55,20 +145,88 @@ enum Commands {
         /// The new name.
         new_name: String,
     },
-    /// Delete the clip with the given name.
+    /// Move the clip with the given name to the trash.
     #[clap(arg_required_else_help = true)]
     Delete {
         /// The name of the clip to delete.
         name: String,
     },
+    /// Set the notes of the clip with the given name, or remove them if none are given.
+    #[clap(arg_required_else_help = true)]
+    Notes {
+        /// The name of the clip.
+        name: String,
+        /// The new notes.
+        notes: Option<String>,
+    },
+    /// Undo the last rename, delete, restore, notes change, or change to a collection's clips.
+    Undo {},
+    /// Redo the last undone change.
+    Redo {},
+    /// List the changes that can be undone, and those that can be redone.
+    History {},
+    /// Create, change or list collections of clips.
+    Collection {
+        #[clap(subcommand)]
+        command: CollectionCommands,
+    },
+    /// List, restore or permanently delete clips in the trash.
+    Trash {
+        #[clap(subcommand)]
+        command: TrashCommands,
+    },
     /// Import the clip at the given path. If a name is not specified, the clip will be
-    /// named after the path.
+    /// named after the file's title tag, or else the path.
     #[clap(arg_required_else_help = true)]
     Import {
         /// The path to import.
         path: String,
         /// The name of the clip to import.
         name: Option<String>,
+        /// The index of the track to import, for files with several. Defaults to the file's
+        /// default track.
+        #[clap(long)]
+        track: Option<usize>,
+        /// The channel to import.
+        #[clap(long, default_value_t = 0)]
+        channel: usize,
+        /// What to do if there is already a clip with the same name.
+        #[clap(long, value_enum, default_value_t = ConflictKind::Error)]
+        on_conflict: ConflictKind,
+        /// How to encode the clip.
+        #[clap(long, value_enum, default_value_t = ProfileKind::Voice)]
+        profile: ProfileKind,
+    },
+    /// Import every audio or video file in the given folder and its subfolders. Clips are
+    /// named after each file's title tag, or else its file name.
+    #[clap(arg_required_else_help = true)]
+    ImportDir {
+        /// The folder to import.
+        folder: String,
+        /// Only import files whose path within the folder matches one of these glob patterns,
+        /// like "*.flac" or "2023/**/*". Defaults to files with common audio and video
+        /// extensions.
+        #[clap(long)]
+        include: Vec<String>,
+        /// Skip files whose path within the folder matches one of these glob patterns.
+        #[clap(long)]
+        exclude: Vec<String>,
+        /// What to do if there is already a clip with the same name.
+        #[clap(long, value_enum, default_value_t = ConflictKind::Error)]
+        on_conflict: ConflictKind,
+        /// How to encode the clips.
+        #[clap(long, value_enum, default_value_t = ProfileKind::Voice)]
+        profile: ProfileKind,
+    },
+    /// Decode the clip with the given name and encode it again with another profile. Audio
+    /// lost by a lossy profile is not brought back.
+    #[clap(arg_required_else_help = true)]
+    Reencode {
+        /// The name of the clip to re-encode.
+        name: String,
+        /// How to encode the clip.
+        #[clap(long, value_enum)]
+        profile: ProfileKind,
     },
     /// Export the clip with the given name to the given path, as a wav file.
     #[clap(arg_required_else_help = true)]
@@ -81,13 +239,437 @@ enum Commands {
     #[clap(arg_required_else_help = true)]
     /// Export all clips to the given folder.
     ExportAll { folder: String },
+    /// Back up every clip to an archive at the given path, for restoring on another machine.
+    /// Unlike export-all, this keeps the audio as it is stored, with its metadata.
+    #[clap(arg_required_else_help = true)]
+    Backup {
+        /// The path to write the archive to.
+        path: String,
+    },
+    /// Restore clips from an archive made with the backup command. Backups of encrypted
+    /// journals ask for the passphrase the journal had when it was backed up.
+    #[clap(arg_required_else_help = true)]
+    Restore {
+        /// The path of the archive.
+        path: String,
+        /// Whether to merge the archive with the existing clips, or to replace them.
+        #[clap(long, value_enum, default_value_t = RestoreKind::Merge)]
+        mode: RestoreKind,
+    },
+    /// Encrypt the clips with a passphrase, which is asked for when the journal is next used.
+    /// Set OXYGEN_PASSPHRASE to avoid being asked. Recordings in progress and backups are
+    /// encrypted too.
+    Encrypt {},
+    /// Store the clips without encryption again.
+    Decrypt {},
+    /// Check the database and every clip, in the trash too, for damage, optionally moving
+    /// clips that can't be loaded into quarantine so the rest of the journal can be used.
+    Fsck {
+        /// Move clips that can't be listed or loaded to the quarantine table, where they are
+        /// kept as they were.
+        #[clap(long)]
+        quarantine: bool,
+        /// Rebuild the database file afterwards.
+        #[clap(long)]
+        vacuum: bool,
+    },
+    /// List recordings that were interrupted before they were saved, or recover one.
+    Recover {
+        /// The ID of the recording to recover, from the list.
+        id: Option<String>,
+        /// Recover all interrupted recordings.
+        #[clap(long, conflicts_with = "id")]
+        all: bool,
+        /// Delete the recording instead of recovering it.
+        #[clap(long, requires = "id")]
+        discard: bool,
+    },
+    /// Export acoustic features of the clip with the given name, one row per frame.
+    #[clap(arg_required_else_help = true)]
+    Features {
+        /// The name of the clip to analyze.
+        name: String,
+        /// The path to export to, ending in ".csv" or ".npy". If not specified, the features
+        /// are printed with the time each frame starts.
+        path: Option<String>,
+        /// Only the frames in which this speaker talks, by name or number. The clip must have
+        /// been diarized.
+        #[clap(long)]
+        speaker: Option<String>,
+        /// The kind of features to compute.
+        #[clap(long, value_enum, default_value_t = FeatureKind::Mfcc)]
+        kind: FeatureKind,
+        /// The window function applied to each frame.
+        #[clap(long, value_enum, default_value_t = WindowKind::Hann)]
+        window: WindowKind,
+        /// The sample rate the clip is resampled to before analysis.
+        #[clap(long, default_value_t = 12000)]
+        sample_rate: u32,
+        /// The number of samples in each frame.
+        #[clap(long, default_value_t = 2048)]
+        n_fft: usize,
+        /// The number of samples between frames.
+        #[clap(long, default_value_t = 200)]
+        hop: usize,
+        /// The number of mel bands (for log-mel and MFCC features).
+        #[clap(long, default_value_t = 40)]
+        n_mels: usize,
+        /// The number of coefficients (for MFCC features).
+        #[clap(long, default_value_t = 13)]
+        n_mfcc: usize,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum CollectionCommands {
+    /// List the collections, with the number of clips in each.
+    List {},
+    /// Create an empty collection.
+    #[clap(arg_required_else_help = true)]
+    Create {
+        /// The name of the collection.
+        name: String,
+    },
+    /// Rename a collection.
+    #[clap(arg_required_else_help = true)]
+    Rename {
+        /// The old name.
+        old_name: String,
+        /// The new name.
+        new_name: String,
+    },
+    /// Delete a collection. The clips in it are kept.
+    #[clap(arg_required_else_help = true)]
+    Delete {
+        /// The name of the collection.
+        name: String,
+    },
+    /// Add clips to the end of a collection.
+    #[clap(arg_required_else_help = true)]
+    Add {
+        /// The name of the collection.
+        collection: String,
+        /// The names of the clips to add.
+        #[clap(required = true)]
+        clips: Vec<String>,
+    },
+    /// Remove clips from a collection. The clips are kept.
+    #[clap(arg_required_else_help = true)]
+    Remove {
+        /// The name of the collection.
+        collection: String,
+        /// The names of the clips to remove.
+        #[clap(required = true)]
+        clips: Vec<String>,
+    },
+    /// Move a clip within a collection.
+    #[clap(arg_required_else_help = true)]
+    Move {
+        /// The name of the collection.
+        collection: String,
+        /// The name of the clip to move.
+        clip: String,
+        /// Where to move the clip to, counting from 1.
+        position: usize,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum SpeakerCommands {
+    /// List the speakers in a clip, with how long each talks for.
+    #[clap(arg_required_else_help = true)]
+    List {
+        /// The name of the clip.
+        clip: String,
+    },
+    /// List who speaks when in a clip.
+    #[clap(arg_required_else_help = true)]
+    Turns {
+        /// The name of the clip.
+        clip: String,
+    },
+    /// Name a speaker in a clip, or remove their name if none is given.
+    #[clap(arg_required_else_help = true)]
+    Name {
+        /// The name of the clip.
+        clip: String,
+        /// The speaker, by name or number.
+        speaker: String,
+        /// The new name.
+        name: Option<String>,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum TrashCommands {
+    /// List the deleted clips.
+    List {},
+    /// Restore the most recently deleted clip with the given name. It is renamed if the name
+    /// has been taken since.
+    #[clap(arg_required_else_help = true)]
+    Restore {
+        /// The name of the clip to restore.
+        name: String,
+    },
+    /// Permanently delete every clip in the trash.
+    Empty {},
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum ConflictKind {
+    /// Fail the import.
+    Error,
+    /// Keep the existing clip and don't import the file.
+    Skip,
+    /// Import the file under a new name, like "Name (2)".
+    Rename,
+    /// Replace the existing clip, moving it to the trash.
+    Overwrite,
+}
+
+impl From<ConflictKind> for ConflictPolicy {
+    fn from(conflict: ConflictKind) -> ConflictPolicy {
+        match conflict {
+            ConflictKind::Error => ConflictPolicy::Error,
+            ConflictKind::Skip => ConflictPolicy::Skip,
+            ConflictKind::Rename => ConflictPolicy::Rename,
+            ConflictKind::Overwrite => ConflictPolicy::Overwrite,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum ProfileKind {
+    /// Opus at 24 kbit/s, for speech.
+    Voice,
+    /// Opus at 64 kbit/s, for singing and instruments.
+    Music,
+    /// Opus at 128 kbit/s, for analysing harmonics.
+    HighFidelity,
+    /// FLAC, which keeps the audio exactly as recorded, in several times the space.
+    Lossless,
+}
+
+impl From<ProfileKind> for EncodingProfile {
+    fn from(profile: ProfileKind) -> EncodingProfile {
+        match profile {
+            ProfileKind::Voice => EncodingProfile::Voice,
+            ProfileKind::Music => EncodingProfile::Music,
+            ProfileKind::HighFidelity => EncodingProfile::HighFidelity,
+            ProfileKind::Lossless => EncodingProfile::Lossless,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum SortKind {
+    Date,
+    /// Ignoring case.
+    Name,
+    Duration,
+    /// The order of the collection given with --collection.
+    Position,
+}
+
+impl From<SortKind> for SortKey {
+    fn from(sort: SortKind) -> SortKey {
+        match sort {
+            SortKind::Date => SortKey::Date,
+            SortKind::Name => SortKey::Name,
+            SortKind::Duration => SortKey::Duration,
+            SortKind::Position => SortKey::Position,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum TranscriptKind {
+    /// SubRip subtitles.
+    Srt,
+    /// WebVTT subtitles.
+    Vtt,
+    /// Plain text, a line for each segment.
+    Text,
+    /// Segments with their words and times.
+    Json,
+}
+
+impl From<TranscriptKind> for TranscriptFormat {
+    fn from(format: TranscriptKind) -> TranscriptFormat {
+        match format {
+            TranscriptKind::Srt => TranscriptFormat::Srt,
+            TranscriptKind::Vtt => TranscriptFormat::WebVtt,
+            TranscriptKind::Text => TranscriptFormat::Text,
+            TranscriptKind::Json => TranscriptFormat::Json,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum RestoreKind {
+    /// Keep existing clips. Clips already in the journal are skipped, and clips whose name
+    /// is taken are renamed.
+    Merge,
+    /// Delete all existing clips first.
+    Replace,
+}
+
+impl From<RestoreKind> for RestoreMode {
+    fn from(mode: RestoreKind) -> RestoreMode {
+        match mode {
+            RestoreKind::Merge => RestoreMode::Merge,
+            RestoreKind::Replace => RestoreMode::Replace,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum FeatureKind {
+    /// Linear-frequency magnitude spectrogram.
+    Stft,
+    /// Mel-band power in dB.
+    LogMel,
+    /// Mel-frequency cepstral coefficients.
+    Mfcc,
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum WindowKind {
+    Hann,
+    Hamming,
+    Blackman,
+    Rectangular,
+}
+
+impl From<WindowKind> for Window {
+    fn from(window: WindowKind) -> Window {
+        match window {
+            WindowKind::Hann => Window::Hann,
+            WindowKind::Hamming => Window::Hamming,
+            WindowKind::Blackman => Window::Blackman,
+            WindowKind::Rectangular => Window::Rectangular,
+        }
+    }
+}
+
+/// Prints import progress to stderr, at most once per second of decoded audio.
+fn progress_reporter() -> impl FnMut(&str, ImportProgress) {
+    let mut last_report = 0.0;
+    move |path, progress| {
+        // Progress goes back to 0 when the next file of a batch starts.
+        if progress.decoded >= last_report && progress.decoded - last_report < 1.0 {
+            return;
+        }
+        last_report = progress.decoded;
+        match progress.fraction() {
+            Some(fraction) => eprint!("\rImporting {}... {:3.0}%", path, fraction * 100.0),
+            None => eprint!("\rImporting {}... {:.0}s", path, progress.decoded),
+        }
+    }
+}
+
+fn print_outcome(path: &str, outcome: &ImportOutcome) {
+    match outcome {
+        ImportOutcome::Imported(meta) => eprintln!("Imported {} as {}", path, meta.name),
+        ImportOutcome::Skipped(name) => {
+            eprintln!("Skipped {}, there is already a clip named {}", path, name)
+        }
+    }
+}
+
+/// Parses a `--since` date, in local time unless it has an offset.
+fn parse_since(date: &str) -> Result<DateTime<Utc>, String> {
+    parse_date(date, false)
+}
+
+/// Parses an `--until` date. A date without a time stands for the end of that day.
+fn parse_until(date: &str) -> Result<DateTime<Utc>, String> {
+    parse_date(date, true)
+}
+
+fn parse_date(date: &str, end_of_day: bool) -> Result<DateTime<Utc>, String> {
+    if let Ok(date) = DateTime::parse_from_rfc3339(date) {
+        return Ok(date.with_timezone(&Utc));
+    }
+    let local = match NaiveDateTime::parse_from_str(date, "%Y-%m-%d %H:%M:%S") {
+        Ok(date) => date,
+        Err(_) => {
+            let day = NaiveDate::parse_from_str(date, "%Y-%m-%d")
+                .map_err(|_| format!("Expected a date like 2023-05-01, got {:?}", date))?;
+            let day = if end_of_day {
+                day.succ_opt().unwrap_or(day)
+            } else {
+                day
+            };
+            day.and_time(NaiveTime::MIN)
+        }
+    };
+
+    Local
+        .from_local_datetime(&local)
+        .earliest()
+        .map(|date| date.with_timezone(&Utc))
+        .ok_or_else(|| format!("{:?} does not exist in the local time zone", date))
+}
+
+fn clip_id(db: &Db, name: &str) -> Result<usize> {
+    db.clip_id(name)?
+        .ok_or_else(|| eyre!("There is no clip named {}", name))
+}
+
+fn collection_id(db: &Db, name: &str) -> Result<usize> {
+    db.collection_id(name)?
+        .ok_or_else(|| eyre!("There is no collection named {}", name))
+}
+
+/// A speaker in a clip, by their name, ignoring case, or their number.
+fn speaker_id(db: &Db, clip_id: usize, speaker: &str) -> Result<usize> {
+    let speakers = db.speakers(clip_id)?;
+    if speakers.is_empty() {
+        return Err(eyre!("The clip has not been diarized"));
+    }
+    speakers
+        .iter()
+        .find(|other| other.label().to_lowercase() == speaker.to_lowercase())
+        .or_else(|| {
+            let id = speaker.parse::<usize>().ok()?;
+            speakers.iter().find(|other| other.id == id)
+        })
+        .map(|speaker| speaker.id)
+        .ok_or_else(|| eyre!("There is no speaker {} in the clip", speaker))
+}
+
+fn print_speakers(db: &Db, clip_id: usize, format: OutputFormat) -> Result<()> {
+    let mut table = Table::new(["id", "name", "speaking time"]);
+    for speaker in db.speakers(clip_id)? {
+        table.push(vec![
+            speaker.id.into(),
+            speaker.name.into(),
+            Cell::Seconds(speaker.speaking_time),
+        ]);
+    }
+    table.print(format)
+}
+
+/// The passphrase from OXYGEN_PASSPHRASE, or else asked for on the terminal.
+fn passphrase(prompt: &str) -> Result<String> {
+    match std::env::var("OXYGEN_PASSPHRASE") {
+        Ok(passphrase) => Ok(passphrase),
+        Err(_) => Ok(rpassword::prompt_password(prompt)?),
+    }
 }
 
 fn main() -> Result<()> {
     env_logger::init();
     color_eyre::install()?;
     let args = Cli::parse();
-    let db = Db::open()?;
+    let mut db = match &args.db {
+        Some(path) => Db::open_at(path)?,
+        None => Db::open()?,
+    };
+    if db.is_locked() {
+        db.unlock(&passphrase("Passphrase: ")?)?;
+    }
 
     #[cfg(feature = "jack")]
     let host = match args.jack {
@@ -99,12 +681,12 @@ fn main() -> Result<()> {
     let host = AudioBackend::Default;
 
     match args.command {
-        Commands::Record { name } => {
+        Commands::Record { name, profile } => {
             let name = name.unwrap_or_else(|| Local::now().format("%Y-%m-%d %H:%M:%S").to_string());
-            if db.load(&name)?.is_some() {
+            if db.name_exists(&name)? {
                 return Err(eyre!("There is already a clip named {}", name));
             }
-            let handle = AudioClip::record(host, name)?;
+            let handle = db.start_recording(host, name)?;
 
             let (tx, rx) = channel();
             ctrlc::set_handler(move || tx.send(()).expect("Could not send signal on channel."))?;
@@ -113,21 +695,54 @@ fn main() -> Result<()> {
             rx.recv()?;
             println!("Got it! Exiting...");
 
-            let mut clip = handle.stop();
+            let clip = db.finish_recording(handle, profile.into())?;
 
             eprintln!("Recorded {} samples", clip.samples.len());
-            db.save(&mut clip)?;
         }
-        Commands::List {} => {
-            println!("{:5} {:30} {:30}", "id", "name", "date");
-            for entry in db.list()? {
-                println!(
-                    "{:5} {:30} {:30}",
-                    entry.id,
-                    entry.name,
-                    entry.date.with_timezone(&Local).format("%Y-%m-%d %H:%M:%S")
-                )
+        Commands::List {
+            collection,
+            sort,
+            desc,
+            since,
+            until,
+            name,
+            limit,
+            offset,
+            json,
+        } => {
+            let collection = collection
+                .map(|collection| collection_id(&db, &collection))
+                .transpose()?;
+            let default_sort = match collection {
+                Some(_) => SortKey::Position,
+                None => SortKey::Date,
+            };
+            let clips = db.list(&ListQuery {
+                sort: sort.map(Into::into).unwrap_or(default_sort),
+                descending: desc,
+                since,
+                until,
+                name_contains: name,
+                collection,
+                limit,
+                offset,
+            })?;
+
+            let mut table = Table::new(["id", "name", "date", "duration", "notes"]);
+            for clip in clips {
+                table.push(vec![
+                    clip.id.into(),
+                    clip.name.into(),
+                    clip.date.into(),
+                    Cell::Seconds(clip.duration),
+                    clip.notes.into(),
+                ]);
             }
+            table.print(if json {
+                OutputFormat::Json
+            } else {
+                args.output
+            })?;
         }
         Commands::Play { name } => {
             if let Some(clip) = db.load(&name)? {
@@ -141,42 +756,322 @@ fn main() -> Result<()> {
                 return Err(eyre!("No such clip."));
             }
         }
-        Commands::Transcribe { name } => {
+        Commands::Transcribe {
+            name,
+            format,
+            out,
+            speaker,
+        } => {
+            let format = match (format, &out) {
+                (Some(format), _) => Some(format.into()),
+                (None, Some(out)) => Some(TranscriptFormat::from_extension(
+                    out.extension().and_then(OsStr::to_str).unwrap_or_default(),
+                )?),
+                (None, None) => None,
+            };
             let mut language_processor = LanguageProcessor::new()?;
-            if let Some(clip) = db.load(&name)? {
-                for segment in &language_processor.transcribe(&clip)? {
-                    println!(
-                        "{:10.3} - {:10.3} {:30}",
-                        (segment.0).0,
-                        (segment.0).1,
-                        segment.1
-                    )
+            let clip = db.load(&name)?.ok_or_else(|| eyre!("No such clip."))?;
+            let clip_id = clip.id.ok_or_else(|| eyre!("The clip has no ID"))?;
+            let speaker = speaker
+                .map(|speaker| speaker_id(&db, clip_id, &speaker))
+                .transpose()?;
+            let speakers = db.speakers(clip_id)?;
+
+            let mut segments = language_processor.transcribe(&clip)?;
+            diarization::label_segments(&mut segments, &db.speaker_turns(clip_id)?);
+            if let Some(speaker) = speaker {
+                segments.retain(|segment| segment.speaker == Some(speaker));
+            }
+            match (format, out) {
+                (Some(format), Some(out)) => {
+                    let mut writer = BufWriter::new(File::create(&out)?);
+                    transcript::write_transcript(&segments, &speakers, format, &mut writer)?;
+                    eprintln!("Wrote {} segments to {}", segments.len(), out.display());
+                }
+                (Some(format), None) => {
+                    transcript::write_transcript(
+                        &segments,
+                        &speakers,
+                        format,
+                        &mut std::io::stdout(),
+                    )?;
+                }
+                (None, _) => {
+                    let mut table = Table::new(["start", "end", "speaker", "text"]);
+                    for segment in segments {
+                        let speaker = speakers
+                            .iter()
+                            .find(|speaker| Some(speaker.id) == segment.speaker)
+                            .map(|speaker| speaker.label());
+                        table.push(vec![
+                            Cell::Number(segment.start),
+                            Cell::Number(segment.end),
+                            speaker.into(),
+                            segment.text.trim().into(),
+                        ]);
+                    }
+                    table.print(args.output)?;
                 }
-            } else {
-                return Err(eyre!("No such clip."));
             }
         }
+        Commands::Diarize {
+            name,
+            speakers,
+            threshold,
+        } => {
+            let clip = db.load(&name)?.ok_or_else(|| eyre!("No such clip."))?;
+            let clip_id = clip.id.ok_or_else(|| eyre!("The clip has no ID"))?;
+            let options = DiarizationOptions {
+                num_speakers: speakers,
+                threshold,
+            };
+            let turns = diarization::diarize(&clip, &options)?;
+            db.set_speaker_turns(clip_id, &turns)?;
+            print_speakers(&db, clip_id, args.output)?;
+        }
+        Commands::Speaker { command } => match command {
+            SpeakerCommands::List { clip } => {
+                print_speakers(&db, clip_id(&db, &clip)?, args.output)?;
+            }
+            SpeakerCommands::Turns { clip } => {
+                let clip_id = clip_id(&db, &clip)?;
+                let speakers = db.speakers(clip_id)?;
+                let mut table = Table::new(["start", "end", "speaker"]);
+                for turn in db.speaker_turns(clip_id)? {
+                    let speaker = speakers
+                        .iter()
+                        .find(|speaker| speaker.id == turn.speaker)
+                        .map(|speaker| speaker.label());
+                    table.push(vec![
+                        Cell::Number(turn.start),
+                        Cell::Number(turn.end),
+                        speaker.into(),
+                    ]);
+                }
+                table.print(args.output)?;
+            }
+            SpeakerCommands::Name {
+                clip,
+                speaker,
+                name,
+            } => {
+                let clip_id = clip_id(&db, &clip)?;
+                let speaker = speaker_id(&db, clip_id, &speaker)?;
+                db.name_speaker(clip_id, speaker, name.as_deref())?;
+            }
+        },
         Commands::Rename { old_name, new_name } => {
             db.rename(&old_name, &new_name)?;
         }
         Commands::Delete { name } => {
             db.delete(&name)?;
         }
-        Commands::Import { name, path } => {
-            let name = match name {
-                Some(name) => name,
-                None => Path::new(&path)
-                    .file_stem()
-                    .ok_or_else(|| eyre!("Invalid path: {}", path))?
-                    .to_str()
-                    .ok_or_else(|| eyre!("Path is not utf8"))?
-                    .to_string(),
+        Commands::Notes { name, notes } => {
+            db.set_notes(&name, notes.as_deref())?;
+        }
+        Commands::Undo {} => match db.undo()? {
+            Some(operation) => eprintln!("Undid: {}", operation),
+            None => eprintln!("Nothing to undo"),
+        },
+        Commands::Redo {} => match db.redo()? {
+            Some(operation) => eprintln!("Redid: {}", operation),
+            None => eprintln!("Nothing to redo"),
+        },
+        Commands::History {} => {
+            let mut table = Table::new(["date", "state", "clip", "change"]);
+            for entry in db.history()? {
+                table.push(vec![
+                    entry.date.into(),
+                    if entry.undone { "undone" } else { "done" }.into(),
+                    entry.operation.clip_id().into(),
+                    entry.operation.to_string().into(),
+                ]);
+            }
+            table.print(args.output)?;
+        }
+        Commands::Collection { command } => match command {
+            CollectionCommands::List {} => {
+                let mut table = Table::new(["id", "name", "clips"]);
+                for collection in db.collections()? {
+                    table.push(vec![
+                        collection.id.into(),
+                        collection.name.into(),
+                        collection.clip_count.into(),
+                    ]);
+                }
+                table.print(args.output)?;
+            }
+            CollectionCommands::Create { name } => {
+                db.create_collection(&name)?;
+            }
+            CollectionCommands::Rename { old_name, new_name } => {
+                db.rename_collection(collection_id(&db, &old_name)?, &new_name)?;
+            }
+            CollectionCommands::Delete { name } => {
+                db.delete_collection(collection_id(&db, &name)?)?;
+            }
+            CollectionCommands::Add { collection, clips } => {
+                let id = collection_id(&db, &collection)?;
+                for clip in clips {
+                    db.add_to_collection(id, clip_id(&db, &clip)?)?;
+                }
+            }
+            CollectionCommands::Remove { collection, clips } => {
+                let id = collection_id(&db, &collection)?;
+                for clip in clips {
+                    db.remove_from_collection(id, clip_id(&db, &clip)?)?;
+                }
+            }
+            CollectionCommands::Move {
+                collection,
+                clip,
+                position,
+            } => {
+                if position == 0 {
+                    return Err(eyre!("Positions start at 1"));
+                }
+                db.move_in_collection(
+                    collection_id(&db, &collection)?,
+                    clip_id(&db, &clip)?,
+                    position - 1,
+                )?;
+            }
+        },
+        Commands::Trash { command } => match command {
+            TrashCommands::List {} => {
+                let mut table = Table::new(["id", "name", "date", "duration", "deleted"]);
+                for entry in db.trash()? {
+                    table.push(vec![
+                        entry.meta.id.into(),
+                        entry.meta.name.into(),
+                        entry.meta.date.into(),
+                        Cell::Seconds(entry.meta.duration),
+                        entry.deleted.into(),
+                    ]);
+                }
+                table.print(args.output)?;
+            }
+            TrashCommands::Restore { name } => {
+                let entry = db
+                    .trash()?
+                    .into_iter()
+                    .rev()
+                    .find(|entry| entry.meta.name == name)
+                    .ok_or_else(|| eyre!("There is no clip named {} in the trash", name))?;
+                let restored = db.restore_from_trash(entry.meta.id)?;
+                if restored == name {
+                    eprintln!("Restored {}", name);
+                } else {
+                    eprintln!("Restored {} as {}", name, restored);
+                }
+            }
+            TrashCommands::Empty {} => {
+                let count = db.empty_trash()?;
+                eprintln!("Permanently deleted {} clips", count);
+            }
+        },
+        Commands::Import {
+            name,
+            path,
+            track,
+            channel,
+            on_conflict,
+            profile,
+        } => {
+            let cancel = Arc::new(AtomicBool::new(false));
+            let handler_cancel = cancel.clone();
+            ctrlc::set_handler(move || handler_cancel.store(true, Ordering::Relaxed))?;
+
+            let mut report = progress_reporter();
+            let options = ImportOptions {
+                track,
+                channel,
+                conflict: on_conflict.into(),
+                profile: profile.into(),
             };
-            if db.load(&name)?.is_some() {
-                return Err(eyre!("There is already a clip named {}", name));
+            let outcome = import::import_file(
+                &db,
+                name.as_deref(),
+                Path::new(&path),
+                &options,
+                &cancel,
+                |progress| report(&path, progress),
+            );
+            eprintln!();
+            print_outcome(&path, &outcome?);
+        }
+        Commands::ImportDir {
+            folder,
+            include,
+            exclude,
+            on_conflict,
+            profile,
+        } => {
+            let cancel = Arc::new(AtomicBool::new(false));
+            let handler_cancel = cancel.clone();
+            ctrlc::set_handler(move || handler_cancel.store(true, Ordering::Relaxed))?;
+
+            let parse = |patterns: Vec<String>| {
+                patterns
+                    .iter()
+                    .map(|pattern| glob::Pattern::new(pattern))
+                    .collect::<Result<Vec<_>, _>>()
+            };
+            let paths = import::find_files(Path::new(&folder), &parse(include)?, &parse(exclude)?)?;
+            let options = ImportOptions {
+                conflict: on_conflict.into(),
+                profile: profile.into(),
+                ..Default::default()
+            };
+
+            let mut report = progress_reporter();
+            let mut current = None;
+            let results = import::import_files(&db, &paths, &options, &cancel, |i, progress| {
+                if current != Some(i) {
+                    if current.is_some() {
+                        eprintln!();
+                    }
+                    current = Some(i);
+                }
+                report(&paths[i].to_string_lossy(), progress)
+            });
+            if current.is_some() {
+                eprintln!();
+            }
+
+            let mut failed = 0;
+            for batch in &results {
+                let path = batch.path.to_string_lossy();
+                match &batch.result {
+                    Ok(outcome) => print_outcome(&path, outcome),
+                    Err(err) => {
+                        failed += 1;
+                        eprintln!("Could not import {}: {}", path, err);
+                    }
+                }
+            }
+            if results.len() < paths.len() {
+                return Err(eyre!(
+                    "Import cancelled, {} files were not imported.",
+                    paths.len() - results.len()
+                ));
             }
-            let mut clip = AudioClip::import(name, path)?;
-            db.save(&mut clip)?;
+            if failed > 0 {
+                return Err(eyre!(
+                    "{} of {} files could not be imported.",
+                    failed,
+                    paths.len()
+                ));
+            }
+        }
+        Commands::Reencode { name, profile } => {
+            let profile = EncodingProfile::from(profile);
+            let clip = db.reencode(&name, profile)?;
+            eprintln!(
+                "Re-encoded {} samples as {}",
+                clip.samples.len(),
+                profile.name()
+            );
         }
         Commands::Export { name, path } => {
             if let Some(clip) = db.load(&name)? {
@@ -195,8 +1090,8 @@ fn main() -> Result<()> {
                 return Err(eyre!("Expected {} to be empty.", folder));
             }
 
-            for entry in db.list()? {
-                if let Some(clip) = db.load(&entry.name)? {
+            for entry in db.list(&ListQuery::default())? {
+                if let Some(clip) = db.load_by_id(entry.id)? {
                     let safe_name = Path::new(&entry.name)
                         .file_name()
                         .unwrap_or_else(|| OsStr::new("invalid"))
@@ -216,6 +1111,209 @@ fn main() -> Result<()> {
 
             eprintln!("Exported to {}", folder);
         }
+        Commands::Backup { path } => {
+            let count = backup::backup(&db, Path::new(&path))?;
+            eprintln!("Backed up {} clips to {}", count, path);
+        }
+        Commands::Restore { path, mode } => {
+            let path = Path::new(&path);
+            let passphrase = match backup::is_encrypted(path)? {
+                true => Some(passphrase("Passphrase of the backup: ")?),
+                false => None,
+            };
+            let summary = backup::restore(&db, path, mode.into(), passphrase.as_deref())?;
+            eprintln!(
+                "Restored {} clips ({} renamed), skipped {} already in the journal",
+                summary.restored, summary.renamed, summary.skipped
+            );
+        }
+        Commands::Encrypt {} => {
+            if db.is_encrypted() {
+                return Err(eyre!("The journal is already encrypted"));
+            }
+            let passphrase = passphrase("New passphrase: ")?;
+            if passphrase.is_empty() {
+                return Err(eyre!("The passphrase can't be empty"));
+            }
+            if std::env::var("OXYGEN_PASSPHRASE").is_err()
+                && rpassword::prompt_password("Repeat the passphrase: ")? != passphrase
+            {
+                return Err(eyre!("The passphrases don't match"));
+            }
+            db.encrypt(&passphrase)?;
+            eprintln!("Encrypted the journal. There is no way to recover the clips without the passphrase.");
+        }
+        Commands::Decrypt {} => {
+            db.decrypt()?;
+            eprintln!("Decrypted the journal");
+        }
+        Commands::Fsck { quarantine, vacuum } => {
+            let options = FsckOptions { quarantine, vacuum };
+            let report = fsck::fsck(&db, &options, |checked, total| {
+                eprint!("\rChecked {}/{} clips", checked, total);
+            })?;
+            eprintln!();
+            let mut table = Table::new(["id", "name", "trashed", "problem", "quarantined"]);
+            for error in &report.integrity_errors {
+                table.push(vec![
+                    Cell::Missing,
+                    Cell::Missing,
+                    Cell::Missing,
+                    format!("database: {}", error).into(),
+                    false.into(),
+                ]);
+            }
+            for problem in &report.problems {
+                table.push(vec![
+                    problem.id.into(),
+                    problem.name.clone().into(),
+                    problem.trashed.into(),
+                    problem.kind.to_string().into(),
+                    problem.quarantined.into(),
+                ]);
+            }
+            table.print(args.output)?;
+            eprintln!(
+                "Checked {} clips, found {} problem(s)",
+                report.clips_checked,
+                report.problems.len()
+            );
+            if report.has_unresolved() {
+                return Err(eyre!("The journal has problems"));
+            }
+        }
+        Commands::Recover { id, all, discard } => match id {
+            Some(id) if discard => {
+                db.discard_recording(&id)?;
+            }
+            Some(id) => {
+                let clip = db.recover_recording(&id)?;
+                eprintln!("Recovered {}", clip.name);
+            }
+            None if all => {
+                for recording in db.unfinished_recordings()? {
+                    let clip = db.recover_recording(&recording.id())?;
+                    eprintln!("Recovered {}", clip.name);
+                }
+            }
+            None => {
+                let mut table = Table::new(["id", "name", "date", "duration"]);
+                for recording in db.unfinished_recordings()? {
+                    table.push(vec![
+                        recording.id().into(),
+                        recording.name.clone().into(),
+                        recording.date.into(),
+                        Cell::Seconds(recording.duration()),
+                    ]);
+                }
+                table.print(args.output)?;
+            }
+        },
+        Commands::Features {
+            name,
+            path,
+            speaker,
+            kind,
+            window,
+            sample_rate,
+            n_fft,
+            hop,
+            n_mels,
+            n_mfcc,
+        } => {
+            if let Some(path) = path.as_ref() {
+                if !path.ends_with(".csv") && !path.ends_with(".npy") {
+                    return Err(eyre!("Expected {} to end in .csv or .npy", path));
+                }
+            }
+
+            let clip = db.load(&name)?.ok_or_else(|| eyre!("No such clip."))?;
+            let clip_id = clip.id.ok_or_else(|| eyre!("The clip has no ID"))?;
+            // Resampled once here rather than for each range.
+            let clip = clip.resample(sample_rate);
+            let ranges = match speaker {
+                Some(speaker) => diarization::speaker_ranges(
+                    &db.speaker_turns(clip_id)?,
+                    speaker_id(&db, clip_id, &speaker)?,
+                    sample_rate,
+                ),
+                None => vec![(0, clip.samples.len())],
+            };
+            let mel = MelOptions {
+                stft: StftOptions {
+                    sample_rate,
+                    n_fft,
+                    hop,
+                    window: window.into(),
+                },
+                n_mels,
+                min_freq: 0.0,
+                max_freq: (sample_rate / 2) as f32,
+            };
+            let mut matrix = Matrix::new(match kind {
+                FeatureKind::Stft => mel.stft.num_bins(),
+                FeatureKind::LogMel => n_mels,
+                FeatureKind::Mfcc => n_mfcc,
+            });
+            // When each frame starts, in seconds.
+            let mut times = Vec::new();
+            for range in ranges {
+                let part = match kind {
+                    FeatureKind::Stft => features::stft(&clip.samples, range, &mel.stft)?,
+                    FeatureKind::LogMel => {
+                        features::log_mel_spectrogram(&clip.samples, range, &mel)?
+                    }
+                    FeatureKind::Mfcc => {
+                        features::mfcc(&clip.samples, range, &MfccOptions { mel, n_mfcc })?
+                    }
+                };
+                // Frames start at multiples of the hop.
+                let first = range.0 / hop * hop;
+                for (i, row) in part.iter_rows().enumerate() {
+                    times.push((first + i * hop) as f64 / sample_rate as f64);
+                    matrix.push_row(row);
+                }
+            }
+
+            let path = match path {
+                Some(path) => path,
+                None => {
+                    let prefix = match kind {
+                        FeatureKind::Stft => "bin",
+                        FeatureKind::LogMel => "mel",
+                        FeatureKind::Mfcc => "mfcc",
+                    };
+                    let mut table = Table::new(
+                        ["time".to_string()]
+                            .into_iter()
+                            .chain((0..matrix.cols()).map(|i| format!("{}_{}", prefix, i))),
+                    );
+                    for (&time, row) in times.iter().zip(matrix.iter_rows()) {
+                        table.push(
+                            [Cell::Number(time)]
+                                .into_iter()
+                                .chain(row.iter().map(|&x| x.into()))
+                                .collect(),
+                        );
+                    }
+                    return table.print(args.output);
+                }
+            };
+
+            let mut writer = BufWriter::new(File::create(&path)?);
+            if path.ends_with(".npy") {
+                matrix.write_npy(&mut writer)?;
+            } else {
+                matrix.write_csv(&mut writer)?;
+            }
+
+            eprintln!(
+                "Exported {} frames of {} values to {}",
+                matrix.rows(),
+                matrix.cols(),
+                path
+            );
+        }
     }
 
     Ok(())
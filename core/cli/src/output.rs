@@ -0,0 +1,281 @@
+use std::io::{self, Write};
+
+use chrono::prelude::*;
+use clap::ValueEnum;
+use color_eyre::eyre::Result;
+use serde_json::{Map, Value};
+
+/// How commands print what they list.
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// Aligned columns, for reading.
+    #[default]
+    Table,
+    /// An array with an object for each row.
+    Json,
+    /// Comma-separated values with a header row.
+    Csv,
+}
+
+/// One value in a table.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Cell {
+    Text(String),
+    Integer(i64),
+    Number(f64),
+    /// A length of time in seconds.
+    Seconds(f64),
+    Date(DateTime<Utc>),
+    Bool(bool),
+    Missing,
+}
+
+impl Cell {
+    /// The value as shown in a table, for reading.
+    fn display(&self) -> String {
+        match self {
+            Cell::Text(text) => text.clone(),
+            Cell::Integer(n) => n.to_string(),
+            Cell::Number(x) => format!("{:.3}", x),
+            Cell::Seconds(seconds) => format!("{:.1}s", seconds),
+            Cell::Date(date) => date
+                .with_timezone(&Local)
+                .format("%Y-%m-%d %H:%M:%S")
+                .to_string(),
+            Cell::Bool(true) => "yes".into(),
+            Cell::Bool(false) => "no".into(),
+            Cell::Missing => String::new(),
+        }
+    }
+
+    /// The value as written to a CSV file, in full precision and with dates in UTC.
+    fn raw(&self) -> String {
+        match self {
+            Cell::Number(x) | Cell::Seconds(x) => x.to_string(),
+            Cell::Date(date) => date.to_rfc3339(),
+            Cell::Bool(b) => b.to_string(),
+            _ => self.display(),
+        }
+    }
+
+    fn to_json(&self) -> Value {
+        match self {
+            Cell::Text(text) => text.clone().into(),
+            Cell::Integer(n) => (*n).into(),
+            Cell::Number(x) | Cell::Seconds(x) => (*x).into(),
+            Cell::Date(date) => date.to_rfc3339().into(),
+            Cell::Bool(b) => (*b).into(),
+            Cell::Missing => Value::Null,
+        }
+    }
+
+    fn is_numeric(&self) -> bool {
+        matches!(self, Cell::Integer(_) | Cell::Number(_) | Cell::Seconds(_))
+    }
+}
+
+impl From<String> for Cell {
+    fn from(text: String) -> Cell {
+        Cell::Text(text)
+    }
+}
+
+impl From<&str> for Cell {
+    fn from(text: &str) -> Cell {
+        Cell::Text(text.into())
+    }
+}
+
+impl From<usize> for Cell {
+    fn from(n: usize) -> Cell {
+        Cell::Integer(n as i64)
+    }
+}
+
+impl From<f32> for Cell {
+    fn from(x: f32) -> Cell {
+        Cell::Number(x.into())
+    }
+}
+
+impl From<bool> for Cell {
+    fn from(b: bool) -> Cell {
+        Cell::Bool(b)
+    }
+}
+
+impl From<DateTime<Utc>> for Cell {
+    fn from(date: DateTime<Utc>) -> Cell {
+        Cell::Date(date)
+    }
+}
+
+impl<T: Into<Cell>> From<Option<T>> for Cell {
+    fn from(value: Option<T>) -> Cell {
+        value.map(Into::into).unwrap_or(Cell::Missing)
+    }
+}
+
+/// Rows of values under named columns, printed in any `OutputFormat`.
+pub struct Table {
+    columns: Vec<String>,
+    rows: Vec<Vec<Cell>>,
+}
+
+impl Table {
+    pub fn new<S: Into<String>>(columns: impl IntoIterator<Item = S>) -> Table {
+        Table {
+            columns: columns.into_iter().map(Into::into).collect(),
+            rows: Vec::new(),
+        }
+    }
+
+    pub fn push(&mut self, row: Vec<Cell>) {
+        debug_assert_eq!(row.len(), self.columns.len());
+        self.rows.push(row);
+    }
+
+    /// Print the table to stdout.
+    pub fn print(&self, format: OutputFormat) -> Result<()> {
+        let stdout = io::stdout();
+        self.write(format, &mut stdout.lock())
+    }
+
+    pub fn write(&self, format: OutputFormat, writer: &mut impl Write) -> Result<()> {
+        match format {
+            OutputFormat::Table => self.write_table(writer),
+            OutputFormat::Json => self.write_json(writer),
+            OutputFormat::Csv => self.write_csv(writer),
+        }
+    }
+
+    /// Write aligned columns, each as wide as its widest value so long names don't break
+    /// the alignment. Numbers are aligned to the right.
+    fn write_table(&self, writer: &mut impl Write) -> Result<()> {
+        let rows: Vec<Vec<String>> = self
+            .rows
+            .iter()
+            .map(|row| row.iter().map(Cell::display).collect())
+            .collect();
+        let widths: Vec<usize> = (0..self.columns.len())
+            .map(|i| {
+                rows.iter()
+                    .map(|row| row[i].chars().count())
+                    .chain([self.columns[i].chars().count()])
+                    .max()
+                    .unwrap_or_default()
+            })
+            .collect();
+        let numeric: Vec<bool> = (0..self.columns.len())
+            .map(|i| self.rows.iter().any(|row| row[i].is_numeric()))
+            .collect();
+
+        let header: Vec<&str> = self.columns.iter().map(String::as_str).collect();
+        for row in [header].into_iter().chain(
+            rows.iter()
+                .map(|row| row.iter().map(String::as_str).collect()),
+        ) {
+            let line: Vec<String> = row
+                .iter()
+                .enumerate()
+                .map(|(i, value)| match numeric[i] {
+                    true => format!("{:>1$}", value, widths[i]),
+                    false => format!("{:1$}", value, widths[i]),
+                })
+                .collect();
+            writeln!(writer, "{}", line.join("  ").trim_end())?;
+        }
+
+        Ok(())
+    }
+
+    fn write_json(&self, writer: &mut impl Write) -> Result<()> {
+        let rows: Vec<Value> = self
+            .rows
+            .iter()
+            .map(|row| {
+                let object: Map<String, Value> = self
+                    .columns
+                    .iter()
+                    .cloned()
+                    .zip(row.iter().map(Cell::to_json))
+                    .collect();
+                Value::Object(object)
+            })
+            .collect();
+        serde_json::to_writer_pretty(&mut *writer, &rows)?;
+        writeln!(writer)?;
+
+        Ok(())
+    }
+
+    fn write_csv(&self, writer: &mut impl Write) -> Result<()> {
+        let header: Vec<String> = self.columns.iter().map(|name| csv_field(name)).collect();
+        writeln!(writer, "{}", header.join(","))?;
+        for row in &self.rows {
+            let line: Vec<String> = row.iter().map(|cell| csv_field(&cell.raw())).collect();
+            writeln!(writer, "{}", line.join(","))?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Quote a CSV field if it contains a comma, quote or line break.
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write(table: &Table, format: OutputFormat) -> String {
+        let mut out = Vec::new();
+        table.write(format, &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn test_formats() {
+        let mut table = Table::new(["id", "name", "duration", "notes"]);
+        table.push(vec![
+            1.into(),
+            "Warmup".into(),
+            Cell::Seconds(2.5),
+            None::<String>.into(),
+        ]);
+        table.push(vec![
+            12.into(),
+            "A \"long\", long name".into(),
+            Cell::Seconds(10.25),
+            "Scales".into(),
+        ]);
+
+        assert_eq!(
+            write(&table, OutputFormat::Table),
+            "id  name                 duration  notes\n \
+             1  Warmup                   2.5s\n\
+             12  A \"long\", long name     10.2s  Scales\n"
+        );
+        assert_eq!(
+            write(&table, OutputFormat::Csv),
+            "id,name,duration,notes\n1,Warmup,2.5,\n12,\"A \"\"long\"\", long name\",10.25,Scales\n"
+        );
+        let json: Value = serde_json::from_str(&write(&table, OutputFormat::Json)).unwrap();
+        assert_eq!(
+            json[1],
+            serde_json::json!({
+                "id": 12,
+                "name": "A \"long\", long name",
+                "duration": 10.25,
+                "notes": "Scales",
+            })
+        );
+        assert_eq!(json[0]["notes"], Value::Null);
+    }
+}
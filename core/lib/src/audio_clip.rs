@@ -2,87 +2,130 @@ use chrono::prelude::*;
 use color_eyre::eyre::{eyre, Result, WrapErr};
 use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
 use cpal::{Host, HostUnavailable, Sample, Stream};
-use dasp::{interpolate::linear::Linear, signal, Signal};
-use std::fs::File;
-use std::path::Path;
+use rtrb::{Consumer, Producer, RingBuffer};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
-use symphonia::core::audio::SampleBuffer;
-use symphonia::core::codecs::DecoderOptions;
-use symphonia::core::errors::Error;
-use symphonia::core::formats::FormatOptions;
-use symphonia::core::io::MediaSourceStream;
-use symphonia::core::meta::MetadataOptions;
-use symphonia::core::probe::Hint;
+use std::thread::JoinHandle;
+use std::time::Duration;
 
-use crate::spectrum;
+use crate::encryption::Cipher;
+use crate::import::{ImportOptions, ImportSource};
+use crate::peaks::PeakPyramid;
+use crate::recovery::JournalWriter;
+use crate::render::{self, RenderOptions, RenderedImage};
+use crate::resample::{self, Quality};
+use crate::spectrum::{self, SpectrogramCache, SpectrogramOptions};
 
 pub struct RecordState {
     clip: AudioClip,
+    journal: Option<JournalWriter>,
 }
 
 pub struct RecordHandle {
     stream: Stream,
     /// Option is only taken in "stop".
     clip: Arc<Mutex<Option<RecordState>>>,
+    /// Moves samples from the audio thread into `clip`.
+    consumer: JoinHandle<()>,
+    stopped: Arc<AtomicBool>,
+    dropped_frames: Arc<AtomicUsize>,
+    journal_path: Option<PathBuf>,
 }
 
 impl RecordHandle {
     pub fn stop(self) -> AudioClip {
         drop(self.stream);
-        let clip = self.clip.lock().unwrap().take().unwrap().clip;
+        self.stopped.store(true, Ordering::Release);
+        if self.consumer.join().is_err() {
+            log::error!("Recording thread panicked");
+        }
+
+        let state = self.clip.lock().unwrap().take().unwrap();
+        if let Some(journal) = state.journal {
+            if let Err(err) = journal.finish() {
+                log::error!("Could not finish journal: {:?}", err);
+            }
+        }
+        let clip = state.clip;
         log::info!("Recorded clip has {} samples", clip.samples.len());
+        let dropped_frames = self.dropped_frames.load(Ordering::Relaxed);
+        if dropped_frames > 0 {
+            log::warn!("Dropped {} frames while recording", dropped_frames);
+        }
         clip
     }
+
+    /// The journal the recording is written to as it is recorded, if any.
+    pub fn journal_path(&self) -> Option<&Path> {
+        self.journal_path.as_deref()
+    }
 }
 
 type RecordStateHandle = Arc<Mutex<Option<RecordState>>>;
 
+/// Playback state shared with the audio thread, which never waits on a lock.
 struct PlaybackState {
-    time: usize,
-    samples: Vec<f32>,
-    changed_cbs: Vec<Box<dyn Fn() + Send>>,
-    changed_cbs_triggered_at: usize,
-    done_cbs: Vec<Box<dyn Fn() + Send>>,
+    /// The next sample to play.
+    time: AtomicUsize,
+    /// A sample to jump to, or `usize::MAX`.
+    seek: AtomicUsize,
+    num_samples: usize,
     sample_rate: usize,
+    stopped: AtomicBool,
+    changed_cbs: Mutex<Vec<Box<dyn Fn() + Send>>>,
+    done_cbs: Mutex<Vec<Box<dyn Fn() + Send>>>,
 }
 
-type PlaybackStateHandle = Arc<Mutex<Option<PlaybackState>>>;
+impl PlaybackState {
+    fn time(&self) -> usize {
+        match self.seek.load(Ordering::Acquire) {
+            usize::MAX => self.time.load(Ordering::Acquire),
+            seek => seek,
+        }
+    }
+}
 
 pub struct PlayHandle {
     _stream: Stream,
-    state: PlaybackStateHandle,
+    state: Arc<PlaybackState>,
 }
 
 impl PlayHandle {
     pub fn connect_changed<F: Fn() + 'static + Send>(&self, f: F) {
-        let mut state = self.state.lock().unwrap();
-        let state = state.as_mut().unwrap();
-        state.changed_cbs.push(Box::new(f));
+        self.state.changed_cbs.lock().unwrap().push(Box::new(f));
     }
 
     pub fn connect_done<F: Fn() + 'static + Send>(&self, f: F) {
-        let mut state = self.state.lock().unwrap();
-        let state = state.as_mut().unwrap();
-
-        if state.time >= state.samples.len() {
+        if self.state.time() >= self.state.num_samples {
             f();
         } else {
-            state.done_cbs.push(Box::new(f));
+            self.state.done_cbs.lock().unwrap().push(Box::new(f));
         }
     }
 
     pub fn seek(&self, time: f64) {
-        let mut state = self.state.lock().unwrap();
-        let state = state.as_mut().unwrap();
-        state.time = (time * state.sample_rate as f64) as usize;
-        state.changed_cbs_triggered_at = 0;
+        let time = ((time * self.state.sample_rate as f64) as usize).min(self.state.num_samples);
+        self.state.seek.store(time, Ordering::Release);
+    }
+}
+
+impl Drop for PlayHandle {
+    fn drop(&mut self) {
+        self.state.stopped.store(true, Ordering::Release);
     }
 }
 
+/// How often recorded samples are collected, and playback callbacks are called.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
 pub trait StreamHandle {
     fn sample_rate(&self) -> u32;
     fn samples(&self) -> usize;
     fn time(&self) -> f64;
+    /// Number of frames lost because the audio thread got ahead of the app. Playback reads from
+    /// memory, so it never drops frames.
+    fn dropped_frames(&self) -> usize;
 }
 
 impl StreamHandle for RecordHandle {
@@ -106,49 +149,62 @@ impl StreamHandle for RecordHandle {
 
         (state.clip.samples.len() as f64) / (state.clip.sample_rate as f64)
     }
+
+    fn dropped_frames(&self) -> usize {
+        self.dropped_frames.load(Ordering::Relaxed)
+    }
 }
 
 impl StreamHandle for PlayHandle {
     fn sample_rate(&self) -> u32 {
-        let mut state = self.state.lock().unwrap();
-        let state = state.as_mut().unwrap();
-
-        state.sample_rate as u32
+        self.state.sample_rate as u32
     }
 
     fn samples(&self) -> usize {
-        let mut state = self.state.lock().unwrap();
-        let state = state.as_mut().unwrap();
-
-        state.samples.len()
+        self.state.num_samples
     }
 
     fn time(&self) -> f64 {
-        let mut state = self.state.lock().unwrap();
-        let state = state.as_mut().unwrap();
+        (self.state.time() as f64) / (self.state.sample_rate as f64)
+    }
 
-        (state.time as f64) / (state.sample_rate as f64)
+    fn dropped_frames(&self) -> usize {
+        0
     }
 }
 
 pub trait ClipHandle {
-    fn render_waveform(&self, range: (usize, usize), width: usize, height: usize) -> Vec<u8>;
+    fn render_waveform(
+        &self,
+        range: (usize, usize),
+        width: usize,
+        height: usize,
+        options: &RenderOptions,
+    ) -> RenderedImage;
     fn render_spectrogram(
         &self,
         range: (usize, usize),
         width: usize,
         height: usize,
-    ) -> Result<Vec<u8>>;
+        options: &SpectrogramOptions,
+        render_options: &RenderOptions,
+    ) -> Result<RenderedImage>;
     fn num_samples(&self) -> usize;
     fn sample_rate(&self) -> usize;
 }
 
 impl ClipHandle for RecordHandle {
-    fn render_waveform(&self, range: (usize, usize), width: usize, height: usize) -> Vec<u8> {
+    fn render_waveform(
+        &self,
+        range: (usize, usize),
+        width: usize,
+        height: usize,
+        options: &RenderOptions,
+    ) -> RenderedImage {
         let mut state = self.clip.lock().unwrap();
         let state = state.as_mut().unwrap();
 
-        state.clip.render_waveform(range, width, height)
+        state.clip.render_waveform(range, width, height, options)
     }
 
     fn render_spectrogram(
@@ -156,13 +212,23 @@ impl ClipHandle for RecordHandle {
         range: (usize, usize),
         width: usize,
         height: usize,
-    ) -> Result<Vec<u8>> {
-        let mut lock = self.clip.lock().unwrap();
-        let state = lock.as_mut().unwrap();
-        let clip = state.clip.clone();
+        options: &SpectrogramOptions,
+        render_options: &RenderOptions,
+    ) -> Result<RenderedImage> {
+        options.validate()?;
+
+        // Only copy the samples recorded since the last draw, and don't hold the lock while
+        // computing the spectrogram.
+        let lock = self.clip.lock().unwrap();
+        let clip = &lock.as_ref().unwrap().clip;
+        let cache = clip.cache.spectrogram.clone();
+        let mut cache = cache.lock().unwrap();
+        cache.prepare(&options.stft_options(), clip.sample_rate)?;
+        let new_samples = clip.samples[cache.source_len().min(clip.samples.len())..].to_vec();
         drop(lock);
 
-        clip.render_spectrogram(range, width, height)
+        cache.extend(&new_samples);
+        cache.render(range, width, height, options, render_options)
     }
 
     fn num_samples(&self) -> usize {
@@ -181,8 +247,14 @@ impl ClipHandle for RecordHandle {
 }
 
 impl ClipHandle for AudioClip {
-    fn render_waveform(&self, range: (usize, usize), width: usize, height: usize) -> Vec<u8> {
-        self.render_waveform(range, width, height)
+    fn render_waveform(
+        &self,
+        range: (usize, usize),
+        width: usize,
+        height: usize,
+        options: &RenderOptions,
+    ) -> RenderedImage {
+        self.render_waveform(range, width, height, options)
     }
 
     fn render_spectrogram(
@@ -190,8 +262,10 @@ impl ClipHandle for AudioClip {
         range: (usize, usize),
         width: usize,
         height: usize,
-    ) -> Result<Vec<u8>> {
-        self.render_spectrogram(range, width, height)
+        options: &SpectrogramOptions,
+        render_options: &RenderOptions,
+    ) -> Result<RenderedImage> {
+        self.render_spectrogram(range, width, height, options, render_options)
     }
 
     fn num_samples(&self) -> usize {
@@ -209,13 +283,78 @@ pub struct AudioClip {
     pub id: Option<usize>,
     pub name: String,
     pub date: DateTime<Utc>,
+    /// Free-form notes, e.g. the artist and comments of an imported file.
+    pub notes: Option<String>,
     pub samples: Vec<f32>,
     pub sample_rate: u32,
+    pub cache: ClipCache,
+}
+
+/// Data derived from a clip's samples to make drawing it fast.
+///
+/// Clones of a clip share its cache. The cache assumes samples are only ever appended, so
+/// create a new cache when replacing a clip's samples.
+#[derive(Clone, Default)]
+pub struct ClipCache {
+    pub spectrogram: Arc<Mutex<SpectrogramCache>>,
+    pub peaks: Arc<Mutex<PeakPyramid>>,
 }
 
+impl ClipCache {
+    pub fn with_peaks(peaks: PeakPyramid) -> ClipCache {
+        ClipCache {
+            peaks: Arc::new(Mutex::new(peaks)),
+            ..ClipCache::default()
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
 pub struct DisplayColumn {
     pub min: f32,
     pub max: f32,
+    /// Sum of the squared samples.
+    pub energy: f32,
+    /// Number of samples.
+    pub len: usize,
+}
+
+impl DisplayColumn {
+    /// A column with no samples; `min` is greater than `max`.
+    pub const EMPTY: DisplayColumn = DisplayColumn {
+        min: f32::INFINITY,
+        max: f32::NEG_INFINITY,
+        energy: 0.0,
+        len: 0,
+    };
+
+    pub fn from_samples(samples: &[f32]) -> DisplayColumn {
+        samples
+            .iter()
+            .fold(DisplayColumn::EMPTY, |column, sample| DisplayColumn {
+                min: column.min.min(*sample),
+                max: column.max.max(*sample),
+                energy: column.energy + sample * sample,
+                len: column.len + 1,
+            })
+    }
+
+    pub fn merge(&self, other: &DisplayColumn) -> DisplayColumn {
+        DisplayColumn {
+            min: self.min.min(other.min),
+            max: self.max.max(other.max),
+            energy: self.energy + other.energy,
+            len: self.len + other.len,
+        }
+    }
+
+    pub fn rms(&self) -> f32 {
+        if self.len == 0 {
+            0.0
+        } else {
+            (self.energy / self.len as f32).sqrt()
+        }
+    }
 }
 
 #[derive(Clone, Copy, Default)]
@@ -239,29 +378,37 @@ impl AudioBackend {
 
 impl AudioClip {
     pub fn resample(&self, sample_rate: u32) -> AudioClip {
+        self.resample_with_quality(sample_rate, Quality::default())
+    }
+
+    pub fn resample_with_quality(&self, sample_rate: u32, quality: Quality) -> AudioClip {
         if self.sample_rate == sample_rate {
             return self.clone();
         }
 
-        let mut signal = signal::from_iter(self.samples.iter().copied());
-        let a = signal.next();
-        let b = signal.next();
-
-        let linear = Linear::new(a, b);
-
         AudioClip {
             id: self.id,
             name: self.name.clone(),
             date: self.date,
-            samples: signal
-                .from_hz_to_hz(linear, self.sample_rate as f64, sample_rate as f64)
-                .take(self.samples.len() * (sample_rate as usize) / (self.sample_rate as usize))
-                .collect(),
+            notes: self.notes.clone(),
+            samples: resample::resample(&self.samples, self.sample_rate, sample_rate, quality),
             sample_rate,
+            cache: ClipCache::default(),
         }
     }
 
     pub fn record(host: AudioBackend, name: String) -> Result<RecordHandle> {
+        Self::record_with_journal(host, name, None, None)
+    }
+
+    /// Record, also writing the recording to a journal at `journal_path`, encrypted with
+    /// `cipher` if given, so that it can be recovered if it is never saved.
+    pub(crate) fn record_with_journal(
+        host: AudioBackend,
+        name: String,
+        journal_path: Option<&Path>,
+        cipher: Option<&Cipher>,
+    ) -> Result<RecordHandle> {
         let host = host.host().wrap_err("Could not open specified host")?;
         let device = host
             .default_input_device()
@@ -273,11 +420,26 @@ impl AudioClip {
             id: None,
             name,
             date: Utc::now(),
+            notes: None,
             samples: Vec::new(),
             sample_rate: config.sample_rate().0,
+            cache: ClipCache::default(),
+        };
+        let journal = match journal_path {
+            Some(path) => Some(JournalWriter::create(
+                path,
+                &clip.name,
+                clip.date,
+                clip.sample_rate,
+                cipher,
+            )?),
+            None => None,
         };
-        let clip = Arc::new(Mutex::new(Some(RecordState { clip })));
-        let clip_2 = clip.clone();
+        // Enough for a few seconds of hiccups on the consumer thread.
+        let (producer, consumer) = RingBuffer::new(clip.sample_rate as usize * 4);
+        let clip = Arc::new(Mutex::new(Some(RecordState { clip, journal })));
+        let stopped = Arc::new(AtomicBool::new(false));
+        let dropped_frames = Arc::new(AtomicUsize::new(0));
 
         log::info!("Begin recording...");
         let err_fn = move |err| {
@@ -286,168 +448,97 @@ impl AudioClip {
 
         let channels = config.channels();
 
-        fn write_input_data<T>(input: &[T], channels: u16, writer: &RecordStateHandle)
-        where
+        fn write_input_data<T>(
+            input: &[T],
+            channels: u16,
+            producer: &mut Producer<f32>,
+            dropped_frames: &AtomicUsize,
+        ) where
             T: cpal::Sample,
             f32: cpal::FromSample<T>,
         {
-            if let Ok(mut guard) = writer.try_lock() {
-                if let Some(state) = guard.as_mut() {
-                    for frame in input.chunks(channels.into()) {
-                        state.clip.samples.push(f32::from_sample(frame[0]));
-                    }
-                }
+            let frames = input.len() / channels as usize;
+            let written = match producer.write_chunk_uninit(frames.min(producer.slots())) {
+                Ok(chunk) => chunk.fill_from_iter(
+                    input
+                        .chunks(channels.into())
+                        .map(|frame| f32::from_sample(frame[0])),
+                ),
+                Err(_) => 0,
+            };
+            if written < frames {
+                dropped_frames.fetch_add(frames - written, Ordering::Relaxed);
             }
         }
 
-        let stream = match config.sample_format() {
-            cpal::SampleFormat::F32 => device.build_input_stream(
-                &config.into(),
-                move |data, _: &_| write_input_data::<f32>(data, channels, &clip_2),
-                err_fn,
-                None,
-            )?,
-            cpal::SampleFormat::I16 => device.build_input_stream(
-                &config.into(),
-                move |data, _: &_| write_input_data::<i16>(data, channels, &clip_2),
-                err_fn,
-                None,
-            )?,
-            cpal::SampleFormat::U16 => device.build_input_stream(
-                &config.into(),
-                move |data, _: &_| write_input_data::<u16>(data, channels, &clip_2),
-                err_fn,
-                None,
-            )?,
-            format => {
-                return Err(eyre!("Unknown sample format {:?}.", format));
+        let stream = {
+            let dropped_frames = dropped_frames.clone();
+            let mut producer = producer;
+            match config.sample_format() {
+                cpal::SampleFormat::F32 => device.build_input_stream(
+                    &config.into(),
+                    move |data, _: &_| {
+                        write_input_data::<f32>(data, channels, &mut producer, &dropped_frames)
+                    },
+                    err_fn,
+                    None,
+                )?,
+                cpal::SampleFormat::I16 => device.build_input_stream(
+                    &config.into(),
+                    move |data, _: &_| {
+                        write_input_data::<i16>(data, channels, &mut producer, &dropped_frames)
+                    },
+                    err_fn,
+                    None,
+                )?,
+                cpal::SampleFormat::U16 => device.build_input_stream(
+                    &config.into(),
+                    move |data, _: &_| {
+                        write_input_data::<u16>(data, channels, &mut producer, &dropped_frames)
+                    },
+                    err_fn,
+                    None,
+                )?,
+                format => {
+                    return Err(eyre!("Unknown sample format {:?}.", format));
+                }
             }
         };
 
+        let consumer = {
+            let clip = clip.clone();
+            let stopped = stopped.clone();
+            std::thread::Builder::new()
+                .name("oxygen-record".into())
+                .spawn(move || read_input_data(consumer, &clip, &stopped))?
+        };
+
         stream.play()?;
 
-        Ok(RecordHandle { stream, clip })
+        Ok(RecordHandle {
+            stream,
+            clip,
+            consumer,
+            stopped,
+            dropped_frames,
+            journal_path: journal_path.map(Path::to_owned),
+        })
     }
 
     pub fn import(name: String, path: String) -> Result<AudioClip> {
-        // Create a media source. Note that the MediaSource trait is automatically implemented for File,
-        // among other types.
-        let file = Box::new(File::open(Path::new(&path))?);
-
-        let creation_time = file.metadata()?.created()?;
-
-        // Create the media source stream using the boxed media source from above.
-        let mss = MediaSourceStream::new(file, Default::default());
-
-        // Create a hint to help the format registry guess what format reader is appropriate. In this
-        // example we'll leave it empty.
-        let hint = Hint::new();
-
-        // Use the default options when reading and decoding.
-        let format_opts: FormatOptions = Default::default();
-        let metadata_opts: MetadataOptions = Default::default();
-        let decoder_opts: DecoderOptions = Default::default();
-
-        // Probe the media source stream for a format.
-        let probed =
-            symphonia::default::get_probe().format(&hint, mss, &format_opts, &metadata_opts)?;
-
-        // Get the format reader yielded by the probe operation.
-        let mut format = probed.format;
-
-        // Get the default track.
-        let track = format
-            .default_track()
-            .ok_or_else(|| eyre!("No default track"))?;
-
-        // Create a decoder for the track.
-        let mut decoder =
-            symphonia::default::get_codecs().make(&track.codec_params, &decoder_opts)?;
-
-        // Store the track identifier, we'll use it to filter packets.
-        let track_id = track.id;
-
-        let mut sample_count = 0;
-        let mut sample_buf = None;
-        let channels = track
-            .codec_params
-            .channels
-            .ok_or_else(|| eyre!("Unknown channel count"))?;
-
+        let mut source = ImportSource::open(Path::new(&path), &ImportOptions::default())?;
         let mut clip = AudioClip {
             id: None,
             name,
-            date: DateTime::<Utc>::from(creation_time),
+            date: source.date(),
+            notes: source.metadata().notes(),
             samples: Vec::new(),
-            sample_rate: track
-                .codec_params
-                .sample_rate
-                .ok_or_else(|| eyre!("Unknown sample rate"))?,
+            sample_rate: source.sample_rate(),
+            cache: ClipCache::default(),
         };
 
-        loop {
-            // Get the next packet from the format reader.
-            let packet = match format.next_packet() {
-                Ok(packet_ok) => packet_ok,
-                Err(Error::IoError(ref packet_err))
-                    if packet_err.kind() == std::io::ErrorKind::UnexpectedEof =>
-                {
-                    break;
-                }
-                Err(packet_err) => {
-                    return Err(packet_err.into());
-                }
-            };
-
-            // If the packet does not belong to the selected track, skip it.
-            if packet.track_id() != track_id {
-                continue;
-            }
-
-            // Decode the packet into audio samples, ignoring any decode errors.
-            match decoder.decode(&packet) {
-                Ok(audio_buf) => {
-                    // The decoded audio samples may now be accessed via the audio buffer if per-channel
-                    // slices of samples in their native decoded format is desired. Use-cases where
-                    // the samples need to be accessed in an interleaved order or converted into
-                    // another sample format, or a byte buffer is required, are covered by copying the
-                    // audio buffer into a sample buffer or raw sample buffer, respectively. In the
-                    // example below, we will copy the audio buffer into a sample buffer in an
-                    // interleaved order while also converting to a f32 sample format.
-
-                    // If this is the *first* decoded packet, create a sample buffer matching the
-                    // decoded audio buffer format.
-                    if sample_buf.is_none() {
-                        // Get the audio buffer specification.
-                        let spec = *audio_buf.spec();
-
-                        // Get the capacity of the decoded buffer. Note: This is capacity, not length!
-                        let duration = audio_buf.capacity() as u64;
-
-                        // Create the f32 sample buffer.
-                        sample_buf = Some(SampleBuffer::<f32>::new(duration, spec));
-                    }
-
-                    // Copy the decoded audio buffer into the sample buffer in an interleaved format.
-                    if let Some(buf) = &mut sample_buf {
-                        buf.copy_interleaved_ref(audio_buf);
-                        let mono: Vec<f32> = buf
-                            .samples()
-                            .iter()
-                            .step_by(channels.count())
-                            .copied()
-                            .collect();
-                        clip.samples.extend_from_slice(&mono);
-
-                        // The samples may now be access via the `samples()` function.
-                        sample_count += buf.samples().len();
-                        log::info!("\rDecoded {} samples", sample_count);
-                    }
-                }
-                Err(Error::DecodeError(_)) => (),
-                Err(_) => break,
-            }
-        }
+        while source.read(&mut clip.samples)? {}
+        log::info!("Decoded {} samples", clip.samples.len());
 
         Ok(clip)
     }
@@ -463,79 +554,83 @@ impl AudioClip {
         log::info!("Begin playback...");
 
         let sample_rate = config.sample_rate().0;
-        let state = PlaybackState {
-            time: 0,
-            samples: self.resample(sample_rate).samples,
-            done_cbs: vec![],
-            changed_cbs: vec![],
-            changed_cbs_triggered_at: 0,
+        let samples = self.resample(sample_rate).samples;
+        let state = Arc::new(PlaybackState {
+            time: AtomicUsize::new(0),
+            seek: AtomicUsize::new(usize::MAX),
+            num_samples: samples.len(),
             sample_rate: sample_rate as usize,
-        };
-        let state: PlaybackStateHandle = Arc::new(Mutex::new(Some(state)));
-        let state_2 = state.clone();
+            stopped: AtomicBool::new(false),
+            changed_cbs: Mutex::new(vec![]),
+            done_cbs: Mutex::new(vec![]),
+        });
         let channels = config.channels();
 
         let err_fn = move |err| {
             log::error!("an error occurred on stream: {}", err);
         };
 
-        fn write_output_data<T>(output: &mut [T], channels: u16, writer: &PlaybackStateHandle)
-        where
+        fn write_output_data<T>(
+            output: &mut [T],
+            channels: u16,
+            samples: &[f32],
+            state: &PlaybackState,
+        ) where
             T: cpal::Sample + cpal::SizedSample + cpal::FromSample<f32>,
         {
-            if let Ok(mut guard) = writer.try_lock() {
-                if let Some(state) = guard.as_mut() {
-                    for frame in output.chunks_mut(channels.into()) {
-                        for sample in frame.iter_mut() {
-                            *sample =
-                                T::from_sample(*state.samples.get(state.time).unwrap_or(&0f32));
-                        }
-                        state.time += 1;
-                    }
-                    if state.time >= state.samples.len() {
-                        for cb in &*state.done_cbs {
-                            cb();
-                        }
-                    }
-                    if state.time >= state.changed_cbs_triggered_at + state.sample_rate / 100 {
-                        for cb in &*state.changed_cbs {
-                            cb();
-                        }
-                        state.changed_cbs_triggered_at = state.time;
-                    }
+            let mut time = match state.seek.swap(usize::MAX, Ordering::AcqRel) {
+                usize::MAX => state.time.load(Ordering::Acquire),
+                seek => seek,
+            };
+            for frame in output.chunks_mut(channels.into()) {
+                let sample = T::from_sample(*samples.get(time).unwrap_or(&0f32));
+                for out in frame.iter_mut() {
+                    *out = sample;
                 }
+                time = (time + 1).min(samples.len());
             }
+            state.time.store(time, Ordering::Release);
         }
 
-        let stream = match config.sample_format() {
-            cpal::SampleFormat::F32 => device.build_output_stream(
-                &config.into(),
-                move |data, _: &_| write_output_data::<f32>(data, channels, &state),
-                err_fn,
-                None,
-            )?,
-            cpal::SampleFormat::I16 => device.build_output_stream(
-                &config.into(),
-                move |data, _: &_| write_output_data::<i16>(data, channels, &state),
-                err_fn,
-                None,
-            )?,
-            cpal::SampleFormat::U16 => device.build_output_stream(
-                &config.into(),
-                move |data, _: &_| write_output_data::<u16>(data, channels, &state),
-                err_fn,
-                None,
-            )?,
-            format => {
-                return Err(eyre!("Unknown sample format {:?}.", format));
+        let stream = {
+            let state = state.clone();
+            match config.sample_format() {
+                cpal::SampleFormat::F32 => device.build_output_stream(
+                    &config.into(),
+                    move |data, _: &_| write_output_data::<f32>(data, channels, &samples, &state),
+                    err_fn,
+                    None,
+                )?,
+                cpal::SampleFormat::I16 => device.build_output_stream(
+                    &config.into(),
+                    move |data, _: &_| write_output_data::<i16>(data, channels, &samples, &state),
+                    err_fn,
+                    None,
+                )?,
+                cpal::SampleFormat::U16 => device.build_output_stream(
+                    &config.into(),
+                    move |data, _: &_| write_output_data::<u16>(data, channels, &samples, &state),
+                    err_fn,
+                    None,
+                )?,
+                format => {
+                    return Err(eyre!("Unknown sample format {:?}.", format));
+                }
             }
         };
 
+        {
+            let state = state.clone();
+            std::thread::Builder::new()
+                .name("oxygen-playback".into())
+                .spawn(move || notify_playback(&state))?;
+        }
+
         stream.play()?;
 
         Ok(PlayHandle {
             _stream: stream,
-            state: state_2,
+            state,
         })
     }
 
@@ -567,18 +662,19 @@ impl AudioClip {
         mut range: (usize, usize),
         width: usize,
         height: usize,
-    ) -> Vec<u8> {
+        options: &RenderOptions,
+    ) -> RenderedImage {
         range.1 = range.1.clamp(range.0, usize::MAX);
 
         let min_t = range.0 as f32;
         let max_t = range.1 as f32;
         let samples_per_pixel = (max_t - min_t) / (width as f32);
 
+        let mut peaks = self.cache.peaks.lock().unwrap();
+        peaks.update(&self.samples);
+
         let columns: Vec<DisplayColumn> = (0..width)
             .map(|pixel_i| {
-                let mut min = 1.0f32;
-                let mut max = -1.0f32;
-
                 let start_sample = (min_t + samples_per_pixel * (pixel_i as f32)).floor() as usize;
                 let end_sample =
                     (min_t + samples_per_pixel * ((pixel_i + 1) as f32)).floor() as usize;
@@ -586,45 +682,28 @@ impl AudioClip {
                 let start_sample = start_sample.clamp(0, self.samples.len());
                 let end_sample = end_sample.clamp(start_sample, self.samples.len());
 
-                for sample in &self.samples[start_sample..end_sample] {
-                    min = min.min(*sample);
-                    max = max.max(*sample);
-                }
+                let mut column = peaks.peak(&self.samples, start_sample, end_sample);
 
-                if min > max {
-                    min = 0.0;
-                    max = 0.0;
+                if column.min > column.max {
+                    column.min = 0.0;
+                    column.max = 0.0;
                 }
-                if min < -1.0 {
-                    min = -1.0;
+                if column.min < -1.0 {
+                    column.min = -1.0;
                 }
-                if max > 1.0 {
-                    max = 1.0;
+                if column.max > 1.0 {
+                    column.max = 1.0;
                 }
 
-                DisplayColumn { min, max }
+                column
             })
             .collect();
 
-        let mut buffer = vec![0; width * height * 4];
-
-        for (x, column) in columns.iter().enumerate() {
-            let min_y = ((height as f32) * (column.min + 1.0) / 2.0)
-                .floor()
-                .max(0.0) as usize;
-            let max_y =
-                (((height as f32) * (column.max + 1.0) / 2.0).ceil() as usize).min(height - 1);
-
-            for y in min_y..=max_y {
-                // purple-900 :)
-                buffer[y * width * 4 + x * 4] = 88;
-                buffer[y * width * 4 + x * 4 + 1] = 28;
-                buffer[y * width * 4 + x * 4 + 2] = 135;
-                buffer[y * width * 4 + x * 4 + 3] = 255;
-            }
-        }
+        let mut image = RenderedImage::new(width, height);
+        render::draw_waveform(&mut image, &columns, options);
+        render::draw_overlays(&mut image, range, self.sample_rate as usize, options);
 
-        buffer
+        image
     }
 
     pub fn render_spectrogram(
@@ -632,8 +711,10 @@ impl AudioClip {
         range: (usize, usize),
         width: usize,
         height: usize,
-    ) -> Result<Vec<u8>> {
-        spectrum::render_spectrogram(self, range, width, height)
+        options: &SpectrogramOptions,
+        render_options: &RenderOptions,
+    ) -> Result<RenderedImage> {
+        spectrum::render_spectrogram(self, range, width, height, options, render_options)
     }
 
     pub fn num_samples(&self) -> usize {
@@ -641,9 +722,62 @@ impl AudioClip {
     }
 }
 
+/// Move recorded samples from the ring buffer into the clip (and its journal) until the stream
+/// is stopped and the buffer is empty.
+fn read_input_data(mut consumer: Consumer<f32>, state: &RecordStateHandle, stopped: &AtomicBool) {
+    loop {
+        let done = stopped.load(Ordering::Acquire) || consumer.is_abandoned();
+        let chunk = match consumer.read_chunk(consumer.slots()) {
+            Ok(chunk) if !chunk.is_empty() => chunk,
+            _ if done => break,
+            _ => {
+                std::thread::sleep(POLL_INTERVAL);
+                continue;
+            }
+        };
+
+        let (first, second) = chunk.as_slices();
+        if let Some(state) = state.lock().unwrap().as_mut() {
+            state.clip.samples.extend_from_slice(first);
+            state.clip.samples.extend_from_slice(second);
+            if let Some(journal) = &state.journal {
+                journal.write([first, second].concat());
+            }
+        }
+        chunk.commit_all();
+    }
+}
+
+/// Call playback callbacks as the playhead moves, until the stream is dropped.
+fn notify_playback(state: &PlaybackState) {
+    let mut last_time = usize::MAX;
+    let mut done = false;
+    while !state.stopped.load(Ordering::Acquire) {
+        let time = state.time();
+        if time != last_time {
+            for cb in &*state.changed_cbs.lock().unwrap() {
+                cb();
+            }
+            last_time = time;
+        }
+
+        if time < state.num_samples {
+            done = false;
+        } else if !done {
+            for cb in &*state.done_cbs.lock().unwrap() {
+                cb();
+            }
+            done = true;
+        }
+
+        std::thread::sleep(POLL_INTERVAL);
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::test_fixtures::clip;
 
     #[test]
     fn test_render_with_zero_samples() {
@@ -651,13 +785,56 @@ mod tests {
             id: Some(1),
             name: "Name".into(),
             date: Utc::now(),
+            notes: None,
             samples: vec![],
             sample_rate: 44100,
+            cache: ClipCache::default(),
         };
-        assert_eq!(clip.render_waveform((0, 0), 100, 1).len(), 100 * 4);
-        assert_eq!(clip.render_waveform((0, 0), 0, 1).len(), 0);
-        assert_eq!(clip.render_waveform((100, 0), 0, 1).len(), 0);
-        assert_eq!(clip.render_waveform((100, 200), 100, 1).len(), 100 * 4);
-        assert_eq!(clip.render_waveform((100, 200), 100, 4).len(), 400 * 4);
+        let options = RenderOptions::default();
+        assert_eq!(
+            clip.render_waveform((0, 0), 100, 1, &options).buffer.len(),
+            100 * 4
+        );
+        assert_eq!(clip.render_waveform((0, 0), 0, 1, &options).buffer.len(), 0);
+        assert_eq!(
+            clip.render_waveform((100, 0), 0, 1, &options).buffer.len(),
+            0
+        );
+        assert_eq!(
+            clip.render_waveform((100, 200), 100, 1, &options)
+                .buffer
+                .len(),
+            100 * 4
+        );
+        assert_eq!(
+            clip.render_waveform((100, 200), 100, 4, &options)
+                .buffer
+                .len(),
+            400 * 4
+        );
+    }
+
+    #[test]
+    fn test_read_input_data_drains_after_stop() {
+        let clip = AudioClip {
+            samples: vec![],
+            ..clip("Name")
+        };
+        let state = Arc::new(Mutex::new(Some(RecordState {
+            clip,
+            journal: None,
+        })));
+        let (mut producer, consumer) = RingBuffer::new(1000);
+        for i in 0..1000 {
+            producer.push(i as f32).unwrap();
+        }
+        let stopped = AtomicBool::new(true);
+
+        read_input_data(consumer, &state, &stopped);
+
+        let state = state.lock().unwrap();
+        let samples = &state.as_ref().unwrap().clip.samples;
+        assert_eq!(samples.len(), 1000);
+        assert_eq!(samples[999], 999.0);
     }
 }
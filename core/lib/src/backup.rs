@@ -0,0 +1,692 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Cursor, Read, Write};
+use std::path::Path;
+
+use chrono::prelude::*;
+use color_eyre::eyre::{eyre, Result};
+use rusqlite::{types::Value, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::db::{Db, StoredClip};
+use crate::diarization::SpeakerTurn;
+use crate::encryption::{open_text, seal_bytes, seal_name, Cipher, KeyParams};
+use crate::import::unused_name;
+use crate::internal_encoding::{v1_to_v2, EncodingProfile};
+
+const MANIFEST_PATH: &str = "manifest.json";
+
+const KEY_PATH: &str = "key.json";
+
+const VERSION: u32 = 5;
+
+/// What is in a backup, stored as JSON at the start of the archive.
+///
+/// A backup is a tar file of:
+///  - "manifest.json", this manifest
+///  - "clips/{id}.opus" for each clip, the audio as stored in the database (`encode_v2`
+///    format, or `encode_v1` in version 1 backups), or "clips/{id}.flac" for lossless clips
+///
+/// A backup of an encrypted journal starts with "key.json", a `BackupKey`, and the manifest
+/// and audio are sealed with the journal's key, so its passphrase is needed to restore it.
+///
+/// Peaks are not backed up, since they are recomputed when clips are drawn.
+#[derive(Debug, Serialize, Deserialize)]
+struct Manifest {
+    version: u32,
+    created: String,
+    clips: Vec<ManifestClip>,
+    /// Backups before version 4 have no collections.
+    #[serde(default)]
+    collections: Vec<ManifestCollection>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ManifestClip {
+    id: usize,
+    name: String,
+    date: String,
+    notes: Option<String>,
+    sample_rate: u32,
+    /// The name of the encoding profile. Clips in backups before version 3 were encoded for
+    /// voice.
+    #[serde(default = "voice")]
+    profile: String,
+    /// Where the audio is in the archive.
+    path: String,
+    size: u64,
+    /// SHA-256 of the audio, in lowercase hex.
+    sha256: String,
+    /// The speakers found by diarization. Backups before version 5 have none.
+    #[serde(default)]
+    speakers: Vec<ManifestSpeaker>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ManifestSpeaker {
+    id: usize,
+    name: Option<String>,
+    /// When they speak, as start and end times in seconds.
+    turns: Vec<(f64, f64)>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ManifestCollection {
+    name: String,
+    /// The IDs of the clips in the collection, as in the manifest, in order.
+    clips: Vec<usize>,
+}
+
+/// How the key of an encrypted backup is derived from its passphrase.
+#[derive(Debug, Serialize, Deserialize)]
+struct BackupKey {
+    salt: Vec<u8>,
+    m_cost: u32,
+    t_cost: u32,
+    p_cost: u32,
+    /// Sealed with the key, to check the passphrase.
+    check_value: Vec<u8>,
+}
+
+fn voice() -> String {
+    EncodingProfile::Voice.name().to_string()
+}
+
+/// How to restore a backup into a journal that already has clips.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum RestoreMode {
+    /// Keep the existing clips. Clips from the backup with the same name and date as an
+    /// existing clip are skipped; other clashing names get a suffix, like "Name (2)".
+    #[default]
+    Merge,
+    /// Delete the existing clips first.
+    Replace,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RestoreSummary {
+    pub restored: usize,
+    /// Clips that were already in the journal.
+    pub skipped: usize,
+    /// Clips restored under a new name.
+    pub renamed: usize,
+}
+
+/// Write every clip in `db` to a backup at `path`, returning the number of clips.
+pub fn backup(db: &Db, path: &Path) -> Result<usize> {
+    let result = write_backup(db, path);
+    if result.is_err() {
+        let _ = std::fs::remove_file(path);
+    }
+
+    result
+}
+
+fn write_backup(db: &Db, path: &Path) -> Result<usize> {
+    let cipher = db.cipher()?;
+    let mut stmt = db
+        .connection
+        .prepare("SELECT id, name, date, notes, sample_rate, profile FROM clips ORDER BY id")?;
+    let rows = stmt.query_map([], |row| {
+        Ok((
+            row.get::<_, usize>(0)?,
+            row.get::<_, Value>(1)?,
+            row.get::<_, String>(2)?,
+            row.get::<_, Value>(3)?,
+            row.get::<_, u32>(4)?,
+            row.get::<_, String>(5)?,
+        ))
+    })?;
+
+    let mut clips = Vec::new();
+    for row in rows {
+        let (id, name, date, notes, sample_rate, profile) = row?;
+        let extension = match EncodingProfile::from_name(&profile)? {
+            EncodingProfile::Lossless => "flac",
+            _ => "opus",
+        };
+        // The manifest comes first so restores can check it before touching the journal, so
+        // the audio is read twice.
+        let (mut opus, _) = db.read_stored_opus(id)?;
+        let (size, sha256) = copy_hashed(&mut opus, &mut io::sink())?;
+        let turns = db.speaker_turns(id)?;
+        let speakers = db
+            .speakers(id)?
+            .into_iter()
+            .map(|speaker| ManifestSpeaker {
+                id: speaker.id,
+                name: speaker.name,
+                turns: turns
+                    .iter()
+                    .filter(|turn| turn.speaker == speaker.id)
+                    .map(|turn| (turn.start, turn.end))
+                    .collect(),
+            })
+            .collect();
+        clips.push(ManifestClip {
+            id,
+            name: open_text(cipher, name)?.ok_or_else(|| eyre!("Clip {} has no name", id))?,
+            date,
+            notes: open_text(cipher, notes)?,
+            sample_rate,
+            profile,
+            path: format!("clips/{}.{}", id, extension),
+            size,
+            sha256,
+            speakers,
+        });
+    }
+
+    let mut collections = Vec::new();
+    for collection in db.collections()? {
+        collections.push(ManifestCollection {
+            name: collection.name,
+            clips: db
+                .list_collection(collection.id)?
+                .into_iter()
+                .map(|meta| meta.id)
+                .collect(),
+        });
+    }
+
+    let manifest = Manifest {
+        version: VERSION,
+        created: Utc::now().to_string(),
+        clips,
+        collections,
+    };
+    let manifest_json = seal_bytes(cipher, serde_json::to_vec_pretty(&manifest)?);
+
+    let mut builder = tar::Builder::new(BufWriter::new(File::create(path)?));
+    let mtime = Utc::now().timestamp().max(0) as u64;
+    if let Some((params, check_value)) = db.key_params()? {
+        let key_json = serde_json::to_vec_pretty(&BackupKey {
+            salt: params.salt,
+            m_cost: params.m_cost,
+            t_cost: params.t_cost,
+            p_cost: params.p_cost,
+            check_value,
+        })?;
+        append(
+            &mut builder,
+            KEY_PATH,
+            key_json.len() as u64,
+            mtime,
+            &key_json[..],
+        )?;
+    }
+    append(
+        &mut builder,
+        MANIFEST_PATH,
+        manifest_json.len() as u64,
+        mtime,
+        &manifest_json[..],
+    )?;
+    for clip in &manifest.clips {
+        let (mut opus, _) = db.read_stored_opus(clip.id)?;
+        match cipher {
+            Some(cipher) => {
+                let mut bytes = Vec::with_capacity(clip.size as usize);
+                opus.read_to_end(&mut bytes)?;
+                let sealed = cipher.seal(&bytes);
+                append(
+                    &mut builder,
+                    &clip.path,
+                    sealed.len() as u64,
+                    mtime,
+                    &sealed[..],
+                )?;
+            }
+            None => append(&mut builder, &clip.path, clip.size, mtime, opus)?,
+        }
+    }
+    builder.into_inner()?.into_inner()?.sync_all()?;
+
+    Ok(manifest.clips.len())
+}
+
+fn append(
+    builder: &mut tar::Builder<impl Write>,
+    path: &str,
+    size: u64,
+    mtime: u64,
+    data: impl Read,
+) -> Result<()> {
+    let mut header = tar::Header::new_gnu();
+    header.set_size(size);
+    header.set_mode(0o644);
+    header.set_mtime(mtime);
+    builder.append_data(&mut header, path, data)?;
+
+    Ok(())
+}
+
+/// Copy `reader` to `writer`, returning the number of bytes and their SHA-256.
+fn copy_hashed(reader: &mut impl Read, writer: &mut impl Write) -> Result<(u64, String)> {
+    let mut reader = HashingReader::new(reader);
+    io::copy(&mut reader, writer)?;
+
+    Ok(reader.finish())
+}
+
+/// Computes the SHA-256 of what is read through it.
+struct HashingReader<R> {
+    reader: R,
+    hasher: Sha256,
+    len: u64,
+}
+
+impl<R: Read> HashingReader<R> {
+    fn new(reader: R) -> HashingReader<R> {
+        HashingReader {
+            reader,
+            hasher: Sha256::new(),
+            len: 0,
+        }
+    }
+
+    /// The number of bytes read, and their SHA-256 in lowercase hex.
+    fn finish(self) -> (u64, String) {
+        (self.len, format!("{:x}", self.hasher.finalize()))
+    }
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.reader.read(buf)?;
+        self.hasher.update(&buf[..read]);
+        self.len += read as u64;
+
+        Ok(read)
+    }
+}
+
+/// Whether the backup at `path` is encrypted, so that `restore` needs its passphrase.
+pub fn is_encrypted(path: &Path) -> Result<bool> {
+    let mut archive = tar::Archive::new(BufReader::new(File::open(path)?));
+    match archive.entries()?.next() {
+        Some(entry) => Ok(entry?.path()?.to_str() == Some(KEY_PATH)),
+        None => Ok(false),
+    }
+}
+
+/// Restore the backup at `path` into `db`. Nothing is changed unless every clip in the
+/// backup is intact. An encrypted backup is opened with `passphrase`, the passphrase of the
+/// journal it was made from.
+pub fn restore(
+    db: &Db,
+    path: &Path,
+    mode: RestoreMode,
+    passphrase: Option<&str>,
+) -> Result<RestoreSummary> {
+    let mut archive = tar::Archive::new(BufReader::new(File::open(path)?));
+    let mut entries = archive.entries()?;
+
+    let mut entry = entries
+        .next()
+        .ok_or_else(|| eyre!("Not a backup, the archive is empty"))??;
+    let mut cipher = None;
+    if entry.path()?.to_str() == Some(KEY_PATH) {
+        let key: BackupKey = serde_json::from_reader(entry)?;
+        let passphrase =
+            passphrase.ok_or_else(|| eyre!("The backup is encrypted, its passphrase is needed"))?;
+        let params = KeyParams {
+            salt: key.salt,
+            m_cost: key.m_cost,
+            t_cost: key.t_cost,
+            p_cost: key.p_cost,
+        };
+        let key_cipher = Cipher::derive(passphrase, &params)?;
+        if !key_cipher.check(&key.check_value) {
+            return Err(eyre!("Wrong passphrase for the backup"));
+        }
+        cipher = Some(key_cipher);
+        entry = entries
+            .next()
+            .ok_or_else(|| eyre!("Not a backup, expected {} after the key", MANIFEST_PATH))??;
+    }
+    if entry.path()?.to_str() != Some(MANIFEST_PATH) {
+        return Err(eyre!("Not a backup, expected {} first", MANIFEST_PATH));
+    }
+    let manifest: Manifest = serde_json::from_reader(open_entry(&mut entry, cipher.as_ref())?)?;
+    if manifest.version > VERSION {
+        return Err(eyre!(
+            "The backup is from a newer version of oxygen (format {})",
+            manifest.version
+        ));
+    }
+
+    // The transaction is rolled back when dropped, if there is an error.
+    let transaction = db.connection.unchecked_transaction()?;
+    if mode == RestoreMode::Replace {
+        transaction.execute_batch(
+            "DELETE FROM speaker_turns WHERE clip_id IN (SELECT id FROM clips);
+             DELETE FROM speakers WHERE clip_id IN (SELECT id FROM clips);
+             DELETE FROM clips; DELETE FROM collection_clips; DELETE FROM collections;",
+        )?;
+    }
+
+    let mut summary = RestoreSummary::default();
+    // The ID in the journal of each clip in the manifest, restored or already there.
+    let mut ids = HashMap::new();
+    let mut seen = vec![false; manifest.clips.len()];
+    for entry in entries {
+        let mut entry = entry?;
+        let entry_path = entry.path()?.to_string_lossy().into_owned();
+        let index = manifest
+            .clips
+            .iter()
+            .position(|clip| clip.path == entry_path)
+            .ok_or_else(|| eyre!("{} is not in the manifest", entry_path))?;
+        if seen[index] {
+            return Err(eyre!("{} is in the backup twice", entry_path));
+        }
+        seen[index] = true;
+        let clip = &manifest.clips[index];
+        if cipher.is_none() && entry.header().size()? != clip.size {
+            return Err(eyre!("The audio of {} is damaged", clip.name));
+        }
+        let date: DateTime<Utc> = clip
+            .date
+            .parse()
+            .map_err(|_| eyre!("Invalid date for {}: {}", clip.name, clip.date))?;
+
+        let existing: Option<(usize, String)> = transaction
+            .query_row(
+                "SELECT id, date FROM clips WHERE name = ?1",
+                [seal_name(db.cipher()?, &clip.name)],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()?;
+        let name = match existing {
+            None => Some(clip.name.clone()),
+            Some((id, existing)) if existing.parse::<DateTime<Utc>>().ok() == Some(date) => {
+                ids.insert(clip.id, id);
+                None
+            }
+            Some(_) => Some(unused_name(db, &clip.name)?),
+        };
+
+        let mut audio = open_entry(&mut entry, cipher.as_ref())
+            .map_err(|_| eyre!("The audio of {} is damaged", clip.name))?;
+        let mut opus = HashingReader::new(&mut audio);
+        let mut restored_id = None;
+        match &name {
+            Some(name) => {
+                let stored = StoredClip {
+                    name,
+                    date,
+                    notes: clip.notes.as_deref(),
+                    sample_rate: clip.sample_rate,
+                    peaks: None,
+                    profile: EncodingProfile::from_name(&clip.profile)?,
+                    duration: None,
+                };
+                let id = if manifest.version == 1 {
+                    let mut v1 = Vec::new();
+                    opus.read_to_end(&mut v1)?;
+                    let v2 = v1_to_v2(clip.sample_rate, &v1)
+                        .map_err(|_| eyre!("The audio of {} is damaged", clip.name))?;
+                    db.insert_stored(&stored, &mut &v2[..], v2.len() as u64)?
+                } else {
+                    db.insert_stored(&stored, &mut opus, clip.size)?
+                };
+                ids.insert(clip.id, id);
+                restored_id = Some(id);
+            }
+            // Skipped clips are still checked, since a damaged backup may be missing others.
+            None => {
+                io::copy(&mut opus, &mut io::sink())?;
+            }
+        }
+        let (len, sha256) = opus.finish();
+        if len != clip.size || sha256 != clip.sha256 {
+            return Err(eyre!("The audio of {} is damaged", clip.name));
+        }
+        if let Some(id) = restored_id {
+            restore_speakers(db, id, &clip.speakers)?;
+        }
+
+        match name {
+            None => summary.skipped += 1,
+            Some(name) => {
+                summary.restored += 1;
+                if name != clip.name {
+                    summary.renamed += 1;
+                }
+            }
+        }
+    }
+
+    if let Some(missing) = seen.iter().position(|seen| !seen) {
+        return Err(eyre!(
+            "The audio of {} is missing",
+            manifest.clips[missing].name
+        ));
+    }
+
+    // Collections are merged with existing ones of the same name.
+    for collection in &manifest.collections {
+        let id = match db.collection_id(&collection.name)? {
+            Some(id) => id,
+            None => db.create_collection(&collection.name)?,
+        };
+        for clip_id in &collection.clips {
+            let clip_id = *ids.get(clip_id).ok_or_else(|| {
+                eyre!(
+                    "Collection {} has a clip that is not in the manifest",
+                    collection.name
+                )
+            })?;
+            if !db.in_collection(id, clip_id)? {
+                db.append_to_collection(id, clip_id)?;
+            }
+        }
+    }
+    transaction.commit()?;
+    // Backups don't store durations.
+    db.fill_durations()?;
+
+    Ok(summary)
+}
+
+/// Add the speakers of a restored clip, with their names.
+fn restore_speakers(db: &Db, clip_id: usize, speakers: &[ManifestSpeaker]) -> Result<()> {
+    for speaker in speakers {
+        let turns: Vec<_> = speaker
+            .turns
+            .iter()
+            .map(|&(start, end)| SpeakerTurn {
+                start,
+                end,
+                speaker: speaker.id,
+            })
+            .collect();
+        db.insert_speaker_turns(clip_id, &turns)?;
+        if speaker.name.is_some() {
+            db.name_speaker(clip_id, speaker.id, speaker.name.as_deref())?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Read an entry of the archive, opening it with `cipher` if the backup is encrypted.
+fn open_entry<'a>(entry: &'a mut impl Read, cipher: Option<&Cipher>) -> Result<Box<dyn Read + 'a>> {
+    match cipher {
+        Some(cipher) => {
+            let mut sealed = Vec::new();
+            entry.read_to_end(&mut sealed)?;
+            Ok(Box::new(Cursor::new(cipher.open(&sealed)?)))
+        }
+        None => Ok(Box::new(entry)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio_clip::AudioClip;
+    use crate::db::ListQuery;
+    use crate::encryption::test_params;
+    use crate::test_fixtures::{clip, temp_path};
+
+    #[test]
+    fn test_backup_and_restore() {
+        let path = temp_path("backup.tar");
+        let db = Db::in_memory().unwrap();
+        db.save(&mut AudioClip {
+            notes: Some("Artist: Me".into()),
+            ..clip("First")
+        })
+        .unwrap();
+        db.save_with_profile(&mut clip("Second"), EncodingProfile::Lossless)
+            .unwrap();
+        let warmups = db.create_collection("Warmups").unwrap();
+        for name in ["Second", "First"] {
+            db.add_to_collection(warmups, db.clip_id(name).unwrap().unwrap())
+                .unwrap();
+        }
+        let first = db.clip_id("First").unwrap().unwrap();
+        let turns = |speakers: &[usize]| -> Vec<SpeakerTurn> {
+            speakers
+                .iter()
+                .enumerate()
+                .map(|(i, &speaker)| SpeakerTurn {
+                    start: i as f64 * 0.025,
+                    end: (i + 1) as f64 * 0.025,
+                    speaker,
+                })
+                .collect()
+        };
+        db.set_speaker_turns(first, &turns(&[1, 2, 1])).unwrap();
+        db.name_speaker(first, 2, Some("Me")).unwrap();
+        assert_eq!(backup(&db, &path).unwrap(), 2);
+
+        let restored = Db::in_memory().unwrap();
+        let mut other = clip("Other");
+        restored.save(&mut other).unwrap();
+        restored
+            .set_speaker_turns(other.id.unwrap(), &turns(&[1]))
+            .unwrap();
+        let summary = restore(&restored, &path, RestoreMode::Replace, None).unwrap();
+        assert_eq!(summary.restored, 2);
+        let restored_first = restored.clip_id("First").unwrap().unwrap();
+        assert_eq!(
+            restored.speakers(restored_first).unwrap(),
+            db.speakers(first).unwrap()
+        );
+        assert_eq!(
+            restored.speaker_turns(restored_first).unwrap(),
+            db.speaker_turns(first).unwrap()
+        );
+        // Replacing forgets the speakers of the clips that were there.
+        let speakers: usize = restored
+            .connection
+            .query_row("SELECT COUNT(*) FROM speakers", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(speakers, 2);
+        let metadata = |db: &Db| {
+            let mut clips: Vec<_> = db
+                .list(&ListQuery::default())
+                .unwrap()
+                .into_iter()
+                .map(|meta| (meta.name, meta.date, meta.notes))
+                .collect();
+            clips.sort();
+            clips
+        };
+        assert_eq!(metadata(&restored), metadata(&db));
+        assert_eq!(
+            restored.load("First").unwrap().unwrap().samples,
+            db.load("First").unwrap().unwrap().samples
+        );
+        let second = restored.load("Second").unwrap().unwrap();
+        assert_eq!(second.samples, db.load("Second").unwrap().unwrap().samples);
+        assert_eq!(
+            restored.profile(second.id.unwrap()).unwrap(),
+            Some(EncodingProfile::Lossless)
+        );
+        let warmups = restored.collection_id("Warmups").unwrap().unwrap();
+        let names: Vec<_> = restored
+            .list_collection(warmups)
+            .unwrap()
+            .into_iter()
+            .map(|meta| meta.name)
+            .collect();
+        assert_eq!(names, ["Second", "First"]);
+
+        // Merging only adds clips that differ.
+        let mut changed = clip("First");
+        changed.date = Utc::now();
+        restored.delete("First").unwrap();
+        restored.save(&mut changed).unwrap();
+        let summary = restore(&restored, &path, RestoreMode::Merge, None).unwrap();
+        assert_eq!(
+            summary,
+            RestoreSummary {
+                restored: 1,
+                skipped: 1,
+                renamed: 1,
+            }
+        );
+        assert!(restored.load("First (2)").unwrap().is_some());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_restore_damaged() {
+        let path = temp_path("backup-damaged.tar");
+        let db = Db::in_memory().unwrap();
+        db.save(&mut clip("First")).unwrap();
+        backup(&db, &path).unwrap();
+
+        // Flip a bit in the audio, which follows the manifest and its header.
+        let mut bytes = std::fs::read(&path).unwrap();
+        let manifest_len = std::str::from_utf8(&bytes[124..135]).unwrap();
+        let manifest_len = usize::from_str_radix(manifest_len.trim_matches('\0'), 8).unwrap();
+        let audio_start = 512 + manifest_len.div_ceil(512) * 512 + 512;
+        bytes[audio_start + 10] ^= 1;
+        std::fs::write(&path, bytes).unwrap();
+
+        let restored = Db::in_memory().unwrap();
+        restored.save(&mut clip("Other")).unwrap();
+        assert!(restore(&restored, &path, RestoreMode::Replace, None).is_err());
+        assert_eq!(restored.list(&ListQuery::default()).unwrap().len(), 1);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_encrypted_backup() {
+        let path = temp_path("backup-encrypted.tar");
+        let mut db = Db::in_memory().unwrap();
+        db.save(&mut AudioClip {
+            notes: Some("Secret notes".into()),
+            ..clip("Secret name")
+        })
+        .unwrap();
+        db.encrypt_with("correct horse", test_params()).unwrap();
+        backup(&db, &path).unwrap();
+
+        let bytes = std::fs::read(&path).unwrap();
+        assert!(!bytes.windows(6).any(|window| window == b"Secret"));
+        assert!(is_encrypted(&path).unwrap());
+
+        let restored = Db::in_memory().unwrap();
+        assert!(restore(&restored, &path, RestoreMode::Merge, None).is_err());
+        assert!(restore(&restored, &path, RestoreMode::Merge, Some("wrong")).is_err());
+        let summary = restore(&restored, &path, RestoreMode::Merge, Some("correct horse")).unwrap();
+        assert_eq!(summary.restored, 1);
+        let secret = restored.load("Secret name").unwrap().unwrap();
+        assert_eq!(secret.notes.as_deref(), Some("Secret notes"));
+        assert_eq!(
+            secret.samples,
+            db.load("Secret name").unwrap().unwrap().samples
+        );
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,331 @@
+use color_eyre::eyre::{eyre, Result};
+use rusqlite::{params, types::Value, OptionalExtension, Params};
+
+use crate::db::{ClipMeta, Db, ListQuery, SortKey};
+use crate::encryption::{open_text, seal_name, Cipher};
+use crate::history::Operation;
+
+/// A named group of clips, like "Warmups", kept in an order of the user's choosing. A clip
+/// can be in any number of collections.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Collection {
+    pub id: usize,
+    pub name: String,
+    /// The number of clips in it, not counting ones in the trash.
+    pub clip_count: usize,
+}
+
+impl Db {
+    /// Create an empty collection, returning its ID.
+    pub fn create_collection(&self, name: &str) -> Result<usize> {
+        if self.collection_id(name)?.is_some() {
+            return Err(eyre!("There is already a collection named \"{}\"", name));
+        }
+        self.connection.execute(
+            "INSERT INTO collections (name) VALUES (?1)",
+            [seal_name(self.cipher()?, name)],
+        )?;
+
+        Ok(self.connection.last_insert_rowid().try_into()?)
+    }
+
+    /// The ID of the collection with the given name, if there is one.
+    pub fn collection_id(&self, name: &str) -> Result<Option<usize>> {
+        Ok(self
+            .connection
+            .query_row(
+                "SELECT id FROM collections WHERE name = ?1",
+                [seal_name(self.cipher()?, name)],
+                |row| row.get(0),
+            )
+            .optional()?)
+    }
+
+    /// Every collection, by name.
+    pub fn collections(&self) -> Result<Vec<Collection>> {
+        self.query_collections(
+            "SELECT id, name, (SELECT COUNT(*) FROM collection_clips JOIN clips ON clips.id = clip_id WHERE collection_id = collections.id) FROM collections",
+            [],
+        )
+    }
+
+    /// The collections a clip is in, by name.
+    pub fn collections_of(&self, clip_id: usize) -> Result<Vec<Collection>> {
+        self.query_collections(
+            "SELECT id, name, (SELECT COUNT(*) FROM collection_clips JOIN clips ON clips.id = clip_id WHERE collection_id = collections.id) FROM collections WHERE id IN (SELECT collection_id FROM collection_clips WHERE clip_id = ?1)",
+            [clip_id],
+        )
+    }
+
+    fn query_collections(&self, sql: &str, params: impl Params) -> Result<Vec<Collection>> {
+        let cipher = self.cipher()?;
+        let mut stmt = self.connection.prepare(sql)?;
+        let rows = stmt.query_map(params, |row| {
+            Ok((
+                row.get::<_, usize>(0)?,
+                row.get::<_, Value>(1)?,
+                row.get::<_, usize>(2)?,
+            ))
+        })?;
+
+        let mut collections = Vec::new();
+        for row in rows {
+            let (id, name, clip_count) = row?;
+            collections.push(Collection {
+                id,
+                name: open_text(cipher, name)?
+                    .ok_or_else(|| eyre!("Collection {} has no name", id))?,
+                clip_count,
+            });
+        }
+        // Encrypted names can't be sorted by SQLite.
+        collections.sort_by(|a, b| a.name.cmp(&b.name));
+
+        Ok(collections)
+    }
+
+    pub fn rename_collection(&self, id: usize, new_name: &str) -> Result<()> {
+        if self.collection_id(new_name)?.is_some() {
+            return Err(eyre!(
+                "There is already a collection named \"{}\"",
+                new_name
+            ));
+        }
+        let rows_changed = self.connection.execute(
+            "UPDATE collections SET name = ?2 WHERE id = ?1",
+            params![id, seal_name(self.cipher()?, new_name)],
+        )?;
+
+        if rows_changed == 0 {
+            return Err(eyre!("There is no collection with ID {}", id));
+        }
+
+        Ok(())
+    }
+
+    /// Delete a collection. The clips in it are kept.
+    pub fn delete_collection(&self, id: usize) -> Result<()> {
+        let transaction = self.connection.unchecked_transaction()?;
+        transaction.execute(
+            "DELETE FROM collection_clips WHERE collection_id = ?1",
+            [id],
+        )?;
+        transaction.execute("DELETE FROM collections WHERE id = ?1", [id])?;
+        transaction.commit()?;
+
+        Ok(())
+    }
+
+    /// The clips in a collection, in its order. Clips in the trash are left out.
+    pub fn list_collection(&self, id: usize) -> Result<Vec<ClipMeta>> {
+        self.list(&ListQuery {
+            collection: Some(id),
+            sort: SortKey::Position,
+            ..Default::default()
+        })
+    }
+
+    /// Add a clip to the end of a collection.
+    pub fn add_to_collection(&self, id: usize, clip_id: usize) -> Result<()> {
+        let transaction = self.connection.unchecked_transaction()?;
+        self.append_to_collection(id, clip_id)?;
+        self.record(&Operation::AddToCollection {
+            id: clip_id,
+            collection: self.collection_name(id)?,
+        })?;
+        transaction.commit()?;
+
+        Ok(())
+    }
+
+    pub fn remove_from_collection(&self, id: usize, clip_id: usize) -> Result<()> {
+        let transaction = self.connection.unchecked_transaction()?;
+        self.take_from_collection(id, clip_id)?;
+        self.record(&Operation::RemoveFromCollection {
+            id: clip_id,
+            collection: self.collection_name(id)?,
+        })?;
+        transaction.commit()?;
+
+        Ok(())
+    }
+
+    /// Move a clip within a collection to `position`, counting from 0. Clips after it move
+    /// down. A position past the end moves it to the end.
+    pub fn move_in_collection(&self, id: usize, clip_id: usize, position: usize) -> Result<()> {
+        let mut clip_ids = self
+            .connection
+            .prepare(
+                "SELECT clip_id FROM collection_clips WHERE collection_id = ?1 ORDER BY position",
+            )?
+            .query_map([id], |row| row.get(0))?
+            .collect::<Result<Vec<usize>, rusqlite::Error>>()?;
+        let index = clip_ids
+            .iter()
+            .position(|&other| other == clip_id)
+            .ok_or_else(|| eyre!("Clip {} is not in collection {}", clip_id, id))?;
+        clip_ids.remove(index);
+        clip_ids.insert(position.min(clip_ids.len()), clip_id);
+
+        let transaction = self.connection.unchecked_transaction()?;
+        for (position, clip_id) in clip_ids.into_iter().enumerate() {
+            transaction.execute(
+                "UPDATE collection_clips SET position = ?3 WHERE collection_id = ?1 AND clip_id = ?2",
+                params![id, clip_id, position],
+            )?;
+        }
+        transaction.commit()?;
+
+        Ok(())
+    }
+
+    fn collection_name(&self, id: usize) -> Result<String> {
+        let name = self
+            .connection
+            .query_row("SELECT name FROM collections WHERE id = ?1", [id], |row| {
+                row.get::<_, Value>(0)
+            })
+            .optional()?
+            .ok_or_else(|| eyre!("There is no collection with ID {}", id))?;
+
+        open_text(self.cipher()?, name)?.ok_or_else(|| eyre!("Collection {} has no name", id))
+    }
+
+    pub(crate) fn in_collection(&self, id: usize, clip_id: usize) -> Result<bool> {
+        Ok(self.connection.query_row(
+            "SELECT EXISTS (SELECT 1 FROM collection_clips WHERE collection_id = ?1 AND clip_id = ?2)",
+            [id, clip_id],
+            |row| row.get(0),
+        )?)
+    }
+
+    /// Add a clip to the end of a collection without recording it in the history.
+    pub(crate) fn append_to_collection(&self, id: usize, clip_id: usize) -> Result<()> {
+        self.collection_name(id)?;
+        let exists: bool = self.connection.query_row(
+            "SELECT EXISTS (SELECT 1 FROM clips WHERE id = ?1)",
+            [clip_id],
+            |row| row.get(0),
+        )?;
+        if !exists {
+            return Err(eyre!("There is no clip with ID {}", clip_id));
+        }
+
+        let rows_changed = self.connection.execute(
+            "INSERT OR IGNORE INTO collection_clips (collection_id, clip_id, position) SELECT ?1, ?2, COALESCE(MAX(position) + 1, 0) FROM collection_clips WHERE collection_id = ?1",
+            [id, clip_id],
+        )?;
+        if rows_changed == 0 {
+            return Err(eyre!("Clip {} is already in this collection", clip_id));
+        }
+
+        Ok(())
+    }
+
+    /// Remove a clip from a collection without recording it in the history.
+    pub(crate) fn take_from_collection(&self, id: usize, clip_id: usize) -> Result<()> {
+        let rows_changed = self.connection.execute(
+            "DELETE FROM collection_clips WHERE collection_id = ?1 AND clip_id = ?2",
+            [id, clip_id],
+        )?;
+        if rows_changed == 0 {
+            return Err(eyre!("Clip {} is not in collection {}", clip_id, id));
+        }
+
+        Ok(())
+    }
+
+    /// Rewrite the names of collections, opening them with `from` and sealing them with `to`.
+    pub(crate) fn reseal_collections(
+        &self,
+        from: Option<&Cipher>,
+        to: Option<&Cipher>,
+    ) -> Result<()> {
+        let rows = self
+            .connection
+            .prepare("SELECT id, name FROM collections")?
+            .query_map([], |row| {
+                Ok((row.get::<_, usize>(0)?, row.get::<_, Value>(1)?))
+            })?
+            .collect::<Result<Vec<_>, rusqlite::Error>>()?;
+
+        for (id, name) in rows {
+            let name =
+                open_text(from, name)?.ok_or_else(|| eyre!("Collection {} has no name", id))?;
+            self.connection.execute(
+                "UPDATE collections SET name = ?2 WHERE id = ?1",
+                params![id, seal_name(to, &name)],
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_fixtures::clip;
+
+    #[test]
+    fn test_collections() {
+        let db = Db::in_memory().unwrap();
+        let ids: Vec<usize> = ["First", "Second", "Third"]
+            .into_iter()
+            .map(|name| {
+                let mut clip = clip(name);
+                db.save(&mut clip).unwrap();
+                clip.id.unwrap()
+            })
+            .collect();
+        let warmups = db.create_collection("Warmups").unwrap();
+        let reference = db.create_collection("Reference").unwrap();
+        assert!(db.create_collection("Warmups").is_err());
+
+        for &id in &ids {
+            db.add_to_collection(warmups, id).unwrap();
+        }
+        db.add_to_collection(reference, ids[1]).unwrap();
+        assert!(db.add_to_collection(reference, ids[1]).is_err());
+        let names = |db: &Db, id| -> Vec<String> {
+            db.list_collection(id)
+                .unwrap()
+                .into_iter()
+                .map(|meta| meta.name)
+                .collect()
+        };
+        assert_eq!(names(&db, warmups), ["First", "Second", "Third"]);
+
+        db.move_in_collection(warmups, ids[2], 0).unwrap();
+        db.move_in_collection(warmups, ids[1], 10).unwrap();
+        assert_eq!(names(&db, warmups), ["Third", "First", "Second"]);
+        let of_second: Vec<_> = db
+            .collections_of(ids[1])
+            .unwrap()
+            .into_iter()
+            .map(|collection| collection.name)
+            .collect();
+        assert_eq!(of_second, ["Reference", "Warmups"]);
+
+        // Clips in the trash are left out, and come back in their place.
+        db.delete("First").unwrap();
+        assert_eq!(names(&db, warmups), ["Third", "Second"]);
+        assert_eq!(db.collections().unwrap()[1].clip_count, 2);
+        db.undo().unwrap();
+        assert_eq!(names(&db, warmups), ["Third", "First", "Second"]);
+
+        db.remove_from_collection(warmups, ids[2]).unwrap();
+        assert_eq!(names(&db, warmups), ["First", "Second"]);
+        assert_eq!(
+            db.undo().unwrap().unwrap().to_string(),
+            format!("Remove clip {} from \"Warmups\"", ids[2])
+        );
+        assert_eq!(names(&db, warmups), ["First", "Second", "Third"]);
+
+        db.rename_collection(warmups, "Daily").unwrap();
+        assert_eq!(db.collection_id("Daily").unwrap(), Some(warmups));
+        db.delete_collection(warmups).unwrap();
+        assert_eq!(db.collections().unwrap().len(), 1);
+        assert_eq!(db.list(&ListQuery::default()).unwrap().len(), 3);
+    }
+}
@@ -1,28 +1,151 @@
-use std::path::Path;
+use std::io::{Cursor, Read};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
 
-use crate::audio_clip::AudioClip;
-use crate::internal_encoding::{decode_v0, decode_v1, encode_v1};
+use crate::audio_clip::{AudioBackend, AudioClip, ClipCache, RecordHandle};
+use crate::encryption::{
+    open_bytes, open_text, seal_bytes, seal_name, seal_text, Cipher, KeyParams,
+};
+use crate::history::Operation;
+use crate::import::EncodedImport;
+use crate::internal_encoding::{
+    decode_v0, encode, encode_v1, v1_to_v2, EncodedAudio, EncodingProfile,
+};
+use crate::peaks::PeakPyramid;
+use crate::recovery::{self, UnfinishedRecording};
 use chrono::prelude::*;
 use color_eyre::eyre::{eyre, Result};
 use directories::ProjectDirs;
-use rusqlite::{params, types::Type, Connection};
+use rusqlite::{
+    params, params_from_iter,
+    types::{Type, Value},
+    Connection, DatabaseName, OptionalExtension, Params,
+};
 
-pub struct Db(Connection);
+/// The columns of the clips table, which the trash and quarantine tables share.
+pub(crate) const CLIP_COLUMNS: &str =
+    "id, name, date, notes, sample_rate, opus, peaks, format, profile, duration";
 
+pub struct Db {
+    pub(crate) connection: Connection,
+    /// The database file, or None for in-memory databases.
+    path: Option<PathBuf>,
+    /// Whether clips are encrypted, and the cipher for them once unlocked.
+    encrypted: bool,
+    cipher: Option<Cipher>,
+    /// Where recordings in progress are journaled. Recordings aren't journaled if this is
+    /// None.
+    journal_dir: Option<PathBuf>,
+}
+
+/// A clip whose audio is already encoded, being inserted.
+pub(crate) struct StoredClip<'a> {
+    pub name: &'a str,
+    pub date: DateTime<Utc>,
+    pub notes: Option<&'a str>,
+    pub sample_rate: u32,
+    pub peaks: Option<Vec<u8>>,
+    pub profile: EncodingProfile,
+    /// The duration in seconds, if known. Unknown durations are worked out from the audio
+    /// when the journal is next opened.
+    pub duration: Option<f64>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
 pub struct ClipMeta {
     pub id: usize,
     pub name: String,
     pub date: DateTime<Utc>,
+    pub notes: Option<String>,
+    /// The duration in seconds, or 0 if the audio is too damaged to tell.
+    pub duration: f64,
+}
+
+/// What `Db::list` lists, and in what order.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ListQuery {
+    pub sort: SortKey,
+    pub descending: bool,
+    /// Only clips recorded at or after this time.
+    pub since: Option<DateTime<Utc>>,
+    /// Only clips recorded before this time.
+    pub until: Option<DateTime<Utc>>,
+    /// Only clips whose name contains this, ignoring case.
+    pub name_contains: Option<String>,
+    /// Only clips in this collection.
+    pub collection: Option<usize>,
+    /// At most this many clips, after skipping `offset` of them.
+    pub limit: Option<usize>,
+    pub offset: usize,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SortKey {
+    #[default]
+    Date,
+    /// Ignoring case.
+    Name,
+    Duration,
+    /// The order of the collection being listed, or by date if there is none.
+    Position,
+}
+/// A stored clip whose audio has been read but not decoded, so that its metadata and
+/// duration are available right away. Ranges of it can be decoded without decoding it all.
+pub struct LazyClip {
+    pub meta: ClipMeta,
+    audio: EncodedAudio,
+    peaks: Option<Vec<u8>>,
+}
+
+impl LazyClip {
+    pub fn sample_rate(&self) -> u32 {
+        self.audio.sample_rate()
+    }
+
+    pub fn num_samples(&self) -> usize {
+        self.audio.num_samples()
+    }
+
+    /// The duration in seconds.
+    pub fn duration(&self) -> f64 {
+        self.num_samples() as f64 / self.sample_rate() as f64
+    }
+
+    /// Decode the samples in `range`, at the clip's sample rate.
+    pub fn decode_range(&self, range: Range<usize>) -> Result<Vec<f32>> {
+        self.audio.decode_range(range)
+    }
+
+    pub fn decode(self) -> Result<AudioClip> {
+        let samples = self.audio.decode()?;
+
+        Ok(AudioClip {
+            id: Some(self.meta.id),
+            name: self.meta.name,
+            date: self.meta.date,
+            notes: self.meta.notes,
+            sample_rate: self.audio.sample_rate(),
+            cache: clip_cache(self.peaks.as_deref(), samples.len()),
+            samples,
+        })
+    }
 }
+
 impl Db {
-    pub fn open() -> Result<Db> {
+    /// The database in the user's data directory.
+    pub fn default_path() -> Result<PathBuf> {
         let proj_dirs = ProjectDirs::from("ca", "nettek", "oxygen").ok_or_else(|| {
             eyre!("Could not find project directories (home directory could not be retreived)")
         })?;
-        let data_dir = proj_dirs.data_dir();
 
+        Ok(proj_dirs.data_dir().join("oxygen.sqlite"))
+    }
+
+    /// Open the database in the user's data directory.
+    pub fn open() -> Result<Db> {
+        let db_file_path = Self::default_path()?;
+        let data_dir = db_file_path.parent().expect("default path has a parent");
         std::fs::create_dir_all(data_dir)?;
-        let db_file_path = data_dir.join("oxygen.sqlite");
 
         if Path::new("oxygen.sqlite").exists() && !db_file_path.exists() {
             log::info!("Migration: moving oxygen.sqlite to {:?}", db_file_path);
@@ -30,8 +153,38 @@ impl Db {
             std::fs::remove_file("oxygen.sqlite")?;
         }
 
-        let connection = Connection::open(db_file_path)?;
-        Self::from_connection(connection)
+        Self::open_with_journal(&db_file_path, data_dir.join("journal"))
+    }
+
+    /// Open the database at `path`, creating it if needed. Recordings in progress are
+    /// journaled next to it, in a folder with the same name and a ".journal" extension, so
+    /// each database has its own.
+    pub fn open_at(path: &Path) -> Result<Db> {
+        if let Some(parent) = path
+            .parent()
+            .filter(|parent| !parent.as_os_str().is_empty())
+        {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        Self::open_with_journal(path, path.with_extension("journal"))
+    }
+
+    fn open_with_journal(path: &Path, journal_dir: PathBuf) -> Result<Db> {
+        let connection = Connection::open(path)?;
+        let mut db = Self::from_connection(connection)?;
+        db.path = Some(path.to_path_buf());
+        db.set_journal_dir(Some(journal_dir))?;
+
+        let unfinished = db.unfinished_recordings()?;
+        if !unfinished.is_empty() {
+            log::warn!(
+                "Found {} unfinished recording(s) that can be recovered",
+                unfinished.len()
+            );
+        }
+
+        Ok(db)
     }
 
     pub fn in_memory() -> Result<Db> {
@@ -45,11 +198,14 @@ impl Db {
                 r.get(0)
             })?;
         connection.pragma_update(None, "page_size", 8192)?;
-        connection.pragma_update(None, "user_version", 2)?;
+
+        // The migrations are applied together, so that a journal is never left part way
+        // between versions.
+        let transaction = connection.unchecked_transaction()?;
 
         if user_version < 1 {
             log::info!("Migration: init schema...");
-            connection.execute(
+            transaction.execute(
                 "
                 CREATE TABLE IF NOT EXISTS clips (
                   id INTEGER PRIMARY KEY,
@@ -66,7 +222,7 @@ impl Db {
         if user_version < 2 {
             log::info!("Migration: updating schema to version 2...");
             let mut stmt =
-                connection.prepare("SELECT id, name, date, sample_rate, samples FROM clips")?;
+                transaction.prepare("SELECT id, name, date, sample_rate, samples FROM clips")?;
             let clip_iter = stmt.query_map([], |row| {
                 let date: String = row.get(2)?;
                 let samples: Vec<u8> = row.get(4)?;
@@ -77,15 +233,17 @@ impl Db {
                     date: date.parse().map_err(|_| {
                         rusqlite::Error::InvalidColumnType(2, "date".to_string(), Type::Text)
                     })?,
+                    notes: None,
                     sample_rate: row.get(3)?,
                     samples: decode_v0(&samples),
+                    cache: ClipCache::default(),
                 })
             })?;
 
             let clips: Vec<_> = clip_iter.collect::<Result<_, rusqlite::Error>>()?;
             for clip in &clips {
                 let (sr, bytes) = encode_v1(clip)?;
-                connection.execute(
+                transaction.execute(
                     "INSERT OR REPLACE INTO clips (id, name, date, sample_rate, samples) VALUES (?1, ?2, ?3, ?4, ?5)",
                     params![
                         clip.id,
@@ -97,142 +255,792 @@ impl Db {
                 )?;
             }
 
-            connection.execute("ALTER TABLE clips RENAME COLUMN samples TO opus", [])?;
+            transaction.execute("ALTER TABLE clips RENAME COLUMN samples TO opus", [])?;
+        }
+
+        if user_version < 3 {
+            log::info!("Migration: updating schema to version 3...");
+            // Peaks are computed when clips are next saved, or when they are drawn.
+            transaction.execute("ALTER TABLE clips ADD COLUMN peaks BLOB", [])?;
+        }
+
+        if user_version < 4 {
+            log::info!("Migration: updating schema to version 4...");
+            transaction.execute("ALTER TABLE clips ADD COLUMN notes TEXT", [])?;
+        }
+
+        if user_version < 5 {
+            log::info!("Migration: updating schema to version 5...");
+            // A row is added when the journal is encrypted.
+            transaction.execute(
+                "
+                CREATE TABLE encryption (
+                  salt BLOB NOT NULL,
+                  m_cost INTEGER NOT NULL,
+                  t_cost INTEGER NOT NULL,
+                  p_cost INTEGER NOT NULL,
+                  check_value BLOB NOT NULL
+                );
+                ",
+                [],
+            )?;
+        }
+
+        if user_version < 6 {
+            log::info!("Migration: updating schema to version 6...");
+            // Clips are converted from encode_v1 below, or once an encrypted journal is
+            // unlocked.
+            transaction.execute(
+                "ALTER TABLE clips ADD COLUMN format INTEGER NOT NULL DEFAULT 1",
+                [],
+            )?;
+        }
+
+        if user_version < 7 {
+            log::info!("Migration: updating schema to version 7...");
+            // Every clip so far was encoded for voice.
+            transaction.execute(
+                "ALTER TABLE clips ADD COLUMN profile TEXT NOT NULL DEFAULT 'voice'",
+                [],
+            )?;
+        }
+
+        if user_version < 8 {
+            log::info!("Migration: updating schema to version 8...");
+            // Clips that fail `fsck` are moved here, as they were, with no constraints on
+            // what they hold.
+            transaction.execute(
+                "
+                CREATE TABLE quarantine (
+                  id INTEGER PRIMARY KEY,
+                  name,
+                  date,
+                  notes,
+                  sample_rate,
+                  opus,
+                  peaks,
+                  format,
+                  profile,
+                  reason TEXT NOT NULL,
+                  quarantined TEXT NOT NULL
+                );
+                ",
+                [],
+            )?;
+        }
+
+        if user_version < 9 {
+            log::info!("Migration: updating schema to version 9...");
+            // Deleted clips are moved to the trash, keeping their IDs, and changes to clips are
+            // recorded in the history so that they can be undone.
+            transaction.execute_batch(
+                "
+                CREATE TABLE trash (
+                  id INTEGER PRIMARY KEY,
+                  name TEXT NOT NULL,
+                  date TEXT NOT NULL,
+                  notes TEXT,
+                  sample_rate INTEGER NOT NULL,
+                  opus BLOB NOT NULL,
+                  peaks BLOB,
+                  format INTEGER NOT NULL,
+                  profile TEXT NOT NULL,
+                  deleted TEXT NOT NULL
+                );
+                CREATE TABLE history (
+                  id INTEGER PRIMARY KEY,
+                  operation TEXT NOT NULL,
+                  clip_id INTEGER NOT NULL,
+                  before TEXT,
+                  after TEXT,
+                  undone INTEGER NOT NULL DEFAULT 0,
+                  date TEXT NOT NULL
+                );
+                ",
+            )?;
+        }
+
+        if user_version < 10 {
+            log::info!("Migration: updating schema to version 10...");
+            // Clips stay in their collections while they are in the trash.
+            transaction.execute_batch(
+                "
+                CREATE TABLE collections (
+                  id INTEGER PRIMARY KEY,
+                  name TEXT NOT NULL UNIQUE
+                );
+                CREATE TABLE collection_clips (
+                  collection_id INTEGER NOT NULL,
+                  clip_id INTEGER NOT NULL,
+                  position INTEGER NOT NULL,
+                  PRIMARY KEY (collection_id, clip_id)
+                );
+                ",
+            )?;
+        }
+
+        if user_version < 11 {
+            log::info!("Migration: updating schema to version 11...");
+            // Durations are worked out by `fill_durations`, now or once an encrypted journal
+            // is unlocked.
+            transaction.execute_batch(
+                "
+                ALTER TABLE clips ADD COLUMN duration REAL;
+                ALTER TABLE trash ADD COLUMN duration REAL;
+                ALTER TABLE quarantine ADD COLUMN duration;
+                ",
+            )?;
+        }
+
+        if user_version < 12 {
+            log::info!("Migration: updating schema to version 12...");
+            // Who speaks when in each diarized clip, and the names given to its speakers.
+            transaction.execute_batch(
+                "
+                CREATE TABLE speaker_turns (
+                  clip_id INTEGER NOT NULL,
+                  start_time REAL NOT NULL,
+                  end_time REAL NOT NULL,
+                  speaker INTEGER NOT NULL
+                );
+                CREATE TABLE speakers (
+                  clip_id INTEGER NOT NULL,
+                  speaker INTEGER NOT NULL,
+                  name TEXT,
+                  PRIMARY KEY (clip_id, speaker)
+                );
+                ",
+            )?;
+        }
+
+        transaction.pragma_update(None, "user_version", 12)?;
+        transaction.commit()?;
+
+        let encrypted =
+            connection.query_row("SELECT EXISTS (SELECT 1 FROM encryption)", [], |row| {
+                row.get(0)
+            })?;
+
+        let db = Db {
+            connection,
+            path: None,
+            encrypted,
+            cipher: None,
+            journal_dir: None,
+        };
+        if !db.encrypted {
+            db.upgrade_clips()?;
+            db.fill_durations()?;
+        }
+
+        Ok(db)
+    }
+
+    /// Convert clips stored in `encode_v1` format to `encode_v2`, without decoding them. A
+    /// clip that can't be converted is left as it is.
+    fn upgrade_clips(&self) -> Result<()> {
+        let cipher = self.cipher()?;
+        let ids = self
+            .connection
+            .prepare("SELECT id FROM clips WHERE format = 1")?
+            .query_map([], |row| row.get(0))?
+            .collect::<Result<Vec<usize>, rusqlite::Error>>()?;
+        if ids.is_empty() {
+            return Ok(());
+        }
+
+        log::info!("Migration: converting {} clips to encode_v2...", ids.len());
+        let transaction = self.connection.unchecked_transaction()?;
+        for id in ids {
+            let (sample_rate, opus): (u32, Vec<u8>) = transaction.query_row(
+                "SELECT sample_rate, opus FROM clips WHERE id = ?1",
+                [id],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )?;
+            let opus = match v1_to_v2(sample_rate, &open_bytes(cipher, opus)?) {
+                Ok(opus) => opus,
+                Err(err) => {
+                    log::error!("Could not convert clip {}: {:?}", id, err);
+                    continue;
+                }
+            };
+            transaction.execute(
+                "UPDATE clips SET opus = ?2, format = 2 WHERE id = ?1",
+                params![id, seal_bytes(cipher, opus)],
+            )?;
+        }
+        transaction.commit()?;
+
+        Ok(())
+    }
+
+    /// Work out the duration of clips saved before durations were stored, from their audio,
+    /// without decoding it. Clips whose audio can't be read are left without a duration.
+    pub(crate) fn fill_durations(&self) -> Result<()> {
+        let cipher = self.cipher()?;
+        for table in ["clips", "trash"] {
+            let ids = self
+                .connection
+                .prepare(&format!("SELECT id FROM {} WHERE duration IS NULL", table))?
+                .query_map([], |row| row.get(0))?
+                .collect::<Result<Vec<usize>, rusqlite::Error>>()?;
+            if ids.is_empty() {
+                continue;
+            }
+
+            log::info!(
+                "Migration: working out the duration of {} clips...",
+                ids.len()
+            );
+            let transaction = self.connection.unchecked_transaction()?;
+            for id in ids {
+                let (format, sample_rate, opus): (u32, u32, Vec<u8>) = transaction.query_row(
+                    &format!(
+                        "SELECT format, sample_rate, opus FROM {} WHERE id = ?1",
+                        table
+                    ),
+                    [id],
+                    |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+                )?;
+                let audio = match open_bytes(cipher, opus)
+                    .and_then(|opus| parse_audio(format, sample_rate, opus))
+                {
+                    Ok(audio) => audio,
+                    Err(err) => {
+                        log::error!("Could not read the duration of clip {}: {:?}", id, err);
+                        continue;
+                    }
+                };
+                transaction.execute(
+                    &format!("UPDATE {} SET duration = ?2 WHERE id = ?1", table),
+                    params![id, audio.num_samples() as f64 / audio.sample_rate() as f64],
+                )?;
+            }
+            transaction.commit()?;
         }
 
-        Ok(Db(connection))
+        Ok(())
     }
 
+    /// Save a clip, encoding it with the profile it was saved with before, or for voice if
+    /// it is new.
     pub fn save(&self, clip: &mut AudioClip) -> Result<()> {
-        let (sr, bytes) = encode_v1(clip)?;
+        let profile = match clip.id {
+            Some(id) => self.profile(id)?.unwrap_or_default(),
+            None => EncodingProfile::default(),
+        };
+        self.save_with_profile(clip, profile)
+    }
+
+    pub fn save_with_profile(&self, clip: &mut AudioClip, profile: EncodingProfile) -> Result<()> {
+        let cipher = self.cipher()?;
+        let (sr, bytes) = encode(clip, profile)?;
+        let id = match clip.id {
+            Some(id) => id,
+            None => self.next_id()?,
+        };
+
+        // The peaks must describe the clip as it will be loaded, at the encoded sample rate.
+        let peaks = if sr == clip.sample_rate {
+            let mut peaks = clip.cache.peaks.lock().unwrap();
+            peaks.update(&clip.samples);
+            peaks.encode()
+        } else {
+            PeakPyramid::from_samples(&clip.resample(sr).samples).encode()
+        };
 
-        self.0.execute(
-            "INSERT OR REPLACE INTO clips (id, name, date, sample_rate, opus) VALUES (?1, ?2, ?3, ?4, ?5)",
+        self.connection.execute(
+            "INSERT OR REPLACE INTO clips (id, name, date, notes, sample_rate, opus, peaks, format, profile, duration) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
             params![
-                clip.id,
-                clip.name,
+                id,
+                seal_name(cipher, &clip.name),
                 clip.date.to_string(),
+                seal_text(cipher, clip.notes.as_deref()),
                 sr,
-                bytes,
+                seal_bytes(cipher, bytes),
+                seal_bytes(cipher, peaks),
+                profile.format(),
+                profile.name(),
+                clip.samples.len() as f64 / clip.sample_rate as f64,
             ],
         )?;
 
-        if clip.id.is_none() {
-            clip.id = Some(self.0.last_insert_rowid().try_into()?);
-        }
+        clip.id = Some(id);
 
         Ok(())
     }
 
-    pub fn load(&self, name: &str) -> Result<Option<AudioClip>> {
-        let mut stmt = self
-            .0
-            .prepare("SELECT id, name, date, sample_rate, opus FROM clips WHERE name = ?1")?;
-        let mut clip_iter = stmt.query_map([name], |row| {
-            let date: String = row.get(2)?;
-            let bytes: Vec<u8> = row.get(4)?;
-            let sample_rate: u32 = row.get(3)?;
-            let samples = decode_v1(sample_rate, &bytes).map_err(|_| {
-                rusqlite::Error::InvalidColumnType(3, "opus".to_string(), Type::Blob)
-            })?;
+    /// An ID for a new clip. IDs of clips in the trash or in quarantine are not reused, so
+    /// that they can be put back, and neither are IDs that collections, the history or
+    /// speakers still refer to.
+    fn next_id(&self) -> Result<usize> {
+        Ok(self.connection.query_row(
+            "SELECT COALESCE(MAX(id), 0) + 1 FROM (SELECT id FROM clips UNION ALL SELECT id FROM trash UNION ALL SELECT id FROM quarantine UNION ALL SELECT clip_id FROM collection_clips UNION ALL SELECT clip_id FROM history UNION ALL SELECT clip_id FROM speakers)",
+            [],
+            |row| row.get(0),
+        )?)
+    }
 
-            Ok(AudioClip {
-                id: Some(row.get(0)?),
-                name: row.get(1)?,
-                date: date.parse().map_err(|_| {
-                    rusqlite::Error::InvalidColumnType(2, "date".to_string(), Type::Text)
-                })?,
-                sample_rate,
-                samples,
+    /// The profile a clip was encoded with, or None if there is no clip with the given ID.
+    pub fn profile(&self, id: usize) -> Result<Option<EncodingProfile>> {
+        let name: Option<String> = self
+            .connection
+            .query_row("SELECT profile FROM clips WHERE id = ?1", [id], |row| {
+                row.get(0)
             })
-        })?;
+            .optional()?;
+        name.map(|name| EncodingProfile::from_name(&name))
+            .transpose()
+    }
 
-        Ok(if let Some(clip) = clip_iter.next() {
-            Some(clip?)
-        } else {
-            None
-        })
+    /// Decode a clip and encode it again with `profile`, keeping its ID. Re-encoding a lossy
+    /// clip as lossless keeps it as it is now, but stops it losing more.
+    pub fn reencode(&self, name: &str, profile: EncodingProfile) -> Result<AudioClip> {
+        let mut clip = self
+            .load(name)?
+            .ok_or_else(|| eyre!("There is no clip named \"{}\"", name))?;
+        self.save_with_profile(&mut clip, profile)?;
+
+        Ok(clip)
+    }
+
+    pub fn reencode_by_id(&self, id: usize, profile: EncodingProfile) -> Result<AudioClip> {
+        let mut clip = self
+            .load_by_id(id)?
+            .ok_or_else(|| eyre!("There is no clip with ID {}", id))?;
+        self.save_with_profile(&mut clip, profile)?;
+
+        Ok(clip)
+    }
+
+    /// Whether there is a clip with the given name.
+    pub fn name_exists(&self, name: &str) -> Result<bool> {
+        Ok(self.connection.query_row(
+            "SELECT EXISTS (SELECT 1 FROM clips WHERE name = ?1)",
+            [seal_name(self.cipher()?, name)],
+            |row| row.get(0),
+        )?)
+    }
+
+    /// Save an imported clip, streaming its encoded audio into the database without holding
+    /// it all in memory. With `replace`, a clip with the same name is moved to the trash, as
+    /// if deleted, so that replacing it can be undone.
+    pub fn save_encoded(
+        &self,
+        name: &str,
+        encoded: &mut EncodedImport,
+        replace: bool,
+    ) -> Result<usize> {
+        let peaks = encoded.peaks.encode();
+        let (date, notes, sample_rate, profile) = (
+            encoded.date,
+            encoded.notes.clone(),
+            encoded.sample_rate,
+            encoded.profile,
+        );
+        let duration = encoded.num_samples as f64 / sample_rate as f64;
+        let (mut opus, opus_len) = encoded.reader()?;
+
+        let transaction = self.connection.unchecked_transaction()?;
+        if replace {
+            if let Some(id) = self.clip_id(name)? {
+                let name = self.move_to_trash(id)?;
+                self.record(&Operation::Delete { id, name })?;
+            }
+        }
+        let clip = StoredClip {
+            name,
+            date,
+            notes: notes.as_deref(),
+            sample_rate,
+            peaks: Some(peaks),
+            profile,
+            duration: Some(duration),
+        };
+        let id = self.insert_stored(&clip, &mut opus, opus_len)?;
+        transaction.commit()?;
+
+        Ok(id)
+    }
+
+    /// Insert a clip whose audio is already encoded, copying `opus_len` bytes of it from
+    /// `opus`. Unencrypted audio is streamed into the database; encrypted audio is sealed in
+    /// memory first.
+    pub(crate) fn insert_stored(
+        &self,
+        clip: &StoredClip,
+        opus: &mut impl Read,
+        opus_len: u64,
+    ) -> Result<usize> {
+        let cipher = self.cipher()?;
+        let sealed_opus = match cipher {
+            Some(cipher) => {
+                let mut bytes = Vec::with_capacity(opus_len as usize);
+                opus.take(opus_len).read_to_end(&mut bytes)?;
+                Some(cipher.seal(&bytes))
+            }
+            None => None,
+        };
+        let stored_len = sealed_opus
+            .as_ref()
+            .map(|sealed| sealed.len() as u64)
+            .unwrap_or(opus_len);
+
+        self.connection.execute(
+            "INSERT INTO clips (id, name, date, notes, sample_rate, opus, peaks, format, profile, duration) VALUES (?1, ?2, ?3, ?4, ?5, zeroblob(?6), ?7, ?8, ?9, ?10)",
+            params![
+                self.next_id()?,
+                seal_name(cipher, clip.name),
+                clip.date.to_string(),
+                seal_text(cipher, clip.notes),
+                clip.sample_rate,
+                stored_len,
+                clip.peaks.clone().map(|peaks| seal_bytes(cipher, peaks)),
+                clip.profile.format(),
+                clip.profile.name(),
+                clip.duration,
+            ],
+        )?;
+        let id = self.connection.last_insert_rowid();
+
+        let mut blob = self
+            .connection
+            .blob_open(DatabaseName::Main, "clips", "opus", id, false)?;
+        let written = match sealed_opus {
+            Some(sealed) => std::io::copy(&mut &sealed[..], &mut blob)?,
+            None => std::io::copy(&mut opus.take(opus_len), &mut blob)?,
+        };
+        if written != stored_len {
+            return Err(eyre!(
+                "Expected {} bytes of audio, got {}",
+                opus_len,
+                written
+            ));
+        }
+
+        Ok(id.try_into()?)
+    }
+
+    /// The encoded audio of a clip, in `encode_v2` format or as FLAC for lossless clips, and
+    /// its length.
+    pub(crate) fn read_stored_opus(&self, id: usize) -> Result<(Box<dyn Read + '_>, u64)> {
+        let cipher = self.cipher()?;
+        let (format, sample_rate): (u32, u32) = self.connection.query_row(
+            "SELECT format, sample_rate FROM clips WHERE id = ?1",
+            [id],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )?;
+        match (cipher, format) {
+            (Some(_), _) | (None, 1) => {
+                let stored: Vec<u8> = self.connection.query_row(
+                    "SELECT opus FROM clips WHERE id = ?1",
+                    [id],
+                    |row| row.get(0),
+                )?;
+                let mut opus = open_bytes(cipher, stored)?;
+                if format == 1 {
+                    opus = v1_to_v2(sample_rate, &opus)?;
+                }
+                let len = opus.len() as u64;
+                Ok((Box::new(Cursor::new(opus)), len))
+            }
+            (None, _) => {
+                let blob = self.connection.blob_open(
+                    DatabaseName::Main,
+                    "clips",
+                    "opus",
+                    id as i64,
+                    true,
+                )?;
+                let len = blob.len() as u64;
+                Ok((Box::new(blob), len))
+            }
+        }
+    }
+
+    pub fn load(&self, name: &str) -> Result<Option<AudioClip>> {
+        self.load_lazy(name)?.map(LazyClip::decode).transpose()
     }
 
     pub fn load_by_id(&self, id: usize) -> Result<Option<AudioClip>> {
-        let mut stmt = self
-            .0
-            .prepare("SELECT id, name, date, sample_rate, opus FROM clips WHERE id = ?1")?;
-        let mut clip_iter = stmt.query_map([id], |row| {
-            let date: String = row.get(2)?;
-            let bytes: Vec<u8> = row.get(4)?;
-            let sample_rate: u32 = row.get(3)?;
-            let samples = decode_v1(sample_rate, &bytes).map_err(|_| {
-                rusqlite::Error::InvalidColumnType(3, "opus".to_string(), Type::Blob)
-            })?;
+        self.load_lazy_by_id(id)?.map(LazyClip::decode).transpose()
+    }
 
-            Ok(AudioClip {
-                id: Some(row.get(0)?),
-                name: row.get(1)?,
-                date: date.parse().map_err(|_| {
-                    rusqlite::Error::InvalidColumnType(2, "date".to_string(), Type::Text)
-                })?,
-                sample_rate,
-                samples,
+    /// Like `load`, but without decoding the audio.
+    pub fn load_lazy(&self, name: &str) -> Result<Option<LazyClip>> {
+        self.load_where("name = ?1", seal_name(self.cipher()?, name))
+    }
+
+    /// Like `load_by_id`, but without decoding the audio.
+    pub fn load_lazy_by_id(&self, id: usize) -> Result<Option<LazyClip>> {
+        self.load_where("id = ?1", Value::Integer(id as i64))
+    }
+
+    fn load_where(&self, condition: &str, param: Value) -> Result<Option<LazyClip>> {
+        let cipher = self.cipher()?;
+        let mut stmt = self.connection.prepare(&format!(
+            "SELECT id, name, date, sample_rate, opus, peaks, notes, format FROM clips WHERE {}",
+            condition
+        ))?;
+        let row = stmt
+            .query_row([param], |row| {
+                Ok((
+                    row.get::<_, usize>(0)?,
+                    row.get::<_, Value>(1)?,
+                    row.get::<_, String>(2)?,
+                    row.get::<_, u32>(3)?,
+                    row.get::<_, Vec<u8>>(4)?,
+                    row.get::<_, Option<Vec<u8>>>(5)?,
+                    row.get::<_, Value>(6)?,
+                    row.get::<_, u32>(7)?,
+                ))
             })
-        })?;
+            .optional()?;
+        let (id, name, date, sample_rate, opus, peaks, notes, format) = match row {
+            Some(row) => row,
+            None => return Ok(None),
+        };
 
-        Ok(if let Some(clip) = clip_iter.next() {
-            Some(clip?)
-        } else {
-            None
-        })
+        let audio = parse_audio(format, sample_rate, open_bytes(cipher, opus)?)?;
+
+        Ok(Some(LazyClip {
+            meta: ClipMeta {
+                id,
+                name: open_text(cipher, name)?.ok_or_else(|| eyre!("Clip {} has no name", id))?,
+                date: date
+                    .parse()
+                    .map_err(|_| eyre!("Invalid date for clip {}: {}", id, date))?,
+                notes: open_text(cipher, notes)?,
+                duration: audio.num_samples() as f64 / audio.sample_rate() as f64,
+            },
+            audio,
+            peaks: peaks.map(|peaks| open_bytes(cipher, peaks)).transpose()?,
+        }))
     }
 
-    pub fn list(&self) -> Result<Vec<ClipMeta>> {
-        let mut stmt = self
-            .0
-            .prepare("SELECT id, name, date FROM clips ORDER BY date")?;
-        let clip_iter = stmt.query_map([], |row| {
-            let date: String = row.get(2)?;
+    /// The clips that match `query`, in its order.
+    pub fn list(&self, query: &ListQuery) -> Result<Vec<ClipMeta>> {
+        let mut sql = "SELECT id, name, date, notes, duration FROM clips".to_string();
+        let mut params = Vec::new();
+        if let Some(collection) = query.collection {
+            sql.push_str(" JOIN collection_clips ON clip_id = id AND collection_id = ?");
+            params.push(Value::Integer(collection as i64));
+        }
+        let mut conditions = Vec::new();
+        if let Some(since) = query.since {
+            conditions.push("date >= ?");
+            params.push(Value::Text(since.to_string()));
+        }
+        if let Some(until) = query.until {
+            conditions.push("date < ?");
+            params.push(Value::Text(until.to_string()));
+        }
+        if !conditions.is_empty() {
+            sql.push_str(" WHERE ");
+            sql.push_str(&conditions.join(" AND "));
+        }
 
-            Ok(ClipMeta {
-                id: row.get(0)?,
-                name: row.get(1)?,
-                date: date.parse().map_err(|_| {
-                    rusqlite::Error::InvalidColumnType(2, "date".to_string(), Type::Text)
-                })?,
-            })
+        let column = match query.sort {
+            SortKey::Duration => "duration",
+            SortKey::Position if query.collection.is_some() => "position",
+            SortKey::Date | SortKey::Name | SortKey::Position => "date",
+        };
+        let direction = if query.descending { "DESC" } else { "ASC" };
+        sql.push_str(&format!(
+            " ORDER BY {} {}, id {}",
+            column, direction, direction
+        ));
+
+        // Names may be encrypted, so they are matched and sorted once they are read.
+        let by_name = query.sort == SortKey::Name || query.name_contains.is_some();
+        if !by_name {
+            sql.push_str(" LIMIT ? OFFSET ?");
+            params.push(Value::Integer(
+                query.limit.map(|limit| limit as i64).unwrap_or(-1),
+            ));
+            params.push(Value::Integer(query.offset as i64));
+        }
+        let mut clips = self.query_metas(&sql, params_from_iter(params))?;
+        if !by_name {
+            return Ok(clips);
+        }
+
+        if let Some(needle) = &query.name_contains {
+            let needle = needle.to_lowercase();
+            clips.retain(|clip| clip.name.to_lowercase().contains(&needle));
+        }
+        if query.sort == SortKey::Name {
+            clips.sort_by(|a, b| {
+                let order = a
+                    .name
+                    .to_lowercase()
+                    .cmp(&b.name.to_lowercase())
+                    .then(a.id.cmp(&b.id));
+                if query.descending {
+                    order.reverse()
+                } else {
+                    order
+                }
+            });
+        }
+
+        Ok(clips
+            .into_iter()
+            .skip(query.offset)
+            .take(query.limit.unwrap_or(usize::MAX))
+            .collect())
+    }
+
+    /// The metadata of the clips that `sql` selects the id, name, date, notes and duration
+    /// of.
+    pub(crate) fn query_metas(&self, sql: &str, params: impl Params) -> Result<Vec<ClipMeta>> {
+        let cipher = self.cipher()?;
+        let mut stmt = self.connection.prepare(sql)?;
+        let rows = stmt.query_map(params, |row| {
+            Ok((
+                row.get::<_, usize>(0)?,
+                row.get::<_, Value>(1)?,
+                row.get::<_, String>(2)?,
+                row.get::<_, Value>(3)?,
+                row.get::<_, Option<f64>>(4)?,
+            ))
         })?;
 
-        Ok(clip_iter.collect::<Result<_, rusqlite::Error>>()?)
+        let mut clips = Vec::new();
+        for row in rows {
+            let (id, name, date, notes, duration) = row?;
+            clips.push(ClipMeta {
+                id,
+                name: open_text(cipher, name)?.ok_or_else(|| eyre!("Clip {} has no name", id))?,
+                date: date
+                    .parse()
+                    .map_err(|_| eyre!("Invalid date for clip {}: {}", id, date))?,
+                notes: open_text(cipher, notes)?,
+                duration: duration.unwrap_or_default(),
+            });
+        }
+
+        Ok(clips)
     }
 
+    /// Move the clip with the given name to the trash, if there is one.
     pub fn delete(&self, name: &str) -> Result<()> {
-        self.0
-            .execute("DELETE FROM clips WHERE name = ?1", [name])?;
-
-        Ok(())
+        match self.clip_id(name)? {
+            Some(id) => self.delete_by_id(id),
+            None => Ok(()),
+        }
     }
 
     pub fn delete_by_id(&self, id: usize) -> Result<()> {
-        self.0.execute("DELETE FROM clips WHERE id = ?1", [id])?;
+        let exists: bool = self.connection.query_row(
+            "SELECT EXISTS (SELECT 1 FROM clips WHERE id = ?1)",
+            [id],
+            |row| row.get(0),
+        )?;
+        if !exists {
+            return Ok(());
+        }
+
+        let transaction = self.connection.unchecked_transaction()?;
+        let name = self.move_to_trash(id)?;
+        self.record(&Operation::Delete { id, name })?;
+        transaction.commit()?;
 
         Ok(())
     }
 
     pub fn rename(&self, old_name: &str, new_name: &str) -> Result<()> {
-        let rows_changed = self.0.execute(
-            "UPDATE clips SET name = ?2 WHERE name = ?1",
-            [old_name, new_name],
+        let id = self
+            .clip_id(old_name)?
+            .ok_or_else(|| eyre!("There is no clip named \"{}\"", old_name))?;
+        self.rename_by_id(id, new_name)
+    }
+
+    pub fn rename_by_id(&self, id: usize, new_name: &str) -> Result<()> {
+        let old_name = self
+            .read_meta(id)?
+            .ok_or_else(|| eyre!("There is no clip with ID {}", id))?
+            .name;
+
+        let transaction = self.connection.unchecked_transaction()?;
+        self.write_name(id, new_name)?;
+        self.record(&Operation::Rename {
+            id,
+            from: old_name,
+            to: new_name.to_string(),
+        })?;
+        transaction.commit()?;
+
+        Ok(())
+    }
+
+    /// Replace the notes of the clip with the given name, or remove them if `notes` is None.
+    pub fn set_notes(&self, name: &str, notes: Option<&str>) -> Result<()> {
+        let id = self
+            .clip_id(name)?
+            .ok_or_else(|| eyre!("There is no clip named \"{}\"", name))?;
+        self.set_notes_by_id(id, notes)
+    }
+
+    pub fn set_notes_by_id(&self, id: usize, notes: Option<&str>) -> Result<()> {
+        let old_notes = self
+            .read_meta(id)?
+            .ok_or_else(|| eyre!("There is no clip with ID {}", id))?
+            .notes;
+
+        let transaction = self.connection.unchecked_transaction()?;
+        self.write_notes(id, notes)?;
+        self.record(&Operation::EditNotes {
+            id,
+            from: old_notes,
+            to: notes.map(str::to_string),
+        })?;
+        transaction.commit()?;
+
+        Ok(())
+    }
+
+    /// The ID of the clip with the given name, if there is one.
+    pub fn clip_id(&self, name: &str) -> Result<Option<usize>> {
+        Ok(self
+            .connection
+            .query_row(
+                "SELECT id FROM clips WHERE name = ?1",
+                [seal_name(self.cipher()?, name)],
+                |row| row.get(0),
+            )
+            .optional()?)
+    }
+
+    /// The metadata of a clip, without reading its audio.
+    fn read_meta(&self, id: usize) -> Result<Option<ClipMeta>> {
+        Ok(self
+            .query_metas(
+                "SELECT id, name, date, notes, duration FROM clips WHERE id = ?1",
+                [id],
+            )?
+            .pop())
+    }
+
+    /// Rename a clip without recording it in the history.
+    pub(crate) fn write_name(&self, id: usize, name: &str) -> Result<()> {
+        let rows_changed = self.connection.execute(
+            "UPDATE clips SET name = ?2 WHERE id = ?1",
+            params![id, seal_name(self.cipher()?, name)],
         )?;
 
         if rows_changed == 0 {
-            return Err(eyre!("There is no clip named \"{}\"", old_name));
+            return Err(eyre!("There is no clip with ID {}", id));
         }
 
         Ok(())
     }
 
-    pub fn rename_by_id(&self, id: usize, new_name: &str) -> Result<()> {
-        let rows_changed = self.0.execute(
-            "UPDATE clips SET name = ?2 WHERE id = ?1",
-            params![id, new_name],
+    /// Replace the notes of a clip without recording it in the history.
+    pub(crate) fn write_notes(&self, id: usize, notes: Option<&str>) -> Result<()> {
+        let rows_changed = self.connection.execute(
+            "UPDATE clips SET notes = ?2 WHERE id = ?1",
+            params![id, seal_text(self.cipher()?, notes)],
         )?;
 
         if rows_changed == 0 {
@@ -241,4 +1049,608 @@ impl Db {
 
         Ok(())
     }
+
+    /// Whether the clips are encrypted with a passphrase.
+    pub fn is_encrypted(&self) -> bool {
+        self.encrypted
+    }
+
+    /// Whether the clips are encrypted and the passphrase has not been given yet. Clips can't
+    /// be read or written until the journal is unlocked.
+    pub fn is_locked(&self) -> bool {
+        self.encrypted && self.cipher.is_none()
+    }
+
+    /// The cipher for the clips, or None if they are not encrypted.
+    pub(crate) fn cipher(&self) -> Result<Option<&Cipher>> {
+        match (&self.cipher, self.encrypted) {
+            (Some(cipher), _) => Ok(Some(cipher)),
+            (None, false) => Ok(None),
+            (None, true) => Err(eyre!(
+                "The journal is encrypted, unlock it with its passphrase first"
+            )),
+        }
+    }
+
+    pub fn unlock(&mut self, passphrase: &str) -> Result<()> {
+        let (params, check_value) = self
+            .key_params()?
+            .ok_or_else(|| eyre!("The journal is not encrypted"))?;
+
+        let cipher = Cipher::derive(passphrase, &params)?;
+        if !cipher.check(&check_value) {
+            return Err(eyre!("Wrong passphrase"));
+        }
+        self.cipher = Some(cipher);
+
+        self.upgrade_clips()?;
+        self.fill_durations()
+    }
+
+    /// How the key is derived from the passphrase, and the value it is checked against, or
+    /// None if the journal is not encrypted.
+    pub(crate) fn key_params(&self) -> Result<Option<(KeyParams, Vec<u8>)>> {
+        if !self.encrypted {
+            return Ok(None);
+        }
+        Ok(Some(self.connection.query_row(
+            "SELECT salt, m_cost, t_cost, p_cost, check_value FROM encryption",
+            [],
+            |row| {
+                Ok((
+                    KeyParams {
+                        salt: row.get(0)?,
+                        m_cost: row.get(1)?,
+                        t_cost: row.get(2)?,
+                        p_cost: row.get(3)?,
+                    },
+                    row.get(4)?,
+                ))
+            },
+        )?))
+    }
+
+    /// Encrypt the name, notes, audio and peaks of every clip, now and in future, with a key
+    /// derived from `passphrase`. Dates are not encrypted, so clips can be listed in order
+    /// without decrypting them all.
+    ///
+    /// Recordings in progress are journaled with the same key, and so are backups.
+    pub fn encrypt(&mut self, passphrase: &str) -> Result<()> {
+        self.encrypt_with(passphrase, KeyParams::generate())
+    }
+
+    pub(crate) fn encrypt_with(&mut self, passphrase: &str, key_params: KeyParams) -> Result<()> {
+        if self.encrypted {
+            return Err(eyre!("The journal is already encrypted"));
+        }
+        let cipher = Cipher::derive(passphrase, &key_params)?;
+
+        let transaction = self.connection.unchecked_transaction()?;
+        transaction.execute(
+            "INSERT INTO encryption (salt, m_cost, t_cost, p_cost, check_value) VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![
+                key_params.salt,
+                key_params.m_cost,
+                key_params.t_cost,
+                key_params.p_cost,
+                cipher.check_value(),
+            ],
+        )?;
+        self.reseal_clips(None, Some(&cipher))?;
+        transaction.commit()?;
+        self.encrypted = true;
+        self.cipher = Some(cipher);
+
+        self.vacuum()
+    }
+
+    /// Store every clip unencrypted again. The journal must be unlocked.
+    pub fn decrypt(&mut self) -> Result<()> {
+        let cipher = self
+            .cipher()?
+            .ok_or_else(|| eyre!("The journal is not encrypted"))?;
+
+        let transaction = self.connection.unchecked_transaction()?;
+        transaction.execute("DELETE FROM encryption", [])?;
+        self.reseal_clips(Some(cipher), None)?;
+        transaction.commit()?;
+        self.encrypted = false;
+        self.cipher = None;
+
+        self.vacuum()
+    }
+
+    /// Rewrite the encrypted fields of every clip, in the trash too, and the names of
+    /// collections and the history, opening them with `from` and sealing them with `to`. One
+    /// clip is held in memory at a time.
+    fn reseal_clips(&self, from: Option<&Cipher>, to: Option<&Cipher>) -> Result<()> {
+        for table in ["clips", "trash"] {
+            self.reseal_table(table, from, to)?;
+        }
+        self.reseal_collections(from, to)?;
+        self.reseal_speakers(from, to)?;
+        self.reseal_history(from, to)
+    }
+
+    fn reseal_table(&self, table: &str, from: Option<&Cipher>, to: Option<&Cipher>) -> Result<()> {
+        let ids = self
+            .connection
+            .prepare(&format!("SELECT id FROM {}", table))?
+            .query_map([], |row| row.get(0))?
+            .collect::<Result<Vec<usize>, rusqlite::Error>>()?;
+
+        for id in ids {
+            let (name, notes, opus, peaks) = self.connection.query_row(
+                &format!(
+                    "SELECT name, notes, opus, peaks FROM {} WHERE id = ?1",
+                    table
+                ),
+                [id],
+                |row| {
+                    Ok((
+                        row.get::<_, Value>(0)?,
+                        row.get::<_, Value>(1)?,
+                        row.get::<_, Vec<u8>>(2)?,
+                        row.get::<_, Option<Vec<u8>>>(3)?,
+                    ))
+                },
+            )?;
+            let name = open_text(from, name)?.ok_or_else(|| eyre!("Clip {} has no name", id))?;
+            let notes = open_text(from, notes)?;
+            let opus = open_bytes(from, opus)?;
+            let peaks = peaks.map(|peaks| open_bytes(from, peaks)).transpose()?;
+
+            self.connection.execute(
+                &format!(
+                    "UPDATE {} SET name = ?2, notes = ?3, opus = ?4, peaks = ?5 WHERE id = ?1",
+                    table
+                ),
+                params![
+                    id,
+                    seal_name(to, &name),
+                    seal_text(to, notes.as_deref()),
+                    seal_bytes(to, opus),
+                    peaks.map(|peaks| seal_bytes(to, peaks)),
+                ],
+            )?;
+        }
+
+        Ok(())
+    }
+
+    /// Rebuild the database file, so that deleted or replaced data is not left in free pages.
+    pub(crate) fn vacuum(&self) -> Result<()> {
+        self.connection.execute_batch("VACUUM")?;
+
+        Ok(())
+    }
+
+    /// The database file, or None for in-memory databases.
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    /// Set where recordings are journaled, creating the directory if needed.
+    pub fn set_journal_dir(&mut self, journal_dir: Option<PathBuf>) -> Result<()> {
+        if let Some(dir) = &journal_dir {
+            std::fs::create_dir_all(dir)?;
+        }
+        self.journal_dir = journal_dir;
+
+        Ok(())
+    }
+
+    /// Start recording, journaling the recording so that it can be recovered if the app exits
+    /// before `finish_recording` is called.
+    pub fn start_recording(&self, host: AudioBackend, name: String) -> Result<RecordHandle> {
+        let journal_path = self
+            .journal_dir
+            .as_ref()
+            .map(|dir| dir.join(format!("{}.journal", Utc::now().format("%Y%m%d-%H%M%S-%f"))));
+        AudioClip::record_with_journal(host, name, journal_path.as_deref(), self.cipher()?)
+    }
+
+    /// Stop recording, save the clip encoded with `profile`, and remove its journal.
+    pub fn finish_recording(
+        &self,
+        handle: RecordHandle,
+        profile: EncodingProfile,
+    ) -> Result<AudioClip> {
+        let journal_path = handle.journal_path().map(Path::to_owned);
+        let mut clip = handle.stop();
+        self.save_with_profile(&mut clip, profile)?;
+        if let Some(path) = journal_path {
+            std::fs::remove_file(path)?;
+        }
+
+        Ok(clip)
+    }
+
+    /// Recordings that were journaled but never saved, oldest first. Encrypted recordings
+    /// are left out until the journal is unlocked.
+    pub fn unfinished_recordings(&self) -> Result<Vec<UnfinishedRecording>> {
+        let cipher = if self.is_locked() {
+            None
+        } else {
+            self.cipher()?
+        };
+        match &self.journal_dir {
+            Some(dir) => recovery::unfinished_recordings(dir, cipher),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    /// Save the unfinished recording with the given ID as a clip, and remove its journal.
+    ///
+    /// If there is already a clip with the recording's name, " (recovered)" is added to it.
+    pub fn recover_recording(&self, id: &str) -> Result<AudioClip> {
+        let path = self.journal_path(id)?;
+        let (recording, encoded) = recovery::read_journal(&path, self.cipher()?)?
+            .ok_or_else(|| eyre!("Recording {} is still in progress", id))?;
+
+        let mut name = recording.name.clone();
+        let mut attempt = 1;
+        while self.name_exists(&name)? {
+            name = match attempt {
+                1 => format!("{} (recovered)", recording.name),
+                n => format!("{} (recovered {})", recording.name, n),
+            };
+            attempt += 1;
+        }
+
+        let mut clip = AudioClip {
+            id: None,
+            name,
+            date: recording.date,
+            notes: None,
+            samples: EncodedAudio::parse(encoded)?.decode()?,
+            sample_rate: recording.sample_rate,
+            cache: ClipCache::default(),
+        };
+        self.save(&mut clip)?;
+        std::fs::remove_file(&path)?;
+
+        Ok(clip)
+    }
+
+    /// Remove the journal of an unfinished recording without saving it.
+    pub fn discard_recording(&self, id: &str) -> Result<()> {
+        let path = self.journal_path(id)?;
+        if recovery::read_journal(&path, self.cipher()?)?.is_none() {
+            return Err(eyre!("Recording {} is still in progress", id));
+        }
+        std::fs::remove_file(path)?;
+
+        Ok(())
+    }
+
+    fn journal_path(&self, id: &str) -> Result<PathBuf> {
+        let dir = self
+            .journal_dir
+            .as_ref()
+            .ok_or_else(|| eyre!("Recordings are not journaled"))?;
+        if id.is_empty() || id.contains(['/', '\\']) || id.starts_with('.') {
+            return Err(eyre!("Invalid recording ID {}", id));
+        }
+        let path = dir.join(format!("{}.journal", id));
+        if !path.exists() {
+            return Err(eyre!("There is no unfinished recording with ID {}", id));
+        }
+
+        Ok(path)
+    }
+}
+
+/// Index stored audio, in the given `format` column, without decoding it.
+pub(crate) fn parse_audio(format: u32, sample_rate: u32, bytes: Vec<u8>) -> Result<EncodedAudio> {
+    match format {
+        1 => EncodedAudio::parse_v1(sample_rate, bytes),
+        2 => EncodedAudio::parse(bytes),
+        3 => EncodedAudio::parse_flac(bytes),
+        format => Err(eyre!("Unknown audio format {}", format)),
+    }
+}
+
+/// A cache for a loaded clip, using stored peaks if they fit the decoded samples.
+///
+/// Decoding pads the clip to a whole number of Opus frames, so the stored peaks may cover
+/// slightly fewer samples. The rest are summarized when the clip is drawn.
+fn clip_cache(peaks: Option<&[u8]>, num_samples: usize) -> ClipCache {
+    match peaks.map(PeakPyramid::decode) {
+        Some(Ok(peaks)) if peaks.num_samples() <= num_samples => ClipCache::with_peaks(peaks),
+        Some(Err(err)) => {
+            log::warn!("Ignoring stored peaks: {:?}", err);
+            ClipCache::default()
+        }
+        _ => ClipCache::default(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_fixtures::{clip, sine, temp_path};
+
+    #[test]
+    fn test_save_and_load_with_peaks() {
+        let db = Db::in_memory().unwrap();
+        let mut clip = AudioClip {
+            samples: sine(48000, 48000),
+            ..clip("Name")
+        };
+        db.save(&mut clip).unwrap();
+
+        let lazy = db.load_lazy("Name").unwrap().unwrap();
+        assert_eq!(lazy.meta.name, "Name");
+        assert_eq!(lazy.num_samples(), 48000);
+        assert_eq!(lazy.decode_range(1000..2000).unwrap().len(), 1000);
+
+        let loaded = db.load("Name").unwrap().unwrap();
+        let peaks = loaded.cache.peaks.lock().unwrap();
+        assert_eq!(peaks.num_samples(), 48000);
+        let peak = peaks.peak(&loaded.samples, 0, 48000);
+        assert!(peak.max > 0.45 && peak.min < -0.45);
+    }
+
+    #[test]
+    fn test_list_query() {
+        let db = Db::in_memory().unwrap();
+        let start = Utc::now();
+        for (i, (name, seconds)) in [("b warmup", 3), ("Reading", 1), ("A warmup", 2)]
+            .into_iter()
+            .enumerate()
+        {
+            db.save(&mut AudioClip {
+                date: start + chrono::Duration::days(i as i64),
+                samples: vec![0.0; 16000 * seconds],
+                sample_rate: 16000,
+                ..clip(name)
+            })
+            .unwrap();
+        }
+        let names = |query: ListQuery| -> Vec<String> {
+            db.list(&query)
+                .unwrap()
+                .into_iter()
+                .map(|meta| meta.name)
+                .collect()
+        };
+
+        assert_eq!(
+            names(ListQuery::default()),
+            ["b warmup", "Reading", "A warmup"]
+        );
+        assert_eq!(
+            names(ListQuery {
+                sort: SortKey::Name,
+                descending: true,
+                ..Default::default()
+            }),
+            ["Reading", "b warmup", "A warmup"]
+        );
+        assert_eq!(
+            names(ListQuery {
+                sort: SortKey::Duration,
+                limit: Some(2),
+                offset: 1,
+                ..Default::default()
+            }),
+            ["A warmup", "b warmup"]
+        );
+        assert_eq!(
+            names(ListQuery {
+                since: Some(start + chrono::Duration::hours(1)),
+                until: Some(start + chrono::Duration::days(2)),
+                ..Default::default()
+            }),
+            ["Reading"]
+        );
+        assert_eq!(
+            names(ListQuery {
+                name_contains: Some("WARM".into()),
+                offset: 1,
+                ..Default::default()
+            }),
+            ["A warmup"]
+        );
+
+        // Durations of clips saved before they were stored are worked out from the audio.
+        db.connection
+            .execute("UPDATE clips SET duration = NULL", [])
+            .unwrap();
+        db.fill_durations().unwrap();
+        let durations: Vec<f64> = db
+            .list(&ListQuery::default())
+            .unwrap()
+            .into_iter()
+            .map(|meta| meta.duration)
+            .collect();
+        assert_eq!(durations, [3.0, 1.0, 2.0]);
+    }
+
+    #[test]
+    fn test_upgrade_clips() {
+        let db = Db::in_memory().unwrap();
+        let mut clip = AudioClip {
+            samples: sine(16000, 16000),
+            sample_rate: 16000,
+            ..clip("Name")
+        };
+        db.save(&mut clip).unwrap();
+        let (_, v1) = encode_v1(&clip).unwrap();
+        db.connection
+            .execute("UPDATE clips SET opus = ?1, format = 1", [&v1])
+            .unwrap();
+        assert_eq!(db.load("Name").unwrap().unwrap().samples.len(), 16000);
+
+        db.upgrade_clips().unwrap();
+        let format: u32 = db
+            .connection
+            .query_row("SELECT format FROM clips", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(format, 2);
+        assert_eq!(db.load("Name").unwrap().unwrap().samples.len(), 16000);
+    }
+
+    #[test]
+    fn test_failed_migration() {
+        let dir = temp_path("failed-migration");
+        let path = dir.join("journal.sqlite");
+        drop(Db::open_at(&path).unwrap());
+
+        // Version 12 fails to create the speakers table, after creating speaker_turns.
+        let connection = Connection::open(&path).unwrap();
+        connection
+            .execute_batch("DROP TABLE speaker_turns; PRAGMA user_version = 11;")
+            .unwrap();
+        drop(connection);
+        assert!(Db::open_at(&path).is_err());
+
+        let connection = Connection::open(&path).unwrap();
+        let user_version: u32 = connection
+            .query_row("SELECT user_version FROM pragma_user_version", [], |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert_eq!(user_version, 11);
+        let has_turns: bool = connection
+            .query_row(
+                "SELECT EXISTS (SELECT 1 FROM sqlite_master WHERE name = 'speaker_turns')",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert!(!has_turns);
+        drop(connection);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_reencode() {
+        let db = Db::in_memory().unwrap();
+        let samples: Vec<f32> = (0..44100)
+            .map(|i| ((i as f32) * 0.05).sin() * 0.5)
+            .collect();
+        let mut clip = AudioClip {
+            notes: Some("Notes".into()),
+            samples: samples.clone(),
+            sample_rate: 44100,
+            ..clip("Name")
+        };
+        db.save_with_profile(&mut clip, EncodingProfile::Lossless)
+            .unwrap();
+        let id = clip.id.unwrap();
+        let lossless = db.load("Name").unwrap().unwrap();
+        assert_eq!(lossless.sample_rate, 44100);
+        assert_eq!(lossless.samples.len(), samples.len());
+
+        // Saving again keeps the profile.
+        db.save(&mut clip).unwrap();
+        assert_eq!(db.profile(id).unwrap(), Some(EncodingProfile::Lossless));
+
+        let reencoded = db.reencode("Name", EncodingProfile::Music).unwrap();
+        assert_eq!(reencoded.id, Some(id));
+        assert_eq!(db.profile(id).unwrap(), Some(EncodingProfile::Music));
+        let music = db.load_by_id(id).unwrap().unwrap();
+        assert_eq!(music.sample_rate, 48000);
+        assert_eq!(music.notes.as_deref(), Some("Notes"));
+        assert!(db.reencode("Missing", EncodingProfile::Voice).is_err());
+    }
+
+    #[test]
+    fn test_open_at() {
+        let dir = temp_path("open-at");
+        let path = dir.join("client").join("journal.sqlite");
+        let db = Db::open_at(&path).unwrap();
+        assert_eq!(db.path(), Some(path.as_path()));
+        assert!(dir.join("client").join("journal.journal").is_dir());
+        db.save(&mut clip("Name")).unwrap();
+        drop(db);
+
+        let db = Db::open_at(&path).unwrap();
+        assert_eq!(db.list(&ListQuery::default()).unwrap().len(), 1);
+        drop(db);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_encrypt() {
+        let dir = temp_path("encrypt");
+        let path = dir.join("journal.sqlite");
+        let mut db = Db::open_at(&path).unwrap();
+        db.save(&mut AudioClip {
+            notes: Some("Secret notes".into()),
+            ..clip("Secret name")
+        })
+        .unwrap();
+        db.save(&mut clip("Trashed name")).unwrap();
+        db.delete("Trashed name").unwrap();
+        db.encrypt_with("correct horse", crate::encryption::test_params())
+            .unwrap();
+        assert!(db.is_encrypted() && !db.is_locked());
+        assert!(db.load("Secret name").unwrap().is_some());
+        drop(db);
+
+        let file = std::fs::read(&path).unwrap();
+        let contains = |needle: &[u8]| file.windows(needle.len()).any(|w| w == needle);
+        assert!(!contains(b"Secret name") && !contains(b"Secret notes"));
+        assert!(!contains(b"Trashed name"));
+
+        let mut db = Db::open_at(&path).unwrap();
+        assert!(db.is_locked());
+        assert!(db.list(&ListQuery::default()).is_err());
+        assert!(db.unlock("battery staple").is_err());
+        db.unlock("correct horse").unwrap();
+        let list = db.list(&ListQuery::default()).unwrap();
+        assert_eq!(list[0].name, "Secret name");
+        assert_eq!(list[0].notes.as_deref(), Some("Secret notes"));
+        assert_eq!(db.trash().unwrap()[0].meta.name, "Trashed name");
+        assert_eq!(
+            db.undo().unwrap().unwrap().to_string(),
+            "Delete \"Trashed name\""
+        );
+        db.rename("Secret name", "Renamed").unwrap();
+        assert!(db.name_exists("Renamed").unwrap());
+
+        db.decrypt().unwrap();
+        drop(db);
+        let db = Db::open_at(&path).unwrap();
+        assert!(!db.is_encrypted());
+        assert!(db.load("Renamed").unwrap().is_some());
+        drop(db);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_recover_recording() {
+        let dir = temp_path("recover-recording");
+        let _ = std::fs::remove_dir_all(&dir);
+        let mut db = Db::in_memory().unwrap();
+        db.set_journal_dir(Some(dir.clone())).unwrap();
+        db.save(&mut clip("Name")).unwrap();
+
+        let journal = recovery::JournalWriter::create(
+            &dir.join("crashed.journal"),
+            "Name",
+            Utc::now(),
+            16000,
+            None,
+        )
+        .unwrap();
+        journal.write(vec![0.1; 16000]);
+        journal.finish().unwrap();
+
+        let unfinished = db.unfinished_recordings().unwrap();
+        assert_eq!(unfinished.len(), 1);
+        assert_eq!(unfinished[0].id(), "crashed");
+
+        let recovered = db.recover_recording("crashed").unwrap();
+        assert_eq!(recovered.name, "Name (recovered)");
+        assert_eq!(recovered.samples.len(), 16000);
+        assert!(db.load("Name (recovered)").unwrap().is_some());
+        assert!(db.unfinished_recordings().unwrap().is_empty());
+        assert!(db.recover_recording("../crashed").is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 }
@@ -0,0 +1,630 @@
+use color_eyre::eyre::{eyre, Result};
+use rusqlite::{params, types::Value};
+
+use crate::audio_clip::AudioClip;
+use crate::db::Db;
+use crate::encryption::{open_text, seal_text, Cipher};
+use crate::features::{self, Matrix, MelOptions, MfccOptions, StftOptions, Window};
+use crate::language_processor::Segment;
+
+/// Clips are resampled to this rate before diarization.
+const SAMPLE_RATE: u32 = 16000;
+/// Samples between frames, 10ms. Voice activity and embeddings are worked out per frame.
+const HOP: usize = 160;
+const N_FFT: usize = 512;
+const N_MFCC: usize = 20;
+/// Frames quieter than this are never speech, however quiet the rest of the clip is.
+const MIN_SPEECH_DB: f32 = -50.0;
+/// How much louder than the quietest tenth of the clip speech must be.
+const SPEECH_MARGIN_DB: f32 = 12.0;
+/// Pauses shorter than this, in frames, don't end a stretch of speech.
+const MAX_PAUSE: usize = 30;
+/// Stretches of speech shorter than this, in frames, are ignored as clicks and breaths.
+const MIN_SPEECH: usize = 20;
+/// Speech is split into windows of about this many frames, each given to one speaker.
+const WINDOW: usize = 100;
+
+/// A stretch of a clip in which one speaker is talking. Times are in seconds from the start
+/// of the clip.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SpeakerTurn {
+    pub start: f64,
+    pub end: f64,
+    pub speaker: usize,
+}
+
+/// Someone talking in a clip. Speakers are numbered from 1 in the order they first speak,
+/// and are only known within their clip.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Speaker {
+    pub id: usize,
+    /// The name the user gave them, if any.
+    pub name: Option<String>,
+    /// How long they talk for altogether, in seconds.
+    pub speaking_time: f64,
+}
+
+impl Speaker {
+    /// The speaker's name, or "Speaker 1" and so on if they have none.
+    pub fn label(&self) -> String {
+        match &self.name {
+            Some(name) => name.clone(),
+            None => format!("Speaker {}", self.id),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DiarizationOptions {
+    /// The number of speakers, if known. Otherwise it is estimated.
+    pub num_speakers: Option<usize>,
+    /// How different two groups of speech must be to belong to different speakers, as a
+    /// multiple of the typical difference between neighbouring windows of the same voice.
+    /// Lower values find more speakers. Unused if `num_speakers` is given.
+    pub threshold: f32,
+}
+
+impl Default for DiarizationOptions {
+    fn default() -> Self {
+        DiarizationOptions {
+            num_speakers: None,
+            threshold: 3.0,
+        }
+    }
+}
+
+/// Work out who speaks when in a clip.
+///
+/// Speech is found by its loudness, split into windows of about a second, and each window
+/// is described by the mean and spread of its MFCCs. Windows are then grouped by
+/// agglomerative clustering, merging the two closest groups until there are
+/// `num_speakers` or the closest are further apart than `threshold` allows.
+pub fn diarize(clip: &AudioClip, options: &DiarizationOptions) -> Result<Vec<SpeakerTurn>> {
+    if options.num_speakers == Some(0) {
+        return Err(eyre!("Expected at least one speaker"));
+    }
+
+    let clip = clip.resample(SAMPLE_RATE);
+    let mfcc = features::mfcc(&clip.samples, (0, clip.samples.len()), &mfcc_options())?;
+    let mut windows = Vec::new();
+    let mut embeddings = Vec::new();
+    for window in speech_windows(&clip.samples) {
+        if let Some(embedding) = embedding(&mfcc, window) {
+            windows.push(window);
+            embeddings.push(embedding);
+        }
+    }
+    let speakers = cluster(&embeddings, options);
+
+    let mut turns: Vec<(usize, usize, usize)> = Vec::new();
+    for (&(start, end), speaker) in windows.iter().zip(speakers) {
+        match turns.last_mut() {
+            Some(last) if last.1 == start && last.2 == speaker => last.1 = end,
+            _ => turns.push((start, end, speaker)),
+        }
+    }
+    let seconds = |frame: usize| (frame * HOP) as f64 / SAMPLE_RATE as f64;
+
+    Ok(turns
+        .into_iter()
+        .map(|(start, end, speaker)| SpeakerTurn {
+            start: seconds(start),
+            end: seconds(end),
+            speaker,
+        })
+        .collect())
+}
+
+fn mfcc_options() -> MfccOptions {
+    MfccOptions {
+        mel: MelOptions {
+            stft: StftOptions {
+                sample_rate: SAMPLE_RATE,
+                n_fft: N_FFT,
+                hop: HOP,
+                window: Window::Hann,
+            },
+            n_mels: 40,
+            min_freq: 0.0,
+            max_freq: (SAMPLE_RATE / 2) as f32,
+        },
+        n_mfcc: N_MFCC,
+    }
+}
+
+/// Find stretches of speech by how much louder they are than the quietest part of the clip,
+/// and split them into windows. Returns ranges of frames.
+fn speech_windows(samples: &[f32]) -> Vec<(usize, usize)> {
+    let loudness: Vec<f32> = samples
+        .chunks(HOP)
+        .map(|frame| {
+            let power = frame.iter().map(|x| x * x).sum::<f32>() / frame.len() as f32;
+            10.0 * power.max(1e-10).log10()
+        })
+        .collect();
+    if loudness.is_empty() {
+        return vec![];
+    }
+    let mut sorted = loudness.clone();
+    sorted.sort_by(|a, b| a.total_cmp(b));
+    let threshold = (sorted[sorted.len() / 10] + SPEECH_MARGIN_DB).max(MIN_SPEECH_DB);
+
+    let mut regions: Vec<(usize, usize)> = Vec::new();
+    for (frame, &db) in loudness.iter().enumerate() {
+        if db < threshold {
+            continue;
+        }
+        match regions.last_mut() {
+            Some(last) if frame - last.1 <= MAX_PAUSE => last.1 = frame + 1,
+            _ => regions.push((frame, frame + 1)),
+        }
+    }
+
+    let mut windows = Vec::new();
+    for (start, end) in regions {
+        if end - start < MIN_SPEECH {
+            continue;
+        }
+        // Split evenly, so that no window is much shorter than the rest.
+        let count = ((end - start + WINDOW / 2) / WINDOW).max(1);
+        for i in 0..count {
+            windows.push((
+                start + (end - start) * i / count,
+                start + (end - start) * (i + 1) / count,
+            ));
+        }
+    }
+
+    windows
+}
+
+/// The mean and standard deviation of each MFCC over a window of frames, leaving out the
+/// first coefficient, which follows loudness rather than voice. None if the window has too
+/// few frames to tell.
+fn embedding(mfcc: &Matrix, (start, end): (usize, usize)) -> Option<Vec<f32>> {
+    // An MFCC row covers N_FFT samples from the start of its frame, so the last few rows of a
+    // window reach past it, often into silence.
+    let end = end.saturating_sub(N_FFT / HOP).min(mfcc.rows());
+    if end < start + MIN_SPEECH / 2 {
+        return None;
+    }
+    let n = (end - start) as f32;
+
+    let mut embedding = Vec::with_capacity(2 * (N_MFCC - 1));
+    for k in 1..N_MFCC {
+        let mean = (start..end).map(|row| mfcc.get(row, k)).sum::<f32>() / n;
+        let variance = (start..end)
+            .map(|row| (mfcc.get(row, k) - mean).powi(2))
+            .sum::<f32>()
+            / n;
+        embedding.push(mean);
+        embedding.push(variance.sqrt());
+    }
+
+    Some(embedding)
+}
+
+fn distance(a: &[f32], b: &[f32]) -> f32 {
+    a.iter()
+        .zip(b)
+        .map(|(x, y)| (x - y).powi(2))
+        .sum::<f32>()
+        .sqrt()
+}
+
+/// Group embeddings by agglomerative clustering with centroid linkage. Returns the speaker
+/// of each embedding, numbered from 1 in order of first appearance.
+///
+/// Each group keeps track of its nearest neighbour, so that a merge only means looking again
+/// at the groups whose neighbour was merged.
+fn cluster(embeddings: &[Vec<f32>], options: &DiarizationOptions) -> Vec<usize> {
+    struct Group {
+        centroid: Vec<f32>,
+        members: Vec<usize>,
+    }
+
+    let mut groups: Vec<Option<Group>> = embeddings
+        .iter()
+        .enumerate()
+        .map(|(i, embedding)| {
+            Some(Group {
+                centroid: embedding.clone(),
+                members: vec![i],
+            })
+        })
+        .collect();
+    let nearest = |groups: &[Option<Group>], i: usize| -> (usize, f32) {
+        let centroid = &groups[i].as_ref().unwrap().centroid;
+        groups
+            .iter()
+            .enumerate()
+            .filter_map(|(j, group)| match group {
+                Some(group) if j != i => Some((j, distance(centroid, &group.centroid))),
+                _ => None,
+            })
+            .min_by(|a, b| a.1.total_cmp(&b.1))
+            .unwrap_or((i, f32::INFINITY))
+    };
+    let mut neighbours: Vec<(usize, f32)> =
+        (0..groups.len()).map(|i| nearest(&groups, i)).collect();
+
+    let mut distances: Vec<f32> = neighbours.iter().map(|&(_, d)| d).collect();
+    distances.sort_by(|a, b| a.total_cmp(b));
+    let scale = distances
+        .get(distances.len() / 2)
+        .copied()
+        .filter(|d| d.is_finite())
+        .unwrap_or_default()
+        .max(1e-3);
+    let target = options.num_speakers.unwrap_or(1);
+
+    let mut count = groups.len();
+    while count > target {
+        let (i, &(j, d)) = neighbours
+            .iter()
+            .enumerate()
+            .filter(|&(i, _)| groups[i].is_some())
+            .min_by(|a, b| a.1 .1.total_cmp(&b.1 .1))
+            .unwrap();
+        if options.num_speakers.is_none() && d > options.threshold * scale {
+            break;
+        }
+
+        let merged = groups[j].take().unwrap();
+        let group = groups[i].as_mut().unwrap();
+        let (a, b) = (group.members.len() as f32, merged.members.len() as f32);
+        for (x, y) in group.centroid.iter_mut().zip(&merged.centroid) {
+            *x = (*x * a + y * b) / (a + b);
+        }
+        group.members.extend(merged.members);
+        count -= 1;
+
+        for k in 0..groups.len() {
+            if k == i || groups[k].is_none() {
+                continue;
+            }
+            if neighbours[k].0 == i || neighbours[k].0 == j {
+                neighbours[k] = nearest(&groups, k);
+            } else {
+                let d = distance(
+                    &groups[k].as_ref().unwrap().centroid,
+                    &groups[i].as_ref().unwrap().centroid,
+                );
+                if d < neighbours[k].1 {
+                    neighbours[k] = (i, d);
+                }
+            }
+        }
+        neighbours[i] = nearest(&groups, i);
+    }
+
+    let mut speakers = vec![0; embeddings.len()];
+    let mut next = 1;
+    for i in 0..embeddings.len() {
+        if speakers[i] != 0 {
+            continue;
+        }
+        let group = groups
+            .iter()
+            .flatten()
+            .find(|group| group.members.contains(&i))
+            .unwrap();
+        for &member in &group.members {
+            speakers[member] = next;
+        }
+        next += 1;
+    }
+
+    speakers
+}
+
+/// Label each segment with the speaker who talks the most during it, if anyone does.
+pub fn label_segments(segments: &mut [Segment], turns: &[SpeakerTurn]) {
+    for segment in segments {
+        let mut overlaps: Vec<(usize, f64)> = Vec::new();
+        for turn in turns {
+            let overlap = turn.end.min(segment.end) - turn.start.max(segment.start);
+            if overlap <= 0.0 {
+                continue;
+            }
+            match overlaps
+                .iter_mut()
+                .find(|(speaker, _)| *speaker == turn.speaker)
+            {
+                Some((_, total)) => *total += overlap,
+                None => overlaps.push((turn.speaker, overlap)),
+            }
+        }
+        segment.speaker = overlaps
+            .into_iter()
+            .max_by(|a, b| a.1.total_cmp(&b.1))
+            .map(|(speaker, _)| speaker);
+    }
+}
+
+/// The ranges of samples in which `speaker` talks, in a clip at `sample_rate`, for analysis
+/// functions that take a range, like `features::mfcc`.
+pub fn speaker_ranges(
+    turns: &[SpeakerTurn],
+    speaker: usize,
+    sample_rate: u32,
+) -> Vec<(usize, usize)> {
+    let sample = |seconds: f64| (seconds * sample_rate as f64).round() as usize;
+    turns
+        .iter()
+        .filter(|turn| turn.speaker == speaker)
+        .map(|turn| (sample(turn.start), sample(turn.end)))
+        .collect()
+}
+
+impl Db {
+    /// Store who speaks when in a clip, replacing what was stored before along with the
+    /// names given to its speakers.
+    pub fn set_speaker_turns(&self, clip_id: usize, turns: &[SpeakerTurn]) -> Result<()> {
+        let exists: bool = self.connection.query_row(
+            "SELECT EXISTS (SELECT 1 FROM clips WHERE id = ?1)",
+            [clip_id],
+            |row| row.get(0),
+        )?;
+        if !exists {
+            return Err(eyre!("There is no clip with ID {}", clip_id));
+        }
+
+        let transaction = self.connection.unchecked_transaction()?;
+        transaction.execute("DELETE FROM speaker_turns WHERE clip_id = ?1", [clip_id])?;
+        transaction.execute("DELETE FROM speakers WHERE clip_id = ?1", [clip_id])?;
+        self.insert_speaker_turns(clip_id, turns)?;
+        transaction.commit()?;
+
+        Ok(())
+    }
+
+    /// Add turns to a clip, and their speakers if they are new.
+    pub(crate) fn insert_speaker_turns(&self, clip_id: usize, turns: &[SpeakerTurn]) -> Result<()> {
+        for turn in turns {
+            self.connection.execute(
+                "INSERT INTO speaker_turns (clip_id, start_time, end_time, speaker) VALUES (?1, ?2, ?3, ?4)",
+                params![clip_id, turn.start, turn.end, turn.speaker],
+            )?;
+            self.connection.execute(
+                "INSERT OR IGNORE INTO speakers (clip_id, speaker) VALUES (?1, ?2)",
+                params![clip_id, turn.speaker],
+            )?;
+        }
+
+        Ok(())
+    }
+
+    /// Who speaks when in a clip, in order. Empty if it hasn't been diarized.
+    pub fn speaker_turns(&self, clip_id: usize) -> Result<Vec<SpeakerTurn>> {
+        let mut stmt = self.connection.prepare(
+            "SELECT start_time, end_time, speaker FROM speaker_turns WHERE clip_id = ?1 ORDER BY start_time",
+        )?;
+        let turns = stmt
+            .query_map([clip_id], |row| {
+                Ok(SpeakerTurn {
+                    start: row.get(0)?,
+                    end: row.get(1)?,
+                    speaker: row.get(2)?,
+                })
+            })?
+            .collect::<Result<Vec<_>, rusqlite::Error>>()?;
+
+        Ok(turns)
+    }
+
+    /// The speakers in a clip, by ID.
+    pub fn speakers(&self, clip_id: usize) -> Result<Vec<Speaker>> {
+        let cipher = self.cipher()?;
+        let mut stmt = self.connection.prepare(
+            "SELECT speaker, name, (SELECT COALESCE(SUM(end_time - start_time), 0) FROM speaker_turns WHERE speaker_turns.clip_id = speakers.clip_id AND speaker_turns.speaker = speakers.speaker) FROM speakers WHERE clip_id = ?1 ORDER BY speaker",
+        )?;
+        let rows = stmt.query_map([clip_id], |row| {
+            Ok((
+                row.get::<_, usize>(0)?,
+                row.get::<_, Value>(1)?,
+                row.get::<_, f64>(2)?,
+            ))
+        })?;
+
+        let mut speakers = Vec::new();
+        for row in rows {
+            let (id, name, speaking_time) = row?;
+            speakers.push(Speaker {
+                id,
+                name: open_text(cipher, name)?,
+                speaking_time,
+            });
+        }
+
+        Ok(speakers)
+    }
+
+    /// Name a speaker in a clip, or remove their name if `name` is None.
+    pub fn name_speaker(&self, clip_id: usize, speaker: usize, name: Option<&str>) -> Result<()> {
+        let rows_changed = self.connection.execute(
+            "UPDATE speakers SET name = ?3 WHERE clip_id = ?1 AND speaker = ?2",
+            params![clip_id, speaker, seal_text(self.cipher()?, name)],
+        )?;
+
+        if rows_changed == 0 {
+            return Err(eyre!("Clip {} has no speaker {}", clip_id, speaker));
+        }
+
+        Ok(())
+    }
+
+    /// Rewrite the names of speakers, opening them with `from` and sealing them with `to`.
+    pub(crate) fn reseal_speakers(&self, from: Option<&Cipher>, to: Option<&Cipher>) -> Result<()> {
+        let rows = self
+            .connection
+            .prepare("SELECT clip_id, speaker, name FROM speakers WHERE name IS NOT NULL")?
+            .query_map([], |row| {
+                Ok((
+                    row.get::<_, usize>(0)?,
+                    row.get::<_, usize>(1)?,
+                    row.get::<_, Value>(2)?,
+                ))
+            })?
+            .collect::<Result<Vec<_>, rusqlite::Error>>()?;
+
+        for (clip_id, speaker, name) in rows {
+            let name = open_text(from, name)?;
+            self.connection.execute(
+                "UPDATE speakers SET name = ?3 WHERE clip_id = ?1 AND speaker = ?2",
+                params![clip_id, speaker, seal_text(to, name.as_deref())],
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_fixtures::clip;
+
+    /// A steady voice-like tone: harmonics of `f0`, weighted by `gain` of each harmonic's
+    /// frequency, over a little noise.
+    fn voice(f0: f32, seconds: f32, gain: impl Fn(f32) -> f32, seed: &mut u32) -> Vec<f32> {
+        let len = (seconds * SAMPLE_RATE as f32) as usize;
+        (0..len)
+            .map(|i| {
+                let t = i as f32 / SAMPLE_RATE as f32;
+                let tone: f32 = (1..40)
+                    .map(|h| h as f32 * f0)
+                    .filter(|&f| f < 7000.0)
+                    .map(|f| gain(f) * (2.0 * std::f32::consts::PI * f * t).sin())
+                    .sum();
+                tone * 0.1 + noise(seed) * 0.01
+            })
+            .collect()
+    }
+
+    fn silence(seconds: f32, seed: &mut u32) -> Vec<f32> {
+        let len = (seconds * SAMPLE_RATE as f32) as usize;
+        (0..len).map(|_| noise(seed) * 0.0001).collect()
+    }
+
+    fn noise(seed: &mut u32) -> f32 {
+        *seed = seed.wrapping_mul(1664525).wrapping_add(1013904223);
+        (*seed >> 8) as f32 / (1 << 24) as f32 - 0.5
+    }
+
+    /// Two voices taking turns: a low, dark one and a higher, brighter one.
+    fn interview() -> AudioClip {
+        let seed = &mut 1;
+        let dark = |f: f32| 1.0 / (1.0 + f / 300.0);
+        let bright = |f: f32| (-((f - 2500.0) / 800.0).powi(2)).exp() + 0.1;
+        let mut samples = Vec::new();
+        for (f0, seconds, bright_voice) in [
+            (110.0, 3.0, false),
+            (210.0, 3.0, true),
+            (110.0, 2.0, false),
+            (210.0, 2.0, true),
+        ] {
+            match bright_voice {
+                true => samples.extend(voice(f0, seconds, bright, seed)),
+                false => samples.extend(voice(f0, seconds, dark, seed)),
+            }
+            samples.extend(silence(0.5, seed));
+        }
+        AudioClip {
+            samples,
+            sample_rate: SAMPLE_RATE,
+            ..clip("Interview")
+        }
+    }
+
+    #[test]
+    fn test_diarize() {
+        let recording = interview();
+        for options in [
+            DiarizationOptions::default(),
+            DiarizationOptions {
+                num_speakers: Some(2),
+                ..Default::default()
+            },
+        ] {
+            let turns = diarize(&recording, &options).unwrap();
+            let speakers: Vec<_> = turns.iter().map(|turn| turn.speaker).collect();
+            assert_eq!(speakers, [1, 2, 1, 2]);
+            assert!((turns[1].start - 3.5).abs() < 0.1, "{:?}", turns);
+            assert!((turns[1].end - 6.5).abs() < 0.1, "{:?}", turns);
+        }
+
+        // One voice is one speaker.
+        let seed = &mut 2;
+        let mut samples = voice(150.0, 3.0, |f| 1.0 / (1.0 + f / 500.0), seed);
+        samples.extend(silence(1.0, seed));
+        samples.extend(voice(150.0, 3.0, |f| 1.0 / (1.0 + f / 500.0), seed));
+        let monologue = AudioClip {
+            samples,
+            sample_rate: SAMPLE_RATE,
+            ..clip("Monologue")
+        };
+        let turns = diarize(&monologue, &DiarizationOptions::default()).unwrap();
+        assert!(turns.iter().all(|turn| turn.speaker == 1), "{:?}", turns);
+
+        let mut segments = vec![Segment {
+            start: 3.0,
+            end: 6.0,
+            text: "Hello".into(),
+            words: vec![],
+            speaker: None,
+        }];
+        let turns = diarize(&recording, &DiarizationOptions::default()).unwrap();
+        label_segments(&mut segments, &turns);
+        assert_eq!(segments[0].speaker, Some(2));
+    }
+
+    #[test]
+    fn test_speakers() {
+        let db = Db::in_memory().unwrap();
+        let mut clip = clip("Interview");
+        db.save(&mut clip).unwrap();
+        let id = clip.id.unwrap();
+        let turns = [
+            SpeakerTurn {
+                start: 0.0,
+                end: 1.5,
+                speaker: 1,
+            },
+            SpeakerTurn {
+                start: 2.0,
+                end: 3.0,
+                speaker: 2,
+            },
+            SpeakerTurn {
+                start: 3.0,
+                end: 4.0,
+                speaker: 1,
+            },
+        ];
+        db.set_speaker_turns(id, &turns).unwrap();
+        assert_eq!(db.speaker_turns(id).unwrap(), turns);
+        assert_eq!(
+            speaker_ranges(&turns, 1, 16000),
+            [(0, 24000), (48000, 64000)]
+        );
+
+        db.name_speaker(id, 2, Some("Me")).unwrap();
+        assert!(db.name_speaker(id, 3, Some("Nobody")).is_err());
+        let speakers = db.speakers(id).unwrap();
+        assert_eq!(
+            speakers
+                .iter()
+                .map(|speaker| (speaker.label(), speaker.speaking_time))
+                .collect::<Vec<_>>(),
+            [("Speaker 1".to_string(), 2.5), ("Me".to_string(), 1.0)]
+        );
+
+        // Speakers go when their clip is deleted for good.
+        db.delete_by_id(id).unwrap();
+        assert_eq!(db.speakers(id).unwrap().len(), 2);
+        db.empty_trash().unwrap();
+        assert!(db.speakers(id).unwrap().is_empty());
+        assert!(db.speaker_turns(id).unwrap().is_empty());
+    }
+}
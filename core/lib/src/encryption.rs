@@ -0,0 +1,191 @@
+use argon2::{Algorithm, Argon2, Params, Version};
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use color_eyre::eyre::{eyre, Result};
+use hmac::{Hmac, Mac};
+use rusqlite::types::Value;
+use sha2::Sha256;
+
+const NONCE_LEN: usize = 24;
+
+/// Encrypted with the key to check passphrases.
+const CHECK_PLAINTEXT: &[u8] = b"oxygen";
+
+/// How the key is derived from the passphrase, stored alongside the encrypted data.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct KeyParams {
+    pub salt: Vec<u8>,
+    /// Argon2id memory cost in KiB, and number of iterations and lanes.
+    pub m_cost: u32,
+    pub t_cost: u32,
+    pub p_cost: u32,
+}
+
+impl KeyParams {
+    /// A random salt and Argon2's recommended costs.
+    pub fn generate() -> KeyParams {
+        let mut salt = vec![0; 16];
+        OsRng.fill_bytes(&mut salt);
+
+        KeyParams {
+            salt,
+            m_cost: Params::DEFAULT_M_COST,
+            t_cost: Params::DEFAULT_T_COST,
+            p_cost: Params::DEFAULT_P_COST,
+        }
+    }
+}
+
+/// Encrypts values with XChaCha20-Poly1305, using a key derived from a passphrase.
+///
+/// Sealed values are the 24 byte nonce followed by the ciphertext and tag.
+#[derive(Clone)]
+pub(crate) struct Cipher {
+    aead: XChaCha20Poly1305,
+    /// Key for deriving nonces in `seal_deterministic`.
+    nonce_key: [u8; 32],
+}
+
+impl Cipher {
+    pub fn derive(passphrase: &str, params: &KeyParams) -> Result<Cipher> {
+        let argon2_params = Params::new(params.m_cost, params.t_cost, params.p_cost, Some(64))
+            .map_err(|err| eyre!("Invalid key parameters: {}", err))?;
+        let mut key = [0u8; 64];
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, argon2_params)
+            .hash_password_into(passphrase.as_bytes(), &params.salt, &mut key)
+            .map_err(|err| eyre!("Could not derive key: {}", err))?;
+
+        let mut nonce_key = [0u8; 32];
+        nonce_key.copy_from_slice(&key[32..]);
+        let aead = XChaCha20Poly1305::new_from_slice(&key[..32])
+            .map_err(|err| eyre!("Could not derive key: {}", err))?;
+        key.fill(0);
+
+        Ok(Cipher { aead, nonce_key })
+    }
+
+    /// A value to store, which `check` accepts only with the same key.
+    pub fn check_value(&self) -> Vec<u8> {
+        self.seal(CHECK_PLAINTEXT)
+    }
+
+    pub fn check(&self, check_value: &[u8]) -> bool {
+        self.open(check_value).ok().as_deref() == Some(CHECK_PLAINTEXT)
+    }
+
+    pub fn seal(&self, plaintext: &[u8]) -> Vec<u8> {
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        self.seal_with_nonce(&nonce, plaintext)
+    }
+
+    /// Seal so that equal plaintexts give equal sealed values, which lets the database look
+    /// them up. This reveals which values are equal, but nothing else.
+    pub fn seal_deterministic(&self, plaintext: &[u8]) -> Vec<u8> {
+        let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(&self.nonce_key)
+            .expect("HMAC takes keys of any length");
+        mac.update(plaintext);
+        let hash = mac.finalize().into_bytes();
+        self.seal_with_nonce(XNonce::from_slice(&hash[..NONCE_LEN]), plaintext)
+    }
+
+    fn seal_with_nonce(&self, nonce: &XNonce, plaintext: &[u8]) -> Vec<u8> {
+        let mut sealed = nonce.to_vec();
+        sealed.extend(
+            self.aead
+                .encrypt(nonce, plaintext)
+                .expect("encryption only fails for huge values"),
+        );
+        sealed
+    }
+
+    pub fn open(&self, sealed: &[u8]) -> Result<Vec<u8>> {
+        if sealed.len() < NONCE_LEN {
+            return Err(eyre!("Encrypted value is too short"));
+        }
+        let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+        self.aead
+            .decrypt(XNonce::from_slice(nonce), ciphertext)
+            .map_err(|_| eyre!("Could not decrypt, the data is damaged or the key is wrong"))
+    }
+}
+
+impl Drop for Cipher {
+    fn drop(&mut self) {
+        self.nonce_key.fill(0);
+    }
+}
+
+/// How a clip's name is stored. Names are sealed deterministically so they can be looked up.
+pub(crate) fn seal_name(cipher: Option<&Cipher>, name: &str) -> Value {
+    match cipher {
+        Some(cipher) => Value::Blob(cipher.seal_deterministic(name.as_bytes())),
+        None => Value::Text(name.to_string()),
+    }
+}
+
+/// How text other than the name is stored.
+pub(crate) fn seal_text(cipher: Option<&Cipher>, text: Option<&str>) -> Value {
+    match (cipher, text) {
+        (_, None) => Value::Null,
+        (Some(cipher), Some(text)) => Value::Blob(cipher.seal(text.as_bytes())),
+        (None, Some(text)) => Value::Text(text.to_string()),
+    }
+}
+
+pub(crate) fn open_text(cipher: Option<&Cipher>, value: Value) -> Result<Option<String>> {
+    match (cipher, value) {
+        (_, Value::Null) => Ok(None),
+        (_, Value::Text(text)) => Ok(Some(text)),
+        (Some(cipher), Value::Blob(sealed)) => Ok(Some(String::from_utf8(cipher.open(&sealed)?)?)),
+        (None, Value::Blob(_)) => Err(eyre!("Found encrypted text in an unencrypted journal")),
+        (_, value) => Err(eyre!("Expected text, got {:?}", value.data_type())),
+    }
+}
+
+pub(crate) fn seal_bytes(cipher: Option<&Cipher>, bytes: Vec<u8>) -> Vec<u8> {
+    match cipher {
+        Some(cipher) => cipher.seal(&bytes),
+        None => bytes,
+    }
+}
+
+pub(crate) fn open_bytes(cipher: Option<&Cipher>, bytes: Vec<u8>) -> Result<Vec<u8>> {
+    match cipher {
+        Some(cipher) => cipher.open(&bytes),
+        None => Ok(bytes),
+    }
+}
+
+#[cfg(test)]
+pub(crate) fn test_params() -> KeyParams {
+    // Cheap enough for debug builds.
+    KeyParams {
+        salt: b"oxygen test salt".to_vec(),
+        m_cost: 64,
+        t_cost: 1,
+        p_cost: 1,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_seal_and_open() {
+        let cipher = Cipher::derive("correct horse", &test_params()).unwrap();
+        let sealed = cipher.seal(b"secret");
+        assert_ne!(sealed, cipher.seal(b"secret"));
+        assert_eq!(cipher.open(&sealed).unwrap(), b"secret");
+        assert_eq!(
+            cipher.seal_deterministic(b"name"),
+            cipher.seal_deterministic(b"name")
+        );
+
+        let wrong = Cipher::derive("battery staple", &test_params()).unwrap();
+        assert!(wrong.open(&sealed).is_err());
+        assert!(!wrong.check(&cipher.check_value()));
+        assert!(cipher.check(&cipher.check_value()));
+    }
+}
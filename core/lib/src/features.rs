@@ -0,0 +1,478 @@
+use color_eyre::eyre::{eyre, Result};
+use realfft::{num_complex::Complex, RealFftPlanner, RealToComplex};
+use std::io::Write;
+use std::sync::Arc;
+
+/// A window function applied to each STFT frame before the FFT.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Window {
+    #[default]
+    Hann,
+    Hamming,
+    Blackman,
+    Rectangular,
+}
+
+impl Window {
+    pub fn coefficients(&self, len: usize) -> Vec<f32> {
+        (0..len)
+            .map(|i| {
+                let x = 2.0 * std::f32::consts::PI * (i as f32) / (len as f32);
+                match self {
+                    Window::Hann => 0.5 * (1.0 - x.cos()),
+                    Window::Hamming => 0.54 - 0.46 * x.cos(),
+                    Window::Blackman => 0.42 - 0.5 * x.cos() + 0.08 * (2.0 * x).cos(),
+                    Window::Rectangular => 1.0,
+                }
+            })
+            .collect()
+    }
+}
+
+/// Parameters for a short-time Fourier transform.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct StftOptions {
+    /// The rate of the analyzed samples. Resample a clip to it first, with
+    /// `AudioClip::resample`.
+    pub sample_rate: u32,
+    /// Number of samples in each FFT frame.
+    pub n_fft: usize,
+    /// Number of samples between the start of consecutive frames.
+    pub hop: usize,
+    pub window: Window,
+}
+
+impl Default for StftOptions {
+    fn default() -> Self {
+        StftOptions {
+            sample_rate: 12000,
+            n_fft: 2048,
+            hop: 200, // 16ms
+            window: Window::Hann,
+        }
+    }
+}
+
+impl StftOptions {
+    /// Number of frequency bins in each frame (the Nyquist bin is dropped).
+    pub fn num_bins(&self) -> usize {
+        self.n_fft / 2
+    }
+
+    /// The centre frequency of the given bin, in Hz.
+    pub fn bin_frequency(&self, bin: usize) -> f32 {
+        (bin as f32) * (self.sample_rate as f32) / (self.n_fft as f32)
+    }
+
+    fn validate(&self) -> Result<()> {
+        if self.sample_rate == 0 || self.n_fft < 2 || self.hop == 0 {
+            return Err(eyre!(
+                "Invalid STFT options (sample rate {}, FFT size {}, hop {})",
+                self.sample_rate,
+                self.n_fft,
+                self.hop
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+/// Parameters for a mel spectrogram.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MelOptions {
+    pub stft: StftOptions,
+    pub n_mels: usize,
+    pub min_freq: f32,
+    pub max_freq: f32,
+}
+
+impl Default for MelOptions {
+    fn default() -> Self {
+        MelOptions {
+            stft: StftOptions::default(),
+            n_mels: 40,
+            min_freq: 0.0,
+            max_freq: 6000.0,
+        }
+    }
+}
+
+/// Parameters for mel-frequency cepstral coefficients.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MfccOptions {
+    pub mel: MelOptions,
+    pub n_mfcc: usize,
+}
+
+impl Default for MfccOptions {
+    fn default() -> Self {
+        MfccOptions {
+            mel: MelOptions::default(),
+            n_mfcc: 13,
+        }
+    }
+}
+
+/// A dense row-major matrix. For features, each row is a frame and each column is a bin or
+/// coefficient.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Matrix {
+    rows: usize,
+    cols: usize,
+    data: Vec<f32>,
+}
+
+impl Matrix {
+    pub fn new(cols: usize) -> Matrix {
+        Matrix {
+            rows: 0,
+            cols,
+            data: Vec::new(),
+        }
+    }
+
+    pub fn zeros(rows: usize, cols: usize) -> Matrix {
+        Matrix {
+            rows,
+            cols,
+            data: vec![0.0; rows * cols],
+        }
+    }
+
+    pub fn rows(&self) -> usize {
+        self.rows
+    }
+
+    pub fn cols(&self) -> usize {
+        self.cols
+    }
+
+    pub fn push_row(&mut self, row: &[f32]) {
+        assert_eq!(row.len(), self.cols, "row has the wrong number of columns");
+        self.data.extend_from_slice(row);
+        self.rows += 1;
+    }
+
+    pub fn row(&self, i: usize) -> &[f32] {
+        &self.data[i * self.cols..(i + 1) * self.cols]
+    }
+
+    pub fn row_mut(&mut self, i: usize) -> &mut [f32] {
+        &mut self.data[i * self.cols..(i + 1) * self.cols]
+    }
+
+    pub fn iter_rows(&self) -> impl Iterator<Item = &[f32]> {
+        // chunks_exact panics on a zero chunk size.
+        self.data.chunks_exact(self.cols.max(1)).take(self.rows)
+    }
+
+    pub fn get(&self, row: usize, col: usize) -> f32 {
+        self.data[row * self.cols + col]
+    }
+
+    /// Returns a new matrix with `f` applied to each element.
+    pub fn map(&self, f: impl Fn(f32) -> f32) -> Matrix {
+        Matrix {
+            rows: self.rows,
+            cols: self.cols,
+            data: self.data.iter().map(|x| f(*x)).collect(),
+        }
+    }
+
+    /// Write the matrix as comma-separated values, one row per line.
+    pub fn write_csv<W: Write>(&self, writer: &mut W) -> Result<()> {
+        for row in self.iter_rows() {
+            let line: Vec<String> = row.iter().map(|x| x.to_string()).collect();
+            writeln!(writer, "{}", line.join(","))?;
+        }
+
+        Ok(())
+    }
+
+    /// Write the matrix as a NumPy .npy (version 1.0) file of little-endian f32.
+    pub fn write_npy<W: Write>(&self, writer: &mut W) -> Result<()> {
+        let mut header = format!(
+            "{{'descr': '<f4', 'fortran_order': False, 'shape': ({}, {}), }}",
+            self.rows, self.cols
+        );
+        // The magic string, version and header length take 10 bytes, and the total header
+        // must be padded with spaces to a multiple of 64 bytes, ending in a newline.
+        let unpadded_len = 10 + header.len() + 1;
+        header.push_str(&" ".repeat((64 - unpadded_len % 64) % 64));
+        header.push('\n');
+
+        writer.write_all(b"\x93NUMPY\x01\x00")?;
+        writer.write_all(&u16::try_from(header.len())?.to_le_bytes())?;
+        writer.write_all(header.as_bytes())?;
+        for x in &self.data {
+            writer.write_all(&x.to_le_bytes())?;
+        }
+
+        Ok(())
+    }
+}
+
+pub fn hz_to_mel(frequency: f32) -> f32 {
+    2595f32 * (1f32 + frequency / 700f32).log10()
+}
+
+pub fn mel_to_hz(mel: f32) -> f32 {
+    700f32 * (10f32.powf(mel / 2595f32) - 1f32)
+}
+
+/// Magnitude spectra of overlapping frames of `signal` in `range`. The signal must already
+/// be at `options.sample_rate`, so that analyzing many ranges of a clip resamples it once.
+///
+/// Frames start at multiples of `options.hop`, so frames computed for different ranges of the
+/// same clip line up.
+pub fn stft(signal: &[f32], range: (usize, usize), options: &StftOptions) -> Result<Matrix> {
+    options.validate()?;
+
+    let (start, end) = range;
+
+    let mut analyzer = FrameAnalyzer::new(options)?;
+    let mut magnitudes = vec![0f32; options.num_bins()];
+    let mut result = Matrix::new(options.num_bins());
+
+    let mut start_i = (start / options.hop) * options.hop;
+    while start_i + options.n_fft < end {
+        analyzer.analyze(signal, start_i, &mut magnitudes)?;
+        result.push_row(&magnitudes);
+
+        start_i += options.hop;
+    }
+
+    Ok(result)
+}
+
+/// Computes the magnitude spectrum of one frame at a time.
+pub(crate) struct FrameAnalyzer {
+    r2c: Arc<dyn RealToComplex<f32>>,
+    window: Vec<f32>,
+    chunk: Vec<f32>,
+    spectrum: Vec<Complex<f32>>,
+}
+
+impl FrameAnalyzer {
+    pub fn new(options: &StftOptions) -> Result<FrameAnalyzer> {
+        options.validate()?;
+
+        let r2c = RealFftPlanner::<f32>::new().plan_fft_forward(options.n_fft);
+        let spectrum = r2c.make_output_vec();
+        Ok(FrameAnalyzer {
+            r2c,
+            window: options.window.coefficients(options.n_fft),
+            chunk: vec![0f32; options.n_fft],
+            spectrum,
+        })
+    }
+
+    /// Write the magnitudes of the frame of `signal` beginning at `start` to `output`. Samples
+    /// past the end of the signal are treated as silence.
+    pub fn analyze(&mut self, signal: &[f32], start: usize, output: &mut [f32]) -> Result<()> {
+        for (i, sample) in self.chunk.iter_mut().enumerate() {
+            *sample = *signal.get(start + i).unwrap_or(&0f32) * self.window[i];
+        }
+
+        self.r2c.process(&mut self.chunk, &mut self.spectrum)?;
+        for (magnitude, bin) in output.iter_mut().zip(self.spectrum.iter()) {
+            *magnitude = bin.norm();
+        }
+
+        Ok(())
+    }
+}
+
+/// Triangular filters mapping linear FFT bins to mel bands.
+pub struct MelFilterbank {
+    /// One row per mel band, one column per FFT bin.
+    filters: Matrix,
+}
+
+impl MelFilterbank {
+    pub fn new(options: &MelOptions) -> Result<MelFilterbank> {
+        options.stft.validate()?;
+        let nyquist = options.stft.sample_rate as f32 / 2.0;
+        if options.n_mels == 0
+            || options.min_freq < 0.0
+            || options.min_freq >= options.max_freq
+            || options.max_freq > nyquist
+        {
+            return Err(eyre!(
+                "Invalid mel options ({} bands from {} to {} Hz, Nyquist is {} Hz)",
+                options.n_mels,
+                options.min_freq,
+                options.max_freq,
+                nyquist
+            ));
+        }
+
+        let min_mel = hz_to_mel(options.min_freq);
+        let max_mel = hz_to_mel(options.max_freq);
+        let edges: Vec<f32> = (0..options.n_mels + 2)
+            .map(|i| {
+                mel_to_hz(min_mel + (max_mel - min_mel) * (i as f32) / (options.n_mels + 1) as f32)
+            })
+            .collect();
+
+        let num_bins = options.stft.num_bins();
+        let mut filters = Matrix::zeros(options.n_mels, num_bins);
+        for band in 0..options.n_mels {
+            let (lower, centre, upper) = (edges[band], edges[band + 1], edges[band + 2]);
+            let row = filters.row_mut(band);
+            for (bin, weight) in row.iter_mut().enumerate() {
+                let frequency = options.stft.bin_frequency(bin);
+                *weight = if frequency > lower && frequency <= centre {
+                    (frequency - lower) / (centre - lower)
+                } else if frequency > centre && frequency < upper {
+                    (upper - frequency) / (upper - centre)
+                } else {
+                    0.0
+                };
+            }
+        }
+
+        Ok(MelFilterbank { filters })
+    }
+
+    pub fn num_bands(&self) -> usize {
+        self.filters.rows()
+    }
+
+    /// Map each row of a power spectrogram to mel bands.
+    pub fn apply(&self, power: &Matrix) -> Result<Matrix> {
+        if power.cols() != self.filters.cols() {
+            return Err(eyre!(
+                "Expected spectra with {} bins, got {}",
+                self.filters.cols(),
+                power.cols()
+            ));
+        }
+
+        let mut result = Matrix::new(self.num_bands());
+        let mut bands = vec![0f32; self.num_bands()];
+        for frame in power.iter_rows() {
+            for (band, filter) in bands.iter_mut().zip(self.filters.iter_rows()) {
+                *band = frame.iter().zip(filter).map(|(x, w)| x * w).sum();
+            }
+            result.push_row(&bands);
+        }
+
+        Ok(result)
+    }
+}
+
+/// Mel-band power of each frame.
+pub fn mel_spectrogram(
+    signal: &[f32],
+    range: (usize, usize),
+    options: &MelOptions,
+) -> Result<Matrix> {
+    let filterbank = MelFilterbank::new(options)?;
+    let power = stft(signal, range, &options.stft)?.map(|x| x * x);
+    filterbank.apply(&power)
+}
+
+/// Mel-band power of each frame, in dB.
+pub fn log_mel_spectrogram(
+    signal: &[f32],
+    range: (usize, usize),
+    options: &MelOptions,
+) -> Result<Matrix> {
+    Ok(mel_spectrogram(signal, range, options)?.map(power_to_db))
+}
+
+/// Mel-frequency cepstral coefficients of each frame (orthonormal DCT-II of the log-mel
+/// spectrogram).
+pub fn mfcc(signal: &[f32], range: (usize, usize), options: &MfccOptions) -> Result<Matrix> {
+    if options.n_mfcc == 0 || options.n_mfcc > options.mel.n_mels {
+        return Err(eyre!(
+            "Expected between 1 and {} coefficients, got {}",
+            options.mel.n_mels,
+            options.n_mfcc
+        ));
+    }
+
+    let log_mel = log_mel_spectrogram(signal, range, &options.mel)?;
+    let n = log_mel.cols();
+    let mut result = Matrix::new(options.n_mfcc);
+    let mut coefficients = vec![0f32; options.n_mfcc];
+    for frame in log_mel.iter_rows() {
+        for (k, coefficient) in coefficients.iter_mut().enumerate() {
+            let scale = if k == 0 {
+                (1.0 / n as f32).sqrt()
+            } else {
+                (2.0 / n as f32).sqrt()
+            };
+            *coefficient = scale
+                * frame
+                    .iter()
+                    .enumerate()
+                    .map(|(i, x)| {
+                        x * (std::f32::consts::PI * (k as f32) * (2 * i + 1) as f32
+                            / (2 * n) as f32)
+                            .cos()
+                    })
+                    .sum::<f32>();
+        }
+        result.push_row(&coefficients);
+    }
+
+    Ok(result)
+}
+
+fn power_to_db(power: f32) -> f32 {
+    10f32 * power.max(1e-10).log10()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sine(frequency: f32, sample_rate: u32, len: usize) -> Vec<f32> {
+        (0..len)
+            .map(|i| {
+                (2.0 * std::f32::consts::PI * frequency * (i as f32) / (sample_rate as f32)).sin()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_stft_peak() {
+        let signal = sine(1000.0, 12000, 12000);
+        let options = StftOptions::default();
+        let spectrum = stft(&signal, (0, signal.len()), &options).unwrap();
+        assert!(spectrum.rows() > 0);
+        assert_eq!(spectrum.cols(), options.num_bins());
+
+        let frame = spectrum.row(spectrum.rows() / 2);
+        let peak = (0..frame.len())
+            .max_by(|a, b| frame[*a].total_cmp(&frame[*b]))
+            .unwrap();
+        assert!((options.bin_frequency(peak) - 1000.0).abs() < 10.0);
+    }
+
+    #[test]
+    fn test_mfcc_shape() {
+        let signal = sine(440.0, 12000, 12000);
+        let options = MfccOptions::default();
+        let mfcc = mfcc(&signal, (0, signal.len()), &options).unwrap();
+        let frames = stft(&signal, (0, signal.len()), &options.mel.stft).unwrap();
+        assert_eq!(mfcc.rows(), frames.rows());
+        assert_eq!(mfcc.cols(), 13);
+        assert!(mfcc.iter_rows().flatten().all(|x| x.is_finite()));
+    }
+
+    #[test]
+    fn test_npy_header_is_aligned() {
+        let mut matrix = Matrix::new(2);
+        matrix.push_row(&[1.0, 2.0]);
+        let mut bytes = Vec::new();
+        matrix.write_npy(&mut bytes).unwrap();
+        let header_len = u16::from_le_bytes([bytes[8], bytes[9]]) as usize;
+        assert_eq!((10 + header_len) % 64, 0);
+        assert_eq!(bytes.len(), 10 + header_len + 8);
+        assert_eq!(bytes[10 + header_len - 1], b'\n');
+    }
+}
@@ -0,0 +1,652 @@
+use std::collections::BTreeSet;
+use std::io::Cursor;
+use std::ops::Range;
+use std::sync::{Arc, Mutex};
+
+use color_eyre::eyre::{eyre, Result};
+use symphonia::core::audio::SampleBuffer;
+use symphonia::core::codecs::{Decoder, DecoderOptions};
+use symphonia::core::errors::Error;
+use symphonia::core::formats::{FormatOptions, FormatReader, SeekMode, SeekTo};
+use symphonia::core::io::MediaSourceStream;
+use symphonia::default::codecs::FlacDecoder;
+use symphonia::default::formats::FlacReader;
+
+/// Samples in each frame, except the last which may be shorter.
+pub(crate) const BLOCK_SIZE: usize = 4096;
+
+/// Samples are stored as 24 bit integers, which is as precise as any microphone.
+const BITS_PER_SAMPLE: u32 = 24;
+
+const MAX_SAMPLE: i64 = (1 << (BITS_PER_SAMPLE - 1)) - 1;
+
+/// Length of the "fLaC" marker and the STREAMINFO block.
+pub(crate) const HEADER_LEN: usize = 42;
+
+const MAX_FIXED_ORDER: usize = 4;
+
+const MAX_PARTITION_ORDER: u32 = 4;
+
+/// Rice parameters are written in 5 bits, and this one means the residuals are not Rice
+/// coded.
+const RICE_ESCAPE: u32 = 31;
+
+/// Encodes samples into FLAC frames, one block at a time. Only the features needed for mono
+/// clips are used: fixed predictors and Rice coded residuals.
+pub struct FlacEncoder {
+    sample_rate: u32,
+    frame_number: u64,
+    num_samples: u64,
+    min_frame_len: u32,
+    max_frame_len: u32,
+}
+
+impl FlacEncoder {
+    pub fn new(sample_rate: u32) -> Result<FlacEncoder> {
+        if sample_rate == 0 || sample_rate >= 1 << 20 {
+            return Err(eyre!("Invalid sample rate {}", sample_rate));
+        }
+
+        Ok(FlacEncoder {
+            sample_rate,
+            frame_number: 0,
+            num_samples: 0,
+            min_frame_len: u32::MAX,
+            max_frame_len: 0,
+        })
+    }
+
+    /// Encode a block of at most `BLOCK_SIZE` samples as one frame, appending it to `output`.
+    pub fn encode_block(&mut self, samples: &[f32], output: &mut Vec<u8>) {
+        assert!(!samples.is_empty() && samples.len() <= BLOCK_SIZE);
+        let samples: Vec<i64> = samples.iter().map(|&sample| quantize(sample)).collect();
+
+        let mut writer = BitWriter::default();
+        // Sync code, fixed block size, block size in 16 bits at the end of the header, sample
+        // rate from STREAMINFO, mono, 24 bits per sample.
+        writer.write(0xfff8, 16);
+        writer.write(0x70, 8);
+        writer.write(0x0c, 8);
+        write_utf8(&mut writer, self.frame_number);
+        writer.write(samples.len() as u64 - 1, 16);
+        let crc = crc8(&writer.bytes);
+        writer.write(crc.into(), 8);
+
+        write_subframe(&mut writer, &samples);
+        writer.align();
+        let crc = crc16(&writer.bytes);
+        writer.write(crc.into(), 16);
+
+        let len = writer.bytes.len() as u32;
+        self.min_frame_len = self.min_frame_len.min(len);
+        self.max_frame_len = self.max_frame_len.max(len);
+        self.frame_number += 1;
+        self.num_samples += samples.len() as u64;
+        output.extend_from_slice(&writer.bytes);
+    }
+
+    pub fn header(&self) -> FlacHeader {
+        FlacHeader {
+            sample_rate: self.sample_rate,
+            num_samples: self.num_samples,
+            min_frame_len: if self.frame_number == 0 {
+                0
+            } else {
+                self.min_frame_len
+            },
+            max_frame_len: self.max_frame_len,
+        }
+    }
+}
+
+/// The "fLaC" marker and STREAMINFO block that go before the frames.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FlacHeader {
+    pub sample_rate: u32,
+    pub num_samples: u64,
+    min_frame_len: u32,
+    max_frame_len: u32,
+}
+
+impl FlacHeader {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut writer = BitWriter::default();
+        writer.write(u32::from_be_bytes(*b"fLaC").into(), 32);
+        // The last metadata block, of type STREAMINFO, with 34 bytes.
+        writer.write(1, 1);
+        writer.write(0, 7);
+        writer.write(34, 24);
+        writer.write(BLOCK_SIZE as u64, 16);
+        writer.write(BLOCK_SIZE as u64, 16);
+        writer.write(self.min_frame_len.into(), 24);
+        writer.write(self.max_frame_len.into(), 24);
+        writer.write(self.sample_rate.into(), 20);
+        writer.write(0, 3);
+        writer.write((BITS_PER_SAMPLE - 1).into(), 5);
+        writer.write(self.num_samples >> 32, 4);
+        writer.write(self.num_samples & 0xffff_ffff, 32);
+        // An MD5 of zero means it wasn't computed.
+        for _ in 0..4 {
+            writer.write(0, 32);
+        }
+
+        writer.bytes
+    }
+
+    fn read(bytes: &[u8]) -> Result<FlacHeader> {
+        if bytes.len() < HEADER_LEN || &bytes[..4] != b"fLaC" || bytes[4] & 0x7f != 0 {
+            return Err(eyre!("Not a FLAC stream"));
+        }
+        let info = &bytes[8..HEADER_LEN];
+        let sample_rate =
+            (u32::from(info[10]) << 12) | (u32::from(info[11]) << 4) | (u32::from(info[12]) >> 4);
+        let channels = ((info[12] >> 1) & 0x07) + 1;
+        if channels != 1 {
+            return Err(eyre!("Unsupported number of channels {}", channels));
+        }
+        let num_samples = (u64::from(info[13] & 0x0f) << 32)
+            | u64::from(u32::from_be_bytes(info[14..18].try_into()?));
+
+        Ok(FlacHeader {
+            sample_rate,
+            num_samples,
+            min_frame_len: u32::from_be_bytes([0, info[4], info[5], info[6]]),
+            max_frame_len: u32::from_be_bytes([0, info[7], info[8], info[9]]),
+        })
+    }
+}
+
+/// A FLAC stream, which can be decoded a range at a time.
+pub(crate) struct FlacAudio {
+    bytes: Arc<[u8]>,
+    header: FlacHeader,
+    /// The seconds of audio in which frames were found to be damaged while decoding.
+    damaged_seconds: Mutex<BTreeSet<usize>>,
+}
+
+impl FlacAudio {
+    pub fn parse(bytes: Vec<u8>) -> Result<FlacAudio> {
+        let header = FlacHeader::read(&bytes)?;
+        if header.sample_rate == 0 {
+            return Err(eyre!("Invalid sample rate {}", header.sample_rate));
+        }
+
+        Ok(FlacAudio {
+            bytes: bytes.into(),
+            header,
+            damaged_seconds: Mutex::default(),
+        })
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.header.sample_rate
+    }
+
+    pub fn num_samples(&self) -> usize {
+        self.header.num_samples as usize
+    }
+
+    /// The seconds of audio that have been decoded as silence because their frames are
+    /// damaged. Only frames that have been decoded are checked.
+    pub fn damaged_seconds(&self) -> Vec<usize> {
+        let damaged = self.damaged_seconds.lock().unwrap();
+        damaged.iter().copied().collect()
+    }
+
+    /// Record that the samples in `range` were decoded as silence.
+    fn mark_damaged(&self, range: Range<usize>) {
+        if range.is_empty() {
+            return;
+        }
+        let sample_rate = self.header.sample_rate as usize;
+        let seconds = range.start / sample_rate..=(range.end - 1) / sample_rate;
+        log::warn!(
+            "Samples {} to {} of a FLAC stream are damaged",
+            range.start,
+            range.end
+        );
+        self.damaged_seconds.lock().unwrap().extend(seconds);
+    }
+
+    /// Decode the samples in `range`, which is clamped to the length of the stream. Decoding
+    /// starts at the frame containing the start of the range. Frames that fail their checksum
+    /// or can't be decoded are replaced with silence.
+    pub fn decode_range(&self, range: Range<usize>) -> Result<Vec<f32>> {
+        let end = range.end.min(self.num_samples());
+        let start = range.start.min(end);
+        if start == end {
+            return Ok(Vec::new());
+        }
+
+        // The stream is shared with the reader rather than copied, since it may be long.
+        let source = MediaSourceStream::new(
+            Box::new(Cursor::new(Arc::clone(&self.bytes))),
+            Default::default(),
+        );
+        let mut reader = FlacReader::try_new(source, &FormatOptions::default())?;
+        let track = reader
+            .default_track()
+            .ok_or_else(|| eyre!("The FLAC stream has no track"))?;
+        let mut decoder = FlacDecoder::try_new(&track.codec_params, &DecoderOptions::default())?;
+        let track_id = track.id;
+        let mut position = reader
+            .seek(
+                SeekMode::Accurate,
+                SeekTo::TimeStamp {
+                    ts: start as u64,
+                    track_id,
+                },
+            )?
+            .actual_ts as usize;
+
+        let mut samples = Vec::with_capacity(end - start);
+        let mut add = |position: usize, frame: &[f32]| {
+            let from = start.saturating_sub(position).min(frame.len());
+            let to = end.saturating_sub(position).min(frame.len());
+            samples.extend_from_slice(&frame[from..to]);
+        };
+        // The frame containing the start is damaged, so the seek went past it.
+        if position > start {
+            let gap = start..position.min(end);
+            add(start, &vec![0.0; gap.len()]);
+            self.mark_damaged(gap);
+        }
+        while position < end {
+            let packet = match reader.next_packet() {
+                Ok(packet) => packet,
+                Err(Error::IoError(err)) if err.kind() == std::io::ErrorKind::UnexpectedEof => {
+                    break
+                }
+                Err(err) => return Err(err.into()),
+            };
+            // The reader skips frames that fail their checksum, leaving a gap.
+            let ts = packet.ts as usize;
+            if ts < position {
+                continue;
+            }
+            if ts > position {
+                let gap = position..ts.min(end);
+                add(position, &vec![0.0; gap.len()]);
+                self.mark_damaged(gap);
+                position = ts;
+            }
+
+            match decoder.decode(&packet) {
+                Ok(decoded) => {
+                    let mut buffer =
+                        SampleBuffer::<f32>::new(decoded.capacity() as u64, *decoded.spec());
+                    buffer.copy_interleaved_ref(decoded);
+                    add(position, buffer.samples());
+                    position += buffer.samples().len();
+                }
+                Err(Error::DecodeError(err)) => {
+                    log::warn!("Failed to decode a FLAC frame: {}", err);
+                    let len = packet.dur as usize;
+                    add(position, &vec![0.0; len]);
+                    self.mark_damaged(position..position + len);
+                    position += len;
+                }
+                Err(err) => return Err(err.into()),
+            }
+        }
+        // Frames missing from the end of the stream.
+        if position < end {
+            add(position, &vec![0.0; end - position]);
+            self.mark_damaged(position..end);
+        }
+
+        Ok(samples)
+    }
+}
+
+fn quantize(sample: f32) -> i64 {
+    ((sample as f64 * (MAX_SAMPLE + 1) as f64).round() as i64).clamp(-MAX_SAMPLE - 1, MAX_SAMPLE)
+}
+
+fn write_subframe(writer: &mut BitWriter, samples: &[i64]) {
+    // Subframe headers are a zero bit, 6 bits of type, and a bit for wasted bits per sample.
+    if samples.iter().all(|&sample| sample == samples[0]) {
+        writer.write(0, 8);
+        writer.write_signed(samples[0], BITS_PER_SAMPLE);
+        return;
+    }
+
+    // The fixed predictor whose residuals are smallest usually codes best.
+    let order = (0..=MAX_FIXED_ORDER.min(samples.len() - 1))
+        .min_by_key(|&order| {
+            fixed_residuals(samples, order)
+                .map(i64::unsigned_abs)
+                .sum::<u64>()
+        })
+        .expect("there is always order 0");
+    let residuals: Vec<u64> = fixed_residuals(samples, order).map(zigzag).collect();
+    let (partition_order, params, bits) = best_partitions(&residuals, samples.len(), order);
+
+    let verbatim_bits = samples.len() as u64 * BITS_PER_SAMPLE as u64;
+    let fixed_bits = order as u64 * BITS_PER_SAMPLE as u64 + bits;
+    if fixed_bits >= verbatim_bits {
+        writer.write(0b0000_0010, 8);
+        for &sample in samples {
+            writer.write_signed(sample, BITS_PER_SAMPLE);
+        }
+        return;
+    }
+
+    writer.write((0b00_1000 | order as u64) << 1, 8);
+    for &sample in &samples[..order] {
+        writer.write_signed(sample, BITS_PER_SAMPLE);
+    }
+    // Rice coding with 5 bit parameters.
+    writer.write(0b01, 2);
+    writer.write(partition_order.into(), 4);
+    let mut residuals = &residuals[..];
+    for (i, &param) in params.iter().enumerate() {
+        let len = partition_len(samples.len(), partition_order, order, i);
+        let (partition, rest) = residuals.split_at(len);
+        residuals = rest;
+        writer.write(param.into(), 5);
+        if param == RICE_ESCAPE {
+            let raw_bits = escape_bits(partition);
+            writer.write(raw_bits.into(), 5);
+            for &residual in partition {
+                writer.write_signed(unzigzag(residual), raw_bits);
+            }
+        } else {
+            for &residual in partition {
+                writer.write_unary(residual >> param);
+                writer.write(residual & ((1 << param) - 1), param);
+            }
+        }
+    }
+}
+
+/// The residuals of the fixed predictor of `order`, after the warm-up samples.
+fn fixed_residuals(samples: &[i64], order: usize) -> impl Iterator<Item = i64> + '_ {
+    samples.windows(order + 1).map(move |w| match order {
+        0 => w[0],
+        1 => w[1] - w[0],
+        2 => w[2] - 2 * w[1] + w[0],
+        3 => w[3] - 3 * w[2] + 3 * w[1] - w[0],
+        _ => w[4] - 4 * w[3] + 6 * w[2] - 4 * w[1] + w[0],
+    })
+}
+
+fn zigzag(residual: i64) -> u64 {
+    ((residual << 1) ^ (residual >> 63)) as u64
+}
+
+fn unzigzag(value: u64) -> i64 {
+    (value >> 1) as i64 ^ -((value & 1) as i64)
+}
+
+/// The number of residuals in partition `i`. The first partition is shorter by the warm-up
+/// samples.
+fn partition_len(block_len: usize, partition_order: u32, order: usize, i: usize) -> usize {
+    let len = block_len >> partition_order;
+    if i == 0 {
+        len - order
+    } else {
+        len
+    }
+}
+
+/// The partition order and Rice parameters that code the residuals in the fewest bits, and
+/// that number of bits.
+fn best_partitions(residuals: &[u64], block_len: usize, order: usize) -> (u32, Vec<u32>, u64) {
+    let mut best: Option<(u32, Vec<u32>, u64)> = None;
+    for partition_order in 0..=MAX_PARTITION_ORDER {
+        let num_partitions = 1 << partition_order;
+        if !block_len.is_multiple_of(num_partitions) || block_len / num_partitions <= order {
+            break;
+        }
+        let mut params = Vec::with_capacity(num_partitions);
+        let mut bits = 6;
+        let mut rest = residuals;
+        for i in 0..num_partitions {
+            let (partition, tail) =
+                rest.split_at(partition_len(block_len, partition_order, order, i));
+            rest = tail;
+            let (param, partition_bits) = best_param(partition);
+            params.push(param);
+            bits += 5 + partition_bits;
+        }
+        if best.as_ref().is_none_or(|best| bits < best.2) {
+            best = Some((partition_order, params, bits));
+        }
+    }
+
+    best.expect("a block can always be one partition")
+}
+
+/// The Rice parameter that codes the residuals in the fewest bits, and that number of bits.
+fn best_param(residuals: &[u64]) -> (u32, u64) {
+    let len = residuals.len() as u64;
+    let escaped = (RICE_ESCAPE, 5 + len * escape_bits(residuals) as u64);
+    if len == 0 {
+        return (0, 0);
+    }
+
+    // The best parameter is close to the log of the mean, so only its neighbours are tried.
+    let mean = residuals.iter().sum::<u64>() / len;
+    let estimate = (64 - mean.leading_zeros()).min(RICE_ESCAPE - 1);
+    (estimate.saturating_sub(1)..=(estimate + 1).min(RICE_ESCAPE - 1))
+        .map(|param| {
+            let bits = residuals
+                .iter()
+                .map(|residual| (residual >> param) + 1 + param as u64)
+                .sum();
+            (param, bits)
+        })
+        .chain([escaped])
+        .min_by_key(|&(_, bits)| bits)
+        .expect("there is always the escape code")
+}
+
+/// The number of bits needed to store every residual as a signed integer.
+fn escape_bits(residuals: &[u64]) -> u32 {
+    let max = residuals.iter().copied().max().unwrap_or(0);
+    // Zigzag coding already added the sign bit.
+    (64 - max.leading_zeros()).max(1)
+}
+
+/// Writes bits most significant first, as FLAC does.
+#[derive(Default)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    buffer: u64,
+    len: u32,
+}
+
+impl BitWriter {
+    /// Write the low `bits` bits of `value`, at most 32.
+    fn write(&mut self, value: u64, bits: u32) {
+        debug_assert!(bits <= 32);
+        if bits == 0 {
+            return;
+        }
+        self.buffer = (self.buffer << bits) | (value & ((1 << bits) - 1));
+        self.len += bits;
+        while self.len >= 8 {
+            self.len -= 8;
+            self.bytes.push((self.buffer >> self.len) as u8);
+        }
+        self.buffer &= (1 << self.len) - 1;
+    }
+
+    fn write_signed(&mut self, value: i64, bits: u32) {
+        self.write(value as u64, bits);
+    }
+
+    /// Write `value` zeros and then a one.
+    fn write_unary(&mut self, mut value: u64) {
+        while value >= 32 {
+            self.write(0, 32);
+            value -= 32;
+        }
+        self.write(1, value as u32 + 1);
+    }
+
+    /// Pad with zeros to a whole byte.
+    fn align(&mut self) {
+        if self.len > 0 {
+            self.write(0, 8 - self.len);
+        }
+    }
+}
+
+/// Write a frame number as FLAC does, in the same way as UTF-8 but up to 36 bits.
+fn write_utf8(writer: &mut BitWriter, value: u64) {
+    if value < 0x80 {
+        writer.write(value, 8);
+        return;
+    }
+    let len = (2..7).find(|len| value < 1 << (5 * len + 1)).unwrap_or(7);
+    let prefix = (0xff00u64 >> len) & 0xff;
+    writer.write(prefix | (value >> (6 * (len - 1))), 8);
+    for i in (0..len - 1).rev() {
+        writer.write(0x80 | ((value >> (6 * i)) & 0x3f), 8);
+    }
+}
+
+fn crc8(bytes: &[u8]) -> u8 {
+    let mut crc = 0u8;
+    for &byte in bytes {
+        crc ^= byte;
+        for _ in 0..8 {
+            crc = if crc & 0x80 != 0 {
+                (crc << 1) ^ 0x07
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+fn crc16(bytes: &[u8]) -> u16 {
+    let mut crc = 0u16;
+    for &byte in bytes {
+        crc ^= u16::from(byte) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x8005
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_flac_round_trip() {
+        // A tone, silence, and noise that doesn't predict well.
+        let mut samples: Vec<f32> = (0..10000).map(|i| (i as f32 * 0.05).sin() * 0.5).collect();
+        samples.extend(std::iter::repeat_n(0.0, BLOCK_SIZE));
+        let mut state = 1u32;
+        samples.extend((0..5000).map(|_| {
+            state = state.wrapping_mul(1664525).wrapping_add(1013904223);
+            (state >> 8) as f32 / (1 << 23) as f32 - 1.0
+        }));
+
+        let mut encoder = FlacEncoder::new(44100).unwrap();
+        let mut frames = Vec::new();
+        for block in samples.chunks(BLOCK_SIZE) {
+            encoder.encode_block(block, &mut frames);
+        }
+        let mut bytes = encoder.header().to_bytes();
+        assert_eq!(bytes.len(), HEADER_LEN);
+        bytes.extend_from_slice(&frames);
+
+        let audio = FlacAudio::parse(bytes).unwrap();
+        assert_eq!(audio.sample_rate(), 44100);
+        assert_eq!(audio.num_samples(), samples.len());
+        let decoded = audio.decode_range(0..samples.len()).unwrap();
+        assert_eq!(decoded.len(), samples.len());
+        let error = decoded
+            .iter()
+            .zip(&samples)
+            .map(|(a, b)| (a - b).abs())
+            .fold(0.0, f32::max);
+        assert!(error <= 1.0 / (1 << 23) as f32, "error {}", error);
+
+        assert_eq!(
+            audio.decode_range(9000..13000).unwrap(),
+            decoded[9000..13000]
+        );
+        assert!(audio.decode_range(30000..40000).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_damaged_flac() {
+        let samples: Vec<f32> = (0..48000 * 3)
+            .map(|i| (i as f32 * 0.05).sin() * 0.5)
+            .collect();
+        let mut encoder = FlacEncoder::new(48000).unwrap();
+        let frames: Vec<Vec<u8>> = samples
+            .chunks(BLOCK_SIZE)
+            .map(|block| {
+                let mut frame = Vec::new();
+                encoder.encode_block(block, &mut frame);
+                frame
+            })
+            .collect();
+        let header = encoder.header().to_bytes();
+
+        // Frame 15 fails its checksum, and frame 20 passes it but has a reserved subframe
+        // type. Both are in the second second.
+        let mut damaged = frames.clone();
+        let middle = damaged[15].len() / 2;
+        damaged[15][middle] ^= 0xff;
+        let frame = &mut damaged[20];
+        frame[8] = 0x04;
+        let crc = crc16(&frame[..frame.len() - 2]);
+        let crc_start = frame.len() - 2;
+        frame[crc_start..].copy_from_slice(&crc.to_be_bytes());
+
+        let audio = FlacAudio::parse([header, damaged.concat()].concat()).unwrap();
+        assert!(audio.damaged_seconds().is_empty());
+        let decoded = audio.decode_range(0..samples.len()).unwrap();
+        assert_eq!(decoded.len(), samples.len());
+        assert_eq!(audio.damaged_seconds(), vec![1]);
+        for (i, frame) in decoded.chunks(BLOCK_SIZE).enumerate() {
+            let original = &samples[i * BLOCK_SIZE..i * BLOCK_SIZE + frame.len()];
+            if i == 15 || i == 20 {
+                assert!(frame.iter().all(|&sample| sample == 0.0), "frame {}", i);
+            } else {
+                let error = frame
+                    .iter()
+                    .zip(original)
+                    .map(|(a, b)| (a - b).abs())
+                    .fold(0.0, f32::max);
+                assert!(
+                    error <= 1.0 / (1 << 23) as f32,
+                    "frame {} error {}",
+                    i,
+                    error
+                );
+            }
+        }
+
+        // A range starting in a damaged frame, and a stream missing its last frame.
+        let range = BLOCK_SIZE * 15 + 100..BLOCK_SIZE * 17;
+        assert_eq!(audio.decode_range(range.clone()).unwrap(), decoded[range]);
+        let truncated = FlacAudio::parse(
+            [
+                encoder.header().to_bytes(),
+                frames[..frames.len() - 1].concat(),
+            ]
+            .concat(),
+        )
+        .unwrap();
+        assert_eq!(
+            truncated.decode_range(0..samples.len()).unwrap().len(),
+            samples.len()
+        );
+        assert_eq!(truncated.damaged_seconds(), vec![2]);
+    }
+}
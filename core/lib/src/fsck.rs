@@ -0,0 +1,361 @@
+use std::fmt;
+
+use chrono::prelude::*;
+use color_eyre::eyre::{eyre, Result};
+use rusqlite::{params, types::Value};
+
+use crate::db::{parse_audio, Db, CLIP_COLUMNS};
+use crate::encryption::{open_bytes, open_text};
+use crate::internal_encoding::EncodingProfile;
+
+/// What `fsck` may change.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct FsckOptions {
+    /// Move clips that can't be loaded out of the journal or the trash, into the quarantine
+    /// table, so that the rest can be listed and loaded.
+    pub quarantine: bool,
+    /// Rebuild the database file afterwards.
+    pub vacuum: bool,
+}
+
+/// What `fsck` found.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct FsckReport {
+    /// What SQLite's integrity check found wrong with the database file. Empty if it is
+    /// intact.
+    pub integrity_errors: Vec<String>,
+    pub clips_checked: usize,
+    pub problems: Vec<ClipProblem>,
+    pub vacuumed: bool,
+}
+
+impl FsckReport {
+    /// Whether anything is still wrong, after quarantining.
+    pub fn has_unresolved(&self) -> bool {
+        !self.integrity_errors.is_empty()
+            || self.problems.iter().any(|problem| !problem.quarantined)
+    }
+}
+
+/// A clip that failed the check.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ClipProblem {
+    pub id: usize,
+    /// The clip's name, if it could be read.
+    pub name: Option<String>,
+    /// Whether the clip is in the trash.
+    pub trashed: bool,
+    pub kind: ProblemKind,
+    /// Whether the clip was moved to the quarantine table.
+    pub quarantined: bool,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ProblemKind {
+    /// The name, date, notes or profile can't be read, so the clip can't be listed.
+    BadMetadata(String),
+    /// The audio can't be decoded, so the clip can't be loaded.
+    BadAudio(String),
+    /// These chunks of the audio, a second each, are damaged. The clip loads, but the chunks
+    /// are concealed, or silent for lossless clips.
+    DamagedAudio(Vec<usize>),
+}
+
+impl ProblemKind {
+    /// Whether the clip can't be used at all, and should be quarantined.
+    pub fn is_fatal(&self) -> bool {
+        !matches!(self, ProblemKind::DamagedAudio(_))
+    }
+}
+
+impl fmt::Display for ProblemKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProblemKind::BadMetadata(reason) => write!(f, "unreadable metadata, {}", reason),
+            ProblemKind::BadAudio(reason) => write!(f, "undecodable audio, {}", reason),
+            ProblemKind::DamagedAudio(chunks) => write!(
+                f,
+                "{} damaged second(s) of audio, starting at chunk {}",
+                chunks.len(),
+                chunks.first().copied().unwrap_or_default()
+            ),
+        }
+    }
+}
+
+impl fmt::Display for ClipProblem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let clip = if self.trashed { "Trashed clip" } else { "Clip" };
+        match &self.name {
+            Some(name) => write!(f, "{} {} ({}): ", clip, self.id, name)?,
+            None => write!(f, "{} {}: ", clip, self.id)?,
+        }
+        write!(f, "{}", self.kind)?;
+        if self.quarantined {
+            write!(f, " (quarantined)")?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Check the database file and decode every clip in `db`, in the trash too, reporting what is
+/// wrong and, if `options` say so, quarantining clips that can't be used. The journal must be
+/// unlocked.
+///
+/// `on_progress` is called with the number of clips checked and the total.
+pub fn fsck(
+    db: &Db,
+    options: &FsckOptions,
+    mut on_progress: impl FnMut(usize, usize),
+) -> Result<FsckReport> {
+    let mut report = FsckReport {
+        integrity_errors: integrity_check(db)?,
+        ..Default::default()
+    };
+
+    let ids = db
+        .connection
+        .prepare("SELECT id, 0 FROM clips UNION ALL SELECT id, 1 FROM trash ORDER BY 2, 1")?
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect::<Result<Vec<(usize, bool)>, rusqlite::Error>>()?;
+    for (i, &(id, trashed)) in ids.iter().enumerate() {
+        let table = if trashed { "trash" } else { "clips" };
+        let (name, kind) = check_clip(db, table, id)?;
+        if let Some(kind) = kind {
+            let mut problem = ClipProblem {
+                id,
+                name,
+                trashed,
+                kind,
+                quarantined: false,
+            };
+            if options.quarantine && problem.kind.is_fatal() {
+                quarantine(db, table, id, &problem.to_string())?;
+                problem.quarantined = true;
+                log::warn!("{}", problem);
+            }
+            report.problems.push(problem);
+        }
+        report.clips_checked += 1;
+        on_progress(i + 1, ids.len());
+    }
+
+    if options.vacuum {
+        db.vacuum()?;
+        report.vacuumed = true;
+    }
+
+    Ok(report)
+}
+
+fn integrity_check(db: &Db) -> Result<Vec<String>> {
+    let messages = db
+        .connection
+        .prepare("PRAGMA integrity_check")?
+        .query_map([], |row| row.get(0))?
+        .collect::<Result<Vec<String>, rusqlite::Error>>()?;
+
+    Ok(messages
+        .into_iter()
+        .filter(|message| message != "ok")
+        .collect())
+}
+
+/// Check that a clip in `table`, the clips or the trash, can be listed and loaded. Returns
+/// its name if it could be read, and what is wrong with it, if anything.
+fn check_clip(db: &Db, table: &str, id: usize) -> Result<(Option<String>, Option<ProblemKind>)> {
+    let cipher = db.cipher()?;
+    let row = db.connection.query_row(
+        &format!(
+            "SELECT name, date, notes, profile, format, sample_rate, opus FROM {} WHERE id = ?1",
+            table
+        ),
+        [id],
+        |row| {
+            Ok((
+                row.get::<_, Value>(0)?,
+                row.get::<_, Value>(1)?,
+                row.get::<_, Value>(2)?,
+                row.get::<_, Value>(3)?,
+                row.get::<_, Value>(4)?,
+                row.get::<_, Value>(5)?,
+                row.get::<_, Value>(6)?,
+            ))
+        },
+    )?;
+    let (name, date, notes, profile, format, sample_rate, opus) = row;
+
+    let name = match open_text(cipher, name) {
+        Ok(Some(name)) => name,
+        Ok(None) => return Ok((None, Some(bad_metadata("the name is missing")))),
+        Err(err) => return Ok((None, Some(bad_metadata(format!("the name: {}", err))))),
+    };
+    let metadata = check_date(date)
+        .and_then(|_| open_text(cipher, notes).map_err(|err| eyre!("the notes: {}", err)))
+        .and_then(|_| match profile {
+            Value::Text(profile) => EncodingProfile::from_name(&profile),
+            _ => Err(eyre!("the profile is not text")),
+        });
+    if let Err(err) = metadata {
+        return Ok((Some(name), Some(bad_metadata(err.to_string()))));
+    }
+
+    let audio = match (format, sample_rate, opus) {
+        (Value::Integer(format), Value::Integer(sample_rate), Value::Blob(opus)) => {
+            open_bytes(cipher, opus).and_then(|opus| {
+                parse_audio(u32::try_from(format)?, u32::try_from(sample_rate)?, opus)
+            })
+        }
+        _ => Err(eyre!("the audio columns have the wrong types")),
+    };
+    let kind = match audio.and_then(|audio| audio.decode().map(|_| audio)) {
+        Ok(audio) if audio.damaged_chunks().is_empty() => None,
+        Ok(audio) => Some(ProblemKind::DamagedAudio(audio.damaged_chunks())),
+        Err(err) => Some(ProblemKind::BadAudio(err.to_string())),
+    };
+
+    Ok((Some(name), kind))
+}
+
+fn check_date(date: Value) -> Result<()> {
+    match date {
+        Value::Text(date) => date
+            .parse::<DateTime<Utc>>()
+            .map(|_| ())
+            .map_err(|_| eyre!("invalid date {:?}", date)),
+        _ => Err(eyre!("the date is not text")),
+    }
+}
+
+fn bad_metadata(reason: impl Into<String>) -> ProblemKind {
+    ProblemKind::BadMetadata(reason.into())
+}
+
+/// Move a clip in `table`, as it is, to the quarantine table.
+fn quarantine(db: &Db, table: &str, id: usize, reason: &str) -> Result<()> {
+    let transaction = db.connection.unchecked_transaction()?;
+    transaction.execute(
+        &format!(
+            "INSERT OR REPLACE INTO quarantine ({columns}, reason, quarantined) SELECT {columns}, ?2, ?3 FROM {table} WHERE id = ?1",
+            columns = CLIP_COLUMNS,
+            table = table
+        ),
+        params![id, reason, Utc::now().to_string()],
+    )?;
+    transaction.execute(&format!("DELETE FROM {} WHERE id = ?1", table), [id])?;
+    transaction.commit()?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio_clip::AudioClip;
+    use crate::db::ListQuery;
+    use crate::test_fixtures::{clip, sine};
+
+    #[test]
+    fn test_fsck() {
+        let db = Db::in_memory().unwrap();
+        for name in ["Good", "Bad date", "Bad audio", "Damaged", "Trashed"] {
+            // Long enough for several chunks, so that damage to one is recoverable.
+            db.save(&mut AudioClip {
+                samples: sine(48000, 48000 * 3),
+                ..clip(name)
+            })
+            .unwrap();
+        }
+        db.delete("Trashed").unwrap();
+        let report = fsck(&db, &FsckOptions::default(), |_, _| {}).unwrap();
+        assert_eq!(report.clips_checked, 5);
+        assert!(!report.has_unresolved());
+
+        db.connection
+            .execute(
+                "UPDATE clips SET date = 'yesterday' WHERE name = 'Bad date'",
+                [],
+            )
+            .unwrap();
+        db.connection
+            .execute("UPDATE trash SET date = 'yesterday'", [])
+            .unwrap();
+        db.connection
+            .execute(
+                "UPDATE clips SET opus = x'0102' WHERE name = 'Bad audio'",
+                [],
+            )
+            .unwrap();
+        let mut damaged: Vec<u8> = db
+            .connection
+            .query_row("SELECT opus FROM clips WHERE name = 'Damaged'", [], |row| {
+                row.get(0)
+            })
+            .unwrap();
+        let middle = damaged.len() / 2;
+        damaged[middle] ^= 0xff;
+        db.connection
+            .execute(
+                "UPDATE clips SET opus = ?1 WHERE name = 'Damaged'",
+                [&damaged],
+            )
+            .unwrap();
+        assert!(db.list(&ListQuery::default()).is_err());
+
+        let report = fsck(&db, &FsckOptions::default(), |_, _| {}).unwrap();
+        assert!(report.has_unresolved());
+        let kinds: Vec<_> = report
+            .problems
+            .iter()
+            .map(|problem| {
+                (
+                    problem.name.as_deref().unwrap(),
+                    problem.kind.is_fatal(),
+                    problem.trashed,
+                )
+            })
+            .collect();
+        assert_eq!(
+            kinds,
+            [
+                ("Bad date", true, false),
+                ("Bad audio", true, false),
+                ("Damaged", false, false),
+                ("Trashed", true, true)
+            ]
+        );
+        assert!(matches!(
+            report.problems[2].kind,
+            ProblemKind::DamagedAudio(ref chunks) if chunks == &[1]
+        ));
+
+        let options = FsckOptions {
+            quarantine: true,
+            vacuum: true,
+        };
+        let report = fsck(&db, &options, |_, _| {}).unwrap();
+        assert!(report.vacuumed);
+        assert_eq!(
+            report
+                .problems
+                .iter()
+                .filter(|problem| problem.quarantined)
+                .count(),
+            3
+        );
+        let names: Vec<_> = db
+            .list(&ListQuery::default())
+            .unwrap()
+            .into_iter()
+            .map(|meta| meta.name)
+            .collect();
+        assert_eq!(names, ["Good", "Damaged"]);
+        let quarantined: usize = db
+            .connection
+            .query_row("SELECT COUNT(*) FROM quarantine", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(quarantined, 3);
+        assert!(db.trash().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,587 @@
+use std::fmt;
+
+use chrono::prelude::*;
+use color_eyre::eyre::{eyre, Result};
+use rusqlite::{params, types::Value, OptionalExtension};
+
+use crate::db::{ClipMeta, Db, CLIP_COLUMNS};
+use crate::encryption::{open_text, seal_name, seal_text, Cipher};
+use crate::import::unused_name;
+
+/// How many operations are kept to be undone. Older ones are forgotten.
+const HISTORY_LIMIT: usize = 100;
+
+/// A change to a clip that can be undone and redone.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Operation {
+    /// The clip was moved to the trash.
+    Delete {
+        id: usize,
+        name: String,
+    },
+    /// The clip was restored from the trash, as `name`.
+    Restore {
+        id: usize,
+        name: String,
+    },
+    Rename {
+        id: usize,
+        from: String,
+        to: String,
+    },
+    EditNotes {
+        id: usize,
+        from: Option<String>,
+        to: Option<String>,
+    },
+    AddToCollection {
+        id: usize,
+        collection: String,
+    },
+    /// The clip was taken out of a collection. Undoing this puts it back at the end.
+    RemoveFromCollection {
+        id: usize,
+        collection: String,
+    },
+}
+
+impl Operation {
+    /// The ID of the clip that was changed.
+    pub fn clip_id(&self) -> usize {
+        match self {
+            Operation::Delete { id, .. }
+            | Operation::Restore { id, .. }
+            | Operation::Rename { id, .. }
+            | Operation::EditNotes { id, .. }
+            | Operation::AddToCollection { id, .. }
+            | Operation::RemoveFromCollection { id, .. } => *id,
+        }
+    }
+
+    fn kind(&self) -> &'static str {
+        match self {
+            Operation::Delete { .. } => "delete",
+            Operation::Restore { .. } => "restore",
+            Operation::Rename { .. } => "rename",
+            Operation::EditNotes { .. } => "notes",
+            Operation::AddToCollection { .. } => "collect",
+            Operation::RemoveFromCollection { .. } => "uncollect",
+        }
+    }
+
+    /// What the operation changed, before and after.
+    fn values(&self) -> (Option<&str>, Option<&str>) {
+        match self {
+            Operation::Delete { name, .. } => (Some(name), None),
+            Operation::Restore { name, .. } => (None, Some(name)),
+            Operation::Rename { from, to, .. } => (Some(from), Some(to)),
+            Operation::EditNotes { from, to, .. } => (from.as_deref(), to.as_deref()),
+            Operation::AddToCollection { collection, .. } => (None, Some(collection)),
+            Operation::RemoveFromCollection { collection, .. } => (Some(collection), None),
+        }
+    }
+
+    fn from_row(
+        kind: &str,
+        id: usize,
+        before: Option<String>,
+        after: Option<String>,
+    ) -> Result<Operation> {
+        let missing = || eyre!("The {} operation on clip {} is incomplete", kind, id);
+        Ok(match kind {
+            "delete" => Operation::Delete {
+                id,
+                name: before.ok_or_else(missing)?,
+            },
+            "restore" => Operation::Restore {
+                id,
+                name: after.ok_or_else(missing)?,
+            },
+            "rename" => Operation::Rename {
+                id,
+                from: before.ok_or_else(missing)?,
+                to: after.ok_or_else(missing)?,
+            },
+            "notes" => Operation::EditNotes {
+                id,
+                from: before,
+                to: after,
+            },
+            "collect" => Operation::AddToCollection {
+                id,
+                collection: after.ok_or_else(missing)?,
+            },
+            "uncollect" => Operation::RemoveFromCollection {
+                id,
+                collection: before.ok_or_else(missing)?,
+            },
+            _ => return Err(eyre!("Unknown operation {:?}", kind)),
+        })
+    }
+
+    /// Make the change again, or revert it if `undo` is set.
+    fn apply(&self, db: &Db, undo: bool) -> Result<()> {
+        match (self, undo) {
+            (Operation::Delete { id, .. }, false) | (Operation::Restore { id, .. }, true) => {
+                db.move_to_trash(*id)?;
+            }
+            (Operation::Delete { id, .. }, true) | (Operation::Restore { id, .. }, false) => {
+                db.move_from_trash(*id)?;
+            }
+            (Operation::Rename { id, from, to }, _) => {
+                db.write_name(*id, if undo { from } else { to })?;
+            }
+            (Operation::EditNotes { id, from, to }, _) => {
+                db.write_notes(*id, if undo { from } else { to }.as_deref())?;
+            }
+            (Operation::AddToCollection { id, collection }, false)
+            | (Operation::RemoveFromCollection { id, collection }, true) => {
+                db.append_to_collection(collection_id(db, collection)?, *id)?;
+            }
+            (Operation::AddToCollection { id, collection }, true)
+            | (Operation::RemoveFromCollection { id, collection }, false) => {
+                db.take_from_collection(collection_id(db, collection)?, *id)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Collections are remembered by name, so an operation can't be undone once its collection
+/// is renamed or deleted.
+fn collection_id(db: &Db, name: &str) -> Result<usize> {
+    db.collection_id(name)?
+        .ok_or_else(|| eyre!("There is no collection named \"{}\"", name))
+}
+
+impl fmt::Display for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Operation::Delete { name, .. } => write!(f, "Delete \"{}\"", name),
+            Operation::Restore { name, .. } => write!(f, "Restore \"{}\"", name),
+            Operation::Rename { from, to, .. } => write!(f, "Rename \"{}\" to \"{}\"", from, to),
+            Operation::EditNotes { id, .. } => write!(f, "Edit the notes of clip {}", id),
+            Operation::AddToCollection { id, collection } => {
+                write!(f, "Add clip {} to \"{}\"", id, collection)
+            }
+            Operation::RemoveFromCollection { id, collection } => {
+                write!(f, "Remove clip {} from \"{}\"", id, collection)
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct HistoryEntry {
+    pub operation: Operation,
+    pub date: DateTime<Utc>,
+    /// Whether the operation has been undone, so that it can be redone.
+    pub undone: bool,
+}
+
+/// A deleted clip, which can be restored until the trash is emptied.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TrashedClip {
+    pub meta: ClipMeta,
+    pub deleted: DateTime<Utc>,
+}
+
+impl Db {
+    /// Revert the most recent operation that has not been undone. Returns None if there is
+    /// nothing to undo.
+    ///
+    /// An operation that can't be undone any more, because its clip was replaced or its old
+    /// name was taken, is forgotten, and the error is returned.
+    pub fn undo(&self) -> Result<Option<Operation>> {
+        self.step(
+            "SELECT id, operation, clip_id, before, after FROM history WHERE undone = 0 ORDER BY id DESC LIMIT 1",
+            true,
+        )
+    }
+
+    /// Make the most recently undone operation again. Returns None if there is nothing to
+    /// redo. Recording a new operation forgets the ones that could be redone.
+    pub fn redo(&self) -> Result<Option<Operation>> {
+        self.step(
+            "SELECT id, operation, clip_id, before, after FROM history WHERE undone = 1 ORDER BY id LIMIT 1",
+            false,
+        )
+    }
+
+    fn step(&self, query: &str, undo: bool) -> Result<Option<Operation>> {
+        let cipher = self.cipher()?;
+        let row = self
+            .connection
+            .query_row(query, [], |row| {
+                Ok((
+                    row.get::<_, usize>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, usize>(2)?,
+                    row.get::<_, Value>(3)?,
+                    row.get::<_, Value>(4)?,
+                ))
+            })
+            .optional()?;
+        let (seq, kind, clip_id, before, after) = match row {
+            Some(row) => row,
+            None => return Ok(None),
+        };
+        let operation = Operation::from_row(
+            &kind,
+            clip_id,
+            open_text(cipher, before)?,
+            open_text(cipher, after)?,
+        )?;
+
+        let transaction = self.connection.unchecked_transaction()?;
+        if let Err(err) = operation.apply(self, undo) {
+            drop(transaction);
+            self.connection
+                .execute("DELETE FROM history WHERE id = ?1", [seq])?;
+            let action = if undo { "undo" } else { "redo" };
+            return Err(eyre!("Can't {} \"{}\": {}", action, operation, err));
+        }
+        transaction.execute(
+            "UPDATE history SET undone = ?2 WHERE id = ?1",
+            params![seq, undo],
+        )?;
+        transaction.commit()?;
+
+        Ok(Some(operation))
+    }
+
+    pub fn can_undo(&self) -> Result<bool> {
+        Ok(self.connection.query_row(
+            "SELECT EXISTS (SELECT 1 FROM history WHERE undone = 0)",
+            [],
+            |row| row.get(0),
+        )?)
+    }
+
+    pub fn can_redo(&self) -> Result<bool> {
+        Ok(self.connection.query_row(
+            "SELECT EXISTS (SELECT 1 FROM history WHERE undone = 1)",
+            [],
+            |row| row.get(0),
+        )?)
+    }
+
+    /// The operations that can be undone or redone, oldest first.
+    pub fn history(&self) -> Result<Vec<HistoryEntry>> {
+        let cipher = self.cipher()?;
+        let mut stmt = self.connection.prepare(
+            "SELECT operation, clip_id, before, after, date, undone FROM history ORDER BY id",
+        )?;
+        let rows = stmt.query_map([], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, usize>(1)?,
+                row.get::<_, Value>(2)?,
+                row.get::<_, Value>(3)?,
+                row.get::<_, String>(4)?,
+                row.get::<_, bool>(5)?,
+            ))
+        })?;
+
+        let mut entries = Vec::new();
+        for row in rows {
+            let (kind, clip_id, before, after, date, undone) = row?;
+            entries.push(HistoryEntry {
+                operation: Operation::from_row(
+                    &kind,
+                    clip_id,
+                    open_text(cipher, before)?,
+                    open_text(cipher, after)?,
+                )?,
+                date: date
+                    .parse()
+                    .map_err(|_| eyre!("Invalid date for operation: {}", date))?,
+                undone,
+            });
+        }
+
+        Ok(entries)
+    }
+
+    /// Add an operation to the history, forgetting the ones that could be redone.
+    pub(crate) fn record(&self, operation: &Operation) -> Result<()> {
+        let cipher = self.cipher()?;
+        let (before, after) = operation.values();
+        self.connection
+            .execute("DELETE FROM history WHERE undone = 1", [])?;
+        self.connection.execute(
+            "INSERT INTO history (operation, clip_id, before, after, date) VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![
+                operation.kind(),
+                operation.clip_id(),
+                seal_text(cipher, before),
+                seal_text(cipher, after),
+                Utc::now().to_string(),
+            ],
+        )?;
+        self.connection.execute(
+            "DELETE FROM history WHERE id NOT IN (SELECT id FROM history ORDER BY id DESC LIMIT ?1)",
+            [HISTORY_LIMIT],
+        )?;
+
+        Ok(())
+    }
+
+    /// Rewrite what the history remembers, opening it with `from` and sealing it with `to`.
+    pub(crate) fn reseal_history(&self, from: Option<&Cipher>, to: Option<&Cipher>) -> Result<()> {
+        let rows = self
+            .connection
+            .prepare("SELECT id, before, after FROM history")?
+            .query_map([], |row| {
+                Ok((
+                    row.get::<_, usize>(0)?,
+                    row.get::<_, Value>(1)?,
+                    row.get::<_, Value>(2)?,
+                ))
+            })?
+            .collect::<Result<Vec<_>, rusqlite::Error>>()?;
+
+        for (id, before, after) in rows {
+            let before = open_text(from, before)?;
+            let after = open_text(from, after)?;
+            self.connection.execute(
+                "UPDATE history SET before = ?2, after = ?3 WHERE id = ?1",
+                params![
+                    id,
+                    seal_text(to, before.as_deref()),
+                    seal_text(to, after.as_deref())
+                ],
+            )?;
+        }
+
+        Ok(())
+    }
+
+    /// The deleted clips, oldest deletion first.
+    pub fn trash(&self) -> Result<Vec<TrashedClip>> {
+        let cipher = self.cipher()?;
+        let mut stmt = self.connection.prepare(
+            "SELECT id, name, date, notes, deleted, duration FROM trash ORDER BY deleted",
+        )?;
+        let rows = stmt.query_map([], |row| {
+            Ok((
+                row.get::<_, usize>(0)?,
+                row.get::<_, Value>(1)?,
+                row.get::<_, String>(2)?,
+                row.get::<_, Value>(3)?,
+                row.get::<_, String>(4)?,
+                row.get::<_, Option<f64>>(5)?,
+            ))
+        })?;
+
+        let mut clips = Vec::new();
+        for row in rows {
+            let (id, name, date, notes, deleted, duration) = row?;
+            clips.push(TrashedClip {
+                meta: ClipMeta {
+                    id,
+                    name: open_text(cipher, name)?
+                        .ok_or_else(|| eyre!("Clip {} has no name", id))?,
+                    date: date
+                        .parse()
+                        .map_err(|_| eyre!("Invalid date for clip {}: {}", id, date))?,
+                    notes: open_text(cipher, notes)?,
+                    duration: duration.unwrap_or_default(),
+                },
+                deleted: deleted
+                    .parse()
+                    .map_err(|_| eyre!("Invalid deletion date for clip {}: {}", id, deleted))?,
+            });
+        }
+
+        Ok(clips)
+    }
+
+    /// Move a clip out of the trash, renaming it like "Name (2)" if its name has been taken
+    /// since. Returns the name it was restored as.
+    pub fn restore_from_trash(&self, id: usize) -> Result<String> {
+        let transaction = self.connection.unchecked_transaction()?;
+        let name = self.move_from_trash(id)?;
+        self.record(&Operation::Restore {
+            id,
+            name: name.clone(),
+        })?;
+        transaction.commit()?;
+
+        Ok(name)
+    }
+
+    /// Delete the clips in the trash for good, taking them out of their collections and
+    /// forgetting their speakers and the operations on them. Returns how many clips were
+    /// deleted.
+    pub fn empty_trash(&self) -> Result<usize> {
+        let transaction = self.connection.unchecked_transaction()?;
+        transaction.execute(
+            "DELETE FROM history WHERE clip_id IN (SELECT id FROM trash)",
+            [],
+        )?;
+        transaction.execute(
+            "DELETE FROM collection_clips WHERE clip_id IN (SELECT id FROM trash)",
+            [],
+        )?;
+        for table in ["speaker_turns", "speakers"] {
+            transaction.execute(
+                &format!(
+                    "DELETE FROM {} WHERE clip_id IN (SELECT id FROM trash)",
+                    table
+                ),
+                [],
+            )?;
+        }
+        let count = transaction.execute("DELETE FROM trash", [])?;
+        transaction.commit()?;
+        self.vacuum()?;
+
+        Ok(count)
+    }
+
+    /// Move a clip to the trash, keeping its ID. Returns its name.
+    pub(crate) fn move_to_trash(&self, id: usize) -> Result<String> {
+        let name = self
+            .connection
+            .query_row("SELECT name FROM clips WHERE id = ?1", [id], |row| {
+                row.get::<_, Value>(0)
+            })
+            .optional()?
+            .ok_or_else(|| eyre!("There is no clip with ID {}", id))?;
+        let name =
+            open_text(self.cipher()?, name)?.ok_or_else(|| eyre!("Clip {} has no name", id))?;
+
+        self.connection.execute(
+            &format!(
+                "INSERT INTO trash ({columns}, deleted) SELECT {columns}, ?2 FROM clips WHERE id = ?1",
+                columns = CLIP_COLUMNS
+            ),
+            params![id, Utc::now().to_string()],
+        )?;
+        self.connection
+            .execute("DELETE FROM clips WHERE id = ?1", [id])?;
+
+        Ok(name)
+    }
+
+    fn move_from_trash(&self, id: usize) -> Result<String> {
+        let cipher = self.cipher()?;
+        let name = self
+            .connection
+            .query_row("SELECT name FROM trash WHERE id = ?1", [id], |row| {
+                row.get::<_, Value>(0)
+            })
+            .optional()?
+            .ok_or_else(|| eyre!("Clip {} is not in the trash", id))?;
+        let name = open_text(cipher, name)?.ok_or_else(|| eyre!("Clip {} has no name", id))?;
+        let name = match self.name_exists(&name)? {
+            true => unused_name(self, &name)?,
+            false => name,
+        };
+
+        self.connection.execute(
+            "UPDATE trash SET name = ?2 WHERE id = ?1",
+            params![id, seal_name(cipher, &name)],
+        )?;
+        self.connection.execute(
+            &format!(
+                "INSERT INTO clips ({columns}) SELECT {columns} FROM trash WHERE id = ?1",
+                columns = CLIP_COLUMNS
+            ),
+            [id],
+        )?;
+        self.connection
+            .execute("DELETE FROM trash WHERE id = ?1", [id])?;
+
+        Ok(name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::ListQuery;
+    use crate::test_fixtures::clip;
+
+    fn names(db: &Db) -> Vec<String> {
+        db.list(&ListQuery::default())
+            .unwrap()
+            .into_iter()
+            .map(|meta| meta.name)
+            .collect()
+    }
+
+    #[test]
+    fn test_undo_redo() {
+        let db = Db::in_memory().unwrap();
+        let mut first = clip("First");
+        db.save(&mut first).unwrap();
+        db.save(&mut clip("Second")).unwrap();
+        assert!(!db.can_undo().unwrap());
+
+        db.rename("First", "Renamed").unwrap();
+        db.set_notes("Renamed", Some("Notes")).unwrap();
+        db.delete("Second").unwrap();
+        assert_eq!(names(&db), ["Renamed"]);
+        assert_eq!(db.trash().unwrap()[0].meta.name, "Second");
+
+        let undone = db.undo().unwrap().unwrap();
+        assert_eq!(undone.to_string(), "Delete \"Second\"");
+        assert_eq!(names(&db), ["Renamed", "Second"]);
+        assert!(db.trash().unwrap().is_empty());
+        db.undo().unwrap();
+        assert_eq!(db.list(&ListQuery::default()).unwrap()[0].notes, None);
+        db.undo().unwrap();
+        assert_eq!(names(&db), ["First", "Second"]);
+        assert!(db.undo().unwrap().is_none());
+
+        db.redo().unwrap();
+        db.redo().unwrap();
+        assert_eq!(
+            db.list(&ListQuery::default()).unwrap()[0].notes.as_deref(),
+            Some("Notes")
+        );
+        let history = db.history().unwrap();
+        assert_eq!(
+            history.iter().map(|entry| entry.undone).collect::<Vec<_>>(),
+            [false, false, true]
+        );
+
+        // A new operation forgets the one that could be redone.
+        db.rename("Second", "Other").unwrap();
+        assert!(!db.can_redo().unwrap());
+        assert_eq!(db.history().unwrap().len(), 3);
+
+        // Undoing a rename whose old name was taken forgets it.
+        db.rename("Renamed", "Third").unwrap();
+        db.save(&mut clip("Renamed")).unwrap();
+        assert!(db.undo().is_err());
+        assert_eq!(db.history().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn test_trash() {
+        let db = Db::in_memory().unwrap();
+        let mut first = clip("First");
+        db.save(&mut first).unwrap();
+        let id = first.id.unwrap();
+        db.delete_by_id(id).unwrap();
+
+        // The ID of a clip in the trash is not reused.
+        let mut second = clip("First");
+        db.save(&mut second).unwrap();
+        assert_ne!(second.id, Some(id));
+
+        assert_eq!(db.restore_from_trash(id).unwrap(), "First (2)");
+        assert!(db.load_by_id(id).unwrap().is_some());
+        db.undo().unwrap();
+        assert!(db.load_by_id(id).unwrap().is_none());
+
+        assert_eq!(db.empty_trash().unwrap(), 1);
+        assert!(db.trash().unwrap().is_empty());
+        assert!(!db.can_undo().unwrap());
+        assert_eq!(names(&db), ["First"]);
+    }
+}
@@ -0,0 +1,972 @@
+use std::fs::File;
+use std::io::{BufWriter, Cursor, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::OnceLock;
+
+use chrono::prelude::*;
+use color_eyre::eyre::{eyre, Result};
+use glob::Pattern;
+use symphonia::core::audio::SampleBuffer;
+use symphonia::core::codecs::{CodecRegistry, Decoder, DecoderOptions, CODEC_TYPE_NULL};
+use symphonia::core::errors::Error;
+use symphonia::core::formats::{FormatOptions, FormatReader, Track};
+use symphonia::core::io::MediaSourceStream;
+use symphonia::core::meta::{MetadataOptions, MetadataRevision, StandardTagKey};
+use symphonia::core::probe::Hint;
+use walkdir::WalkDir;
+
+use crate::db::{ClipMeta, Db};
+use crate::internal_encoding::{EncodingProfile, StreamingEncoder};
+use crate::opus_decoder::OpusDecoder;
+use crate::peaks::PeakPyramid;
+
+/// How far an import has got.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ImportProgress {
+    /// Seconds of audio decoded so far.
+    pub decoded: f64,
+    /// Length of the file in seconds, if the format records it.
+    pub total: Option<f64>,
+}
+
+impl ImportProgress {
+    /// Fraction of the file decoded so far, between 0 and 1.
+    pub fn fraction(&self) -> Option<f64> {
+        self.total
+            .filter(|total| *total > 0.0)
+            .map(|total| (self.decoded / total).min(1.0))
+    }
+}
+
+/// The codecs symphonia was built with, plus Opus.
+fn codecs() -> &'static CodecRegistry {
+    static CODECS: OnceLock<CodecRegistry> = OnceLock::new();
+    CODECS.get_or_init(|| {
+        let mut registry = CodecRegistry::new();
+        symphonia::default::register_enabled_codecs(&mut registry);
+        registry.register_all::<OpusDecoder>();
+        registry
+    })
+}
+
+/// Whether a track can be decoded as audio. Symphonia also lists tracks it does not know, like
+/// video tracks.
+fn is_audio(track: &Track) -> bool {
+    track.codec_params.codec != CODEC_TYPE_NULL
+        && track.codec_params.sample_rate.is_some()
+        && codecs().get_codec(track.codec_params.codec).is_some()
+}
+
+/// What to do when there is already a clip with the name an import would get.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ConflictPolicy {
+    /// Fail the import.
+    #[default]
+    Error,
+    /// Leave the existing clip alone and don't import the file.
+    Skip,
+    /// Add a suffix to the name, like "Name (2)".
+    Rename,
+    /// Replace the existing clip, moving it to the trash.
+    Overwrite,
+}
+
+/// Which part of a file to import, and how.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ImportOptions {
+    /// Index of the track to import, in the order the container lists them. Defaults to the
+    /// container's default track.
+    pub track: Option<usize>,
+    /// Channel to import. Defaults to the first channel.
+    pub channel: usize,
+    pub conflict: ConflictPolicy,
+    /// How to encode the imported clip.
+    pub profile: EncodingProfile,
+}
+
+/// What became of an import that did not fail.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ImportOutcome {
+    Imported(ClipMeta),
+    /// There was already a clip with the given name, and the policy was to skip.
+    Skipped(String),
+}
+
+impl ImportOutcome {
+    /// The name of the new clip, or of the clip that was in the way.
+    pub fn name(&self) -> &str {
+        match self {
+            ImportOutcome::Imported(meta) => &meta.name,
+            ImportOutcome::Skipped(name) => name,
+        }
+    }
+}
+
+/// Extensions of the files `find_files` picks when no patterns are given.
+pub const IMPORT_EXTENSIONS: &[&str] = &[
+    "wav", "wave", "aif", "aiff", "caf", "flac", "mp3", "m4a", "aac", "mp4", "m4v", "mov", "ogg",
+    "oga", "opus", "mka", "mkv", "webm",
+];
+
+/// Tags read from the container, where present.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SourceMetadata {
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub date: Option<DateTime<Utc>>,
+    pub comment: Option<String>,
+}
+
+impl SourceMetadata {
+    /// Fill in anything still missing from `revision`.
+    fn read(&mut self, revision: &MetadataRevision) {
+        for tag in revision.tags() {
+            let value = tag.value.to_string().trim().to_string();
+            if value.is_empty() {
+                continue;
+            }
+            match tag.std_key {
+                Some(StandardTagKey::TrackTitle) => {
+                    self.title.get_or_insert(value);
+                }
+                Some(StandardTagKey::Artist) => {
+                    self.artist.get_or_insert(value);
+                }
+                Some(StandardTagKey::Date | StandardTagKey::OriginalDate)
+                    if self.date.is_none() =>
+                {
+                    self.date = parse_tag_date(&value);
+                }
+                Some(StandardTagKey::Comment | StandardTagKey::Description) => {
+                    self.comment.get_or_insert(value);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// The artist and comment, for the clip's notes.
+    pub fn notes(&self) -> Option<String> {
+        let lines: Vec<String> = self
+            .artist
+            .iter()
+            .map(|artist| format!("Artist: {}", artist))
+            .chain(self.comment.iter().cloned())
+            .collect();
+        if lines.is_empty() {
+            None
+        } else {
+            Some(lines.join("\n"))
+        }
+    }
+}
+
+/// Parse a date tag, which is usually ISO 8601 but is often just a date or a year. Dates
+/// without a time zone are assumed to be local.
+fn parse_tag_date(value: &str) -> Option<DateTime<Utc>> {
+    if let Ok(date) = DateTime::parse_from_rfc3339(value) {
+        return Some(date.with_timezone(&Utc));
+    }
+    let naive = ["%Y-%m-%dT%H:%M:%S", "%Y-%m-%d %H:%M:%S", "%Y-%m-%dT%H:%M"]
+        .iter()
+        .find_map(|format| NaiveDateTime::parse_from_str(value, format).ok())
+        .or_else(|| {
+            NaiveDate::parse_from_str(value, "%Y-%m-%d")
+                .ok()
+                .or_else(|| {
+                    value
+                        .parse::<i32>()
+                        .ok()
+                        .and_then(|year| NaiveDate::from_ymd_opt(year, 1, 1))
+                })
+                .and_then(|date| date.and_hms_opt(0, 0, 0))
+        })?;
+    Local
+        .from_local_datetime(&naive)
+        .earliest()
+        .map(|date| date.with_timezone(&Utc))
+}
+
+/// When the file was created, or else last modified. Creation times are unsupported on some
+/// filesystems.
+fn file_date(file: &File) -> Option<DateTime<Utc>> {
+    let metadata = file.metadata().ok()?;
+    metadata
+        .created()
+        .or_else(|_| metadata.modified())
+        .ok()
+        .map(DateTime::<Utc>::from)
+}
+
+/// Decodes one channel of an audio file, one packet at a time.
+pub struct ImportSource {
+    format: Box<dyn FormatReader>,
+    decoder: Box<dyn Decoder>,
+    track_id: u32,
+    channels: usize,
+    channel: usize,
+    sample_rate: u32,
+    /// Length of the track in seconds, if known.
+    duration: Option<f64>,
+    decoded_frames: u64,
+    sample_buf: Option<SampleBuffer<f32>>,
+    name: String,
+    date: DateTime<Utc>,
+    metadata: SourceMetadata,
+}
+
+impl ImportSource {
+    pub fn open(path: &Path, options: &ImportOptions) -> Result<ImportSource> {
+        // Create a media source. Note that the MediaSource trait is automatically implemented for File,
+        // among other types.
+        let file = File::open(path)?;
+        let file_date = file_date(&file);
+
+        // Create the media source stream using the boxed media source from above.
+        let mss = MediaSourceStream::new(Box::new(file), Default::default());
+
+        // Create a hint to help the format registry guess what format reader is appropriate.
+        let mut hint = Hint::new();
+        if let Some(extension) = path.extension().and_then(|ext| ext.to_str()) {
+            hint.with_extension(extension);
+        }
+
+        // Use the default options when reading and decoding.
+        let format_opts: FormatOptions = Default::default();
+        let metadata_opts: MetadataOptions = Default::default();
+        let decoder_opts: DecoderOptions = Default::default();
+
+        // Probe the media source stream for a format.
+        let mut probed =
+            symphonia::default::get_probe().format(&hint, mss, &format_opts, &metadata_opts)?;
+
+        // Tags in the container take precedence over tags found while probing (e.g. ID3).
+        let mut metadata = SourceMetadata::default();
+        if let Some(revision) = probed.format.metadata().current() {
+            metadata.read(revision);
+        }
+        if let Some(revision) = probed.metadata.get().as_ref().and_then(|m| m.current()) {
+            metadata.read(revision);
+        }
+
+        // Get the format reader yielded by the probe operation.
+        let format = probed.format;
+
+        let track = match options.track {
+            Some(index) => format.tracks().get(index).ok_or_else(|| {
+                eyre!(
+                    "No track {}, the file has {} tracks",
+                    index,
+                    format.tracks().len()
+                )
+            })?,
+            // Video containers often list the video track first.
+            None => format
+                .default_track()
+                .filter(|track| is_audio(track))
+                .or_else(|| format.tracks().iter().find(|track| is_audio(track)))
+                .ok_or_else(|| eyre!("No audio track"))?,
+        };
+
+        // Create a decoder for the track.
+        let decoder = codecs().make(&track.codec_params, &decoder_opts)?;
+
+        // Matroska only records a layout.
+        let channels = track
+            .codec_params
+            .channels
+            .or_else(|| track.codec_params.channel_layout.map(|l| l.into_channels()))
+            .ok_or_else(|| eyre!("Unknown channel count"))?
+            .count();
+        if options.channel >= channels {
+            return Err(eyre!(
+                "No channel {}, the track has {} channels",
+                options.channel,
+                channels
+            ));
+        }
+
+        let sample_rate = track
+            .codec_params
+            .sample_rate
+            .ok_or_else(|| eyre!("Unknown sample rate"))?;
+
+        let name = match &metadata.title {
+            Some(title) => title.clone(),
+            None => path
+                .file_stem()
+                .ok_or_else(|| eyre!("Invalid path: {:?}", path))?
+                .to_str()
+                .ok_or_else(|| eyre!("Path is not utf8"))?
+                .to_string(),
+        };
+
+        Ok(ImportSource {
+            track_id: track.id,
+            channels,
+            channel: options.channel,
+            sample_rate,
+            duration: track.codec_params.n_frames.map(|n_frames| {
+                match track.codec_params.time_base {
+                    Some(time_base) => {
+                        let time = time_base.calc_time(n_frames);
+                        time.seconds as f64 + time.frac
+                    }
+                    None => n_frames as f64 / sample_rate as f64,
+                }
+            }),
+            decoded_frames: 0,
+            sample_buf: None,
+            name,
+            date: metadata.date.or(file_date).unwrap_or_else(Utc::now),
+            metadata,
+            format,
+            decoder,
+        })
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    /// The title tag, or else the file name without its extension.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The date tag, or else when the file was created or modified.
+    pub fn date(&self) -> DateTime<Utc> {
+        self.date
+    }
+
+    pub fn metadata(&self) -> &SourceMetadata {
+        &self.metadata
+    }
+
+    pub fn progress(&self) -> ImportProgress {
+        ImportProgress {
+            decoded: self.decoded_frames as f64 / self.sample_rate as f64,
+            total: self.duration,
+        }
+    }
+
+    /// Decode the next packet, appending its samples to `output`. Returns false at the end of
+    /// the file.
+    pub fn read(&mut self, output: &mut Vec<f32>) -> Result<bool> {
+        loop {
+            // Get the next packet from the format reader.
+            let packet = match self.format.next_packet() {
+                Ok(packet_ok) => packet_ok,
+                Err(Error::IoError(ref packet_err))
+                    if packet_err.kind() == std::io::ErrorKind::UnexpectedEof =>
+                {
+                    return Ok(false);
+                }
+                Err(packet_err) => {
+                    return Err(packet_err.into());
+                }
+            };
+
+            // If the packet does not belong to the selected track, skip it.
+            if packet.track_id() != self.track_id {
+                continue;
+            }
+
+            // Decode the packet into audio samples, ignoring any decode errors.
+            match self.decoder.decode(&packet) {
+                Ok(audio_buf) => {
+                    // If this is the *first* decoded packet, create a sample buffer matching the
+                    // decoded audio buffer format. Note: the capacity is not the length!
+                    let sample_buf = self.sample_buf.get_or_insert_with(|| {
+                        SampleBuffer::<f32>::new(audio_buf.capacity() as u64, *audio_buf.spec())
+                    });
+
+                    // Copy the decoded audio buffer into the sample buffer in an interleaved format.
+                    sample_buf.copy_interleaved_ref(audio_buf);
+                    let samples = sample_buf.samples();
+                    output.extend(
+                        samples
+                            .iter()
+                            .skip(self.channel)
+                            .step_by(self.channels)
+                            .copied(),
+                    );
+                    self.decoded_frames += (samples.len() / self.channels) as u64;
+
+                    return Ok(true);
+                }
+                Err(Error::DecodeError(_)) => (),
+                Err(_) => return Ok(false),
+            }
+        }
+    }
+}
+
+/// A file decoded and encoded into a temporary file, ready for `Db::save_encoded`.
+pub struct EncodedImport {
+    /// The name to save the clip under if none is given.
+    pub name: String,
+    pub date: DateTime<Utc>,
+    pub notes: Option<String>,
+    pub sample_rate: u32,
+    pub num_samples: usize,
+    pub peaks: PeakPyramid,
+    pub profile: EncodingProfile,
+    /// The `encode_v2` or FLAC header for what is in `file`.
+    header: Vec<u8>,
+    path: PathBuf,
+    file: File,
+    len: u64,
+}
+
+impl EncodedImport {
+    /// The encoded clip, in the format for its profile, and its length in bytes.
+    pub(crate) fn reader(&mut self) -> Result<(impl Read + '_, u64)> {
+        self.file.seek(SeekFrom::Start(0))?;
+        let len = self.header.len() as u64 + self.len;
+        Ok((Cursor::new(&self.header).chain(&mut self.file), len))
+    }
+
+    /// Save the clip under the given name, or else its own, resolving a clash with an
+    /// existing clip according to `conflict`.
+    pub fn save(
+        mut self,
+        db: &Db,
+        name: Option<&str>,
+        conflict: ConflictPolicy,
+    ) -> Result<ImportOutcome> {
+        let mut name = name.unwrap_or(&self.name).to_string();
+        if db.name_exists(&name)? {
+            match conflict {
+                ConflictPolicy::Error => {
+                    return Err(eyre!("There is already a clip named {}", name));
+                }
+                ConflictPolicy::Skip => return Ok(ImportOutcome::Skipped(name)),
+                ConflictPolicy::Rename => name = unused_name(db, &name)?,
+                ConflictPolicy::Overwrite => {}
+            }
+        }
+
+        let replace = conflict == ConflictPolicy::Overwrite;
+        let id = db.save_encoded(&name, &mut self, replace)?;
+
+        Ok(ImportOutcome::Imported(ClipMeta {
+            id,
+            name,
+            date: self.date,
+            notes: self.notes.clone(),
+            duration: self.num_samples as f64 / self.sample_rate as f64,
+        }))
+    }
+}
+
+impl Drop for EncodedImport {
+    fn drop(&mut self) {
+        if let Err(err) = std::fs::remove_file(&self.path) {
+            log::warn!("Could not remove {:?}: {}", self.path, err);
+        }
+    }
+}
+
+/// Decode, resample and encode the file at `path` with bounded memory use.
+///
+/// `on_progress` is called after each decoded packet. The import stops with an error if
+/// `cancel` is set.
+pub fn encode_file(
+    path: &Path,
+    options: &ImportOptions,
+    cancel: &AtomicBool,
+    mut on_progress: impl FnMut(ImportProgress),
+) -> Result<EncodedImport> {
+    let mut source = ImportSource::open(path, options)?;
+
+    let temp_path = std::env::temp_dir().join(format!(
+        "oxygen-import-{}-{}.opus",
+        std::process::id(),
+        Utc::now().format("%Y%m%d%H%M%S%f")
+    ));
+    let file = File::options()
+        .read(true)
+        .write(true)
+        .create_new(true)
+        .open(&temp_path)?;
+    let mut encoded = EncodedImport {
+        name: source.name().to_string(),
+        date: source.date(),
+        notes: source.metadata().notes(),
+        sample_rate: 0,
+        num_samples: 0,
+        peaks: PeakPyramid::default(),
+        profile: options.profile,
+        header: Vec::new(),
+        path: temp_path,
+        file: file.try_clone()?,
+        len: 0,
+    };
+
+    let mut encoder =
+        StreamingEncoder::new(source.sample_rate(), options.profile, BufWriter::new(file))?
+            .with_peaks();
+    let mut samples = Vec::new();
+    while source.read(&mut samples)? {
+        if cancel.load(Ordering::Relaxed) {
+            return Err(eyre!("Import cancelled"));
+        }
+        encoder.write(&samples)?;
+        samples.clear();
+        on_progress(source.progress());
+    }
+
+    encoded.sample_rate = encoder.sample_rate();
+    let (_, header, peaks) = encoder.finish()?;
+    let num_samples = header.num_samples();
+    encoded.num_samples = num_samples.try_into()?;
+    encoded.header = header.to_bytes();
+    encoded.peaks = peaks.unwrap_or_default();
+    encoded.len = encoded.file.metadata()?.len();
+    log::info!(
+        "Encoded {} samples from {:?} into {} bytes",
+        num_samples,
+        path,
+        encoded.len
+    );
+
+    Ok(encoded)
+}
+
+/// The first of "Name (2)", "Name (3)", ... that is not taken.
+pub(crate) fn unused_name(db: &Db, name: &str) -> Result<String> {
+    for n in 2.. {
+        let candidate = format!("{} ({})", name, n);
+        if !db.name_exists(&candidate)? {
+            return Ok(candidate);
+        }
+    }
+    unreachable!()
+}
+
+/// Import the file at `path` into `db`, without decoding the whole file into memory. The clip
+/// is named `name`, or else after the file's title tag or file name.
+pub fn import_file(
+    db: &Db,
+    name: Option<&str>,
+    path: &Path,
+    options: &ImportOptions,
+    cancel: &AtomicBool,
+    on_progress: impl FnMut(ImportProgress),
+) -> Result<ImportOutcome> {
+    if let Some(skipped) = skip_existing(db, name, path, options)? {
+        return Ok(skipped);
+    }
+
+    encode_file(path, options, cancel, on_progress)?.save(db, name, options.conflict)
+}
+
+/// With `ConflictPolicy::Skip`, whether a file would be skipped because its name is taken,
+/// worked out before decoding it, which is the slow part.
+pub fn skip_existing(
+    db: &Db,
+    name: Option<&str>,
+    path: &Path,
+    options: &ImportOptions,
+) -> Result<Option<ImportOutcome>> {
+    if options.conflict != ConflictPolicy::Skip {
+        return Ok(None);
+    }
+    let name = match name {
+        Some(name) => name.to_string(),
+        None => ImportSource::open(path, options)?.name().to_string(),
+    };
+    if db.name_exists(&name)? {
+        return Ok(Some(ImportOutcome::Skipped(name)));
+    }
+
+    Ok(None)
+}
+
+/// The files under `dir`, recursively, whose path relative to `dir` matches one of `include`
+/// and none of `exclude`. With no `include` patterns, files with one of the
+/// `IMPORT_EXTENSIONS` are included. Sorted by path.
+pub fn find_files(dir: &Path, include: &[Pattern], exclude: &[Pattern]) -> Result<Vec<PathBuf>> {
+    let mut paths = Vec::new();
+    for entry in WalkDir::new(dir).follow_links(true) {
+        let entry = entry?;
+        if !entry.file_type().is_file() {
+            continue;
+        }
+        let relative = entry.path().strip_prefix(dir)?;
+        let included = if include.is_empty() {
+            relative
+                .extension()
+                .and_then(|ext| ext.to_str())
+                .map(|ext| IMPORT_EXTENSIONS.contains(&ext.to_lowercase().as_str()))
+                .unwrap_or(false)
+        } else {
+            include.iter().any(|pattern| pattern.matches_path(relative))
+        };
+        if included && !exclude.iter().any(|pattern| pattern.matches_path(relative)) {
+            paths.push(entry.into_path());
+        }
+    }
+    paths.sort();
+
+    Ok(paths)
+}
+
+/// The result of importing one file of a batch.
+#[derive(Debug)]
+pub struct BatchImport {
+    pub path: PathBuf,
+    pub result: Result<ImportOutcome>,
+}
+
+/// Import each of `paths` in turn, carrying on past files that fail. Clips are named after
+/// their file's title tag or file name.
+///
+/// `on_progress` is called with the index of the file being imported. If `cancel` is set,
+/// the file being imported fails and the rest are left out of the results.
+pub fn import_files(
+    db: &Db,
+    paths: &[PathBuf],
+    options: &ImportOptions,
+    cancel: &AtomicBool,
+    mut on_progress: impl FnMut(usize, ImportProgress),
+) -> Vec<BatchImport> {
+    let mut results = Vec::with_capacity(paths.len());
+    for (i, path) in paths.iter().enumerate() {
+        let result = import_file(db, None, path, options, cancel, |progress| {
+            on_progress(i, progress)
+        });
+        results.push(BatchImport {
+            path: path.clone(),
+            result,
+        });
+        if cancel.load(Ordering::Relaxed) {
+            break;
+        }
+    }
+
+    results
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio_clip::AudioClip;
+    use crate::db::ListQuery;
+    use crate::history::Operation;
+    use crate::test_fixtures::*;
+
+    fn decode(path: &Path) -> (u32, Vec<f32>) {
+        let mut source = ImportSource::open(path, &ImportOptions::default()).unwrap();
+        let mut samples = Vec::new();
+        while source.read(&mut samples).unwrap() {}
+        std::fs::remove_file(path).unwrap();
+        (source.sample_rate(), samples)
+    }
+
+    /// The RMS of a sine with an amplitude of 0.5 is 0.354.
+    fn assert_sine(samples: &[f32], tolerance: f32) {
+        let rms = (samples.iter().map(|s| s * s).sum::<f32>() / samples.len() as f32).sqrt();
+        assert!((rms - 0.354).abs() < tolerance, "rms was {}", rms);
+    }
+
+    #[test]
+    fn test_import_file() {
+        let path = temp_path("import-test.wav");
+        let spec = hound::WavSpec {
+            channels: 2,
+            sample_rate: 44100,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        };
+        let mut writer = hound::WavWriter::create(&path, spec).unwrap();
+        for i in 0..44100 {
+            let sample = (((i as f32) * 0.05).sin() * 16000.0) as i16;
+            writer.write_sample(sample).unwrap();
+            writer.write_sample(0i16).unwrap();
+        }
+        writer.finalize().unwrap();
+
+        let db = Db::in_memory().unwrap();
+        let mut progress = Vec::new();
+        let meta = import_file(
+            &db,
+            Some("Imported"),
+            &path,
+            &ImportOptions::default(),
+            &AtomicBool::new(false),
+            |p| progress.push(p),
+        )
+        .unwrap();
+        let ImportOutcome::Imported(meta) = meta else {
+            panic!("Expected an import, got {:?}", meta);
+        };
+        assert_eq!(progress.last().unwrap().fraction(), Some(1.0));
+
+        let clip = db.load_by_id(meta.id).unwrap().unwrap();
+        assert_eq!(clip.name, "Imported");
+        assert_eq!(clip.sample_rate, 48000);
+        assert!(clip.samples.len() >= 48000);
+        assert_eq!(clip.cache.peaks.lock().unwrap().num_samples(), 48000);
+
+        let options = ImportOptions::default();
+        assert!(encode_file(&path, &options, &AtomicBool::new(true), |_| {}).is_err());
+
+        // The second channel is silent.
+        let options = ImportOptions {
+            channel: 1,
+            ..Default::default()
+        };
+        let mut source = ImportSource::open(&path, &options).unwrap();
+        assert_eq!(
+            source.name(),
+            format!("oxygen-fixture-{}-import-test", std::process::id())
+        );
+        let mut samples = Vec::new();
+        while source.read(&mut samples).unwrap() {}
+        assert_eq!(samples.len(), 44100);
+        assert!(samples.iter().all(|s| *s == 0.0));
+
+        let options = ImportOptions {
+            channel: 2,
+            ..Default::default()
+        };
+        assert!(ImportSource::open(&path, &options).is_err());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_parse_tag_date() {
+        assert_eq!(
+            parse_tag_date("2021-03-04T05:06:07Z"),
+            Some(Utc.with_ymd_and_hms(2021, 3, 4, 5, 6, 7).unwrap())
+        );
+        let local = |y, m, d| {
+            Local
+                .with_ymd_and_hms(y, m, d, 0, 0, 0)
+                .unwrap()
+                .with_timezone(&Utc)
+        };
+        assert_eq!(parse_tag_date("2021-03-04"), Some(local(2021, 3, 4)));
+        assert_eq!(parse_tag_date("1999"), Some(local(1999, 1, 1)));
+        assert_eq!(parse_tag_date("last tuesday"), None);
+    }
+
+    #[test]
+    fn test_import_wav_variants() {
+        let sine = sine(44100, 22050);
+        for (bits_per_sample, sample_format) in [
+            (8, hound::SampleFormat::Int),
+            (24, hound::SampleFormat::Int),
+            (32, hound::SampleFormat::Float),
+        ] {
+            let path = temp_path(&format!("{}.wav", bits_per_sample));
+            let spec = hound::WavSpec {
+                channels: 1,
+                sample_rate: 44100,
+                bits_per_sample,
+                sample_format,
+            };
+            let mut writer = hound::WavWriter::create(&path, spec).unwrap();
+            for sample in &sine {
+                match sample_format {
+                    hound::SampleFormat::Float => writer.write_sample(*sample).unwrap(),
+                    hound::SampleFormat::Int => {
+                        let max = (1 << (bits_per_sample - 1)) - 1;
+                        writer
+                            .write_sample((sample * max as f32).round() as i32)
+                            .unwrap()
+                    }
+                }
+            }
+            writer.finalize().unwrap();
+
+            let (sample_rate, samples) = decode(&path);
+            assert_eq!(sample_rate, 44100);
+            assert_eq!(samples.len(), sine.len());
+            assert_sine(&samples, 0.01);
+        }
+    }
+
+    #[test]
+    fn test_import_flac() {
+        let path = temp_path("test.flac");
+        let sine = sine(44100, 10000);
+        write_flac(&path, 44100, &sine);
+
+        let (sample_rate, samples) = decode(&path);
+        assert_eq!(sample_rate, 44100);
+        assert_eq!(samples.len(), sine.len());
+        for (sample, expected) in samples.iter().zip(&sine) {
+            assert!((sample - expected).abs() < 1e-4);
+        }
+    }
+
+    #[test]
+    fn test_import_ogg_opus() {
+        let path = temp_path("test.ogg");
+        write_ogg_opus(&path, &sine(48000, 9600), "Sine");
+
+        let source = ImportSource::open(&path, &ImportOptions::default()).unwrap();
+        assert_eq!(source.name(), "Sine");
+        let (sample_rate, samples) = decode(&path);
+        assert_eq!(sample_rate, 48000);
+        assert_eq!(samples.len(), 9600 - OPUS_PRE_SKIP as usize);
+        assert_sine(&samples, 0.05);
+    }
+
+    #[test]
+    fn test_import_ogg_vorbis() {
+        let path = temp_path("test-vorbis.ogg");
+        let sine = sine(44100, 12800);
+        write_ogg_vorbis(&path, 44100, &sine, "Sine");
+
+        let source = ImportSource::open(&path, &ImportOptions::default()).unwrap();
+        assert_eq!(source.name(), "Sine");
+        let (sample_rate, samples) = decode(&path);
+        assert_eq!(sample_rate, 44100);
+        assert_eq!(samples.len(), sine.len());
+        let error = samples
+            .iter()
+            .zip(&sine)
+            .map(|(a, b)| (a - b).abs())
+            .fold(0.0, f32::max);
+        assert!(error < 0.001, "error was {}", error);
+    }
+
+    #[test]
+    fn test_import_webm_video() {
+        let path = temp_path("test.webm");
+        let (head, packets) = opus_packets(&sine(48000, 9600));
+        let audio = MkvAudio {
+            codec_id: "A_OPUS",
+            codec_private: Some(head),
+            sample_rate: 48000.0,
+            bit_depth: None,
+            blocks: packets
+                .into_iter()
+                .enumerate()
+                .map(|(i, packet)| (i as i16 * 20, packet))
+                .collect(),
+        };
+        write_mkv(&path, "webm", &audio, true);
+
+        let (sample_rate, samples) = decode(&path);
+        assert_eq!(sample_rate, 48000);
+        assert_eq!(samples.len(), 9600 - OPUS_PRE_SKIP as usize);
+        assert_sine(&samples, 0.05);
+    }
+
+    #[test]
+    fn test_import_mkv_flac() {
+        let path = temp_path("test.mkv");
+        let sine = sine(44100, 10000);
+        let (header, frames) = flac_stream(44100, &sine);
+        let audio = MkvAudio {
+            codec_id: "A_FLAC",
+            codec_private: Some(header),
+            sample_rate: 44100.0,
+            bit_depth: Some(16),
+            blocks: frames
+                .into_iter()
+                .enumerate()
+                .map(|(i, frame)| ((i * 4096 * 1000 / 44100) as i16, frame))
+                .collect(),
+        };
+        write_mkv(&path, "matroska", &audio, true);
+
+        let (sample_rate, samples) = decode(&path);
+        assert_eq!(sample_rate, 44100);
+        assert_eq!(samples.len(), sine.len());
+        assert_sine(&samples, 0.01);
+    }
+
+    #[test]
+    fn test_import_caf_alac() {
+        let path = temp_path("test.caf");
+        let sine = sine(44100, 10000);
+        write_caf_alac(&path, 44100, &sine);
+
+        let clip =
+            AudioClip::import("ALAC".to_string(), path.to_str().unwrap().to_string()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(clip.sample_rate, 44100);
+        assert_eq!(clip.samples.len(), sine.len());
+        for (sample, expected) in clip.samples.iter().zip(&sine) {
+            assert!((sample - expected).abs() < 1e-4);
+        }
+    }
+
+    #[test]
+    fn test_import_conflicts() {
+        let dir = temp_path("batch");
+        std::fs::create_dir_all(dir.join("nested")).unwrap();
+        for path in ["a.flac", "nested/b.flac", "nested/c.FLAC"] {
+            write_flac(&dir.join(path), 44100, &sine(44100, 4410));
+        }
+        std::fs::write(dir.join("notes.txt"), "not audio").unwrap();
+
+        let paths = find_files(&dir, &[], &[]).unwrap();
+        assert_eq!(
+            paths,
+            vec![
+                dir.join("a.flac"),
+                dir.join("nested/b.flac"),
+                dir.join("nested/c.FLAC")
+            ]
+        );
+        let include = [Pattern::new("nested/*").unwrap()];
+        let exclude = [Pattern::new("*.FLAC").unwrap()];
+        assert_eq!(
+            find_files(&dir, &include, &exclude).unwrap(),
+            vec![dir.join("nested/b.flac")]
+        );
+
+        let db = Db::in_memory().unwrap();
+        let import = |conflict| {
+            let options = ImportOptions {
+                conflict,
+                ..Default::default()
+            };
+            import_files(&db, &paths, &options, &AtomicBool::new(false), |_, _| {})
+                .into_iter()
+                .map(|batch| batch.result.unwrap())
+                .collect::<Vec<_>>()
+        };
+
+        let outcomes = import(ConflictPolicy::Error);
+        assert!(matches!(&outcomes[0], ImportOutcome::Imported(meta) if meta.name == "a"));
+
+        let outcomes = import(ConflictPolicy::Skip);
+        assert_eq!(outcomes[1], ImportOutcome::Skipped("b".to_string()));
+
+        let outcomes = import(ConflictPolicy::Rename);
+        assert_eq!(outcomes[2].name(), "c (2)");
+
+        let outcomes = import(ConflictPolicy::Overwrite);
+        assert_eq!(outcomes[0].name(), "a");
+        assert_eq!(db.list(&ListQuery::default()).unwrap().len(), 6);
+
+        // Overwritten clips go to the trash, and can be put back.
+        let trash = db.trash().unwrap();
+        assert_eq!(trash.len(), 3);
+        assert!(matches!(
+            db.undo().unwrap(),
+            Some(Operation::Delete { name, .. }) if name == "c"
+        ));
+        let a = trash.iter().find(|clip| clip.meta.name == "a").unwrap();
+        assert_eq!(db.restore_from_trash(a.meta.id).unwrap(), "a (3)");
+        assert_eq!(db.load("a (3)").unwrap().unwrap().id, Some(a.meta.id));
+        assert_eq!(db.trash().unwrap().len(), 1);
+        assert_eq!(db.list(&ListQuery::default()).unwrap().len(), 8);
+
+        let options = ImportOptions::default();
+        let results = import_files(&db, &paths, &options, &AtomicBool::new(false), |_, _| {});
+        assert!(results.iter().all(|batch| batch.result.is_err()));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,12 +1,16 @@
 use audiopus::{
     coder::{Decoder, Encoder},
     packet::Packet,
-    Application, Bitrate, Channels, Error as OpusError, ErrorCode as OpusErrorCode, MutSignals,
-    SampleRate,
+    Application, Bitrate, Channels, MutSignals, SampleRate,
 };
 use color_eyre::{eyre::eyre, Result};
+use std::io::Write;
+use std::ops::Range;
 
 use crate::audio_clip::AudioClip;
+use crate::flac::{self, FlacAudio, FlacEncoder, FlacHeader};
+use crate::peaks::PeakPyramid;
+use crate::resample::{Quality, StreamingResampler};
 
 pub fn decode_v0(bytes: &[u8]) -> Vec<f32> {
     let mut samples = Vec::with_capacity(bytes.len() / 4);
@@ -23,119 +27,972 @@ pub fn decode_v0(bytes: &[u8]) -> Vec<f32> {
 ///  - for each packet:
 ///    - 2 bytes, number of bytes in packet as a u16 in big endian
 ///    - the raw packet
+///
+/// Clips are stored in `encode_v2` format since schema version 6.
 pub fn encode_v1(clip: &AudioClip) -> Result<(u32, Vec<u8>)> {
-    let sample_rate: i32 = clip.sample_rate.try_into()?;
-    let resampled: AudioClip;
-    let (samples, sample_rate) = match SampleRate::try_from(sample_rate) {
-        Ok(sample_rate) => (&clip.samples, sample_rate),
-        Err(_) => {
-            resampled = clip.resample(48000);
-            (&resampled.samples, SampleRate::Hz48000)
-        }
-    };
-    let mut encoder = Encoder::new(sample_rate, Channels::Mono, Application::Audio)?;
-    encoder.set_bitrate(Bitrate::BitsPerSecond(24000))?;
-
-    let frame_size = (sample_rate as i32 / 1000 * 20) as usize;
-
-    let mut output = vec![0u8; samples.len().max(128)];
-    let mut samples_i = 0;
-    let mut output_i = 0;
-    let mut end_buffer = vec![0f32; frame_size];
-
-    // Store number of samples.
-    {
-        let samples: u32 = samples.len().try_into()?;
-        let bytes = samples.to_be_bytes();
-        output[..4].clone_from_slice(&bytes[..4]);
-        output_i += 4;
-    }
-
-    while samples_i < samples.len() {
-        match encoder.encode_float(
-            if samples_i + frame_size < samples.len() {
-                &samples[samples_i..(samples_i + frame_size)]
-            } else {
-                end_buffer[..(samples.len() - samples_i)].clone_from_slice(
-                    &samples[samples_i..((samples.len() - samples_i) + samples_i)],
-                );
+    let mut encoder = StreamingEncoder::new(clip.sample_rate, EncodingProfile::Voice, Vec::new())?;
+    encoder.write(&clip.samples)?;
+    let sample_rate = encoder.sample_rate();
+    let (packets, header, _) = encoder.finish()?;
 
-                &end_buffer
-            },
-            &mut output[output_i + 2..],
-        ) {
-            Ok(pkt_len) => {
-                samples_i += frame_size;
-                let bytes = u16::try_from(pkt_len)?.to_be_bytes();
-                output[output_i] = bytes[0];
-                output[output_i + 1] = bytes[1];
-                output_i += pkt_len + 2;
+    let mut output = Vec::with_capacity(4 + packets.len());
+    output.extend_from_slice(&u32::try_from(header.num_samples())?.to_be_bytes());
+    output.extend_from_slice(&packets);
+
+    Ok((sample_rate, output))
+}
+
+/// Identifies `encode_v2` data.
+const V2_MAGIC: &[u8; 4] = b"OXOP";
+
+const V2_VERSION: u8 = 2;
+
+/// Length of the fixed part of the `encode_v2` header, including its CRC.
+const V2_HEADER_LEN: usize = 36;
+
+const V2_INDEX_ENTRY_LEN: usize = 16;
+
+/// Packets in each chunk of `encode_v2` data, one second at 20ms per packet.
+const PACKETS_PER_CHUNK: u16 = 50;
+
+/// Encode a clip into a versioned opus container, which can be seeked and partially
+/// recovered if damaged.
+///
+/// Format is:
+///  - 4 bytes, "OXOP"
+///  - 1 byte, version (2)
+///  - 4 bytes, sample rate as a u32 in big endian
+///  - 1 byte, number of channels
+///  - 4 bytes, bitrate the packets were encoded at in bits per second, as a u32 in big endian
+///  - 4 bytes, duration of each packet in microseconds, as a u32 in big endian
+///  - 8 bytes, number of samples as a u64 in big endian
+///  - 2 bytes, number of packets in each chunk as a u16 in big endian
+///  - 4 bytes, number of chunks as a u32 in big endian
+///  - 4 bytes, CRC-32 of the header so far
+///  - for each chunk, the seek index:
+///    - 8 bytes, offset of the chunk from the first packet as a u64 in big endian
+///    - 4 bytes, number of bytes in the chunk as a u32 in big endian
+///    - 4 bytes, CRC-32 of the chunk
+///  - 4 bytes, CRC-32 of the seek index
+///  - for each packet, as in `encode_v1`:
+///    - 2 bytes, number of bytes in packet as a u16 in big endian
+///    - the raw packet
+///
+/// Every chunk but the last has the same number of packets. The last packet is padded with
+/// silence.
+pub fn encode_v2(clip: &AudioClip) -> Result<(u32, Vec<u8>)> {
+    encode(clip, EncodingProfile::Voice)
+}
+
+/// Encode a clip with `profile`, in `encode_v2` format for the Opus profiles or as a FLAC
+/// stream for the lossless one. Returns the encoded sample rate and the encoded bytes.
+pub fn encode(clip: &AudioClip, profile: EncodingProfile) -> Result<(u32, Vec<u8>)> {
+    let mut encoder = StreamingEncoder::new(clip.sample_rate, profile, Vec::new())?;
+    encoder.write(&clip.samples)?;
+    let sample_rate = encoder.sample_rate();
+    let (frames, header, _) = encoder.finish()?;
+
+    let mut output = header.to_bytes();
+    output.extend_from_slice(&frames);
+
+    Ok((sample_rate, output))
+}
+
+/// Convert `encode_v1` data to `encode_v2`, without decoding it.
+pub fn v1_to_v2(sample_rate: u32, bytes: &[u8]) -> Result<Vec<u8>> {
+    if bytes.len() < 4 {
+        return Err(eyre!("Invalid number of bytes in encoded data"));
+    }
+    let num_samples = u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+    let header = V2Header::from_packets(sample_rate, num_samples.into(), &bytes[4..])?;
+
+    let mut output = header.to_bytes();
+    output.extend_from_slice(&bytes[4..]);
+
+    Ok(output)
+}
+
+/// The header and seek index of `encode_v2` data.
+#[derive(Clone, Debug)]
+pub struct V2Header {
+    pub sample_rate: u32,
+    pub bitrate: u32,
+    pub frame_duration_us: u32,
+    pub num_samples: u64,
+    packets_per_chunk: u16,
+    chunks: Vec<Chunk>,
+    /// The number of packets in the last chunk, while packets are being added.
+    packets_in_last_chunk: u16,
+    crc: crc32fast::Hasher,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Chunk {
+    offset: u64,
+    len: u32,
+    crc: u32,
+}
+
+impl V2Header {
+    fn new(sample_rate: SampleRate, bitrate: u32) -> V2Header {
+        V2Header {
+            sample_rate: sample_rate as i32 as u32,
+            bitrate,
+            frame_duration_us: 20_000,
+            num_samples: 0,
+            packets_per_chunk: PACKETS_PER_CHUNK,
+            chunks: Vec::new(),
+            packets_in_last_chunk: 0,
+            crc: crc32fast::Hasher::new(),
+        }
+    }
+
+    /// Index packets in the format of `encode_v1`, which were encoded at `BITRATE`.
+    pub fn from_packets(sample_rate: u32, num_samples: u64, packets: &[u8]) -> Result<V2Header> {
+        let sample_rate = opus_sample_rate(sample_rate)
+            .ok_or_else(|| eyre!("Invalid sample rate {}", sample_rate))?;
+        let mut header = V2Header::new(sample_rate, BITRATE);
+        header.num_samples = num_samples;
+        for packet in scan_packets(packets, 0) {
+            let packet = packet.ok_or_else(|| eyre!("Invalid encoding"))?;
+            header.add_packet(&packets[packet.start - 2..packet.end]);
+        }
+        header.finish_chunk();
+
+        Ok(header)
+    }
+
+    /// Add a packet, with its length, to the index.
+    fn add_packet(&mut self, packet: &[u8]) {
+        if self.chunks.is_empty() || self.packets_in_last_chunk == self.packets_per_chunk {
+            self.finish_chunk();
+            let offset = self
+                .chunks
+                .last()
+                .map(|chunk| chunk.offset + chunk.len as u64)
+                .unwrap_or(0);
+            self.chunks.push(Chunk {
+                offset,
+                len: 0,
+                crc: 0,
+            });
+            self.packets_in_last_chunk = 0;
+        }
+        let chunk = self.chunks.last_mut().expect("a chunk was added");
+        chunk.len += packet.len() as u32;
+        self.crc.update(packet);
+        self.packets_in_last_chunk += 1;
+    }
+
+    fn finish_chunk(&mut self) {
+        if let Some(chunk) = self.chunks.last_mut() {
+            chunk.crc = std::mem::take(&mut self.crc).finalize();
+        }
+    }
+
+    /// The header and seek index, to be followed by the packets.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes =
+            Vec::with_capacity(V2_HEADER_LEN + self.chunks.len() * V2_INDEX_ENTRY_LEN + 4);
+        bytes.extend_from_slice(V2_MAGIC);
+        bytes.push(V2_VERSION);
+        bytes.extend_from_slice(&self.sample_rate.to_be_bytes());
+        bytes.push(1);
+        bytes.extend_from_slice(&self.bitrate.to_be_bytes());
+        bytes.extend_from_slice(&self.frame_duration_us.to_be_bytes());
+        bytes.extend_from_slice(&self.num_samples.to_be_bytes());
+        bytes.extend_from_slice(&self.packets_per_chunk.to_be_bytes());
+        bytes.extend_from_slice(&(self.chunks.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(&crc32fast::hash(&bytes).to_be_bytes());
+
+        let index_start = bytes.len();
+        for chunk in &self.chunks {
+            bytes.extend_from_slice(&chunk.offset.to_be_bytes());
+            bytes.extend_from_slice(&chunk.len.to_be_bytes());
+            bytes.extend_from_slice(&chunk.crc.to_be_bytes());
+        }
+        let index_crc = crc32fast::hash(&bytes[index_start..]);
+        bytes.extend_from_slice(&index_crc.to_be_bytes());
+
+        bytes
+    }
+
+    /// Read the header, and the seek index if it is intact. Returns them and where the
+    /// packets start.
+    fn read(bytes: &[u8]) -> Result<(V2Header, Option<Vec<Chunk>>, usize)> {
+        if bytes.len() < V2_HEADER_LEN || &bytes[..4] != V2_MAGIC {
+            return Err(eyre!("Not an encoded clip"));
+        }
+        if bytes[4] != V2_VERSION {
+            return Err(eyre!("Unsupported encoding version {}", bytes[4]));
+        }
+        let crc = u32::from_be_bytes(bytes[32..36].try_into()?);
+        if crc32fast::hash(&bytes[..32]) != crc {
+            return Err(eyre!("The header of the encoded clip is damaged"));
+        }
+        if bytes[9] != 1 {
+            return Err(eyre!("Unsupported number of channels {}", bytes[9]));
+        }
+
+        let num_chunks = u32::from_be_bytes(bytes[28..32].try_into()?) as usize;
+        let header = V2Header {
+            sample_rate: u32::from_be_bytes(bytes[5..9].try_into()?),
+            bitrate: u32::from_be_bytes(bytes[10..14].try_into()?),
+            frame_duration_us: u32::from_be_bytes(bytes[14..18].try_into()?),
+            num_samples: u64::from_be_bytes(bytes[18..26].try_into()?),
+            packets_per_chunk: u16::from_be_bytes(bytes[26..28].try_into()?),
+            chunks: Vec::new(),
+            packets_in_last_chunk: 0,
+            crc: crc32fast::Hasher::new(),
+        };
+        if header.packets_per_chunk == 0 {
+            return Err(eyre!("The header of the encoded clip is damaged"));
+        }
+
+        let index_end = V2_HEADER_LEN + num_chunks * V2_INDEX_ENTRY_LEN;
+        let index = bytes.get(V2_HEADER_LEN..index_end + 4).and_then(|index| {
+            let (entries, crc) = index.split_at(index.len() - 4);
+            if crc32fast::hash(entries).to_be_bytes() != crc {
+                return None;
             }
-            Err(OpusError::Opus(OpusErrorCode::BufferTooSmall)) => {
-                log::error!(
-                    "Needed to increase buffer size, opus is compressing less well than expected."
-                );
-                output.resize(output.len() * 2, 0u8);
+            Some(
+                entries
+                    .chunks_exact(V2_INDEX_ENTRY_LEN)
+                    .map(|entry| Chunk {
+                        offset: u64::from_be_bytes(entry[..8].try_into().unwrap()),
+                        len: u32::from_be_bytes(entry[8..12].try_into().unwrap()),
+                        crc: u32::from_be_bytes(entry[12..16].try_into().unwrap()),
+                    })
+                    .collect(),
+            )
+        });
+
+        Ok((header, index, index_end + 4))
+    }
+}
+
+/// Encodes samples into the packets of `encode_v1` as they arrive, without the leading sample
+/// count, so that long recordings and imports never need to be fully in memory. With the
+/// lossless profile, samples are encoded into FLAC frames instead.
+pub struct StreamingEncoder<W: Write> {
+    writer: W,
+    codec: Codec,
+    sample_rate: u32,
+    resampler: Option<StreamingResampler>,
+    pending: Vec<f32>,
+    num_samples: usize,
+    peaks: Option<PeakPyramid>,
+}
+
+enum Codec {
+    Opus {
+        encoder: Encoder,
+        sample_rate: SampleRate,
+        packet: Vec<u8>,
+        header: V2Header,
+    },
+    Flac {
+        encoder: FlacEncoder,
+        buffer: Vec<u8>,
+    },
+}
+
+impl Codec {
+    /// Number of samples encoded at a time.
+    fn frame_size(&self) -> usize {
+        match self {
+            Codec::Opus { sample_rate, .. } => opus_frame_size(*sample_rate),
+            Codec::Flac { .. } => flac::BLOCK_SIZE,
+        }
+    }
+
+    fn encode(&mut self, frame: &[f32], writer: &mut impl Write) -> Result<()> {
+        match self {
+            Codec::Opus {
+                encoder,
+                packet,
+                header,
+                ..
+            } => {
+                let len = encoder.encode_float(frame, &mut packet[2..])?;
+                packet[..2].copy_from_slice(&u16::try_from(len)?.to_be_bytes());
+                writer.write_all(&packet[..2 + len])?;
+                header.add_packet(&packet[..2 + len]);
             }
-            Err(e) => {
-                return Err(eyre!(e));
+            Codec::Flac { encoder, buffer } => {
+                buffer.clear();
+                encoder.encode_block(frame, buffer);
+                writer.write_all(buffer)?;
             }
         }
+
+        Ok(())
+    }
+}
+
+impl<W: Write> StreamingEncoder<W> {
+    /// For the Opus profiles, samples are resampled to 48 kHz unless `sample_rate` is
+    /// supported by Opus. Lossless samples are kept at their own rate.
+    pub fn new(
+        sample_rate: u32,
+        profile: EncodingProfile,
+        writer: W,
+    ) -> Result<StreamingEncoder<W>> {
+        let (codec, resampler) = match profile.bitrate() {
+            Some(bitrate) => {
+                let (encoded_rate, resampler) = match opus_sample_rate(sample_rate) {
+                    Some(encoded_rate) => (encoded_rate, None),
+                    None => (
+                        SampleRate::Hz48000,
+                        Some(StreamingResampler::new(
+                            sample_rate,
+                            48000,
+                            Quality::default(),
+                        )),
+                    ),
+                };
+                let codec = Codec::Opus {
+                    encoder: opus_encoder(encoded_rate, bitrate)?,
+                    sample_rate: encoded_rate,
+                    // The largest possible Opus packet is 1275 bytes.
+                    packet: vec![0u8; 4000],
+                    header: V2Header::new(encoded_rate, bitrate),
+                };
+                (codec, resampler)
+            }
+            None => {
+                let codec = Codec::Flac {
+                    encoder: FlacEncoder::new(sample_rate)?,
+                    buffer: Vec::new(),
+                };
+                (codec, None)
+            }
+        };
+
+        Ok(StreamingEncoder {
+            writer,
+            sample_rate: match &codec {
+                Codec::Opus { sample_rate, .. } => *sample_rate as i32 as u32,
+                Codec::Flac { .. } => sample_rate,
+            },
+            codec,
+            resampler,
+            pending: Vec::new(),
+            num_samples: 0,
+            peaks: None,
+        })
+    }
+
+    /// Also summarize the encoded samples for drawing.
+    pub fn with_peaks(mut self) -> StreamingEncoder<W> {
+        self.peaks = Some(PeakPyramid::default());
+        self
+    }
+
+    /// The sample rate of the encoded samples.
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.writer
+    }
+
+    pub fn write(&mut self, samples: &[f32]) -> Result<()> {
+        let start = self.pending.len();
+        match &mut self.resampler {
+            Some(resampler) => resampler.process(samples, &mut self.pending),
+            None => self.pending.extend_from_slice(samples),
+        }
+        self.num_samples += self.pending.len() - start;
+        if let Some(peaks) = &mut self.peaks {
+            peaks.extend(&self.pending[start..]);
+        }
+
+        self.write_frames(false)
+    }
+
+    /// Encode every whole frame of pending samples, and with `last` the rest too.
+    fn write_frames(&mut self, last: bool) -> Result<()> {
+        let frame_size = self.codec.frame_size();
+        let mut frames = self.pending.chunks_exact(frame_size);
+        for frame in &mut frames {
+            self.codec.encode(frame, &mut self.writer)?;
+        }
+        let remainder = frames.remainder();
+        if last && !remainder.is_empty() {
+            match self.codec {
+                // Opus frames have a fixed size, so the last is padded with silence.
+                Codec::Opus { .. } => {
+                    let mut frame = remainder.to_vec();
+                    frame.resize(frame_size, 0.0);
+                    self.codec.encode(&frame, &mut self.writer)?;
+                }
+                Codec::Flac { .. } => self.codec.encode(remainder, &mut self.writer)?,
+            }
+            self.pending.clear();
+        } else {
+            let remainder = remainder.len();
+            self.pending.drain(..self.pending.len() - remainder);
+        }
+
+        Ok(())
+    }
+
+    /// Encode the remaining samples. Returns the writer, the header for what was written to
+    /// it (with the number of samples encoded, at the encoded sample rate), and the peaks if
+    /// requested.
+    pub fn finish(mut self) -> Result<(W, EncodedHeader, Option<PeakPyramid>)> {
+        if let Some(mut resampler) = self.resampler.take() {
+            let mut tail = Vec::new();
+            resampler.flush(&mut tail);
+            self.write(&tail)?;
+        }
+        self.write_frames(true)?;
+        self.writer.flush()?;
+
+        let header = match self.codec {
+            Codec::Opus { mut header, .. } => {
+                header.finish_chunk();
+                header.num_samples = self.num_samples as u64;
+                EncodedHeader::Opus(header)
+            }
+            Codec::Flac { encoder, .. } => EncodedHeader::Flac(encoder.header()),
+        };
+
+        Ok((self.writer, header, self.peaks))
+    }
+}
+
+/// The header that goes before what a `StreamingEncoder` wrote: the `encode_v2` header and
+/// seek index for Opus packets, or the FLAC header for FLAC frames.
+#[derive(Clone, Debug)]
+pub enum EncodedHeader {
+    Opus(V2Header),
+    Flac(FlacHeader),
+}
+
+impl EncodedHeader {
+    pub fn num_samples(&self) -> u64 {
+        match self {
+            EncodedHeader::Opus(header) => header.num_samples,
+            EncodedHeader::Flac(header) => header.num_samples,
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        match self {
+            EncodedHeader::Opus(header) => header.to_bytes(),
+            EncodedHeader::Flac(header) => header.to_bytes(),
+        }
+    }
+}
+
+/// The Opus sample rate matching `sample_rate`, if any. Other rates are encoded at 48 kHz.
+pub(crate) fn opus_sample_rate(sample_rate: u32) -> Option<SampleRate> {
+    SampleRate::try_from(i32::try_from(sample_rate).ok()?).ok()
+}
+
+/// Number of samples in each 20ms packet.
+pub(crate) fn opus_frame_size(sample_rate: SampleRate) -> usize {
+    (sample_rate as i32 / 1000 * 20) as usize
+}
+
+/// The bitrate clips were encoded at before there were profiles, in bits per second.
+const BITRATE: u32 = 24000;
+
+/// How a clip's audio is encoded, chosen when it is recorded or imported.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum EncodingProfile {
+    /// Opus at 24 kbit/s, which keeps speech clear in little space.
+    #[default]
+    Voice,
+    /// Opus at 64 kbit/s, for singing and instruments.
+    Music,
+    /// Opus at 128 kbit/s, for analysing harmonics.
+    HighFidelity,
+    /// FLAC, which keeps every sample as it was recorded.
+    Lossless,
+}
+
+impl EncodingProfile {
+    pub const ALL: [EncodingProfile; 4] = [
+        EncodingProfile::Voice,
+        EncodingProfile::Music,
+        EncodingProfile::HighFidelity,
+        EncodingProfile::Lossless,
+    ];
+
+    /// The name stored in the database.
+    pub fn name(&self) -> &'static str {
+        match self {
+            EncodingProfile::Voice => "voice",
+            EncodingProfile::Music => "music",
+            EncodingProfile::HighFidelity => "high-fidelity",
+            EncodingProfile::Lossless => "lossless",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Result<EncodingProfile> {
+        EncodingProfile::ALL
+            .into_iter()
+            .find(|profile| profile.name() == name)
+            .ok_or_else(|| eyre!("Unknown encoding profile {}", name))
+    }
+
+    /// The Opus bitrate in bits per second, or None for FLAC.
+    fn bitrate(&self) -> Option<u32> {
+        match self {
+            EncodingProfile::Voice => Some(BITRATE),
+            EncodingProfile::Music => Some(64000),
+            EncodingProfile::HighFidelity => Some(128000),
+            EncodingProfile::Lossless => None,
+        }
+    }
+
+    /// The format that clips with this profile are stored in: 2 for `encode_v2`, or 3 for
+    /// FLAC.
+    pub(crate) fn format(&self) -> u32 {
+        match self {
+            EncodingProfile::Lossless => 3,
+            _ => 2,
+        }
     }
+}
 
-    output.truncate(output_i);
+pub(crate) fn opus_encoder(sample_rate: SampleRate, bitrate: u32) -> Result<Encoder> {
+    let mut encoder = Encoder::new(sample_rate, Channels::Mono, Application::Audio)?;
+    encoder.set_bitrate(Bitrate::BitsPerSecond(bitrate as i32))?;
+    Ok(encoder)
+}
 
-    Ok((sample_rate as i32 as u32, output))
+/// The packets of data in the format of `encode_v1`, after the sample count, starting at
+/// `start`. Yields the range of each packet without its length, and then None if the data
+/// ends in the middle of a packet.
+fn scan_packets(bytes: &[u8], start: usize) -> impl Iterator<Item = Option<Range<usize>>> + '_ {
+    let mut i = start;
+    std::iter::from_fn(move || {
+        if i >= bytes.len() {
+            return None;
+        }
+        let packet = match (bytes.get(i), bytes.get(i + 1)) {
+            (Some(&a), Some(&b)) => {
+                let len: usize = u16::from_be_bytes([a, b]).into();
+                Some(i + 2..i + 2 + len).filter(|packet| packet.end <= bytes.len())
+            }
+            _ => None,
+        };
+        i = match &packet {
+            Some(packet) => packet.end,
+            None => bytes.len(),
+        };
+        Some(packet)
+    })
 }
 
 pub fn decode_v1(sample_rate: u32, bytes: &[u8]) -> Result<Vec<f32>> {
-    let sample_rate: i32 = sample_rate.try_into()?;
-    let sample_rate = SampleRate::try_from(sample_rate)?;
-    let mut decoder = Decoder::new(sample_rate, Channels::Mono)?;
+    EncodedAudio::parse_v1(sample_rate, bytes.to_vec())?.decode()
+}
 
-    let frame_size = (sample_rate as i32 / 1000 * 20) as usize;
+/// Packets decoded and discarded before a range, so that the decoder has converged when the
+/// range starts. Opus recommends at least 80ms, but tonal audio takes longer to converge.
+const PREROLL_PACKETS: usize = 10;
 
-    let mut bytes_i = 0;
-    if bytes.len() < 4 {
-        return Err(eyre!("Invalid number of bytes in encoded data"));
+/// Encoded audio, indexed so that a range of it can be decoded without decoding everything
+/// before it.
+pub struct EncodedAudio(Encoded);
+
+enum Encoded {
+    Opus(OpusAudio),
+    Flac(FlacAudio),
+}
+
+impl EncodedAudio {
+    /// Index the packets of `encode_v2` data, without decoding them. Chunks that fail their
+    /// checksum are decoded as lost packets, which Opus conceals.
+    pub fn parse(bytes: Vec<u8>) -> Result<EncodedAudio> {
+        Ok(EncodedAudio(Encoded::Opus(OpusAudio::parse(bytes)?)))
     }
-    let num_samples: usize =
-        u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]).try_into()?;
-    bytes_i += 4;
 
-    let mut samples = vec![0f32; num_samples + frame_size];
-    let mut samples_i = 0;
+    /// Index the packets of `encode_v1` data, without decoding them.
+    pub fn parse_v1(sample_rate: u32, bytes: Vec<u8>) -> Result<EncodedAudio> {
+        Ok(EncodedAudio(Encoded::Opus(OpusAudio::parse_v1(
+            sample_rate,
+            bytes,
+        )?)))
+    }
 
-    while bytes_i < bytes.len() {
-        let pkt_len: usize = match (bytes.get(bytes_i), bytes.get(bytes_i + 1)) {
-            (Some(&a), Some(&b)) => u16::from_be_bytes([a, b]).into(),
+    /// Read the header of a FLAC stream, without decoding it.
+    pub fn parse_flac(bytes: Vec<u8>) -> Result<EncodedAudio> {
+        Ok(EncodedAudio(Encoded::Flac(FlacAudio::parse(bytes)?)))
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        match &self.0 {
+            Encoded::Opus(audio) => audio.sample_rate(),
+            Encoded::Flac(audio) => audio.sample_rate(),
+        }
+    }
+
+    /// The number of samples that were encoded. Decoding Opus gives slightly more, since the
+    /// last packet is padded with silence.
+    pub fn num_samples(&self) -> usize {
+        match &self.0 {
+            Encoded::Opus(audio) => audio.num_samples(),
+            Encoded::Flac(audio) => audio.num_samples(),
+        }
+    }
+
+    /// The indices of chunks that failed their checksum, or could not be found. Chunks are a
+    /// second long. FLAC frames are only checked as they are decoded, so for FLAC these are
+    /// the seconds with damaged frames that have been decoded so far.
+    pub fn damaged_chunks(&self) -> Vec<usize> {
+        match &self.0 {
+            Encoded::Opus(audio) => audio.damaged_chunks().to_vec(),
+            Encoded::Flac(audio) => audio.damaged_seconds(),
+        }
+    }
+
+    /// Decode everything.
+    pub fn decode(&self) -> Result<Vec<f32>> {
+        match &self.0 {
+            Encoded::Opus(audio) => audio.decode(),
+            Encoded::Flac(audio) => audio.decode_range(0..audio.num_samples()),
+        }
+    }
+
+    /// Decode the samples in `range`, which is clamped to the decoded length. Only the
+    /// packets or frames covering the range, and for Opus a few before it, are decoded.
+    pub fn decode_range(&self, range: Range<usize>) -> Result<Vec<f32>> {
+        match &self.0 {
+            Encoded::Opus(audio) => audio.decode_range(range),
+            Encoded::Flac(audio) => audio.decode_range(range),
+        }
+    }
+}
+
+/// Opus packets, indexed so that a range of them can be decoded without decoding everything
+/// before it.
+struct OpusAudio {
+    sample_rate: SampleRate,
+    frame_size: usize,
+    num_samples: usize,
+    bytes: Vec<u8>,
+    /// The start and end of each packet in `bytes`, or None if it was damaged.
+    packets: Vec<Option<Range<usize>>>,
+    /// The chunks that were damaged, for `encode_v2` data.
+    damaged_chunks: Vec<usize>,
+}
+
+impl OpusAudio {
+    /// Index the packets of `encode_v2` data, without decoding them. Chunks that fail their
+    /// checksum are decoded as lost packets, which Opus conceals.
+    fn parse(bytes: Vec<u8>) -> Result<OpusAudio> {
+        let (header, index, packets_start) = V2Header::read(&bytes)?;
+        let sample_rate = opus_sample_rate(header.sample_rate)
+            .ok_or_else(|| eyre!("Invalid sample rate {}", header.sample_rate))?;
+        let frame_size =
+            (header.sample_rate as u64 * header.frame_duration_us as u64 / 1_000_000) as usize;
+        if frame_size == 0 {
+            return Err(eyre!("Invalid frame duration {}", header.frame_duration_us));
+        }
+        let num_samples = usize::try_from(header.num_samples)?;
+        let num_packets = num_samples.div_ceil(frame_size);
+        let packets_per_chunk = header.packets_per_chunk as usize;
+        let num_chunks = num_packets.div_ceil(packets_per_chunk);
+
+        let mut packets = Vec::with_capacity(num_packets);
+        let mut damaged_chunks = Vec::new();
+        match index {
+            Some(index) if index.len() == num_chunks => {
+                for (i, chunk) in index.iter().enumerate() {
+                    let expected = packets_per_chunk.min(num_packets - i * packets_per_chunk);
+                    let chunk_packets = (packets_start as u64)
+                        .checked_add(chunk.offset)
+                        .and_then(|start| {
+                            let start = usize::try_from(start).ok()?;
+                            let bytes = bytes.get(start..start.checked_add(chunk.len as usize)?)?;
+                            (crc32fast::hash(bytes) == chunk.crc).then_some(start)
+                        })
+                        .and_then(|start| {
+                            let end = start + chunk.len as usize;
+                            scan_packets(&bytes[..end], start)
+                                .collect::<Option<Vec<_>>>()
+                                .filter(|chunk_packets| chunk_packets.len() == expected)
+                        });
+                    match chunk_packets {
+                        Some(chunk_packets) => packets.extend(chunk_packets.into_iter().map(Some)),
+                        None => {
+                            packets.extend(std::iter::repeat_n(None, expected));
+                            damaged_chunks.push(i);
+                        }
+                    }
+                }
+            }
             _ => {
-                return Err(eyre!("Invalid encoding"));
+                // Without the index, the packets are read until they stop making sense.
+                log::warn!("The seek index of an encoded clip is damaged");
+                packets.extend(
+                    scan_packets(&bytes, packets_start)
+                        .map_while(|packet| packet)
+                        .take(num_packets)
+                        .map(Some),
+                );
+                if packets.len() < num_packets {
+                    damaged_chunks.extend(packets.len() / packets_per_chunk..num_chunks);
+                }
+                packets.resize(num_packets, None);
             }
-        };
-        bytes_i += 2;
-        if bytes_i + pkt_len > bytes.len() {
-            return Err(eyre!("Invalid encoding"));
         }
-        if samples_i + frame_size > samples.len() {
-            return Err(eyre!("Invalid encoding"));
+        if !damaged_chunks.is_empty() {
+            log::warn!(
+                "{} of {} chunks of an encoded clip are damaged",
+                damaged_chunks.len(),
+                num_chunks
+            );
         }
 
-        let actual_frame_size = decoder.decode_float(
-            Some(Packet::try_from(&bytes[bytes_i..bytes_i + pkt_len])?),
-            MutSignals::try_from(&mut samples[samples_i..samples_i + frame_size])?,
-            false,
-        )?;
+        Ok(OpusAudio {
+            sample_rate,
+            frame_size,
+            num_samples,
+            bytes,
+            packets,
+            damaged_chunks,
+        })
+    }
 
-        if actual_frame_size != frame_size {
-            return Err(eyre!("Invalid frame size"));
+    /// Index the packets of `encode_v1` data, without decoding them.
+    fn parse_v1(sample_rate: u32, bytes: Vec<u8>) -> Result<OpusAudio> {
+        let sample_rate = SampleRate::try_from(i32::try_from(sample_rate)?)?;
+        if bytes.len() < 4 {
+            return Err(eyre!("Invalid number of bytes in encoded data"));
         }
+        let num_samples: usize =
+            u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]).try_into()?;
+        let packets = scan_packets(&bytes, 4)
+            .map(|packet| packet.map(Some).ok_or_else(|| eyre!("Invalid encoding")))
+            .collect::<Result<_>>()?;
 
-        bytes_i += pkt_len;
-        samples_i += actual_frame_size;
+        Ok(OpusAudio {
+            sample_rate,
+            frame_size: opus_frame_size(sample_rate),
+            num_samples,
+            bytes,
+            packets,
+            damaged_chunks: Vec::new(),
+        })
     }
 
-    samples.truncate(samples_i);
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate as i32 as u32
+    }
+
+    /// The number of samples that were encoded. Decoding gives slightly more, since the last
+    /// packet is padded with silence.
+    fn num_samples(&self) -> usize {
+        self.num_samples
+    }
+
+    /// The indices of chunks that failed their checksum, or could not be found.
+    fn damaged_chunks(&self) -> &[usize] {
+        &self.damaged_chunks
+    }
+
+    /// Decode every packet.
+    fn decode(&self) -> Result<Vec<f32>> {
+        self.decode_range(0..self.packets.len() * self.frame_size)
+    }
+
+    /// Decode the samples in `range`, which is clamped to the decoded length. Only the
+    /// packets covering the range, and a few before it, are decoded.
+    fn decode_range(&self, range: Range<usize>) -> Result<Vec<f32>> {
+        let frame_size = self.frame_size;
+        let end = range.end.min(self.packets.len() * frame_size);
+        let start = range.start.min(end);
+
+        let first_packet = start / frame_size;
+        let preroll = first_packet.min(PREROLL_PACKETS);
+        let packets = first_packet - preroll..end.div_ceil(frame_size);
+
+        let mut decoder = Decoder::new(self.sample_rate, Channels::Mono)?;
+        let mut samples = vec![0f32; packets.len() * frame_size];
+        for (i, packet) in self.packets[packets.clone()].iter().enumerate() {
+            let output = &mut samples[i * frame_size..(i + 1) * frame_size];
+            let decoded = match packet {
+                Some(packet) => Packet::try_from(&self.bytes[packet.clone()])
+                    .and_then(|packet| {
+                        decoder.decode_float(
+                            Some(packet),
+                            MutSignals::try_from(&mut *output)?,
+                            false,
+                        )
+                    })
+                    .ok(),
+                None => None,
+            };
+            // Packets that are lost or can't be decoded are concealed.
+            let actual_frame_size = match decoded {
+                Some(actual_frame_size) => actual_frame_size,
+                None => decoder.decode_float(None, MutSignals::try_from(output)?, false)?,
+            };
+
+            if actual_frame_size != frame_size {
+                return Err(eyre!("Invalid frame size"));
+            }
+        }
+
+        let offset = packets.start * frame_size;
+        samples.truncate(end - offset);
+        samples.drain(..start - offset);
 
-    Ok(samples)
+        Ok(samples)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_fixtures::{clip, sine};
+
+    #[test]
+    fn test_decode_range() {
+        let clip = AudioClip {
+            samples: sine(16000, 16000),
+            sample_rate: 16000,
+            ..clip("Name")
+        };
+        let (_, bytes) = encode_v2(&clip).unwrap();
+        let encoded = EncodedAudio::parse(bytes).unwrap();
+        assert_eq!(encoded.num_samples(), 16000);
+
+        let all = encoded.decode().unwrap();
+        let range = encoded.decode_range(5000..7000).unwrap();
+        assert_eq!(range.len(), 2000);
+        let error = range
+            .iter()
+            .zip(&all[5000..7000])
+            .map(|(a, b)| (a - b).abs())
+            .fold(0.0, f32::max);
+        assert!(error < 0.05, "error {}", error);
+
+        assert_eq!(encoded.decode_range(15900..20000).unwrap().len(), 100);
+        assert!(encoded.decode_range(20000..30000).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_v2_damaged() {
+        let clip = AudioClip {
+            samples: sine(48000, 48000 * 3),
+            ..clip("Name")
+        };
+        let (_, v1) = encode_v1(&clip).unwrap();
+        let (_, bytes) = encode_v2(&clip).unwrap();
+        assert_eq!(v1_to_v2(48000, &v1).unwrap(), bytes);
+        let all = EncodedAudio::parse(bytes.clone())
+            .unwrap()
+            .decode()
+            .unwrap();
+
+        // Damage the second of three chunks.
+        let mut damaged = bytes.clone();
+        let packets_start = V2_HEADER_LEN + 3 * V2_INDEX_ENTRY_LEN + 4;
+        let middle = packets_start + (damaged.len() - packets_start) / 2;
+        damaged[middle] ^= 0xff;
+        let encoded = EncodedAudio::parse(damaged.clone()).unwrap();
+        assert_eq!(encoded.damaged_chunks(), vec![1]);
+        let decoded = encoded.decode().unwrap();
+        assert_eq!(decoded.len(), all.len());
+        assert_eq!(decoded[..48000], all[..48000]);
+
+        // Without the index, the packets before the damage are kept.
+        damaged[V2_HEADER_LEN] ^= 0xff;
+        let encoded = EncodedAudio::parse(damaged).unwrap();
+        assert_eq!(encoded.decode().unwrap().len(), all.len());
+
+        let mut bad_header = bytes;
+        bad_header[6] ^= 0xff;
+        assert!(EncodedAudio::parse(bad_header).is_err());
+    }
+
+    #[test]
+    fn test_v2_damaged_index() {
+        // 120 packets, so the last of three chunks is short.
+        let short_end = AudioClip {
+            samples: sine(48000, 960 * 120 - 100),
+            ..clip("Name")
+        };
+        let (_, bytes) = encode_v2(&short_end).unwrap();
+        let all = EncodedAudio::parse(bytes.clone())
+            .unwrap()
+            .decode()
+            .unwrap();
+
+        let mut damaged = bytes;
+        damaged[V2_HEADER_LEN] ^= 0xff;
+        let encoded = EncodedAudio::parse(damaged).unwrap();
+        assert!(encoded.damaged_chunks().is_empty());
+        assert_eq!(encoded.decode().unwrap(), all);
+
+        let empty = AudioClip {
+            samples: vec![],
+            ..clip("Empty")
+        };
+        let (_, mut damaged) = encode_v2(&empty).unwrap();
+        damaged[V2_HEADER_LEN] ^= 0xff;
+        let encoded = EncodedAudio::parse(damaged).unwrap();
+        assert!(encoded.damaged_chunks().is_empty());
+        assert!(encoded.decode().unwrap().is_empty());
+    }
+
+    /// The signal to noise ratio of `decoded` in dB, lined up with `original` since Opus
+    /// delays its output slightly.
+    fn snr(original: &[f32], decoded: &[f32]) -> f64 {
+        let compared = 1000..original.len() - 2000;
+        (0..1000)
+            .map(|delay| {
+                let (signal, noise) = compared.clone().fold((0.0, 0.0), |(signal, noise), i| {
+                    let error = (decoded[i + delay] - original[i]) as f64;
+                    (signal + (original[i] as f64).powi(2), noise + error.powi(2))
+                });
+                10.0 * (signal / noise.max(f64::MIN_POSITIVE)).log10()
+            })
+            .fold(f64::MIN, f64::max)
+    }
+
+    #[test]
+    fn test_profiles_snr() {
+        // A sung note, rich in harmonics.
+        let samples: Vec<f32> = (0..48000 * 2)
+            .map(|i| {
+                let t = i as f32 / 48000.0;
+                (1..=10)
+                    .map(|h| (std::f32::consts::TAU * 220.0 * h as f32 * t).sin() * 0.3 / h as f32)
+                    .sum()
+            })
+            .collect();
+        let clip = AudioClip {
+            samples: samples.clone(),
+            ..clip("Name")
+        };
+
+        let snrs: Vec<f64> = EncodingProfile::ALL
+            .into_iter()
+            .map(|profile| {
+                let (sample_rate, bytes) = encode(&clip, profile).unwrap();
+                assert_eq!(sample_rate, 48000);
+                let encoded = match profile {
+                    EncodingProfile::Lossless => EncodedAudio::parse_flac(bytes).unwrap(),
+                    _ => EncodedAudio::parse(bytes).unwrap(),
+                };
+                assert_eq!(encoded.num_samples(), samples.len());
+                snr(&samples, &encoded.decode().unwrap())
+            })
+            .collect();
+        assert!(snrs[0] > 15.0, "SNRs {:?}", snrs);
+        assert!(snrs[1] > 25.0, "SNRs {:?}", snrs);
+        assert!(snrs[2] > 32.0, "SNRs {:?}", snrs);
+        // 24 bit samples are accurate to about 140 dB.
+        assert!(snrs[3] > 120.0, "SNRs {:?}", snrs);
+    }
 }
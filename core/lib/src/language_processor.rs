@@ -1,8 +1,9 @@
 use crate::audio_clip::AudioClip;
 use color_eyre::eyre::{eyre, Result};
+use serde::Serialize;
 use std::sync::mpsc::{channel, Receiver, Sender};
 use std::sync::{Arc, Mutex};
-use whisper_rs::{FullParams, SamplingStrategy, WhisperContext, WhisperError};
+use whisper_rs::{FullParams, SamplingStrategy, WhisperContext, WhisperError, WhisperState};
 
 #[cfg(not(feature = "whisper_dummy"))]
 const GGML_BASE_EN_Q5: &[u8] = include_bytes!("./ggml-base.en-q5_0.bin");
@@ -11,7 +12,25 @@ pub struct LanguageProcessor {
     whisper_context: Option<WhisperContext>,
 }
 
-pub type Segment = ((f64, f64), String);
+/// A stretch of speech in a transcript. Times are in seconds from the start of the clip.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Segment {
+    pub start: f64,
+    pub end: f64,
+    pub text: String,
+    /// The words in the segment with their own times, or nothing if whisper could not time
+    /// them.
+    pub words: Vec<Word>,
+    /// Who is speaking, once labelled by `diarization::label_segments`.
+    pub speaker: Option<usize>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Word {
+    pub start: f64,
+    pub end: f64,
+    pub text: String,
+}
 
 impl LanguageProcessor {
     pub fn new() -> Result<LanguageProcessor> {
@@ -33,10 +52,9 @@ impl LanguageProcessor {
     /// Return a transcript of the audio using whisper.cpp
     #[cfg(not(feature = "whisper_dummy"))]
     pub fn transcribe(&mut self, clip: &AudioClip) -> Result<Vec<Segment>> {
-        let mut state = self
-            .whisper_context()?
-            .create_state()
-            .expect("failed to create state");
+        let context = self.whisper_context()?;
+        let eot = context.token_eot();
+        let mut state = context.create_state().expect("failed to create state");
 
         // create a params object
         // note that currently the only implemented strategy is Greedy, BeamSearch is a WIP
@@ -80,13 +98,17 @@ impl LanguageProcessor {
 
             match segment {
                 Ok(segment) => {
-                    segments.push((
-                        (
-                            (start_timestamp as f64) * 10f64 / 1000f64,
-                            (end_timestamp as f64) * 10f64 / 1000f64,
-                        ),
-                        segment,
-                    ));
+                    let words = segment_words(&state, i, eot).unwrap_or_else(|err| {
+                        log::warn!("Could not time the words of a segment: {:?}", err);
+                        vec![]
+                    });
+                    segments.push(Segment {
+                        start: centiseconds(start_timestamp),
+                        end: centiseconds(end_timestamp),
+                        text: segment,
+                        words,
+                        speaker: None,
+                    });
                 }
                 Err(WhisperError::InvalidUtf8 { .. }) => {
                     // Whisper does not always give valid unicode... max_len=1 seems to
@@ -106,6 +128,40 @@ impl LanguageProcessor {
     }
 }
 
+/// Whisper's timestamps are in hundredths of a second.
+#[cfg(not(feature = "whisper_dummy"))]
+fn centiseconds(timestamp: i64) -> f64 {
+    (timestamp as f64) / 100f64
+}
+
+/// Join the text tokens of a segment into words, using the token timestamps. A token
+/// starting with a space starts a new word. Special tokens, which come after the
+/// end-of-text token, are skipped.
+#[cfg(not(feature = "whisper_dummy"))]
+fn segment_words(state: &WhisperState, segment: i32, eot: i32) -> Result<Vec<Word>> {
+    let mut words: Vec<Word> = Vec::new();
+    for token in 0..state.full_n_tokens(segment)? {
+        if state.full_get_token_id(segment, token)? >= eot {
+            continue;
+        }
+        let text = state.full_get_token_text(segment, token)?;
+        let data = state.full_get_token_data(segment, token)?;
+        match words.last_mut() {
+            Some(word) if !text.starts_with(' ') => {
+                word.text.push_str(&text);
+                word.end = centiseconds(data.t1);
+            }
+            _ => words.push(Word {
+                start: centiseconds(data.t0),
+                end: centiseconds(data.t1),
+                text: text.trim_start().to_string(),
+            }),
+        }
+    }
+
+    Ok(words)
+}
+
 enum Event {
     Transcribe(AudioClip, Sender<Result<Vec<Segment>>>),
 }
@@ -1,8 +1,26 @@
 pub mod audio_clip;
+pub mod backup;
+pub mod collections;
 pub mod db;
+pub mod diarization;
+mod encryption;
+pub mod features;
+pub mod flac;
+pub mod fsck;
+pub mod history;
+pub mod import;
 pub mod internal_encoding;
 pub mod language_processor;
+pub mod opus_decoder;
+pub mod peaks;
+pub mod recovery;
+pub mod render;
+pub mod resample;
 pub mod spectrum;
+pub mod transcript;
+
+#[cfg(test)]
+mod test_fixtures;
 
 #[cfg(feature = "napi")]
 pub mod napi;
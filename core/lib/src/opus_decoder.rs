@@ -0,0 +1,128 @@
+use std::sync::Mutex;
+
+use audiopus::{
+    coder::{Decoder as RawDecoder, GenericCtl},
+    packet::Packet as RawPacket,
+    Channels, MutSignals, SampleRate,
+};
+use symphonia::core::audio::{
+    AsAudioBufferRef, AudioBuffer, AudioBufferRef, Layout, Signal, SignalSpec,
+};
+use symphonia::core::codecs::{
+    CodecDescriptor, CodecParameters, Decoder, DecoderOptions, FinalizeResult, CODEC_TYPE_OPUS,
+};
+use symphonia::core::errors::{decode_error, unsupported_error, Result};
+use symphonia::core::formats::Packet;
+use symphonia::core::support_codec;
+
+/// Longest Opus packet, 120ms at 48kHz.
+const MAX_FRAMES: usize = 5760;
+
+/// Decodes the Opus tracks of Ogg and Matroska/WebM files, which symphonia can demux but not
+/// decode.
+///
+/// Only mono and stereo streams (channel mapping family 0) are supported.
+pub struct OpusDecoder {
+    params: CodecParameters,
+    /// The raw decoder is not `Sync`, which symphonia requires.
+    decoder: Mutex<RawDecoder>,
+    channels: usize,
+    /// Samples still to be dropped from the start of the stream, from the "pre-skip" field of
+    /// the identification header.
+    pre_skip: usize,
+    pcm: Vec<f32>,
+    buf: AudioBuffer<f32>,
+}
+
+impl Decoder for OpusDecoder {
+    fn try_new(params: &CodecParameters, _options: &DecoderOptions) -> Result<OpusDecoder> {
+        // The identification header is "OpusHead", a version byte, the channel count, then the
+        // pre-skip as a little endian u16.
+        let head = params
+            .extra_data
+            .as_deref()
+            .filter(|head| head.len() >= 19 && head.starts_with(b"OpusHead"));
+        let channels = match head {
+            Some(head) => head[9] as usize,
+            None => params.channels.map(|c| c.count()).unwrap_or(1),
+        };
+        let pre_skip = head
+            .map(|head| u16::from_le_bytes([head[10], head[11]]) as usize)
+            .unwrap_or(0);
+
+        let (raw_channels, layout) = match channels {
+            1 => (Channels::Mono, Layout::Mono),
+            2 => (Channels::Stereo, Layout::Stereo),
+            _ => return unsupported_error("opus: more than 2 channels"),
+        };
+        let decoder = match RawDecoder::new(SampleRate::Hz48000, raw_channels) {
+            Ok(decoder) => decoder,
+            Err(_) => return unsupported_error("opus: could not create decoder"),
+        };
+
+        Ok(OpusDecoder {
+            params: params.clone(),
+            decoder: Mutex::new(decoder),
+            channels,
+            pre_skip,
+            pcm: vec![0.0; MAX_FRAMES * channels],
+            buf: AudioBuffer::new(
+                MAX_FRAMES as u64,
+                SignalSpec::new_with_layout(48000, layout),
+            ),
+        })
+    }
+
+    fn supported_codecs() -> &'static [CodecDescriptor] {
+        &[support_codec!(CODEC_TYPE_OPUS, "opus", "Opus")]
+    }
+
+    fn reset(&mut self) {
+        if let Err(err) = self.decoder.get_mut().unwrap().reset_state() {
+            log::warn!("Could not reset Opus decoder: {}", err);
+        }
+    }
+
+    fn codec_params(&self) -> &CodecParameters {
+        &self.params
+    }
+
+    fn decode(&mut self, packet: &Packet) -> Result<AudioBufferRef<'_>> {
+        self.buf.clear();
+
+        let decoded = RawPacket::try_from(packet.buf()).and_then(|raw_packet| {
+            self.decoder.get_mut().unwrap().decode_float(
+                Some(raw_packet),
+                MutSignals::try_from(&mut self.pcm[..])?,
+                false,
+            )
+        });
+        let frames = match decoded {
+            Ok(frames) => frames,
+            Err(_) => return decode_error("opus: invalid packet"),
+        };
+
+        let skip = self.pre_skip.min(frames);
+        self.pre_skip -= skip;
+        self.buf.render_reserved(Some(frames - skip));
+        for channel in 0..self.channels {
+            let samples = self.pcm[skip * self.channels..frames * self.channels]
+                .iter()
+                .skip(channel)
+                .step_by(self.channels);
+            for (output, sample) in self.buf.chan_mut(channel).iter_mut().zip(samples) {
+                *output = *sample;
+            }
+        }
+
+        Ok(self.buf.as_audio_buffer_ref())
+    }
+
+    fn finalize(&mut self) -> FinalizeResult {
+        Default::default()
+    }
+
+    fn last_decoded(&self) -> AudioBufferRef<'_> {
+        self.buf.as_audio_buffer_ref()
+    }
+}
@@ -0,0 +1,235 @@
+use crate::audio_clip::DisplayColumn;
+use color_eyre::eyre::{eyre, Result};
+
+/// Number of samples summarized by each entry of the finest level.
+const BLOCK_SIZE: usize = 256;
+
+const ENCODING_VERSION: u8 = 2;
+
+/// A multi-resolution min/max/RMS summary of a clip, used to draw waveforms without scanning every
+/// sample.
+///
+/// Level 0 has one entry per `BLOCK_SIZE` samples, and each following level has one entry per
+/// two entries of the level below. The last entry of each level may cover a partial block.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PeakPyramid {
+    num_samples: usize,
+    levels: Vec<Vec<DisplayColumn>>,
+}
+
+impl PeakPyramid {
+    pub fn from_samples(samples: &[f32]) -> PeakPyramid {
+        let mut pyramid = PeakPyramid::default();
+        pyramid.extend(samples);
+        pyramid
+    }
+
+    /// Number of samples summarized so far.
+    pub fn num_samples(&self) -> usize {
+        self.num_samples
+    }
+
+    /// Summarize samples appended to the clip.
+    pub fn extend(&mut self, samples: &[f32]) {
+        if samples.is_empty() {
+            return;
+        }
+
+        if self.levels.is_empty() {
+            self.levels.push(Vec::new());
+        }
+
+        // The last block may be partial, in which case it is topped up first.
+        let first_changed = self.num_samples / BLOCK_SIZE;
+        let mut offset = 0;
+        while offset < samples.len() {
+            let block = (self.num_samples + offset) / BLOCK_SIZE;
+            let block_end = (block + 1) * BLOCK_SIZE - self.num_samples;
+            let chunk = &samples[offset..block_end.min(samples.len())];
+            let peak = DisplayColumn::from_samples(chunk);
+            if block < self.levels[0].len() {
+                self.levels[0][block] = self.levels[0][block].merge(&peak);
+            } else {
+                self.levels[0].push(peak);
+            }
+            offset += chunk.len();
+        }
+        self.num_samples += samples.len();
+
+        self.rebuild_from(first_changed);
+    }
+
+    /// Summarize any samples of `samples`, the whole clip, that were appended since the
+    /// pyramid was last updated.
+    pub fn update(&mut self, samples: &[f32]) {
+        if self.num_samples < samples.len() {
+            self.extend(&samples[self.num_samples..]);
+        }
+    }
+
+    /// Recompute upper levels from level-0 entry `first_changed` onwards.
+    fn rebuild_from(&mut self, mut first_changed: usize) {
+        let mut level = 1;
+        while self.levels[level - 1].len() > 1 {
+            if self.levels.len() == level {
+                self.levels.push(Vec::new());
+            }
+            first_changed /= 2;
+
+            let below_len = self.levels[level - 1].len();
+            let len = below_len.div_ceil(2);
+            self.levels[level].truncate(first_changed);
+            for i in first_changed..len {
+                let below = &self.levels[level - 1];
+                let mut peak = below[i * 2];
+                if let Some(other) = below.get(i * 2 + 1) {
+                    peak = peak.merge(other);
+                }
+                self.levels[level].push(peak);
+            }
+
+            level += 1;
+        }
+    }
+
+    /// The min, max and RMS of `samples[start..end]`, which must be the samples this pyramid
+    /// summarizes.
+    pub fn peak(&self, samples: &[f32], start: usize, end: usize) -> DisplayColumn {
+        let end = end.min(self.num_samples).min(samples.len());
+        if start >= end {
+            return DisplayColumn::default();
+        }
+
+        let first_block = start.div_ceil(BLOCK_SIZE);
+        let end_block = end / BLOCK_SIZE;
+        if first_block >= end_block {
+            return DisplayColumn::from_samples(&samples[start..end]);
+        }
+
+        let mut peak = DisplayColumn::from_samples(&samples[start..first_block * BLOCK_SIZE])
+            .merge(&DisplayColumn::from_samples(
+                &samples[end_block * BLOCK_SIZE..end],
+            ));
+
+        // Cover the whole blocks with the coarsest aligned entries that fit.
+        let mut block = first_block;
+        while block < end_block {
+            let mut level = 0;
+            while level + 1 < self.levels.len()
+                && block & ((1 << (level + 1)) - 1) == 0
+                && block + (1 << (level + 1)) <= end_block
+            {
+                level += 1;
+            }
+            peak = peak.merge(&self.levels[level][block >> level]);
+            block += 1 << level;
+        }
+
+        peak
+    }
+
+    /// Encode the finest level. Coarser levels are rebuilt when decoding.
+    ///
+    /// Format is:
+    ///  - 1 byte, version
+    ///  - 8 bytes, number of samples as a u64 in big endian
+    ///  - for each block, the min, max and RMS as i16 in big endian, scaled so that 1.0 is 32767
+    pub fn encode(&self) -> Vec<u8> {
+        let level = self.levels.first().map(|l| &l[..]).unwrap_or(&[]);
+        let mut bytes = Vec::with_capacity(9 + level.len() * 6);
+        bytes.push(ENCODING_VERSION);
+        bytes.extend_from_slice(&(self.num_samples as u64).to_be_bytes());
+        for peak in level {
+            let min = (peak.min * 32767.0).floor().clamp(-32768.0, 32767.0) as i16;
+            let max = (peak.max * 32767.0).ceil().clamp(-32768.0, 32767.0) as i16;
+            let rms = (peak.rms() * 32767.0).round().clamp(0.0, 32767.0) as i16;
+            bytes.extend_from_slice(&min.to_be_bytes());
+            bytes.extend_from_slice(&max.to_be_bytes());
+            bytes.extend_from_slice(&rms.to_be_bytes());
+        }
+        bytes
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<PeakPyramid> {
+        if bytes.len() < 9 || bytes[0] != ENCODING_VERSION {
+            return Err(eyre!("Invalid peak encoding"));
+        }
+        let num_samples: usize = u64::from_be_bytes(bytes[1..9].try_into()?).try_into()?;
+        let entries = &bytes[9..];
+        if !entries.chunks_exact(6).remainder().is_empty()
+            || entries.len() / 6 != num_samples.div_ceil(BLOCK_SIZE)
+        {
+            return Err(eyre!("Invalid peak encoding"));
+        }
+
+        let level = entries
+            .chunks_exact(6)
+            .enumerate()
+            .map(|(i, entry)| {
+                let len = BLOCK_SIZE.min(num_samples - i * BLOCK_SIZE);
+                let rms = i16::from_be_bytes([entry[4], entry[5]]) as f32 / 32767.0;
+                DisplayColumn {
+                    min: i16::from_be_bytes([entry[0], entry[1]]) as f32 / 32767.0,
+                    max: i16::from_be_bytes([entry[2], entry[3]]) as f32 / 32767.0,
+                    energy: rms * rms * len as f32,
+                    len,
+                }
+            })
+            .collect();
+
+        let mut pyramid = PeakPyramid {
+            num_samples,
+            levels: vec![level],
+        };
+        pyramid.rebuild_from(0);
+        Ok(pyramid)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn samples(len: usize) -> Vec<f32> {
+        (0..len).map(|i| ((i as f32) * 0.013).sin() * 0.8).collect()
+    }
+
+    #[test]
+    fn test_peak_matches_scan() {
+        let samples = samples(100_000);
+        let pyramid = PeakPyramid::from_samples(&samples);
+        for (start, end) in [(0, 100_000), (17, 5000), (300, 301), (65_536, 99_999)] {
+            let scanned = DisplayColumn::from_samples(&samples[start..end]);
+            let peak = pyramid.peak(&samples, start, end);
+            assert_eq!(
+                (peak.min, peak.max, peak.len),
+                (scanned.min, scanned.max, scanned.len)
+            );
+            assert!((peak.rms() - scanned.rms()).abs() < 1e-4);
+        }
+    }
+
+    #[test]
+    fn test_extend_matches_from_samples() {
+        let samples = samples(10_000);
+        let mut pyramid = PeakPyramid::default();
+        for chunk in samples.chunks(777) {
+            pyramid.extend(chunk);
+        }
+        assert_eq!(
+            pyramid.encode(),
+            PeakPyramid::from_samples(&samples).encode()
+        );
+    }
+
+    #[test]
+    fn test_encode_roundtrip() {
+        let samples = samples(10_000);
+        let pyramid = PeakPyramid::from_samples(&samples);
+        let decoded = PeakPyramid::decode(&pyramid.encode()).unwrap();
+        assert_eq!(decoded.num_samples(), pyramid.num_samples());
+        let peak = decoded.peak(&samples, 0, 10_000);
+        assert!(peak.min <= -0.79 && peak.max >= 0.79);
+        assert!((peak.rms() - 0.8 / 2f32.sqrt()).abs() < 0.01);
+    }
+}
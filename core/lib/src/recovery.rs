@@ -0,0 +1,433 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use audiopus::SampleRate;
+use chrono::prelude::*;
+use color_eyre::eyre::{eyre, Result};
+
+use crate::encryption::Cipher;
+use crate::internal_encoding::{
+    opus_frame_size, opus_sample_rate, EncodingProfile, StreamingEncoder, V2Header,
+};
+
+const MAGIC: &[u8; 4] = b"OXYJ";
+
+const VERSION: u8 = 2;
+
+/// How often the journal is flushed to disk. At most this much audio is lost in a crash.
+const SYNC_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Opus packets of an in-progress recording, appended to a file as they are encoded so that
+/// the recording can be recovered if the app exits before it is saved.
+///
+/// Format is:
+///  - 4 bytes, "OXYJ"
+///  - 1 byte, version
+///  - 4 bytes, sample rate of the packets as a u32 in big endian
+///  - 8 bytes, date the recording started as milliseconds since the epoch, as an i64 in big
+///    endian
+///  - 1 byte, 1 if the journal is encrypted, otherwise 0
+///  - 2 bytes, length of the name as a u16 in big endian
+///  - the name, as UTF-8, sealed with the clips' key if encrypted
+///  - for each 20ms packet, as in `encode_v1`:
+///    - 2 bytes, number of bytes in packet as a u16 in big endian
+///    - the raw packet
+///
+/// If encrypted, the packets are instead sealed in chunks, one each time the journal is
+/// flushed, so that the audio never reaches the disk in the clear:
+///  - 4 bytes, length of the sealed chunk as a u32 in big endian
+///  - the packets written since the last chunk, sealed
+///
+/// Version 1 journals have no encryption byte and are never encrypted.
+///
+/// The file is locked while it is written, so recordings in progress (in this or another
+/// process) are never mistaken for unfinished ones.
+pub struct JournalWriter {
+    path: PathBuf,
+    sender: Option<Sender<Vec<f32>>>,
+    thread: Option<JoinHandle<Result<()>>>,
+}
+
+impl JournalWriter {
+    /// Create a journal at `path`, encrypted with `cipher` if given.
+    pub(crate) fn create(
+        path: &Path,
+        name: &str,
+        date: DateTime<Utc>,
+        sample_rate: u32,
+        cipher: Option<&Cipher>,
+    ) -> Result<JournalWriter> {
+        let encoded_rate = opus_sample_rate(sample_rate).unwrap_or(SampleRate::Hz48000);
+
+        let file = OpenOptions::new().write(true).create_new(true).open(path)?;
+        file.try_lock()
+            .map_err(|err| eyre!("Could not lock journal {:?}: {}", path, err))?;
+
+        let mut writer = BufWriter::new(file);
+        let name = match cipher {
+            Some(cipher) => cipher.seal(name.as_bytes()),
+            None => name.as_bytes().to_vec(),
+        };
+        let name_len = u16::try_from(name.len()).map_err(|_| eyre!("Name is too long"))?;
+        writer.write_all(MAGIC)?;
+        writer.write_all(&[VERSION])?;
+        writer.write_all(&(encoded_rate as i32 as u32).to_be_bytes())?;
+        writer.write_all(&date.timestamp_millis().to_be_bytes())?;
+        writer.write_all(&[cipher.is_some() as u8])?;
+        writer.write_all(&name_len.to_be_bytes())?;
+        writer.write_all(&name)?;
+        writer.flush()?;
+        writer.get_ref().sync_all()?;
+
+        let writer = JournalSink {
+            file: writer,
+            cipher: cipher.cloned(),
+            chunk: Vec::new(),
+        };
+        let (sender, receiver) = channel();
+        let thread = std::thread::Builder::new()
+            .name("oxygen-journal".into())
+            .spawn(move || write_packets(writer, receiver, sample_rate))?;
+
+        Ok(JournalWriter {
+            path: path.to_owned(),
+            sender: Some(sender),
+            thread: Some(thread),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Queue samples to be encoded and written. This does not block, so it can be called
+    /// from the audio thread.
+    pub fn write(&self, samples: Vec<f32>) {
+        if let Some(sender) = &self.sender {
+            // If the writer thread failed, the error is reported by `finish`.
+            let _ = sender.send(samples);
+        }
+    }
+
+    /// Write all queued samples and close the journal.
+    pub fn finish(mut self) -> Result<PathBuf> {
+        self.close()?;
+        Ok(std::mem::take(&mut self.path))
+    }
+
+    fn close(&mut self) -> Result<()> {
+        self.sender.take();
+        match self.thread.take() {
+            Some(thread) => thread
+                .join()
+                .map_err(|_| eyre!("Journal thread panicked"))?,
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for JournalWriter {
+    fn drop(&mut self) {
+        if let Err(err) = self.close() {
+            log::error!("Could not write journal {:?}: {:?}", self.path, err);
+        }
+    }
+}
+
+/// Where the packets of a journal are written. Unencrypted packets go straight to the file;
+/// encrypted ones are kept until the sink is flushed, and then sealed as one chunk.
+struct JournalSink {
+    file: BufWriter<File>,
+    cipher: Option<Cipher>,
+    chunk: Vec<u8>,
+}
+
+impl JournalSink {
+    /// Write everything so far to the disk.
+    fn sync(&mut self) -> Result<()> {
+        self.flush()?;
+        self.file.get_ref().sync_data()?;
+
+        Ok(())
+    }
+}
+
+impl Write for JournalSink {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self.cipher {
+            Some(_) => {
+                self.chunk.extend_from_slice(buf);
+                Ok(buf.len())
+            }
+            None => self.file.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if let Some(cipher) = &self.cipher {
+            if !self.chunk.is_empty() {
+                let sealed = cipher.seal(&self.chunk);
+                self.file.write_all(&(sealed.len() as u32).to_be_bytes())?;
+                self.file.write_all(&sealed)?;
+                self.chunk.clear();
+            }
+        }
+        self.file.flush()
+    }
+}
+
+fn write_packets(
+    writer: JournalSink,
+    receiver: Receiver<Vec<f32>>,
+    sample_rate: u32,
+) -> Result<()> {
+    // Journals are always Opus at the voice bitrate. Recordings are encoded with their own
+    // profile when they are saved.
+    let mut encoder = StreamingEncoder::new(sample_rate, EncodingProfile::Voice, writer)?;
+    let mut last_sync = Instant::now();
+
+    for samples in receiver {
+        encoder.write(&samples)?;
+
+        if last_sync.elapsed() >= SYNC_INTERVAL {
+            encoder.get_mut().sync()?;
+            last_sync = Instant::now();
+        }
+    }
+
+    let (mut writer, _, _) = encoder.finish()?;
+    writer.sync()?;
+
+    Ok(())
+}
+
+/// A journal left behind by a recording that was never saved.
+#[derive(Clone, Debug)]
+pub struct UnfinishedRecording {
+    pub path: PathBuf,
+    pub name: String,
+    pub date: DateTime<Utc>,
+    pub sample_rate: u32,
+    /// Number of samples in the complete packets of the journal.
+    pub num_samples: usize,
+}
+
+impl UnfinishedRecording {
+    /// An identifier for the recording, based on its file name.
+    pub fn id(&self) -> String {
+        self.path
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().into_owned())
+            .unwrap_or_default()
+    }
+
+    pub fn duration(&self) -> f64 {
+        self.num_samples as f64 / self.sample_rate as f64
+    }
+}
+
+/// Read a journal, returning its header and its complete packets in `encode_v2` format.
+/// Encrypted journals are opened with `cipher`.
+///
+/// A packet cut short by a crash is dropped. Returns `None` if the journal is locked because
+/// it is still being written.
+pub(crate) fn read_journal(
+    path: &Path,
+    cipher: Option<&Cipher>,
+) -> Result<Option<(UnfinishedRecording, Vec<u8>)>> {
+    let mut file = File::open(path)?;
+    if file.try_lock_shared().is_err() {
+        return Ok(None);
+    }
+    let mut bytes = Vec::new();
+    file.read_to_end(&mut bytes)?;
+
+    if bytes.len() < 19 || &bytes[..4] != MAGIC || !(1..=VERSION).contains(&bytes[4]) {
+        return Err(eyre!("{:?} is not a recording journal", path));
+    }
+    let sample_rate = u32::from_be_bytes(bytes[5..9].try_into()?);
+    let date = Utc
+        .timestamp_millis_opt(i64::from_be_bytes(bytes[9..17].try_into()?))
+        .single()
+        .ok_or_else(|| eyre!("Invalid date in journal {:?}", path))?;
+    let (encrypted, name_start) = match bytes[4] {
+        1 => (false, 17),
+        _ => (bytes[17] == 1, 18),
+    };
+    let cipher = match (encrypted, cipher) {
+        (true, Some(cipher)) => Some(cipher),
+        (true, None) => return Err(eyre!("{:?} is encrypted", path)),
+        (false, _) => None,
+    };
+    let name_len = bytes
+        .get(name_start..name_start + 2)
+        .map(|len| u16::from_be_bytes([len[0], len[1]]) as usize)
+        .ok_or_else(|| eyre!("Invalid name in journal {:?}", path))?;
+    let packets_start = name_start + 2 + name_len;
+    let name = bytes
+        .get(name_start + 2..packets_start)
+        .ok_or_else(|| eyre!("Invalid name in journal {:?}", path))?;
+    let name = match cipher {
+        Some(cipher) => cipher.open(name)?,
+        None => name.to_vec(),
+    };
+    let name = String::from_utf8(name)?;
+    let sample_rate_enum = opus_sample_rate(sample_rate)
+        .ok_or_else(|| eyre!("Invalid sample rate in journal {:?}", path))?;
+    let frame_size = opus_frame_size(sample_rate_enum);
+
+    let mut packets = match cipher {
+        Some(cipher) => open_chunks(&bytes[packets_start..], cipher),
+        None => bytes.split_off(packets_start),
+    };
+    let mut i = 0;
+    let mut num_packets = 0;
+    while i + 2 <= packets.len() {
+        let len = u16::from_be_bytes([packets[i], packets[i + 1]]) as usize;
+        if len == 0 || i + 2 + len > packets.len() {
+            break;
+        }
+        i += 2 + len;
+        num_packets += 1;
+    }
+    packets.truncate(i);
+
+    let num_samples = num_packets * frame_size;
+    let mut encoded = V2Header::from_packets(sample_rate, num_samples as u64, &packets)?.to_bytes();
+    encoded.extend_from_slice(&packets);
+
+    Ok(Some((
+        UnfinishedRecording {
+            path: path.to_owned(),
+            name,
+            date,
+            sample_rate,
+            num_samples,
+        },
+        encoded,
+    )))
+}
+
+/// Open the sealed chunks of packets of an encrypted journal, up to the first that was cut
+/// short by a crash or can't be opened.
+fn open_chunks(mut bytes: &[u8], cipher: &Cipher) -> Vec<u8> {
+    let mut packets = Vec::new();
+    while bytes.len() >= 4 {
+        let len = u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize;
+        let chunk = match bytes.get(4..4 + len).map(|chunk| cipher.open(chunk)) {
+            Some(Ok(chunk)) => chunk,
+            _ => break,
+        };
+        packets.extend(chunk);
+        bytes = &bytes[4 + len..];
+    }
+
+    packets
+}
+
+/// Journals in `dir` that are not being written. Encrypted journals are skipped without
+/// `cipher`.
+pub(crate) fn unfinished_recordings(
+    dir: &Path,
+    cipher: Option<&Cipher>,
+) -> Result<Vec<UnfinishedRecording>> {
+    let mut recordings = Vec::new();
+    if !dir.exists() {
+        return Ok(recordings);
+    }
+
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().and_then(|ext| ext.to_str()) != Some("journal") {
+            continue;
+        }
+        match read_journal(&path, cipher) {
+            Ok(Some((recording, _))) => recordings.push(recording),
+            Ok(None) => {}
+            Err(err) => log::warn!("Skipping journal: {:?}", err),
+        }
+    }
+    recordings.sort_by_key(|recording| recording.date);
+
+    Ok(recordings)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encryption::test_params;
+    use crate::internal_encoding::EncodedAudio;
+    use crate::test_fixtures::temp_path;
+
+    #[test]
+    fn test_torn_journal_is_recoverable() {
+        let dir = temp_path("journal");
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("torn.journal");
+        let _ = std::fs::remove_file(&path);
+
+        // 44.1 kHz is resampled to 48 kHz as it is written.
+        let writer = JournalWriter::create(&path, "Name", Utc::now(), 44100, None).unwrap();
+        assert!(read_journal(&path, None).unwrap().is_none());
+        for _ in 0..10 {
+            writer.write(vec![0.25; 4410]);
+        }
+        writer.finish().unwrap();
+
+        // Simulate a crash part way through writing a packet.
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&[0, 100, 1, 2, 3]).unwrap();
+        drop(file);
+
+        let recordings = unfinished_recordings(&dir, None).unwrap();
+        assert_eq!(recordings.len(), 1);
+        assert_eq!(recordings[0].name, "Name");
+        assert_eq!(recordings[0].sample_rate, 48000);
+        assert_eq!(recordings[0].num_samples, 48000);
+
+        let (_, encoded) = read_journal(&path, None).unwrap().unwrap();
+        let decoded = EncodedAudio::parse(encoded).unwrap().decode().unwrap();
+        assert_eq!(decoded.len(), 48000);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_encrypted_journal() {
+        let dir = temp_path("journal-encrypted");
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("secret.journal");
+        let cipher = Cipher::derive("correct horse", &test_params()).unwrap();
+
+        let writer =
+            JournalWriter::create(&path, "Secret name", Utc::now(), 48000, Some(&cipher)).unwrap();
+        writer.write(vec![0.25; 24000]);
+        writer.write(vec![0.25; 24000]);
+        writer.finish().unwrap();
+
+        // A crash part way through writing a chunk.
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&[0, 0, 1, 0, 1, 2, 3]).unwrap();
+        drop(file);
+
+        let bytes = std::fs::read(&path).unwrap();
+        assert!(!bytes.windows(6).any(|window| window == b"Secret"));
+        assert!(unfinished_recordings(&dir, None).unwrap().is_empty());
+        assert!(read_journal(&path, None).is_err());
+
+        let recordings = unfinished_recordings(&dir, Some(&cipher)).unwrap();
+        assert_eq!(recordings.len(), 1);
+        assert_eq!(recordings[0].name, "Secret name");
+        assert_eq!(recordings[0].num_samples, 48000);
+
+        let (_, encoded) = read_journal(&path, Some(&cipher)).unwrap().unwrap();
+        let decoded = EncodedAudio::parse(encoded).unwrap().decode().unwrap();
+        assert_eq!(decoded.len(), 48000);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
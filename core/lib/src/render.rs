@@ -0,0 +1,343 @@
+use crate::spectrum::SpectrogramOptions;
+
+/// Minimum distance between time ticks, in pixels.
+const MIN_TIME_TICK_SPACING: f32 = 80.0;
+
+/// Minimum distance between frequency ticks, in pixels.
+const MIN_FREQUENCY_TICK_SPACING: f32 = 24.0;
+
+/// Time tick intervals to choose from, in seconds.
+const TIME_STEPS: [f64; 22] = [
+    0.001, 0.002, 0.005, 0.01, 0.02, 0.05, 0.1, 0.2, 0.5, 1.0, 2.0, 5.0, 10.0, 15.0, 30.0, 60.0,
+    120.0, 300.0, 600.0, 900.0, 1800.0, 3600.0,
+];
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Theme {
+    #[default]
+    Light,
+    Dark,
+}
+
+/// RGBA colours for drawing. Overlays with an alpha below 255 are blended.
+struct Palette {
+    waveform: [u8; 4],
+    rms: [u8; 4],
+    grid: [u8; 4],
+    selection: [u8; 4],
+    playhead: [u8; 4],
+}
+
+impl Theme {
+    fn palette(&self) -> Palette {
+        match self {
+            Theme::Light => Palette {
+                // purple-900 :)
+                waveform: [88, 28, 135, 255],
+                // purple-500
+                rms: [168, 85, 247, 255],
+                grid: [0, 0, 0, 40],
+                // blue-500
+                selection: [59, 130, 246, 70],
+                // red-600
+                playhead: [220, 38, 38, 255],
+            },
+            Theme::Dark => Palette {
+                // purple-300
+                waveform: [216, 180, 254, 255],
+                // purple-600
+                rms: [147, 51, 234, 255],
+                grid: [255, 255, 255, 48],
+                // blue-400
+                selection: [96, 165, 250, 80],
+                // red-400
+                playhead: [248, 113, 113, 255],
+            },
+        }
+    }
+}
+
+/// What to draw on top of waveforms and spectrograms.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct RenderOptions {
+    pub theme: Theme,
+    /// Shade the RMS level inside the min/max envelope of waveforms.
+    pub show_rms: bool,
+    /// Draw a line at each time tick, and at each frequency tick of spectrograms.
+    pub show_grid: bool,
+    /// Highlight this range, in samples.
+    pub selection: Option<(usize, usize)>,
+    /// Draw a line at this sample.
+    pub playhead: Option<usize>,
+}
+
+/// A labelled position on an axis.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Tick {
+    /// Pixels from the left edge for time ticks, or from the top edge for frequency ticks.
+    pub position: f32,
+    /// Seconds for time ticks, or Hz for frequency ticks.
+    pub value: f64,
+    pub label: String,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RenderedImage {
+    pub width: usize,
+    pub height: usize,
+    /// RGBA pixels, row by row from the top.
+    pub buffer: Vec<u8>,
+    pub time_ticks: Vec<Tick>,
+    /// Empty for waveforms.
+    pub frequency_ticks: Vec<Tick>,
+}
+
+impl RenderedImage {
+    pub fn new(width: usize, height: usize) -> RenderedImage {
+        RenderedImage {
+            width,
+            height,
+            buffer: vec![0; width * height * 4],
+            time_ticks: Vec::new(),
+            frequency_ticks: Vec::new(),
+        }
+    }
+
+    fn blend(&mut self, x: usize, y: usize, color: [u8; 4]) {
+        let offset = (y * self.width + x) * 4;
+        let pixel = &mut self.buffer[offset..offset + 4];
+        let alpha = color[3] as f32 / 255.0;
+        for c in 0..3 {
+            pixel[c] = (pixel[c] as f32 * (1.0 - alpha) + color[c] as f32 * alpha).round() as u8;
+        }
+        pixel[3] = (pixel[3] as f32 + (255.0 - pixel[3] as f32) * alpha).round() as u8;
+    }
+
+    fn fill_column(&mut self, x: usize, ys: std::ops::Range<usize>, color: [u8; 4]) {
+        for y in ys {
+            self.blend(x, y, color);
+        }
+    }
+
+    fn fill_row(&mut self, y: usize, color: [u8; 4]) {
+        for x in 0..self.width {
+            self.blend(x, y, color);
+        }
+    }
+}
+
+/// Draw a waveform's min/max envelope (and optionally RMS) for each column.
+pub(crate) fn draw_waveform(
+    image: &mut RenderedImage,
+    columns: &[crate::audio_clip::DisplayColumn],
+    options: &RenderOptions,
+) {
+    let palette = options.theme.palette();
+    let height = image.height;
+    let to_y = |value: f32| (height as f32) * (value + 1.0) / 2.0;
+
+    for (x, column) in columns.iter().enumerate() {
+        let min_y = to_y(column.min).floor().max(0.0) as usize;
+        let max_y = (to_y(column.max).ceil() as usize).min(height - 1);
+        image.fill_column(x, min_y..max_y + 1, palette.waveform);
+
+        if options.show_rms && column.len > 0 {
+            let rms = column.rms().min(1.0);
+            let min_y = to_y(-rms).floor().max(min_y as f32) as usize;
+            let max_y = (to_y(rms).ceil() as usize).min(max_y);
+            image.fill_column(x, min_y..max_y + 1, palette.rms);
+        }
+    }
+}
+
+/// Compute time ticks and draw the selection, grid and playhead.
+pub(crate) fn draw_overlays(
+    image: &mut RenderedImage,
+    range: (usize, usize),
+    sample_rate: usize,
+    options: &RenderOptions,
+) {
+    let palette = options.theme.palette();
+    image.time_ticks = time_ticks(range, sample_rate, image.width);
+    if image.width == 0 || image.height == 0 || range.1 <= range.0 {
+        return;
+    }
+
+    let samples_per_pixel = (range.1 - range.0) as f32 / image.width as f32;
+    let to_x = |sample: usize| (sample as f32 - range.0 as f32) / samples_per_pixel;
+
+    if let Some((start, end)) = options.selection {
+        let start_x = to_x(start).round().clamp(0.0, image.width as f32) as usize;
+        let end_x = to_x(end).round().clamp(0.0, image.width as f32) as usize;
+        for x in start_x..end_x {
+            image.fill_column(x, 0..image.height, palette.selection);
+        }
+    }
+
+    if options.show_grid {
+        let xs: Vec<usize> = image
+            .time_ticks
+            .iter()
+            .map(|tick| tick.position.round() as usize)
+            .filter(|x| *x < image.width)
+            .collect();
+        for x in xs {
+            image.fill_column(x, 0..image.height, palette.grid);
+        }
+
+        let ys: Vec<usize> = image
+            .frequency_ticks
+            .iter()
+            .map(|tick| tick.position.round() as usize)
+            .filter(|y| *y < image.height)
+            .collect();
+        for y in ys {
+            image.fill_row(y, palette.grid);
+        }
+    }
+
+    if let Some(playhead) = options.playhead {
+        let x = to_x(playhead).floor();
+        if x >= 0.0 && (x as usize) < image.width {
+            image.fill_column(x as usize, 0..image.height, palette.playhead);
+        }
+    }
+}
+
+/// Evenly spaced ticks for a range of samples drawn across `width` pixels.
+pub fn time_ticks(range: (usize, usize), sample_rate: usize, width: usize) -> Vec<Tick> {
+    if range.1 <= range.0 || sample_rate == 0 || width == 0 {
+        return Vec::new();
+    }
+
+    let start = range.0 as f64 / sample_rate as f64;
+    let end = range.1 as f64 / sample_rate as f64;
+    let pixels_per_second = width as f64 / (end - start);
+    let step = match TIME_STEPS
+        .iter()
+        .find(|step| *step * pixels_per_second >= MIN_TIME_TICK_SPACING as f64)
+    {
+        Some(step) => *step,
+        None => return Vec::new(),
+    };
+
+    let mut ticks = Vec::new();
+    let mut i = (start / step).ceil() as u64;
+    while (i as f64) * step <= end {
+        let value = (i as f64) * step;
+        ticks.push(Tick {
+            position: ((value - start) * pixels_per_second) as f32,
+            value,
+            label: format_time(value, step),
+        });
+        i += 1;
+    }
+
+    ticks
+}
+
+/// Ticks at round frequencies for a spectrogram `height` pixels tall.
+pub fn frequency_ticks(options: &SpectrogramOptions, height: usize) -> Vec<Tick> {
+    if height == 0 {
+        return Vec::new();
+    }
+
+    let trans_min = options.scale.transform(options.min_freq);
+    let trans_max = options.scale.transform(options.max_freq);
+    let to_y = |frequency: f32| {
+        (height as f32)
+            * (1.0 - (options.scale.transform(frequency) - trans_min) / (trans_max - trans_min))
+    };
+
+    // Candidates are 1, 2 and 5 times powers of ten. On a linear scale they thin out evenly,
+    // elsewhere each is kept if it is far enough above the last.
+    let mut candidates = Vec::new();
+    let mut decade = 10f32;
+    while decade <= options.max_freq {
+        for multiple in [1.0, 2.0, 5.0] {
+            let frequency = decade * multiple;
+            if frequency >= options.min_freq && frequency <= options.max_freq {
+                candidates.push(frequency);
+            }
+        }
+        decade *= 10.0;
+    }
+
+    let mut ticks: Vec<Tick> = Vec::new();
+    for frequency in candidates {
+        let y = to_y(frequency);
+        if let Some(last) = ticks.last() {
+            if last.position - y < MIN_FREQUENCY_TICK_SPACING {
+                continue;
+            }
+        }
+        ticks.push(Tick {
+            position: y,
+            value: frequency as f64,
+            label: format_frequency(frequency),
+        });
+    }
+
+    ticks
+}
+
+fn format_time(seconds: f64, step: f64) -> String {
+    if step >= 1.0 && seconds >= 60.0 {
+        let total = seconds.round() as u64;
+        if total >= 3600 {
+            format!("{}:{:02}:{:02}", total / 3600, total / 60 % 60, total % 60)
+        } else {
+            format!("{}:{:02}", total / 60, total % 60)
+        }
+    } else {
+        let decimals = if step >= 1.0 {
+            0
+        } else {
+            (-step.log10()).ceil() as usize
+        };
+        format!("{:.*}s", decimals, seconds)
+    }
+}
+
+fn format_frequency(frequency: f32) -> String {
+    if frequency >= 1000.0 {
+        let khz = frequency / 1000.0;
+        if khz.fract() == 0.0 {
+            format!("{} kHz", khz)
+        } else {
+            format!("{:.1} kHz", khz)
+        }
+    } else {
+        format!("{} Hz", frequency)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_time_ticks() {
+        let ticks = time_ticks((0, 48000 * 10), 48000, 800);
+        let labels: Vec<&str> = ticks.iter().map(|tick| tick.label.as_str()).collect();
+        assert_eq!(
+            labels,
+            ["0s", "1s", "2s", "3s", "4s", "5s", "6s", "7s", "8s", "9s", "10s"]
+        );
+        assert_eq!(ticks[5].position, 400.0);
+
+        let ticks = time_ticks((48000 * 60, 48000 * 600), 48000, 800);
+        assert_eq!(ticks[0].label, "1:00");
+    }
+
+    #[test]
+    fn test_frequency_ticks_are_spaced() {
+        let options = SpectrogramOptions::default();
+        let ticks = frequency_ticks(&options, 300);
+        assert!(!ticks.is_empty());
+        for pair in ticks.windows(2) {
+            assert!(pair[0].position - pair[1].position >= MIN_FREQUENCY_TICK_SPACING);
+        }
+        assert!(ticks.iter().any(|tick| tick.label == "1 kHz"));
+    }
+}
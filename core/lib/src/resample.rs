@@ -0,0 +1,304 @@
+/// Largest number of filter phases precomputed for a resampler. Ratios that need more (e.g.
+/// 44100 Hz to 44099 Hz) interpolate between neighbouring phases instead.
+const MAX_PHASES: usize = 1024;
+
+/// Trade-off between speed and accuracy when resampling.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Quality {
+    /// Linear interpolation. Cheap, but aliases and rolls off high frequencies.
+    Linear,
+    /// Windowed sinc with 8 zero crossings on each side.
+    Fast,
+    /// Windowed sinc with 16 zero crossings on each side.
+    #[default]
+    Medium,
+    /// Windowed sinc with 32 zero crossings on each side.
+    High,
+}
+
+impl Quality {
+    /// Zero crossings on each side, fraction of the Nyquist frequency kept, and Kaiser beta.
+    fn sinc_parameters(&self) -> Option<(usize, f64, f64)> {
+        match self {
+            Quality::Linear => None,
+            Quality::Fast => Some((8, 0.85, 6.0)),
+            Quality::Medium => Some((16, 0.92, 8.6)),
+            Quality::High => Some((32, 0.96, 10.0)),
+        }
+    }
+}
+
+/// Resample a whole signal.
+pub fn resample(samples: &[f32], from_rate: u32, to_rate: u32, quality: Quality) -> Vec<f32> {
+    if from_rate == to_rate {
+        return samples.to_vec();
+    }
+
+    let mut resampler = StreamingResampler::new(from_rate, to_rate, quality);
+    let mut output =
+        Vec::with_capacity(samples.len() * (to_rate as usize) / (from_rate as usize) + 1);
+    resampler.process(samples, &mut output);
+    resampler.flush(&mut output);
+    output
+}
+
+/// A polyphase resampler that accepts input in arbitrary chunks, e.g. from a live recording.
+///
+/// Output is delayed by `latency()` input samples, since each output sample depends on input
+/// after it. Call `flush` at the end of the stream to produce the rest.
+pub struct StreamingResampler {
+    /// Output samples per `down` input samples, with common factors removed.
+    up: u64,
+    down: u64,
+    /// Input samples used on each side of an output sample.
+    radius: usize,
+    phases: usize,
+    /// `phases + 1` rows of `2 * radius` coefficients.
+    table: Vec<f32>,
+    /// Recent input. `history[0]` is input sample `history_start`, which may be negative
+    /// (the signal is zero before the start).
+    history: Vec<f32>,
+    history_start: i64,
+    /// The next output sample is at input time `position + fraction / up`.
+    position: i64,
+    fraction: u64,
+    consumed: u64,
+    produced: u64,
+}
+
+impl StreamingResampler {
+    pub fn new(from_rate: u32, to_rate: u32, quality: Quality) -> StreamingResampler {
+        assert!(
+            from_rate > 0 && to_rate > 0,
+            "Sample rates must be positive"
+        );
+        let divisor = gcd(from_rate as u64, to_rate as u64);
+        let up = to_rate as u64 / divisor;
+        let down = from_rate as u64 / divisor;
+        let phases = (up as usize).min(MAX_PHASES);
+        // Without a rate change, the linear kernel is the identity.
+        let quality = if up == down { Quality::Linear } else { quality };
+
+        // Kernel as a function of distance in input samples.
+        let (radius, kernel): (usize, Box<dyn Fn(f64) -> f64>) = match quality.sinc_parameters() {
+            None => (1, Box::new(|t: f64| (1.0 - t.abs()).max(0.0))),
+            Some((zero_crossings, rolloff, beta)) => {
+                // When downsampling, the cutoff is the output's Nyquist frequency, and the
+                // kernel is stretched to match.
+                let scale = (up as f64 / down as f64).min(1.0);
+                let cutoff = scale * rolloff;
+                let half_width = zero_crossings as f64 / scale;
+                let radius = half_width.ceil() as usize;
+                (
+                    radius,
+                    Box::new(move |t: f64| {
+                        if t.abs() >= half_width {
+                            return 0.0;
+                        }
+                        let x = t / half_width;
+                        cutoff * sinc(cutoff * t) * bessel_i0(beta * (1.0 - x * x).sqrt())
+                            / bessel_i0(beta)
+                    }),
+                )
+            }
+        };
+
+        let taps = 2 * radius;
+        let mut table = Vec::with_capacity((phases + 1) * taps);
+        for phase in 0..=phases {
+            let offset = phase as f64 / phases as f64;
+            let row: Vec<f64> = (0..taps)
+                .map(|j| kernel(offset + radius as f64 - 1.0 - j as f64))
+                .collect();
+            // Normalize so that a constant signal passes through unchanged.
+            let sum: f64 = row.iter().sum();
+            table.extend(row.iter().map(|c| (c / sum) as f32));
+        }
+
+        StreamingResampler {
+            up,
+            down,
+            radius,
+            phases,
+            table,
+            history: vec![0.0; radius],
+            history_start: -(radius as i64),
+            position: 0,
+            fraction: 0,
+            consumed: 0,
+            produced: 0,
+        }
+    }
+
+    /// Number of input samples buffered before the corresponding output is produced.
+    pub fn latency(&self) -> usize {
+        self.radius
+    }
+
+    /// Resample `input`, appending whatever output is ready.
+    pub fn process(&mut self, input: &[f32], output: &mut Vec<f32>) {
+        self.history.extend_from_slice(input);
+        self.consumed += input.len() as u64;
+        self.run(output, u64::MAX);
+    }
+
+    /// Produce the output that was waiting on input after the end of the stream. The resampler
+    /// can be reused for a new stream afterwards.
+    pub fn flush(&mut self, output: &mut Vec<f32>) {
+        let total = (self.consumed * self.up).div_ceil(self.down);
+        self.history
+            .extend(std::iter::repeat_n(0.0, self.radius + 1));
+        self.run(output, total);
+
+        self.history = vec![0.0; self.radius];
+        self.history_start = -(self.radius as i64);
+        self.position = 0;
+        self.fraction = 0;
+        self.consumed = 0;
+        self.produced = 0;
+    }
+
+    fn run(&mut self, output: &mut Vec<f32>, limit: u64) {
+        let taps = 2 * self.radius;
+        let history_end = self.history_start + self.history.len() as i64;
+        while self.produced < limit && self.position + (self.radius as i64) < history_end {
+            let first = (self.position + 1 - self.radius as i64 - self.history_start) as usize;
+            let input = &self.history[first..first + taps];
+
+            let phase = self.fraction as f64 * self.phases as f64 / self.up as f64;
+            let row = phase.floor() as usize;
+            let weight = (phase - row as f64) as f32;
+            let a = &self.table[row * taps..(row + 1) * taps];
+            let mut sample = dot(a, input);
+            if weight > 0.0 {
+                let b = &self.table[(row + 1) * taps..(row + 2) * taps];
+                sample += (dot(b, input) - sample) * weight;
+            }
+            output.push(sample);
+            self.produced += 1;
+
+            self.fraction += self.down;
+            self.position += (self.fraction / self.up) as i64;
+            self.fraction %= self.up;
+        }
+
+        // Drop input that no future output depends on.
+        let keep_from = self.position + 1 - self.radius as i64;
+        if keep_from > self.history_start {
+            let drop = ((keep_from - self.history_start) as usize).min(self.history.len());
+            self.history.drain(..drop);
+            self.history_start += drop as i64;
+        }
+    }
+}
+
+fn dot(a: &[f32], b: &[f32]) -> f32 {
+    a.iter().zip(b).map(|(a, b)| a * b).sum()
+}
+
+fn gcd(mut a: u64, mut b: u64) -> u64 {
+    while b != 0 {
+        (a, b) = (b, a % b);
+    }
+    a
+}
+
+fn sinc(x: f64) -> f64 {
+    if x == 0.0 {
+        1.0
+    } else {
+        let x = std::f64::consts::PI * x;
+        x.sin() / x
+    }
+}
+
+/// Zeroth-order modified Bessel function of the first kind, for the Kaiser window.
+fn bessel_i0(x: f64) -> f64 {
+    let mut sum = 1.0;
+    let mut term = 1.0;
+    let mut k = 1.0;
+    while term > sum * 1e-12 {
+        term *= (x / (2.0 * k)) * (x / (2.0 * k));
+        sum += term;
+        k += 1.0;
+    }
+    sum
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dasp::{interpolate::linear::Linear, signal, Signal};
+
+    /// The resampler `AudioClip::resample` used before this module.
+    fn dasp_linear(samples: &[f32], from_rate: u32, to_rate: u32) -> Vec<f32> {
+        let mut signal = signal::from_iter(samples.iter().copied());
+        let a = signal.next();
+        let b = signal.next();
+        signal
+            .from_hz_to_hz(Linear::new(a, b), from_rate as f64, to_rate as f64)
+            .take(samples.len() * (to_rate as usize) / (from_rate as usize))
+            .collect()
+    }
+
+    fn sine(frequency: f64, sample_rate: u32, len: usize) -> Vec<f32> {
+        (0..len)
+            .map(|i| {
+                (2.0 * std::f64::consts::PI * frequency * i as f64 / sample_rate as f64).sin()
+                    as f32
+                    * 0.5
+            })
+            .collect()
+    }
+
+    /// RMS level in dB relative to a 0.5 amplitude sine, ignoring the edges.
+    fn level(samples: &[f32]) -> f64 {
+        let middle = &samples[2000..samples.len() - 2000];
+        let rms =
+            (middle.iter().map(|s| (*s as f64).powi(2)).sum::<f64>() / middle.len() as f64).sqrt();
+        20.0 * (rms / (0.5 / 2f64.sqrt())).log10()
+    }
+
+    #[test]
+    fn test_passband_ripple() {
+        let mut sinc_ripple = 0f64;
+        let mut linear_ripple = 0f64;
+        for frequency in [100.0, 1000.0, 4000.0, 8000.0, 12000.0, 16000.0] {
+            let input = sine(frequency, 44100, 44100);
+            let sinc = resample(&input, 44100, 48000, Quality::Medium);
+            let linear = dasp_linear(&input, 44100, 48000);
+            sinc_ripple = sinc_ripple.max(level(&sinc).abs());
+            linear_ripple = linear_ripple.max(level(&linear).abs());
+        }
+        assert!(sinc_ripple < 0.05, "sinc ripple {} dB", sinc_ripple);
+        assert!(linear_ripple > 1.0, "linear ripple {} dB", linear_ripple);
+    }
+
+    #[test]
+    fn test_aliasing() {
+        // 12 kHz is above the Nyquist frequency at 16 kHz, so it should be removed, not folded
+        // down to 4 kHz.
+        let input = sine(12000.0, 48000, 48000);
+        let sinc = level(&resample(&input, 48000, 16000, Quality::Medium));
+        let linear = level(&dasp_linear(&input, 48000, 16000));
+        assert!(sinc < -70.0, "sinc alias at {} dB", sinc);
+        assert!(linear > -10.0, "linear alias at {} dB", linear);
+    }
+
+    #[test]
+    fn test_streaming_matches_whole() {
+        let input = sine(440.0, 44100, 10_000);
+        for quality in [Quality::Linear, Quality::Fast, Quality::High] {
+            let whole = resample(&input, 44100, 12000, quality);
+            assert_eq!(whole.len(), (10_000usize * 12000).div_ceil(44100));
+
+            let mut resampler = StreamingResampler::new(44100, 12000, quality);
+            let mut streamed = Vec::new();
+            for chunk in input.chunks(333) {
+                resampler.process(chunk, &mut streamed);
+            }
+            resampler.flush(&mut streamed);
+            assert_eq!(streamed, whole);
+        }
+    }
+}
@@ -1,42 +1,141 @@
 use crate::audio_clip::AudioClip;
-use color_eyre::eyre::Result;
-use realfft::RealFftPlanner;
-
-pub fn spectrogram(clip: &AudioClip, mut range: (usize, usize)) -> Result<Vec<Vec<f32>>> {
-    let resampled = clip.resample(12000);
-    let n_fft = 2048;
-    let offset = 200; // 16ms
-
-    let signal = resampled.samples;
-    let mut fft = RealFftPlanner::<f32>::new();
-    let r2c = fft.plan_fft_forward(n_fft);
-    let mut spectrums = Vec::new();
-    let mut start_i = (range.0 / n_fft) * n_fft;
-    let mut chunk = vec![0f32; n_fft];
-    range.0 = range.0 * (resampled.sample_rate as usize) / (clip.sample_rate as usize);
-    range.1 = range.1 * (resampled.sample_rate as usize) / (clip.sample_rate as usize);
-
-    while start_i + n_fft < range.1 {
-        for i in start_i..start_i + n_fft {
-            chunk[i - start_i] = *signal.get(i).unwrap_or(&0f32);
+use crate::features::{hz_to_mel, FrameAnalyzer, StftOptions, Window};
+use crate::render::{self, RenderOptions, RenderedImage};
+use crate::resample::{Quality, StreamingResampler};
+use color_eyre::eyre::{eyre, Result};
+use std::collections::HashMap;
+
+/// Number of frames in each cached tile.
+const TILE_FRAMES: usize = 256;
+
+/// Maximum number of tiles kept in a cache before the least recently used are dropped.
+const MAX_TILES: usize = 64;
+
+/// How frequencies are mapped to the vertical axis.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum FrequencyScale {
+    #[default]
+    Mel,
+    Linear,
+    Log,
+}
+
+impl FrequencyScale {
+    pub(crate) fn transform(&self, frequency: f32) -> f32 {
+        match self {
+            FrequencyScale::Mel => hz_to_mel(frequency),
+            FrequencyScale::Linear => frequency,
+            FrequencyScale::Log => frequency.max(f32::MIN_POSITIVE).log10(),
         }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Colormap {
+    #[default]
+    Acton,
+    Grayscale,
+    /// Black through red and yellow to white.
+    Heat,
+}
 
-        // Hann window
-        for (i, sample) in chunk.iter_mut().enumerate() {
-            let x = (i as f32) / (n_fft as f32);
-            *sample *= 0.5 * (1.0 - (2.0 * std::f32::consts::PI * x).cos());
+impl Colormap {
+    /// The colour for an intensity between 0 and 1, as RGB.
+    fn color(&self, a: f32) -> [u8; 3] {
+        let a = a.clamp(0.0, 1.0);
+        match self {
+            Colormap::Acton => {
+                let color = COLORMAP[(255f32 * a) as usize];
+                [
+                    (color[0] * 255.0) as u8,
+                    (color[1] * 255.0) as u8,
+                    (color[2] * 255.0) as u8,
+                ]
+            }
+            Colormap::Grayscale => [(a * 255.0) as u8; 3],
+            Colormap::Heat => [
+                ((a * 3.0).min(1.0) * 255.0) as u8,
+                ((a * 3.0 - 1.0).clamp(0.0, 1.0) * 255.0) as u8,
+                ((a * 3.0 - 2.0).clamp(0.0, 1.0) * 255.0) as u8,
+            ],
         }
+    }
+}
 
-        // fft
-        let mut spectrum = r2c.make_output_vec();
-        r2c.process(&mut chunk, &mut spectrum)?;
-        let mut spectrum = spectrum.to_vec();
-        spectrum.truncate(n_fft / 2);
-        spectrums.push(spectrum.into_iter().map(|x| x.re.abs()).collect());
+/// Parameters for drawing a spectrogram.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SpectrogramOptions {
+    /// The frequency at the bottom of the image, in Hz.
+    pub min_freq: f32,
+    /// The frequency at the top of the image, in Hz. The clip is resampled to twice this rate
+    /// before analysis.
+    pub max_freq: f32,
+    /// Gain, in dB, drawn as the lowest colour.
+    pub min_gain: f32,
+    /// Gain, in dB, drawn as the highest colour.
+    pub max_gain: f32,
+    pub scale: FrequencyScale,
+    pub colormap: Colormap,
+    /// FFT size, in samples at the analysis rate. Larger windows resolve frequency better but
+    /// smear time.
+    pub n_fft: usize,
+    pub window: Window,
+}
 
-        start_i += offset;
+impl Default for SpectrogramOptions {
+    fn default() -> Self {
+        SpectrogramOptions {
+            min_freq: 1.0,
+            max_freq: 6000.0,
+            min_gain: -80.0,
+            max_gain: 20.0,
+            scale: FrequencyScale::Mel,
+            colormap: Colormap::Acton,
+            n_fft: 2048,
+            window: Window::Hann,
+        }
+    }
+}
+
+impl SpectrogramOptions {
+    pub fn validate(&self) -> Result<()> {
+        if self.min_freq < 0.0 || self.min_freq >= self.max_freq {
+            return Err(eyre!(
+                "Invalid frequency range {} to {} Hz",
+                self.min_freq,
+                self.max_freq
+            ));
+        }
+        if self.scale == FrequencyScale::Log && self.min_freq <= 0.0 {
+            return Err(eyre!(
+                "A log frequency scale needs a minimum frequency above 0 Hz"
+            ));
+        }
+        if self.min_gain >= self.max_gain {
+            return Err(eyre!(
+                "Invalid gain range {} to {} dB",
+                self.min_gain,
+                self.max_gain
+            ));
+        }
+        if self.n_fft < 2 {
+            return Err(eyre!("Invalid FFT size {}", self.n_fft));
+        }
+
+        Ok(())
+    }
+
+    /// The STFT used to draw the spectrogram: the clip is resampled so that `max_freq` is the
+    /// Nyquist frequency, with a hop of about 16ms.
+    pub fn stft_options(&self) -> StftOptions {
+        let sample_rate = (self.max_freq * 2.0).ceil() as u32;
+        StftOptions {
+            sample_rate,
+            n_fft: self.n_fft,
+            hop: (sample_rate as usize / 60).max(1),
+            window: self.window,
+        }
     }
-    Ok(spectrums)
 }
 
 pub fn render_spectrogram(
@@ -44,60 +143,216 @@ pub fn render_spectrogram(
     range: (usize, usize),
     width: usize,
     height: usize,
-) -> Result<Vec<u8>> {
-    let spectrums = spectrogram(clip, range)?;
-
-    let pixels_per_spectrum = (width as f32) / (spectrums.len() as f32);
-    let mut buffer = vec![0; width * height * 4];
-
-    let min_freq = 1f32;
-    let max_freq = 6000f32;
-
-    for x in 0..width {
-        let column = (x as f32) / pixels_per_spectrum;
-
-        if let Some(spectrum) = spectrums.get(column as usize) {
-            let mut prev_y = 0;
-            let mut prev_num = 0f32;
-            let mut prev_denom = 0f32;
-            for (i, cell) in spectrum.iter().enumerate() {
-                let frequency = (i as f32) * 12000f32 / 2048f32;
-                let trans_min = 2595f32 * (1f32 + min_freq / 700f32).log10();
-                let trans_max = 2595f32 * (1f32 + max_freq / 700f32).log10();
-                let trans_val = 2595f32 * (1f32 + frequency / 700f32).log10();
-                let coord = 2f32 * (trans_val - trans_min) / (trans_max - trans_min) - 1f32;
-                let this_y = ((coord + 1.0) / 2.0 * (height as f32)).round() as usize;
-
-                if this_y > prev_y {
-                    while prev_y < this_y {
-                        if prev_denom > 0.0 {
-                            let color = COLORMAP[(255f32 * (prev_num / prev_denom)) as usize];
-                            buffer[(height - prev_y - 1) * width * 4 + x * 4] =
-                                (color[0] * 255.0) as u8;
-                            buffer[(height - prev_y - 1) * width * 4 + x * 4 + 1] =
-                                (color[1] * 255.0) as u8;
-                            buffer[(height - prev_y - 1) * width * 4 + x * 4 + 2] =
-                                (color[2] * 255.0) as u8;
-                            buffer[(height - prev_y - 1) * width * 4 + x * 4 + 3] = 255;
-                        }
-
-                        prev_y += 1;
-                    }
-                    prev_num = 0f32;
-                    prev_denom = 0f32;
-                }
+    options: &SpectrogramOptions,
+    render_options: &RenderOptions,
+) -> Result<RenderedImage> {
+    options.validate()?;
+    let mut cache = clip.cache.spectrogram.lock().unwrap();
+    cache.prepare(&options.stft_options(), clip.sample_rate)?;
+    let consumed = cache.source_len().min(clip.samples.len());
+    cache.extend(&clip.samples[consumed..]);
+    cache.render(range, width, height, options, render_options)
+}
+
+struct Tile {
+    frames: Vec<Option<Box<[f32]>>>,
+    last_used: u64,
+}
+
+/// STFT frames of a clip, kept across redraws.
+///
+/// Frames are computed only when drawn, and stored in tiles of consecutive frames so that
+/// zooming or scrolling reuses earlier work. Samples can be appended as a recording grows;
+/// frames that were already complete stay valid.
+#[derive(Default)]
+pub struct SpectrogramCache {
+    /// STFT options and source sample rate the cache was built with.
+    key: Option<(StftOptions, u32)>,
+    analyzer: Option<FrameAnalyzer>,
+    source_len: usize,
+    resampler: Option<StreamingResampler>,
+    resampled: Vec<f32>,
+    tiles: HashMap<usize, Tile>,
+    clock: u64,
+    scratch: Vec<f32>,
+}
+
+impl SpectrogramCache {
+    /// Clear the cache unless it was built for the same options and source sample rate.
+    pub fn prepare(&mut self, options: &StftOptions, sample_rate: u32) -> Result<()> {
+        if self.key == Some((*options, sample_rate)) {
+            return Ok(());
+        }
+
+        *self = SpectrogramCache {
+            key: Some((*options, sample_rate)),
+            analyzer: Some(FrameAnalyzer::new(options)?),
+            resampler: Some(StreamingResampler::new(
+                sample_rate,
+                options.sample_rate,
+                Quality::default(),
+            )),
+            scratch: vec![0f32; options.num_bins()],
+            ..SpectrogramCache::default()
+        };
+
+        Ok(())
+    }
+
+    /// Number of samples of the source clip added so far.
+    pub fn source_len(&self) -> usize {
+        self.source_len
+    }
+
+    /// Append samples of the source clip.
+    pub fn extend(&mut self, samples: &[f32]) {
+        self.source_len += samples.len();
+        if let Some(resampler) = &mut self.resampler {
+            resampler.process(samples, &mut self.resampled);
+        }
+    }
 
-                let gain = 20f32 * cell.log10();
-                let min_gain = -80f32;
-                let max_gain = 20f32;
-                let a = ((gain - min_gain) / (max_gain - min_gain)).clamp(0.0f32, 1.0f32);
-                prev_num += a;
-                prev_denom += 1f32;
+    /// Draw the frames that `features::stft` would compute on the resampled clip, for `range`
+    /// (in source samples).
+    pub fn render(
+        &mut self,
+        range: (usize, usize),
+        width: usize,
+        height: usize,
+        options: &SpectrogramOptions,
+        render_options: &RenderOptions,
+    ) -> Result<RenderedImage> {
+        let (stft_options, source_rate) = self
+            .key
+            .ok_or_else(|| eyre!("Spectrogram cache was not prepared"))?;
+        let start = range.0 * (stft_options.sample_rate as usize) / (source_rate as usize);
+        let end = range.1 * (stft_options.sample_rate as usize) / (source_rate as usize);
+        let first_frame = start / stft_options.hop;
+        let end_frame = if end > stft_options.n_fft {
+            (end - stft_options.n_fft - 1) / stft_options.hop + 1
+        } else {
+            0
+        };
+        let num_frames = end_frame.saturating_sub(first_frame);
+
+        let pixels_per_spectrum = (width as f32) / (num_frames as f32);
+        let mut image = RenderedImage::new(width, height);
+
+        for x in 0..width {
+            let column = ((x as f32) / pixels_per_spectrum) as usize;
+            if column < num_frames {
+                let spectrum = self.frame(first_frame + column)?;
+                draw_column(
+                    &mut image.buffer,
+                    x,
+                    width,
+                    height,
+                    spectrum,
+                    &stft_options,
+                    options,
+                );
+            }
+        }
+
+        image.frequency_ticks = render::frequency_ticks(options, height);
+        render::draw_overlays(&mut image, range, source_rate as usize, render_options);
+
+        Ok(image)
+    }
+
+    fn frame(&mut self, index: usize) -> Result<&[f32]> {
+        let (stft_options, _) = self
+            .key
+            .ok_or_else(|| eyre!("Spectrogram cache was not prepared"))?;
+        let analyzer = self
+            .analyzer
+            .as_mut()
+            .ok_or_else(|| eyre!("Spectrogram cache was not prepared"))?;
+        let start = index * stft_options.hop;
+
+        if start >= self.resampled.len() {
+            self.scratch.fill(0f32);
+            return Ok(&self.scratch);
+        }
+
+        if start + stft_options.n_fft > self.resampled.len() {
+            // The frame is still being recorded, so it isn't cached.
+            analyzer.analyze(&self.resampled, start, &mut self.scratch)?;
+            return Ok(&self.scratch);
+        }
+
+        let tile_i = index / TILE_FRAMES;
+        if !self.tiles.contains_key(&tile_i) && self.tiles.len() >= MAX_TILES {
+            if let Some(oldest) = self
+                .tiles
+                .iter()
+                .min_by_key(|(_, tile)| tile.last_used)
+                .map(|(i, _)| *i)
+            {
+                self.tiles.remove(&oldest);
             }
         }
+
+        self.clock += 1;
+        let tile = self.tiles.entry(tile_i).or_insert_with(|| Tile {
+            frames: vec![None; TILE_FRAMES],
+            last_used: 0,
+        });
+        tile.last_used = self.clock;
+
+        let frame = &mut tile.frames[index % TILE_FRAMES];
+        if frame.is_none() {
+            let mut magnitudes = vec![0f32; stft_options.num_bins()];
+            analyzer.analyze(&self.resampled, start, &mut magnitudes)?;
+            *frame = Some(magnitudes.into_boxed_slice());
+        }
+
+        Ok(frame.as_deref().unwrap_or(&[]))
     }
+}
+
+fn draw_column(
+    buffer: &mut [u8],
+    x: usize,
+    width: usize,
+    height: usize,
+    spectrum: &[f32],
+    stft_options: &StftOptions,
+    options: &SpectrogramOptions,
+) {
+    let trans_min = options.scale.transform(options.min_freq);
+    let trans_max = options.scale.transform(options.max_freq);
+
+    let mut prev_y = 0;
+    let mut prev_num = 0f32;
+    let mut prev_denom = 0f32;
+    for (i, cell) in spectrum.iter().enumerate() {
+        let frequency = stft_options.bin_frequency(i);
+        let trans_val = options.scale.transform(frequency);
+        let coord = 2f32 * (trans_val - trans_min) / (trans_max - trans_min) - 1f32;
+        let this_y = (((coord + 1.0) / 2.0 * (height as f32)).round() as usize).min(height);
+
+        if this_y > prev_y {
+            while prev_y < this_y {
+                if prev_denom > 0.0 {
+                    let color = options.colormap.color(prev_num / prev_denom);
+                    let offset = (height - prev_y - 1) * width * 4 + x * 4;
+                    buffer[offset..offset + 3].copy_from_slice(&color);
+                    buffer[offset + 3] = 255;
+                }
+
+                prev_y += 1;
+            }
+            prev_num = 0f32;
+            prev_denom = 0f32;
+        }
 
-    Ok(buffer)
+        let gain = 20f32 * cell.log10();
+        let a = ((gain - options.min_gain) / (options.max_gain - options.min_gain))
+            .clamp(0.0f32, 1.0f32);
+        prev_num += a;
+        prev_denom += 1f32;
+    }
 }
 
 // acton colourmap https://github.com/tsipkens/cmap/blob/master/acton.m
@@ -360,3 +615,86 @@ const COLORMAP: [[f32; 3]; 256] = [
     [0.898_946_05, 0.896_037_16, 0.937_266],
     [0.900_471_75, 0.900_123_1, 0.940_051_14],
 ];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_fixtures::clip;
+
+    #[test]
+    fn test_render_with_options() {
+        let clip = AudioClip {
+            samples: (0..48000).map(|i| ((i as f32) * 0.1).sin()).collect(),
+            ..clip("Name")
+        };
+        for scale in [
+            FrequencyScale::Mel,
+            FrequencyScale::Linear,
+            FrequencyScale::Log,
+        ] {
+            let options = SpectrogramOptions {
+                max_freq: 12000.0,
+                scale,
+                colormap: Colormap::Heat,
+                ..SpectrogramOptions::default()
+            };
+            let image = render_spectrogram(
+                &clip,
+                (0, 48000),
+                50,
+                40,
+                &options,
+                &RenderOptions::default(),
+            )
+            .unwrap();
+            assert_eq!(image.buffer.len(), 50 * 40 * 4);
+            assert!(!image.frequency_ticks.is_empty());
+        }
+
+        let options = SpectrogramOptions {
+            min_freq: 0.0,
+            scale: FrequencyScale::Log,
+            ..SpectrogramOptions::default()
+        };
+        assert!(render_spectrogram(
+            &clip,
+            (0, 48000),
+            50,
+            40,
+            &options,
+            &RenderOptions::default()
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_cache_grows_incrementally() {
+        let samples: Vec<f32> = (0..48000).map(|i| ((i as f32) * 0.05).sin()).collect();
+        let options = SpectrogramOptions::default();
+        let render_options = RenderOptions::default();
+        let stft_options = options.stft_options();
+
+        let mut whole = SpectrogramCache::default();
+        whole.prepare(&stft_options, 48000).unwrap();
+        whole.extend(&samples);
+
+        let mut growing = SpectrogramCache::default();
+        growing.prepare(&stft_options, 48000).unwrap();
+        for chunk in samples.chunks(1000) {
+            growing.extend(chunk);
+            growing
+                .render((0, 96000), 64, 32, &options, &render_options)
+                .unwrap();
+        }
+
+        assert_eq!(growing.resampled.len(), whole.resampled.len());
+        assert_eq!(
+            growing
+                .render((0, 96000), 64, 32, &options, &render_options)
+                .unwrap(),
+            whole
+                .render((0, 96000), 64, 32, &options, &render_options)
+                .unwrap()
+        );
+    }
+}
@@ -0,0 +1,644 @@
+//! Writers for small audio files in the formats `import` supports, since there are no encoders
+//! for most of them in our dependencies. They favour simplicity over compression: FLAC and ALAC
+//! frames are stored verbatim.
+
+use std::path::{Path, PathBuf};
+
+use crate::audio_clip::{AudioClip, ClipCache};
+use crate::internal_encoding::opus_encoder;
+use audiopus::SampleRate;
+use chrono::prelude::*;
+
+/// A path in the temp dir that is unique to this process and test.
+pub fn temp_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("oxygen-fixture-{}-{}", std::process::id(), name))
+}
+
+/// A 440 Hz sine with an amplitude of 0.5.
+pub fn sine(sample_rate: u32, len: usize) -> Vec<f32> {
+    (0..len)
+        .map(|i| (2.0 * std::f32::consts::PI * 440.0 * i as f32 / sample_rate as f32).sin() * 0.5)
+        .collect()
+}
+
+/// A new clip of a tenth of a second of `sine` at 48 kHz, recorded now.
+pub fn clip(name: &str) -> AudioClip {
+    AudioClip {
+        id: None,
+        name: name.into(),
+        date: Utc::now(),
+        notes: None,
+        samples: sine(48000, 4800),
+        sample_rate: 48000,
+        cache: ClipCache::default(),
+    }
+}
+
+fn to_i16(sample: f32) -> i16 {
+    (sample * i16::MAX as f32).round() as i16
+}
+
+/// Write a mono FLAC file made of verbatim subframes.
+pub fn write_flac(path: &Path, sample_rate: u32, samples: &[f32]) {
+    let (mut bytes, frames) = flac_stream(sample_rate, samples);
+    for frame in frames {
+        bytes.extend(frame);
+    }
+    std::fs::write(path, bytes).unwrap();
+}
+
+/// The stream marker and STREAMINFO block of a mono FLAC stream, and its frames.
+pub fn flac_stream(sample_rate: u32, samples: &[f32]) -> (Vec<u8>, Vec<Vec<u8>>) {
+    const BLOCK_SIZE: usize = 4096;
+
+    let mut bytes = b"fLaC".to_vec();
+    // Last metadata block, STREAMINFO, 34 bytes.
+    bytes.extend_from_slice(&[0x80, 0, 0, 34]);
+    bytes.extend_from_slice(&(BLOCK_SIZE as u16).to_be_bytes());
+    bytes.extend_from_slice(&(BLOCK_SIZE as u16).to_be_bytes());
+    // Unknown minimum and maximum frame sizes.
+    bytes.extend_from_slice(&[0; 6]);
+    // 20 bits of sample rate, 3 bits of channels - 1, 5 bits of bits per sample - 1, and 36 bits
+    // of total samples.
+    let info: u64 = ((sample_rate as u64) << 44) | (15 << 36) | samples.len() as u64;
+    bytes.extend_from_slice(&info.to_be_bytes());
+    // Unknown MD5.
+    bytes.extend_from_slice(&[0; 16]);
+
+    let frames = samples
+        .chunks(BLOCK_SIZE)
+        .enumerate()
+        .map(|(frame_number, block)| {
+            // Sync code with fixed blocking, a 16 bit block size at the end of the header, the
+            // sample rate from STREAMINFO, mono, 16 bits per sample, and the frame number, UTF-8
+            // coded.
+            assert!(frame_number < 0x80);
+            let mut frame = vec![0xff, 0xf8, 0x70, 0x08, frame_number as u8];
+            frame.extend_from_slice(&(block.len() as u16 - 1).to_be_bytes());
+            frame.push(crc8(&frame));
+            // A verbatim subframe.
+            frame.push(0x02);
+            for sample in block {
+                frame.extend_from_slice(&to_i16(*sample).to_be_bytes());
+            }
+            let crc = crc16(&frame);
+            frame.extend_from_slice(&crc.to_be_bytes());
+            frame
+        })
+        .collect();
+
+    (bytes, frames)
+}
+
+fn crc8(bytes: &[u8]) -> u8 {
+    let mut crc = 0u8;
+    for byte in bytes {
+        crc ^= byte;
+        for _ in 0..8 {
+            crc = if crc & 0x80 != 0 {
+                (crc << 1) ^ 0x07
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+fn crc16(bytes: &[u8]) -> u16 {
+    let mut crc = 0u16;
+    for byte in bytes {
+        crc ^= (*byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x8005
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+/// Number of samples the Opus encoder adds to the start of the stream.
+pub const OPUS_PRE_SKIP: u16 = 312;
+
+/// Encode mono 48 kHz samples as 20 ms Opus packets, and the identification header that goes
+/// with them.
+pub fn opus_packets(samples: &[f32]) -> (Vec<u8>, Vec<Vec<u8>>) {
+    let mut head = b"OpusHead".to_vec();
+    head.extend_from_slice(&[1, 1]);
+    head.extend_from_slice(&OPUS_PRE_SKIP.to_le_bytes());
+    head.extend_from_slice(&48000u32.to_le_bytes());
+    // Output gain and channel mapping family 0.
+    head.extend_from_slice(&[0, 0, 0]);
+
+    let encoder = opus_encoder(SampleRate::Hz48000, 24000).unwrap();
+    let mut packet = vec![0; 4000];
+    let packets = samples
+        .chunks(960)
+        .map(|frame| {
+            let mut frame = frame.to_vec();
+            frame.resize(960, 0.0);
+            let len = encoder.encode_float(&frame, &mut packet).unwrap();
+            packet[..len].to_vec()
+        })
+        .collect();
+
+    (head, packets)
+}
+
+/// Write mono 48 kHz samples as an Ogg Opus file with a title tag, one packet per page.
+pub fn write_ogg_opus(path: &Path, samples: &[f32], title: &str) {
+    let (head, packets) = opus_packets(samples);
+
+    let mut tags = b"OpusTags".to_vec();
+    let vendor = b"oxygen";
+    tags.extend_from_slice(&(vendor.len() as u32).to_le_bytes());
+    tags.extend_from_slice(vendor);
+    let comment = format!("TITLE={}", title);
+    tags.extend_from_slice(&1u32.to_le_bytes());
+    tags.extend_from_slice(&(comment.len() as u32).to_le_bytes());
+    tags.extend_from_slice(comment.as_bytes());
+
+    let mut bytes = Vec::new();
+    write_ogg_page(&mut bytes, 0x02, 0, 0, &head);
+    write_ogg_page(&mut bytes, 0, 0, 1, &tags);
+    let mut granule = OPUS_PRE_SKIP as u64;
+    for (i, packet) in packets.iter().enumerate() {
+        let last = i + 1 == packets.len();
+        granule = if last {
+            OPUS_PRE_SKIP as u64 + samples.len() as u64
+        } else {
+            granule + 960
+        };
+        let header_type = if last { 0x04 } else { 0 };
+        write_ogg_page(&mut bytes, header_type, granule, i as u32 + 2, packet);
+    }
+
+    std::fs::write(path, bytes).unwrap();
+}
+
+/// Vorbis block size, as a power of two. Only short blocks are used.
+const VORBIS_BLOCK_EXP: u32 = 8;
+
+/// Write mono samples as an Ogg Vorbis file with a title tag, one packet per page. The number
+/// of samples must be a multiple of half a block, 128.
+///
+/// Each block's MDCT coefficients are stored as residues over a floor of 1, in two passes: a
+/// coarse one with a step of 1/128 and a fine one with a step of 1/32768, so the audio comes
+/// back almost exactly.
+pub fn write_ogg_vorbis(path: &Path, sample_rate: u32, samples: &[f32], title: &str) {
+    let n = 1usize << VORBIS_BLOCK_EXP;
+    assert_eq!(samples.len() % (n / 2), 0);
+
+    let mut ident = vec![1];
+    ident.extend_from_slice(b"vorbis");
+    ident.extend_from_slice(&0u32.to_le_bytes());
+    ident.push(1);
+    ident.extend_from_slice(&sample_rate.to_le_bytes());
+    // Maximum, nominal and minimum bitrates.
+    ident.extend_from_slice(&[0; 12]);
+    ident.push(((VORBIS_BLOCK_EXP << 4) | VORBIS_BLOCK_EXP) as u8);
+    ident.push(1);
+
+    let mut comment = vec![3];
+    comment.extend_from_slice(b"vorbis");
+    let vendor = b"oxygen";
+    comment.extend_from_slice(&(vendor.len() as u32).to_le_bytes());
+    comment.extend_from_slice(vendor);
+    let title = format!("TITLE={}", title);
+    comment.extend_from_slice(&1u32.to_le_bytes());
+    comment.extend_from_slice(&(title.len() as u32).to_le_bytes());
+    comment.extend_from_slice(title.as_bytes());
+    comment.push(1);
+
+    let mut setup = LsbBitWriter::default();
+    // Three codebooks: the partition classes, and the coarse and fine residues.
+    setup.write(2, 8);
+    write_vorbis_codebook(&mut setup, &[1, 1], None);
+    write_vorbis_codebook(&mut setup, &[8; 256], Some((vorbis_float(0, true), 7)));
+    write_vorbis_codebook(&mut setup, &[8; 256], Some((vorbis_float(-8, true), 15)));
+    // One time domain transform, which must be 0.
+    setup.write(0, 6);
+    setup.write(0, 16);
+    // One floor of type 1, with no partitions, so it is a line between two posts.
+    setup.write(0, 6);
+    setup.write(1, 16);
+    setup.write(0, 5);
+    setup.write(0, 2);
+    setup.write(VORBIS_BLOCK_EXP - 1, 4);
+    // One residue of type 1 over the whole block, in one partition of one class that uses
+    // codebook 1 in the first pass and codebook 2 in the second.
+    setup.write(0, 6);
+    setup.write(1, 16);
+    setup.write(0, 24);
+    setup.write(n as u32 / 2, 24);
+    setup.write(n as u32 / 2 - 1, 24);
+    setup.write(0, 6);
+    setup.write(0, 8);
+    setup.write(0b011, 3);
+    setup.write(0, 1);
+    setup.write(1, 8);
+    setup.write(2, 8);
+    // One mapping, with one submap and no coupling.
+    setup.write(0, 6);
+    setup.write(0, 16);
+    setup.write(0, 1);
+    setup.write(0, 1);
+    setup.write(0, 2);
+    setup.write(0, 8);
+    setup.write(0, 8);
+    setup.write(0, 8);
+    // One mode, with short blocks.
+    setup.write(0, 6);
+    setup.write(0, 1);
+    setup.write(0, 16);
+    setup.write(0, 16);
+    setup.write(0, 8);
+    // Framing.
+    setup.write(1, 1);
+    let mut setup_packet = vec![5];
+    setup_packet.extend_from_slice(b"vorbis");
+    setup_packet.extend(setup.finish());
+
+    // The decoder outputs the second half of each block overlapped with the first half of the
+    // next, so half a block of silence on each side gives back exactly the samples.
+    let mut padded = vec![0.0; n / 2];
+    padded.extend_from_slice(samples);
+    padded.resize(samples.len() + n, 0.0);
+    let window: Vec<f32> = (0..n)
+        .map(|i| {
+            let x = std::f32::consts::PI * (i as f32 + 0.5) / n as f32;
+            (std::f32::consts::FRAC_PI_2 * x.sin().powi(2)).sin()
+        })
+        .collect();
+
+    let mut bytes = Vec::new();
+    write_ogg_page(&mut bytes, 0x02, 0, 0, &ident);
+    write_ogg_page(&mut bytes, 0, 0, 1, &comment);
+    write_ogg_page(&mut bytes, 0, 0, 2, &setup_packet);
+    let num_blocks = samples.len() / (n / 2) + 1;
+    for block in 0..num_blocks {
+        let start = block * n / 2;
+        let coefficients = (0..n / 2).map(|k| {
+            let sum: f32 = (0..n)
+                .map(|i| {
+                    let phase = 2.0 * std::f32::consts::PI / n as f32
+                        * (i as f32 + 0.5 + n as f32 / 4.0)
+                        * (k as f32 + 0.5);
+                    window[i] * padded[start + i] * phase.cos()
+                })
+                .sum();
+            // Scaled to match the decoder's inverse MDCT.
+            sum * 4.0 / n as f32
+        });
+
+        let mut packet = LsbBitWriter::default();
+        // An audio packet, whose floor is used and is 1 throughout.
+        packet.write(0, 1);
+        packet.write(1, 1);
+        packet.write(255, 8);
+        packet.write(255, 8);
+        // The partition's class.
+        packet.write(0, 1);
+        let (coarse, fine): (Vec<u32>, Vec<u32>) = coefficients
+            .map(|coefficient| {
+                let coarse = ((coefficient + 1.0) * 128.0).round().clamp(0.0, 255.0);
+                let rest = coefficient - (coarse / 128.0 - 1.0);
+                let fine = ((rest + 1.0 / 256.0) * 32768.0).round().clamp(0.0, 255.0);
+                (coarse as u32, fine as u32)
+            })
+            .unzip();
+        for entry in coarse.into_iter().chain(fine) {
+            // Codewords are read most significant bit first.
+            for bit in (0..8).rev() {
+                packet.write((entry >> bit) & 1, 1);
+            }
+        }
+
+        let last = block + 1 == num_blocks;
+        let header_type = if last { 0x04 } else { 0 };
+        let granule = (block * n / 2) as u64;
+        write_ogg_page(
+            &mut bytes,
+            header_type,
+            granule,
+            block as u32 + 3,
+            &packet.finish(),
+        );
+    }
+
+    std::fs::write(path, bytes).unwrap();
+}
+
+/// Write a Vorbis codebook with the given codeword lengths. With a lookup, its entries are
+/// the scalars `minimum + entry * 2^delta_exp`, where `minimum` is packed as Vorbis floats
+/// are.
+fn write_vorbis_codebook(writer: &mut LsbBitWriter, lengths: &[u32], lookup: Option<(u32, i32)>) {
+    writer.write(0x564342, 24);
+    writer.write(1, 16);
+    writer.write(lengths.len() as u32, 24);
+    // Not ordered, not sparse.
+    writer.write(0, 1);
+    writer.write(0, 1);
+    for length in lengths {
+        writer.write(length - 1, 5);
+    }
+    match lookup {
+        Some((minimum, delta_exp)) => {
+            writer.write(1, 4);
+            writer.write(minimum, 32);
+            writer.write(vorbis_float(-delta_exp, false), 32);
+            writer.write(7, 4);
+            writer.write(0, 1);
+            for entry in 0..lengths.len() as u32 {
+                writer.write(entry, 8);
+            }
+        }
+        None => writer.write(0, 4),
+    }
+}
+
+/// 2 to the power of `exponent`, packed as a Vorbis float.
+fn vorbis_float(exponent: i32, negative: bool) -> u32 {
+    ((negative as u32) << 31) | (((788 + exponent) as u32) << 21) | 1
+}
+
+fn write_ogg_page(
+    bytes: &mut Vec<u8>,
+    header_type: u8,
+    granule: u64,
+    sequence: u32,
+    packet: &[u8],
+) {
+    let start = bytes.len();
+    bytes.extend_from_slice(b"OggS");
+    bytes.push(0);
+    bytes.push(header_type);
+    bytes.extend_from_slice(&granule.to_le_bytes());
+    // Serial number.
+    bytes.extend_from_slice(&1u32.to_le_bytes());
+    bytes.extend_from_slice(&sequence.to_le_bytes());
+    // CRC, filled in below.
+    bytes.extend_from_slice(&[0; 4]);
+    let mut lacing = vec![255; packet.len() / 255];
+    lacing.push((packet.len() % 255) as u8);
+    bytes.push(lacing.len() as u8);
+    bytes.extend_from_slice(&lacing);
+    bytes.extend_from_slice(packet);
+
+    let mut crc = 0u32;
+    for byte in &bytes[start..] {
+        crc ^= (*byte as u32) << 24;
+        for _ in 0..8 {
+            crc = if crc & 0x8000_0000 != 0 {
+                (crc << 1) ^ 0x04c1_1db7
+            } else {
+                crc << 1
+            };
+        }
+    }
+    bytes[start + 22..start + 26].copy_from_slice(&crc.to_le_bytes());
+}
+
+/// The audio track of a Matroska file.
+pub struct MkvAudio {
+    pub codec_id: &'static str,
+    pub codec_private: Option<Vec<u8>>,
+    pub sample_rate: f64,
+    pub bit_depth: Option<u64>,
+    /// Timestamp in milliseconds, and data, of each block.
+    pub blocks: Vec<(i16, Vec<u8>)>,
+}
+
+/// Write a Matroska file with one mono audio track. With `with_video`, a video track with no
+/// frames is listed before it, as in screen recordings.
+pub fn write_mkv(path: &Path, doc_type: &str, audio: &MkvAudio, with_video: bool) {
+    let header = [
+        element(0x4286, &uint(1)),
+        element(0x42f7, &uint(1)),
+        element(0x42f2, &uint(4)),
+        element(0x42f3, &uint(8)),
+        element(0x4282, doc_type.as_bytes()),
+        element(0x4287, &uint(4)),
+        element(0x4285, &uint(2)),
+    ]
+    .concat();
+
+    let info = [
+        element(0x2ad7b1, &uint(1_000_000)),
+        element(0x4d80, b"oxygen"),
+        element(0x5741, b"oxygen"),
+    ]
+    .concat();
+
+    let mut tracks = Vec::new();
+    let mut audio_track_number = 1;
+    if with_video {
+        let video = [element(0xb0, &uint(320)), element(0xba, &uint(240))].concat();
+        tracks.extend(element(
+            0xae,
+            &[
+                element(0xd7, &uint(1)),
+                element(0x73c5, &uint(1)),
+                element(0x83, &uint(1)),
+                element(0x86, b"V_VP8"),
+                element(0xe0, &video),
+            ]
+            .concat(),
+        ));
+        audio_track_number = 2;
+    }
+    let mut audio_settings = [
+        element(0xb5, &audio.sample_rate.to_be_bytes()),
+        element(0x9f, &uint(1)),
+    ]
+    .concat();
+    if let Some(bit_depth) = audio.bit_depth {
+        audio_settings.extend(element(0x6264, &uint(bit_depth)));
+    }
+    let mut audio_entry = [
+        element(0xd7, &uint(audio_track_number)),
+        element(0x73c5, &uint(audio_track_number)),
+        element(0x83, &uint(2)),
+        element(0x86, audio.codec_id.as_bytes()),
+        element(0xe1, &audio_settings),
+    ]
+    .concat();
+    if let Some(codec_private) = &audio.codec_private {
+        audio_entry.extend(element(0x63a2, codec_private));
+    }
+    tracks.extend(element(0xae, &audio_entry));
+
+    let mut cluster = element(0xe7, &uint(0));
+    for (timestamp, data) in &audio.blocks {
+        // Track number as a 1 byte vint, relative timestamp, and the keyframe flag.
+        let mut block = vec![0x80 | audio_track_number as u8];
+        block.extend_from_slice(&timestamp.to_be_bytes());
+        block.push(0x80);
+        block.extend_from_slice(data);
+        cluster.extend(element(0xa3, &block));
+    }
+
+    let segment = [
+        element(0x1549a966, &info),
+        element(0x1654ae6b, &tracks),
+        element(0x1f43b675, &cluster),
+    ]
+    .concat();
+
+    let bytes = [element(0x1a45dfa3, &header), element(0x18538067, &segment)].concat();
+    std::fs::write(path, bytes).unwrap();
+}
+
+/// An EBML element with an 8 byte size.
+fn element(id: u32, data: &[u8]) -> Vec<u8> {
+    let id_bytes = id.to_be_bytes();
+    let id_start = id_bytes.iter().position(|byte| *byte != 0).unwrap();
+    let mut bytes = id_bytes[id_start..].to_vec();
+    bytes.push(0x01);
+    bytes.extend_from_slice(&(data.len() as u64).to_be_bytes()[1..]);
+    bytes.extend_from_slice(data);
+    bytes
+}
+
+fn uint(value: u64) -> Vec<u8> {
+    value.to_be_bytes().to_vec()
+}
+
+/// Write a mono 16 bit Core Audio Format file of ALAC frames that use the uncompressed escape.
+pub fn write_caf_alac(path: &Path, sample_rate: u32, samples: &[f32]) {
+    const FRAME_LENGTH: usize = 4096;
+
+    let mut bytes = b"caff".to_vec();
+    bytes.extend_from_slice(&1u16.to_be_bytes());
+    bytes.extend_from_slice(&0u16.to_be_bytes());
+
+    let mut desc = (sample_rate as f64).to_be_bytes().to_vec();
+    desc.extend_from_slice(b"alac");
+    // Format flags, variable bytes per packet, frames per packet, channels, and bits per
+    // channel, which is 0 for compressed formats.
+    for value in [0, 0, FRAME_LENGTH as u32, 1, 0] {
+        desc.extend_from_slice(&value.to_be_bytes());
+    }
+    caf_chunk(&mut bytes, b"desc", &desc);
+
+    let mut cookie = (FRAME_LENGTH as u32).to_be_bytes().to_vec();
+    // Version, bit depth, the rice parameters pb, mb and kb, channels and max run.
+    cookie.extend_from_slice(&[0, 16, 40, 10, 14, 1, 0, 255]);
+    // Max frame bytes, average bit rate and sample rate.
+    for value in [0, 0, sample_rate] {
+        cookie.extend_from_slice(&value.to_be_bytes());
+    }
+    caf_chunk(&mut bytes, b"kuki", &cookie);
+
+    let frames: Vec<Vec<u8>> = samples
+        .chunks(FRAME_LENGTH)
+        .map(|frame| {
+            let mut bits = BitWriter::default();
+            // A single channel element, with instance tag 0 and 12 unused bits.
+            bits.write(0, 3);
+            bits.write(0, 4);
+            bits.write(0, 12);
+            let partial = frame.len() < FRAME_LENGTH;
+            bits.write(partial as u32, 1);
+            // No shift, uncompressed.
+            bits.write(0, 2);
+            bits.write(1, 1);
+            if partial {
+                bits.write(frame.len() as u32, 32);
+            }
+            for sample in frame {
+                bits.write(to_i16(*sample) as u16 as u32, 16);
+            }
+            // End tag.
+            bits.write(7, 3);
+            bits.finish()
+        })
+        .collect();
+
+    let mut packet_table = (frames.len() as i64).to_be_bytes().to_vec();
+    packet_table.extend_from_slice(&(samples.len() as i64).to_be_bytes());
+    // Priming and remainder frames.
+    packet_table.extend_from_slice(&0i32.to_be_bytes());
+    packet_table
+        .extend_from_slice(&((frames.len() * FRAME_LENGTH - samples.len()) as i32).to_be_bytes());
+    for frame in &frames {
+        // Sizes are variable length integers, 7 bits per byte.
+        let size = frame.len();
+        let mut size_bytes = vec![(size & 0x7f) as u8];
+        let mut rest = size >> 7;
+        while rest > 0 {
+            size_bytes.insert(0, 0x80 | (rest & 0x7f) as u8);
+            rest >>= 7;
+        }
+        packet_table.extend(size_bytes);
+    }
+    caf_chunk(&mut bytes, b"pakt", &packet_table);
+
+    // Edit count, then the packets.
+    let mut data = 0u32.to_be_bytes().to_vec();
+    for frame in frames {
+        data.extend(frame);
+    }
+    caf_chunk(&mut bytes, b"data", &data);
+
+    std::fs::write(path, bytes).unwrap();
+}
+
+fn caf_chunk(bytes: &mut Vec<u8>, chunk_type: &[u8; 4], data: &[u8]) {
+    bytes.extend_from_slice(chunk_type);
+    bytes.extend_from_slice(&(data.len() as i64).to_be_bytes());
+    bytes.extend_from_slice(data);
+}
+
+/// Writes bits least significant first, as Vorbis packs them.
+#[derive(Default)]
+struct LsbBitWriter {
+    bytes: Vec<u8>,
+    len: u32,
+}
+
+impl LsbBitWriter {
+    fn write(&mut self, value: u32, len: u32) {
+        for bit in 0..len {
+            if self.len.is_multiple_of(8) {
+                self.bytes.push(0);
+            }
+            let last = self.bytes.last_mut().expect("a byte was added");
+            *last |= (((value >> bit) & 1) as u8) << (self.len % 8);
+            self.len += 1;
+        }
+    }
+
+    fn finish(self) -> Vec<u8> {
+        self.bytes
+    }
+}
+
+/// Writes bits most significant first.
+#[derive(Default)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    bits: u64,
+    len: u32,
+}
+
+impl BitWriter {
+    fn write(&mut self, value: u32, len: u32) {
+        self.bits = (self.bits << len) | (value as u64 & ((1 << len) - 1));
+        self.len += len;
+        while self.len >= 8 {
+            self.len -= 8;
+            self.bytes.push((self.bits >> self.len) as u8);
+        }
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.len > 0 {
+            self.write(0, 8 - self.len);
+        }
+        self.bytes
+    }
+}
@@ -0,0 +1,216 @@
+use std::io::Write;
+
+use color_eyre::eyre::{eyre, Result};
+use serde::Serialize;
+
+use crate::diarization::Speaker;
+use crate::language_processor::Segment;
+
+/// A file format for a transcript.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TranscriptFormat {
+    /// SubRip subtitles, numbered cues with comma-separated milliseconds.
+    Srt,
+    /// WebVTT subtitles, for the HTML `<track>` element.
+    WebVtt,
+    /// The text of each segment on its own line, without times.
+    Text,
+    /// An array of segments, each with its start and end in seconds, its text, its words and
+    /// its speaker.
+    Json,
+}
+
+impl TranscriptFormat {
+    /// The usual file extension, without the dot.
+    pub fn extension(&self) -> &'static str {
+        match self {
+            TranscriptFormat::Srt => "srt",
+            TranscriptFormat::WebVtt => "vtt",
+            TranscriptFormat::Text => "txt",
+            TranscriptFormat::Json => "json",
+        }
+    }
+
+    pub fn from_extension(extension: &str) -> Result<TranscriptFormat> {
+        match extension.to_lowercase().as_str() {
+            "srt" => Ok(TranscriptFormat::Srt),
+            "vtt" => Ok(TranscriptFormat::WebVtt),
+            "txt" => Ok(TranscriptFormat::Text),
+            "json" => Ok(TranscriptFormat::Json),
+            _ => Err(eyre!("Unknown transcript format \"{}\"", extension)),
+        }
+    }
+}
+
+/// A segment as written to JSON, with its speaker's name.
+#[derive(Serialize)]
+struct JsonSegment<'a> {
+    #[serde(flatten)]
+    segment: &'a Segment,
+    speaker_name: Option<String>,
+}
+
+/// Write a transcript in the given format. Segments labelled with a speaker are prefixed
+/// with the speaker's name from `speakers`, or "Speaker 1" and so on.
+pub fn write_transcript<W: Write>(
+    segments: &[Segment],
+    speakers: &[Speaker],
+    format: TranscriptFormat,
+    writer: &mut W,
+) -> Result<()> {
+    let label = |segment: &Segment| {
+        segment.speaker.map(|id| {
+            speakers
+                .iter()
+                .find(|speaker| speaker.id == id)
+                .map(Speaker::label)
+                .unwrap_or_else(|| format!("Speaker {}", id))
+        })
+    };
+    let prefixed = |segment: &Segment| match label(segment) {
+        Some(label) => format!("{}: {}", label, segment.text.trim()),
+        None => segment.text.trim().to_string(),
+    };
+
+    match format {
+        TranscriptFormat::Srt => {
+            for (i, segment) in segments.iter().enumerate() {
+                writeln!(
+                    writer,
+                    "{}\n{} --> {}\n{}\n",
+                    i + 1,
+                    timestamp(segment.start, ','),
+                    timestamp(segment.end, ','),
+                    prefixed(segment)
+                )?;
+            }
+        }
+        TranscriptFormat::WebVtt => {
+            writeln!(writer, "WEBVTT\n")?;
+            for segment in segments {
+                let text = escape_cue(segment.text.trim());
+                writeln!(
+                    writer,
+                    "{} --> {}\n{}\n",
+                    timestamp(segment.start, '.'),
+                    timestamp(segment.end, '.'),
+                    match label(segment) {
+                        Some(label) => format!("<v {}>{}", escape_cue(&label), text),
+                        None => text,
+                    }
+                )?;
+            }
+        }
+        TranscriptFormat::Text => {
+            for segment in segments {
+                writeln!(writer, "{}", prefixed(segment))?;
+            }
+        }
+        TranscriptFormat::Json => {
+            let segments: Vec<_> = segments
+                .iter()
+                .map(|segment| JsonSegment {
+                    segment,
+                    speaker_name: label(segment),
+                })
+                .collect();
+            serde_json::to_writer_pretty(&mut *writer, &segments)?;
+            writeln!(writer)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Format seconds as `hh:mm:ss` followed by `separator` and milliseconds, as subtitles
+/// expect.
+fn timestamp(seconds: f64, separator: char) -> String {
+    let millis = (seconds.max(0.0) * 1000.0).round() as u64;
+    format!(
+        "{:02}:{:02}:{:02}{}{:03}",
+        millis / 3_600_000,
+        millis / 60_000 % 60,
+        millis / 1000 % 60,
+        separator,
+        millis % 1000
+    )
+}
+
+/// WebVTT cue text can't contain "-->", and treats `&` and `<` as markup.
+fn escape_cue(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::language_processor::Word;
+
+    fn write(segments: &[Segment], format: TranscriptFormat) -> String {
+        let mut out = Vec::new();
+        let speakers = [Speaker {
+            id: 2,
+            name: Some("Me".into()),
+            speaking_time: 1.75,
+        }];
+        write_transcript(segments, &speakers, format, &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn test_formats() {
+        let segments = vec![
+            Segment {
+                start: 0.0,
+                end: 2.5,
+                text: " Hello there.".into(),
+                words: vec![
+                    Word {
+                        start: 0.0,
+                        end: 0.8,
+                        text: "Hello".into(),
+                    },
+                    Word {
+                        start: 0.8,
+                        end: 2.5,
+                        text: "there.".into(),
+                    },
+                ],
+                speaker: None,
+            },
+            Segment {
+                start: 3661.25,
+                end: 3663.0,
+                text: " Salt & <pepper>".into(),
+                words: vec![],
+                speaker: Some(2),
+            },
+        ];
+
+        assert_eq!(
+            write(&segments, TranscriptFormat::Srt),
+            "1\n00:00:00,000 --> 00:00:02,500\nHello there.\n\n\
+             2\n01:01:01,250 --> 01:01:03,000\nMe: Salt & <pepper>\n\n"
+        );
+        assert_eq!(
+            write(&segments, TranscriptFormat::WebVtt),
+            "WEBVTT\n\n\
+             00:00:00.000 --> 00:00:02.500\nHello there.\n\n\
+             01:01:01.250 --> 01:01:03.000\n<v Me>Salt &amp; &lt;pepper&gt;\n\n"
+        );
+        assert_eq!(
+            write(&segments, TranscriptFormat::Text),
+            "Hello there.\nMe: Salt & <pepper>\n"
+        );
+
+        let json: serde_json::Value =
+            serde_json::from_str(&write(&segments, TranscriptFormat::Json)).unwrap();
+        assert_eq!(json[0]["words"][1]["text"], "there.");
+        assert_eq!(json[1]["start"], 3661.25);
+        assert_eq!(json[1]["speaker"], 2);
+        assert_eq!(json[1]["speaker_name"], "Me");
+        assert_eq!(json[0]["speaker_name"], serde_json::Value::Null);
+    }
+}
@@ -1,21 +1,43 @@
 use napi::bindgen_prelude::{AsyncTask, FromNapiValue, ToNapiValue};
 
-use std::{ffi::OsStr, path::Path};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::{
+    ffi::OsStr,
+    fs::File,
+    io::BufWriter,
+    path::{Path, PathBuf},
+};
 
 use chrono::prelude::*;
 use napi::{
     bindgen_prelude::Buffer,
-    threadsafe_function::{ErrorStrategy, ThreadsafeFunction, ThreadsafeFunctionCallMode},
+    threadsafe_function::{
+        ErrorStrategy, ThreadSafeCallContext, ThreadsafeFunction, ThreadsafeFunctionCallMode,
+    },
     Env, Error, JsDate, JsFunction, JsUnknown, Result, Task,
 };
 use napi_derive::napi;
 use oxygen_core::audio_clip::{
     AudioBackend, AudioClip, ClipHandle, PlayHandle, RecordHandle, StreamHandle,
 };
-use oxygen_core::db::{ClipMeta, Db};
+use oxygen_core::backup::{self, RestoreMode, RestoreSummary};
+use oxygen_core::collections::Collection;
+use oxygen_core::db::{ClipMeta, Db, LazyClip, ListQuery, SortKey};
+use oxygen_core::diarization::{self, DiarizationOptions, Speaker, SpeakerTurn};
+use oxygen_core::history::{HistoryEntry, Operation};
+use oxygen_core::import::{
+    self, BatchImport, ConflictPolicy, ImportOptions, ImportOutcome, ImportProgress,
+};
+use oxygen_core::internal_encoding::EncodingProfile;
 use oxygen_core::language_processor::{AsyncLanguageProcessor, Segment, TranscriptionHandle};
+use oxygen_core::recovery::UnfinishedRecording;
+use oxygen_core::render::{RenderOptions, RenderedImage, Theme, Tick};
+use oxygen_core::spectrum::{Colormap, FrequencyScale, SpectrogramOptions};
+use oxygen_core::transcript::{self, TranscriptFormat};
 
-pub struct TranscriptionTask(Option<TranscriptionHandle>);
+/// Transcribes a clip, labelling the segments with who speaks when in it.
+pub struct TranscriptionTask(Option<TranscriptionHandle>, Vec<SpeakerTurn>);
 
 impl Task for TranscriptionTask {
     type Output = Vec<Segment>;
@@ -27,16 +49,304 @@ impl Task for TranscriptionTask {
             .take()
             .ok_or_else(|| Error::from_reason("no handle"))?;
 
-        handle
+        let mut segments = handle
             .resolve()
-            .map_err(|e| Error::from_reason(format!("{:?}", e)))
+            .map_err(|e| Error::from_reason(format!("{:?}", e)))?;
+        diarization::label_segments(&mut segments, &self.1);
+
+        Ok(segments)
     }
 
     fn resolve(&mut self, _env: Env, output: Vec<Segment>) -> Result<Self::JsValue> {
+        Ok(output.into_iter().map(JsSegment::from).collect())
+    }
+}
+
+/// Transcribes a clip and writes the transcript to a file, resolving to its path.
+pub struct TranscriptExportTask {
+    handle: Option<TranscriptionHandle>,
+    turns: Vec<SpeakerTurn>,
+    speakers: Vec<Speaker>,
+    format: TranscriptFormat,
+    path: String,
+}
+
+impl Task for TranscriptExportTask {
+    type Output = String;
+    type JsValue = String;
+
+    fn compute(&mut self) -> Result<Self::Output> {
+        let handle = self
+            .handle
+            .take()
+            .ok_or_else(|| Error::from_reason("no handle"))?;
+        let mut segments = handle
+            .resolve()
+            .map_err(|e| Error::from_reason(format!("{:?}", e)))?;
+        diarization::label_segments(&mut segments, &self.turns);
+
+        let mut writer = BufWriter::new(
+            File::create(&self.path).map_err(|e| Error::from_reason(format!("{:?}", e)))?,
+        );
+        transcript::write_transcript(&segments, &self.speakers, self.format, &mut writer)
+            .map_err(|e| Error::from_reason(format!("{:?}", e)))?;
+
+        Ok(self.path.clone())
+    }
+
+    fn resolve(&mut self, _env: Env, output: String) -> Result<Self::JsValue> {
+        Ok(output)
+    }
+}
+
+pub struct ImportTask {
+    db: Arc<Mutex<Db>>,
+    path: String,
+    options: ImportOptions,
+    cancel: Arc<AtomicBool>,
+    on_progress: ThreadsafeFunction<JsImportProgress, ErrorStrategy::Fatal>,
+}
+
+impl Task for ImportTask {
+    type Output = ImportOutcome;
+    type JsValue = JsImportResult;
+
+    fn compute(&mut self) -> Result<Self::Output> {
+        // The database is only locked to save, so the UI stays responsive while decoding.
+        let on_progress = &self.on_progress;
+        let encoded = import::encode_file(
+            Path::new(&self.path),
+            &self.options,
+            &self.cancel,
+            |progress| {
+                on_progress.call(progress.into(), ThreadsafeFunctionCallMode::NonBlocking);
+            },
+        )
+        .map_err(|e| Error::from_reason(format!("{:?}", e)))?;
+
+        encoded
+            .save(&self.db.lock().unwrap(), None, self.options.conflict)
+            .map_err(|e| Error::from_reason(format!("{:?}", e)))
+    }
+
+    fn resolve(&mut self, _env: Env, output: ImportOutcome) -> Result<Self::JsValue> {
+        Ok(JsImportResult::new(
+            self.path.clone(),
+            Ok::<_, Error>(output),
+        ))
+    }
+}
+
+pub struct BatchImportTask {
+    db: Arc<Mutex<Db>>,
+    paths: Vec<String>,
+    options: ImportOptions,
+    cancel: Arc<AtomicBool>,
+    on_progress: ThreadsafeFunction<JsImportProgress, ErrorStrategy::Fatal>,
+}
+
+impl Task for BatchImportTask {
+    type Output = Vec<BatchImport>;
+    type JsValue = Vec<JsImportResult>;
+
+    fn compute(&mut self) -> Result<Self::Output> {
+        // As in `ImportTask`, the database is only locked to save each file.
+        let mut results = Vec::with_capacity(self.paths.len());
+        for (index, path) in self.paths.iter().enumerate() {
+            let on_progress = &self.on_progress;
+            let skipped = import::skip_existing(
+                &self.db.lock().unwrap(),
+                None,
+                Path::new(path),
+                &self.options,
+            );
+            let result = match skipped {
+                Ok(Some(skipped)) => Ok(skipped),
+                Ok(None) => {
+                    import::encode_file(Path::new(path), &self.options, &self.cancel, |progress| {
+                        let progress = JsImportProgress {
+                            index: Some(index as u32),
+                            ..progress.into()
+                        };
+                        on_progress.call(progress, ThreadsafeFunctionCallMode::NonBlocking);
+                    })
+                    .and_then(|encoded| {
+                        encoded.save(&self.db.lock().unwrap(), None, self.options.conflict)
+                    })
+                }
+                Err(err) => Err(err),
+            };
+            results.push(BatchImport {
+                path: PathBuf::from(path),
+                result,
+            });
+            if self.cancel.load(Ordering::Relaxed) {
+                break;
+            }
+        }
+
+        Ok(results)
+    }
+
+    fn resolve(&mut self, _env: Env, output: Vec<BatchImport>) -> Result<Self::JsValue> {
         Ok(output
             .into_iter()
-            .map(|((t0, t1), segment)| JsSegment { t0, t1, segment })
-            .collect::<Vec<JsSegment>>())
+            .map(|batch| {
+                JsImportResult::new(batch.path.to_string_lossy().into_owned(), batch.result)
+            })
+            .collect())
+    }
+}
+
+/// Works out who speaks when in a clip and stores it, resolving to the speakers.
+pub struct DiarizationTask {
+    db: Arc<Mutex<Db>>,
+    id: usize,
+    options: DiarizationOptions,
+}
+
+impl Task for DiarizationTask {
+    type Output = Vec<Speaker>;
+    type JsValue = Vec<JsSpeaker>;
+
+    fn compute(&mut self) -> Result<Self::Output> {
+        // The journal is only locked to load the clip and store the result.
+        let clip = self
+            .db
+            .lock()
+            .unwrap()
+            .load_by_id(self.id)
+            .map_err(|e| Error::from_reason(format!("{:?}", e)))?
+            .ok_or_else(|| Error::from_reason(format!("No clip with ID {}", self.id)))?;
+        let turns = diarization::diarize(&clip, &self.options)
+            .map_err(|e| Error::from_reason(format!("{:?}", e)))?;
+
+        let db = self.db.lock().unwrap();
+        db.set_speaker_turns(self.id, &turns)
+            .and_then(|_| db.speakers(self.id))
+            .map_err(|e| Error::from_reason(format!("{:?}", e)))
+    }
+
+    fn resolve(&mut self, _env: Env, output: Vec<Speaker>) -> Result<Self::JsValue> {
+        Ok(output.into_iter().map(JsSpeaker).collect())
+    }
+}
+
+pub struct BackupTask {
+    db: Arc<Mutex<Db>>,
+    path: String,
+}
+
+impl Task for BackupTask {
+    type Output = usize;
+    type JsValue = u32;
+
+    fn compute(&mut self) -> Result<Self::Output> {
+        backup::backup(&self.db.lock().unwrap(), Path::new(&self.path))
+            .map_err(|e| Error::from_reason(format!("{:?}", e)))
+    }
+
+    fn resolve(&mut self, _env: Env, output: usize) -> Result<Self::JsValue> {
+        Ok(output as u32)
+    }
+}
+
+pub struct RestoreTask {
+    db: Arc<Mutex<Db>>,
+    path: String,
+    mode: RestoreMode,
+    passphrase: Option<String>,
+    update_cb: ThreadsafeFunction<(), ErrorStrategy::Fatal>,
+}
+
+impl Task for RestoreTask {
+    type Output = RestoreSummary;
+    type JsValue = JsRestoreSummary;
+
+    fn compute(&mut self) -> Result<Self::Output> {
+        let summary = backup::restore(
+            &self.db.lock().unwrap(),
+            Path::new(&self.path),
+            self.mode,
+            self.passphrase.as_deref(),
+        )
+        .map_err(|e| Error::from_reason(format!("{:?}", e)))?;
+        self.update_cb
+            .call((), ThreadsafeFunctionCallMode::NonBlocking);
+
+        Ok(summary)
+    }
+
+    fn resolve(&mut self, _env: Env, output: RestoreSummary) -> Result<Self::JsValue> {
+        Ok(JsRestoreSummary {
+            restored: output.restored as u32,
+            skipped: output.skipped as u32,
+            renamed: output.renamed as u32,
+        })
+    }
+}
+
+/// Encrypts the journal with `passphrase`, or decrypts it if there is none.
+pub struct EncryptionTask {
+    db: Arc<Mutex<Db>>,
+    passphrase: Option<String>,
+    update_cb: ThreadsafeFunction<(), ErrorStrategy::Fatal>,
+}
+
+impl Task for EncryptionTask {
+    type Output = ();
+    type JsValue = ();
+
+    fn compute(&mut self) -> Result<Self::Output> {
+        let mut db = self.db.lock().unwrap();
+        match &self.passphrase {
+            Some(passphrase) => db.encrypt(passphrase),
+            None => db.decrypt(),
+        }
+        .map_err(|e| Error::from_reason(format!("{:?}", e)))?;
+        self.update_cb
+            .call((), ThreadsafeFunctionCallMode::NonBlocking);
+
+        Ok(())
+    }
+
+    fn resolve(&mut self, _env: Env, _output: ()) -> Result<Self::JsValue> {
+        Ok(())
+    }
+}
+
+/// Decodes a clip selected with `set_current_clip_id`.
+pub struct LoadClipTask {
+    clip: Option<LazyClip>,
+    /// Shared with `Tab::Load`. It is released as soon as the clip is decoded, so the tab
+    /// can take the clip without copying it.
+    loaded: Option<Arc<OnceLock<AudioClip>>>,
+    update_cb: ThreadsafeFunction<(), ErrorStrategy::Fatal>,
+}
+
+impl Task for LoadClipTask {
+    type Output = ();
+    type JsValue = ();
+
+    fn compute(&mut self) -> Result<Self::Output> {
+        let clip = self
+            .clip
+            .take()
+            .expect("LoadClipTask is only computed once")
+            .decode()
+            .map_err(|e| Error::from_reason(format!("{:?}", e)))?;
+        if let Some(loaded) = self.loaded.take() {
+            // Another clip may have been selected, in which case this one is dropped.
+            let _ = loaded.set(clip);
+        }
+        self.update_cb
+            .call((), ThreadsafeFunctionCallMode::NonBlocking);
+
+        Ok(())
+    }
+
+    fn resolve(&mut self, _env: Env, _output: ()) -> Result<Self::JsValue> {
+        Ok(())
     }
 }
 
@@ -55,6 +365,13 @@ enum Tab {
         audio_clip: AudioClip,
         time: f64,
     },
+    /// A clip that was selected, whose audio is still being decoded. Its name may have been
+    /// changed since.
+    Load {
+        meta: ClipMeta,
+        duration: f64,
+        audio_clip: Arc<OnceLock<AudioClip>>,
+    },
 }
 
 impl Default for Tab {
@@ -69,15 +386,377 @@ pub enum RenderMode {
     Spectrogram,
 }
 
+#[napi]
+pub enum SpectrogramScale {
+    Mel,
+    Linear,
+    Log,
+}
+
+#[napi]
+pub enum SpectrogramColormap {
+    Acton,
+    Grayscale,
+    Heat,
+}
+
+#[napi(object)]
+pub struct JsSpectrogramOptions {
+    pub min_freq: f64,
+    pub max_freq: f64,
+    pub min_gain: f64,
+    pub max_gain: f64,
+    pub scale: SpectrogramScale,
+    pub colormap: SpectrogramColormap,
+    pub n_fft: u32,
+}
+
+impl From<SpectrogramOptions> for JsSpectrogramOptions {
+    fn from(options: SpectrogramOptions) -> Self {
+        JsSpectrogramOptions {
+            min_freq: options.min_freq as f64,
+            max_freq: options.max_freq as f64,
+            min_gain: options.min_gain as f64,
+            max_gain: options.max_gain as f64,
+            scale: match options.scale {
+                FrequencyScale::Mel => SpectrogramScale::Mel,
+                FrequencyScale::Linear => SpectrogramScale::Linear,
+                FrequencyScale::Log => SpectrogramScale::Log,
+            },
+            colormap: match options.colormap {
+                Colormap::Acton => SpectrogramColormap::Acton,
+                Colormap::Grayscale => SpectrogramColormap::Grayscale,
+                Colormap::Heat => SpectrogramColormap::Heat,
+            },
+            n_fft: options.n_fft as u32,
+        }
+    }
+}
+
+impl From<JsSpectrogramOptions> for SpectrogramOptions {
+    fn from(options: JsSpectrogramOptions) -> Self {
+        SpectrogramOptions {
+            min_freq: options.min_freq as f32,
+            max_freq: options.max_freq as f32,
+            min_gain: options.min_gain as f32,
+            max_gain: options.max_gain as f32,
+            scale: match options.scale {
+                SpectrogramScale::Mel => FrequencyScale::Mel,
+                SpectrogramScale::Linear => FrequencyScale::Linear,
+                SpectrogramScale::Log => FrequencyScale::Log,
+            },
+            colormap: match options.colormap {
+                SpectrogramColormap::Acton => Colormap::Acton,
+                SpectrogramColormap::Grayscale => Colormap::Grayscale,
+                SpectrogramColormap::Heat => Colormap::Heat,
+            },
+            n_fft: options.n_fft as usize,
+            ..SpectrogramOptions::default()
+        }
+    }
+}
+
+#[napi]
+pub enum RenderTheme {
+    Light,
+    Dark,
+}
+
+#[napi(object)]
+#[derive(Clone)]
+pub struct JsRenderOptions {
+    pub theme: RenderTheme,
+    pub show_rms: bool,
+    pub show_grid: bool,
+    pub show_playhead: bool,
+    /// Start of the highlighted range, in seconds.
+    pub selection_start: Option<f64>,
+    /// End of the highlighted range, in seconds.
+    pub selection_end: Option<f64>,
+}
+
+impl Default for JsRenderOptions {
+    fn default() -> Self {
+        JsRenderOptions {
+            theme: RenderTheme::Light,
+            show_rms: false,
+            show_grid: false,
+            show_playhead: false,
+            selection_start: None,
+            selection_end: None,
+        }
+    }
+}
+
+#[napi(object)]
+pub struct JsTick {
+    pub position: f64,
+    pub value: f64,
+    pub label: String,
+}
+
+impl From<Tick> for JsTick {
+    fn from(tick: Tick) -> Self {
+        JsTick {
+            position: tick.position as f64,
+            value: tick.value,
+            label: tick.label,
+        }
+    }
+}
+
+#[napi(object)]
+pub struct JsRenderedImage {
+    pub buffer: Buffer,
+    pub time_ticks: Vec<JsTick>,
+    pub frequency_ticks: Vec<JsTick>,
+}
+
+impl From<RenderedImage> for JsRenderedImage {
+    fn from(image: RenderedImage) -> Self {
+        JsRenderedImage {
+            buffer: image.buffer.into(),
+            time_ticks: image.time_ticks.into_iter().map(JsTick::from).collect(),
+            frequency_ticks: image
+                .frequency_ticks
+                .into_iter()
+                .map(JsTick::from)
+                .collect(),
+        }
+    }
+}
+
+#[napi(object)]
+pub struct JsImportProgress {
+    /// Index of the file being imported, for batch imports.
+    pub index: Option<u32>,
+    /// Seconds of audio decoded so far.
+    pub decoded: f64,
+    /// Length of the file in seconds, if known.
+    pub total: Option<f64>,
+}
+
+impl From<ImportProgress> for JsImportProgress {
+    fn from(progress: ImportProgress) -> Self {
+        JsImportProgress {
+            index: None,
+            decoded: progress.decoded,
+            total: progress.total,
+        }
+    }
+}
+
+#[napi]
+pub enum JsConflictPolicy {
+    Error,
+    Skip,
+    Rename,
+    Overwrite,
+}
+
+impl From<JsConflictPolicy> for ConflictPolicy {
+    fn from(conflict: JsConflictPolicy) -> Self {
+        match conflict {
+            JsConflictPolicy::Error => ConflictPolicy::Error,
+            JsConflictPolicy::Skip => ConflictPolicy::Skip,
+            JsConflictPolicy::Rename => ConflictPolicy::Rename,
+            JsConflictPolicy::Overwrite => ConflictPolicy::Overwrite,
+        }
+    }
+}
+
+#[napi]
+pub enum JsEncodingProfile {
+    Voice,
+    Music,
+    HighFidelity,
+    Lossless,
+}
+
+impl From<JsEncodingProfile> for EncodingProfile {
+    fn from(profile: JsEncodingProfile) -> Self {
+        match profile {
+            JsEncodingProfile::Voice => EncodingProfile::Voice,
+            JsEncodingProfile::Music => EncodingProfile::Music,
+            JsEncodingProfile::HighFidelity => EncodingProfile::HighFidelity,
+            JsEncodingProfile::Lossless => EncodingProfile::Lossless,
+        }
+    }
+}
+
+impl From<EncodingProfile> for JsEncodingProfile {
+    fn from(profile: EncodingProfile) -> Self {
+        match profile {
+            EncodingProfile::Voice => JsEncodingProfile::Voice,
+            EncodingProfile::Music => JsEncodingProfile::Music,
+            EncodingProfile::HighFidelity => JsEncodingProfile::HighFidelity,
+            EncodingProfile::Lossless => JsEncodingProfile::Lossless,
+        }
+    }
+}
+
+#[napi]
+pub enum JsSortKey {
+    Date,
+    Name,
+    Duration,
+    /// The order of the collection being listed.
+    Position,
+}
+
+impl From<JsSortKey> for SortKey {
+    fn from(sort: JsSortKey) -> Self {
+        match sort {
+            JsSortKey::Date => SortKey::Date,
+            JsSortKey::Name => SortKey::Name,
+            JsSortKey::Duration => SortKey::Duration,
+            JsSortKey::Position => SortKey::Position,
+        }
+    }
+}
+
+/// Which clips `getClips` lists, and in what order. Every field is optional.
+#[napi(object)]
+pub struct JsListQuery {
+    /// Defaults to date.
+    pub sort: Option<JsSortKey>,
+    pub descending: Option<bool>,
+    /// Only clips recorded at or after this time, in milliseconds since the epoch as from
+    /// `Date.getTime()`.
+    pub since: Option<f64>,
+    /// Only clips recorded before this time, in milliseconds since the epoch.
+    pub until: Option<f64>,
+    /// Only clips whose name contains this, ignoring case.
+    pub name_contains: Option<String>,
+    /// Only clips in the collection with this ID.
+    pub collection: Option<u32>,
+    pub limit: Option<u32>,
+    pub offset: Option<u32>,
+}
+
+impl From<JsListQuery> for ListQuery {
+    fn from(query: JsListQuery) -> Self {
+        let date = |millis: f64| Utc.timestamp_millis_opt(millis as i64).single();
+        ListQuery {
+            sort: query.sort.map(Into::into).unwrap_or_default(),
+            descending: query.descending.unwrap_or(false),
+            since: query.since.and_then(date),
+            until: query.until.and_then(date),
+            name_contains: query.name_contains,
+            collection: query.collection.map(|id| id as usize),
+            limit: query.limit.map(|limit| limit as usize),
+            offset: query.offset.unwrap_or(0) as usize,
+        }
+    }
+}
+
+#[napi]
+pub enum JsRestoreMode {
+    Merge,
+    Replace,
+}
+
+impl From<JsRestoreMode> for RestoreMode {
+    fn from(mode: JsRestoreMode) -> Self {
+        match mode {
+            JsRestoreMode::Merge => RestoreMode::Merge,
+            JsRestoreMode::Replace => RestoreMode::Replace,
+        }
+    }
+}
+
+#[napi(object)]
+pub struct JsRestoreSummary {
+    pub restored: u32,
+    /// Clips that were already in the journal.
+    pub skipped: u32,
+    /// Clips restored under a new name.
+    pub renamed: u32,
+}
+
+#[napi]
+pub enum JsImportStatus {
+    Imported,
+    Skipped,
+    Failed,
+}
+
+/// What became of one imported file.
+#[napi(object)]
+pub struct JsImportResult {
+    pub path: String,
+    pub status: JsImportStatus,
+    /// The ID of the new clip, if imported.
+    pub id: Option<u32>,
+    /// The name of the new clip, or of the existing clip if skipped.
+    pub name: Option<String>,
+    /// Why the import failed, if it did.
+    pub error: Option<String>,
+}
+
+impl JsImportResult {
+    fn new<E: std::fmt::Display>(
+        path: String,
+        result: std::result::Result<ImportOutcome, E>,
+    ) -> Self {
+        let (status, id, name, error) = match result {
+            Ok(ImportOutcome::Imported(meta)) => (
+                JsImportStatus::Imported,
+                Some(meta.id as u32),
+                Some(meta.name),
+                None,
+            ),
+            Ok(ImportOutcome::Skipped(name)) => (JsImportStatus::Skipped, None, Some(name), None),
+            Err(err) => (JsImportStatus::Failed, None, None, Some(err.to_string())),
+        };
+        JsImportResult {
+            path,
+            status,
+            id,
+            name,
+            error,
+        }
+    }
+}
+
+#[napi(object)]
+#[derive(Default)]
+pub struct JsImportOptions {
+    /// Index of the track to import. Defaults to the file's default track.
+    pub track: Option<u32>,
+    /// Channel to import. Defaults to the first channel.
+    pub channel: Option<u32>,
+    /// What to do if there is already a clip with the same name. Defaults to failing.
+    pub conflict: Option<JsConflictPolicy>,
+    /// How to encode the clips. Defaults to voice.
+    pub profile: Option<JsEncodingProfile>,
+}
+
+impl From<JsImportOptions> for ImportOptions {
+    fn from(options: JsImportOptions) -> Self {
+        ImportOptions {
+            track: options.track.map(|track| track as usize),
+            channel: options.channel.unwrap_or(0) as usize,
+            conflict: options.conflict.map(Into::into).unwrap_or_default(),
+            profile: options.profile.map(Into::into).unwrap_or_default(),
+        }
+    }
+}
+
 #[napi]
 pub struct UiState {
     tab: Tab,
-    db: Db,
-    deleted_clip: Option<AudioClip>,
+    db: Arc<Mutex<Db>>,
+    /// Set to cancel the imports in progress.
+    import_cancel: Arc<AtomicBool>,
     update_cb: ThreadsafeFunction<(), ErrorStrategy::Fatal>,
     host: AudioBackend,
     language_processor: AsyncLanguageProcessor,
     render_mode: RenderMode,
+    /// How new recordings are encoded.
+    encoding_profile: EncodingProfile,
+    spectrogram_options: SpectrogramOptions,
+    render_options: JsRenderOptions,
 }
 
 #[napi]
@@ -85,6 +764,57 @@ pub struct JsSegment {
     pub t0: f64,
     pub t1: f64,
     pub segment: String,
+    /// The words with their own times, or empty if they could not be timed.
+    pub words: Vec<JsWord>,
+    /// The ID of the speaker, if the clip has been diarized.
+    pub speaker: Option<u32>,
+}
+
+impl From<Segment> for JsSegment {
+    fn from(segment: Segment) -> Self {
+        JsSegment {
+            t0: segment.start,
+            t1: segment.end,
+            segment: segment.text,
+            words: segment
+                .words
+                .into_iter()
+                .map(|word| JsWord {
+                    t0: word.start,
+                    t1: word.end,
+                    word: word.text,
+                })
+                .collect(),
+            speaker: segment.speaker.map(|speaker| speaker as u32),
+        }
+    }
+}
+
+#[napi(object)]
+#[derive(Clone)]
+pub struct JsWord {
+    pub t0: f64,
+    pub t1: f64,
+    pub word: String,
+}
+
+#[napi]
+pub enum JsTranscriptFormat {
+    Srt,
+    WebVtt,
+    Text,
+    Json,
+}
+
+impl From<JsTranscriptFormat> for TranscriptFormat {
+    fn from(format: JsTranscriptFormat) -> Self {
+        match format {
+            JsTranscriptFormat::Srt => TranscriptFormat::Srt,
+            JsTranscriptFormat::WebVtt => TranscriptFormat::WebVtt,
+            JsTranscriptFormat::Text => TranscriptFormat::Text,
+            JsTranscriptFormat::Json => TranscriptFormat::Json,
+        }
+    }
 }
 
 #[napi]
@@ -106,6 +836,17 @@ impl JsClipMeta {
     pub fn get_date(&self, env: Env) -> Result<JsDate> {
         env.create_date(self.0.date.timestamp_millis() as f64)
     }
+
+    #[napi(getter)]
+    pub fn get_notes(&self) -> Option<&str> {
+        self.0.notes.as_deref()
+    }
+
+    /// The duration in seconds.
+    #[napi(getter)]
+    pub fn get_duration(&self) -> f64 {
+        self.0.duration
+    }
 }
 
 impl From<ClipMeta> for JsClipMeta {
@@ -120,25 +861,173 @@ impl From<&AudioClip> for JsClipMeta {
             id: clip.id.unwrap_or(0),
             name: clip.name.clone(),
             date: clip.date,
+            notes: clip.notes.clone(),
+            duration: clip.samples.len() as f64 / clip.sample_rate as f64,
         })
     }
 }
 
+#[napi]
+pub struct JsSpeaker(Speaker);
+
+#[napi]
+impl JsSpeaker {
+    #[napi(getter)]
+    pub fn get_id(&self) -> usize {
+        self.0.id
+    }
+
+    #[napi(getter)]
+    pub fn get_name(&self) -> Option<&str> {
+        self.0.name.as_deref()
+    }
+
+    /// The speaker's name, or "Speaker 1" and so on.
+    #[napi(getter)]
+    pub fn get_label(&self) -> String {
+        self.0.label()
+    }
+
+    /// How long the speaker talks for, in seconds.
+    #[napi(getter)]
+    pub fn get_speaking_time(&self) -> f64 {
+        self.0.speaking_time
+    }
+}
+
+#[napi]
+pub struct JsSpeakerTurn(SpeakerTurn);
+
+#[napi]
+impl JsSpeakerTurn {
+    #[napi(getter)]
+    pub fn get_t0(&self) -> f64 {
+        self.0.start
+    }
+
+    #[napi(getter)]
+    pub fn get_t1(&self) -> f64 {
+        self.0.end
+    }
+
+    #[napi(getter)]
+    pub fn get_speaker(&self) -> usize {
+        self.0.speaker
+    }
+}
+
+#[napi]
+pub struct JsCollection(Collection);
+
+#[napi]
+impl JsCollection {
+    #[napi(getter)]
+    pub fn get_id(&self) -> usize {
+        self.0.id
+    }
+
+    #[napi(getter)]
+    pub fn get_name(&self) -> &str {
+        &self.0.name
+    }
+
+    /// The number of clips in the collection, not counting deleted ones.
+    #[napi(getter)]
+    pub fn get_clip_count(&self) -> usize {
+        self.0.clip_count
+    }
+}
+
+impl From<Collection> for JsCollection {
+    fn from(collection: Collection) -> Self {
+        JsCollection(collection)
+    }
+}
+
+#[napi]
+pub struct JsHistoryEntry(HistoryEntry);
+
+#[napi]
+impl JsHistoryEntry {
+    /// A description of the change, like "Rename "A" to "B"".
+    #[napi(getter)]
+    pub fn get_description(&self) -> String {
+        self.0.operation.to_string()
+    }
+
+    #[napi(getter)]
+    pub fn get_clip_id(&self) -> usize {
+        self.0.operation.clip_id()
+    }
+
+    #[napi(getter, ts_return_type = "Date")]
+    pub fn get_date(&self, env: Env) -> Result<JsDate> {
+        env.create_date(self.0.date.timestamp_millis() as f64)
+    }
+
+    /// Whether the change has been undone, so that it can be redone.
+    #[napi(getter)]
+    pub fn get_undone(&self) -> bool {
+        self.0.undone
+    }
+}
+
+impl From<HistoryEntry> for JsHistoryEntry {
+    fn from(entry: HistoryEntry) -> Self {
+        JsHistoryEntry(entry)
+    }
+}
+
+#[napi]
+pub struct JsUnfinishedRecording(UnfinishedRecording);
+
+#[napi]
+impl JsUnfinishedRecording {
+    #[napi(getter)]
+    pub fn get_id(&self) -> String {
+        self.0.id()
+    }
+
+    #[napi(getter)]
+    pub fn get_name(&self) -> &str {
+        &self.0.name
+    }
+
+    #[napi(getter, ts_return_type = "Date")]
+    pub fn get_date(&self, env: Env) -> Result<JsDate> {
+        env.create_date(self.0.date.timestamp_millis() as f64)
+    }
+
+    #[napi(getter)]
+    pub fn get_duration(&self) -> f64 {
+        self.0.duration()
+    }
+}
+
 #[napi]
 impl UiState {
+    /// Opens the database at `db_path` if given, or else the default database, unless
+    /// `in_memory` is set.
     #[napi(constructor)]
-    pub fn new(update_cb: JsFunction, log_cb: JsFunction, in_memory: bool) -> Result<UiState> {
+    pub fn new(
+        update_cb: JsFunction,
+        log_cb: JsFunction,
+        in_memory: bool,
+        db_path: Option<String>,
+    ) -> Result<UiState> {
         JsLogger::set_logger(log_cb)?;
 
         Ok(UiState {
             tab: Tab::Record { handle: None },
-            db: if in_memory {
-                Db::in_memory()
-            } else {
-                Db::open()
-            }
-            .map_err(|e| Error::from_reason(format!("{:?}", e)))?,
-            deleted_clip: None,
+            db: Arc::new(Mutex::new(
+                match (in_memory, db_path) {
+                    (true, _) => Db::in_memory(),
+                    (false, Some(path)) => Db::open_at(Path::new(&path)),
+                    (false, None) => Db::open(),
+                }
+                .map_err(|e| Error::from_reason(format!("{:?}", e)))?,
+            )),
+            import_cancel: Arc::new(AtomicBool::new(false)),
             update_cb: update_cb
                 .create_threadsafe_function(0, |_ctx| Ok(vec![] as Vec<JsUnknown>))?,
 
@@ -156,13 +1045,105 @@ impl UiState {
                 .map_err(|e| Error::from_reason(format!("{:?}", e)))?,
 
             render_mode: RenderMode::Waveform,
+            encoding_profile: EncodingProfile::default(),
+            spectrogram_options: SpectrogramOptions::default(),
+            render_options: JsRenderOptions::default(),
+        })
+    }
+
+    /// The database file of the open journal, or null if it is in memory.
+    #[napi(getter)]
+    pub fn get_db_path(&self) -> Option<String> {
+        self.db
+            .lock()
+            .unwrap()
+            .path()
+            .map(|path| path.to_string_lossy().into_owned())
+    }
+
+    /// Switch to the journal at `path`, creating it if needed, or to the default journal if
+    /// `path` is null. Playback stops, imports in progress are cancelled, and the record tab
+    /// is selected. Other tasks in progress finish in the journal they started in.
+    #[napi]
+    pub fn open_db(&mut self, path: Option<String>) -> Result<()> {
+        if let Tab::Record { handle: Some(_) } = &self.tab {
+            return Err(Error::from_reason(
+                "Stop recording before switching journals",
+            ));
+        }
+
+        let db = match path {
+            Some(path) => Db::open_at(Path::new(&path)),
+            None => Db::open(),
+        }
+        .map_err(|e| Error::from_reason(format!("{:?}", e)))?;
+
+        // Tasks in progress hold on to the journal they started with, so that they don't load
+        // from one journal and save to another.
+        self.cancel_imports();
+        self.db = Arc::new(Mutex::new(db));
+        self.tab = Tab::Record { handle: None };
+
+        self.update_cb
+            .call((), ThreadsafeFunctionCallMode::NonBlocking);
+
+        Ok(())
+    }
+
+    /// Whether the open journal is encrypted with a passphrase.
+    #[napi(getter)]
+    pub fn get_encrypted(&self) -> bool {
+        self.db.lock().unwrap().is_encrypted()
+    }
+
+    /// Whether the open journal is encrypted and waiting for `unlock`. Clips can't be listed,
+    /// played or saved until then.
+    #[napi(getter)]
+    pub fn get_locked(&self) -> bool {
+        self.db.lock().unwrap().is_locked()
+    }
+
+    #[napi]
+    pub fn unlock(&mut self, passphrase: String) -> Result<()> {
+        self.db
+            .lock()
+            .unwrap()
+            .unlock(&passphrase)
+            .map_err(|e| Error::from_reason(format!("{:?}", e)))?;
+
+        self.update_cb
+            .call((), ThreadsafeFunctionCallMode::NonBlocking);
+
+        Ok(())
+    }
+
+    /// Encrypt the clips of the open journal with `passphrase`. This rewrites every clip.
+    #[napi(ts_return_type = "Promise<void>")]
+    pub fn encrypt(&self, passphrase: String) -> AsyncTask<EncryptionTask> {
+        AsyncTask::new(EncryptionTask {
+            db: self.db.clone(),
+            passphrase: Some(passphrase),
+            update_cb: self.update_cb.clone(),
+        })
+    }
+
+    /// Store the clips of the open journal without encryption again. It must be unlocked.
+    #[napi(ts_return_type = "Promise<void>")]
+    pub fn decrypt(&self) -> AsyncTask<EncryptionTask> {
+        AsyncTask::new(EncryptionTask {
+            db: self.db.clone(),
+            passphrase: None,
+            update_cb: self.update_cb.clone(),
         })
     }
 
+    /// The clips that match `query`, or every clip by date if it is not given.
     #[napi]
-    pub fn get_clips(&self) -> Result<Vec<JsClipMeta>> {
+    pub fn get_clips(&self, query: Option<JsListQuery>) -> Result<Vec<JsClipMeta>> {
         self.db
-            .list()
+            .lock()
+            .unwrap()
+            .list(&query.map(Into::into).unwrap_or_default())
             .map_err(|e| Error::from_reason(format!("{:?}", e)))
             .map(|clips| clips.into_iter().map(JsClipMeta::from).collect())
     }
@@ -174,30 +1155,85 @@ impl UiState {
             Tab::Play { audio_clip, .. } | Tab::Pause { audio_clip, .. } => {
                 Some(audio_clip.id.expect("Saved clips must have IDs"))
             }
+            Tab::Load { meta, .. } => Some(meta.id),
         }
     }
 
-    #[napi(getter)]
-    pub fn get_current_clip(&self) -> Option<JsClipMeta> {
-        match &self.tab {
-            Tab::Record { .. } => None,
-            Tab::Play { audio_clip, .. } | Tab::Pause { audio_clip, .. } => {
-                Some(JsClipMeta::from(audio_clip))
-            }
-        }
+    #[napi(getter)]
+    pub fn get_current_clip(&self) -> Option<JsClipMeta> {
+        match &self.tab {
+            Tab::Record { .. } => None,
+            Tab::Play { audio_clip, .. } | Tab::Pause { audio_clip, .. } => {
+                Some(JsClipMeta::from(audio_clip))
+            }
+            Tab::Load { meta, .. } => Some(JsClipMeta::from(meta.clone())),
+        }
+    }
+
+    /// How new recordings are encoded.
+    #[napi(getter)]
+    pub fn get_encoding_profile(&self) -> JsEncodingProfile {
+        self.encoding_profile.into()
+    }
+
+    #[napi]
+    pub fn set_encoding_profile(&mut self, profile: JsEncodingProfile) {
+        self.encoding_profile = profile.into();
+
+        self.update_cb
+            .call((), ThreadsafeFunctionCallMode::NonBlocking);
+    }
+
+    #[napi(getter)]
+    pub fn get_render_mode(&self) -> RenderMode {
+        self.render_mode
+    }
+
+    #[napi]
+    pub fn set_render_mode(&mut self, render_mode: RenderMode) {
+        self.render_mode = render_mode;
+
+        self.update_cb
+            .call((), ThreadsafeFunctionCallMode::NonBlocking);
+    }
+
+    #[napi(getter)]
+    pub fn get_spectrogram_options(&self) -> JsSpectrogramOptions {
+        self.spectrogram_options.into()
+    }
+
+    #[napi]
+    pub fn set_spectrogram_options(&mut self, options: JsSpectrogramOptions) -> Result<()> {
+        let options = SpectrogramOptions::from(options);
+        options
+            .validate()
+            .map_err(|e| Error::from_reason(format!("{:?}", e)))?;
+        self.spectrogram_options = options;
+
+        self.update_cb
+            .call((), ThreadsafeFunctionCallMode::NonBlocking);
+
+        Ok(())
     }
 
     #[napi(getter)]
-    pub fn get_render_mode(&self) -> RenderMode {
-        self.render_mode
+    pub fn get_render_options(&self) -> JsRenderOptions {
+        self.render_options.clone()
     }
 
     #[napi]
-    pub fn set_render_mode(&mut self, render_mode: RenderMode) {
-        self.render_mode = render_mode;
+    pub fn set_render_options(&mut self, options: JsRenderOptions) -> Result<()> {
+        if let (Some(start), Some(end)) = (options.selection_start, options.selection_end) {
+            if !(0.0..=end).contains(&start) {
+                return Err(Error::from_reason("Invalid selection"));
+            }
+        }
+        self.render_options = options;
 
         self.update_cb
             .call((), ThreadsafeFunctionCallMode::NonBlocking);
+
+        Ok(())
     }
 
     #[napi(getter)]
@@ -205,21 +1241,57 @@ impl UiState {
         matches!(&self.tab, Tab::Record { .. })
     }
 
-    #[napi]
-    pub fn set_current_clip_id(&mut self, id: u32) -> Result<()> {
-        if let Some(audio_clip) = self
+    /// Select a clip. Its name and duration are available right away, and its audio once the
+    /// returned promise resolves. Returns null if there is no clip with this ID.
+    #[napi(ts_return_type = "Promise<void> | null")]
+    pub fn set_current_clip_id(&mut self, id: u32) -> Result<Option<AsyncTask<LoadClipTask>>> {
+        let clip = match self
             .db
-            .load_by_id(id as usize)
+            .lock()
+            .unwrap()
+            .load_lazy_by_id(id as usize)
             .map_err(|e| Error::from_reason(format!("{:?}", e)))?
         {
+            Some(clip) => clip,
+            None => return Ok(None),
+        };
+
+        let loaded = Arc::new(OnceLock::new());
+        self.tab = Tab::Load {
+            meta: clip.meta.clone(),
+            duration: clip.duration(),
+            audio_clip: loaded.clone(),
+        };
+        self.update_cb
+            .call((), ThreadsafeFunctionCallMode::NonBlocking);
+
+        Ok(Some(AsyncTask::new(LoadClipTask {
+            clip: Some(clip),
+            loaded: Some(loaded),
+            update_cb: self.update_cb.clone(),
+        })))
+    }
+
+    /// Pause on the clip selected with `set_current_clip_id`, if it has been decoded.
+    fn finish_loading(&mut self) {
+        match &self.tab {
+            Tab::Load { audio_clip, .. } if audio_clip.get().is_some() => {}
+            _ => return,
+        }
+        if let Tab::Load {
+            meta, audio_clip, ..
+        } = std::mem::take(&mut self.tab)
+        {
+            let mut audio_clip = match Arc::try_unwrap(audio_clip) {
+                Ok(loaded) => loaded.into_inner().expect("the clip was decoded"),
+                Err(loaded) => loaded.get().expect("the clip was decoded").clone(),
+            };
+            audio_clip.name = meta.name;
             self.tab = Tab::Pause {
                 audio_clip,
                 time: 0.0,
             };
-            self.update_cb
-                .call((), ThreadsafeFunctionCallMode::NonBlocking);
         }
-        Ok(())
     }
 
     #[napi]
@@ -231,6 +1303,7 @@ impl UiState {
 
     #[napi]
     pub fn play(&mut self, on_done: JsFunction) -> Result<()> {
+        self.finish_loading();
         self.tab = match std::mem::take(&mut self.tab) {
             Tab::Pause { audio_clip, time } => {
                 let new_handle = audio_clip
@@ -268,7 +1341,11 @@ impl UiState {
     pub fn record(&mut self) -> Result<()> {
         if let Tab::Record { handle } = &mut self.tab {
             let name = Local::now().format("%Y-%m-%d %H:%M:%S").to_string();
-            let new_handle = AudioClip::record(self.host, name)
+            let new_handle = self
+                .db
+                .lock()
+                .unwrap()
+                .start_recording(self.host, name)
                 .map_err(|e| Error::from_reason(format!("{:?}", e)))?;
 
             *handle = Some(new_handle);
@@ -282,6 +1359,7 @@ impl UiState {
 
     #[napi]
     pub fn seek(&mut self, time: f64) -> Result<()> {
+        self.finish_loading();
         match &mut self.tab {
             Tab::Play { handle, .. } => {
                 handle.seek(time);
@@ -291,7 +1369,7 @@ impl UiState {
             } => {
                 *paused_time = time;
             }
-            Tab::Record { .. } => {}
+            Tab::Record { .. } | Tab::Load { .. } => {}
         }
 
         self.update_cb
@@ -302,12 +1380,15 @@ impl UiState {
 
     #[napi]
     pub fn stop(&mut self) -> Result<()> {
+        self.finish_loading();
         self.tab = match std::mem::take(&mut self.tab) {
             Tab::Record { mut handle } => {
                 if let Some(handle) = handle.take() {
-                    let mut audio_clip = handle.stop();
-                    self.db
-                        .save(&mut audio_clip)
+                    let audio_clip = self
+                        .db
+                        .lock()
+                        .unwrap()
+                        .finish_recording(handle, self.encoding_profile)
                         .map_err(|e| Error::from_reason(format!("{:?}", e)))?;
 
                     Tab::Pause {
@@ -322,7 +1403,7 @@ impl UiState {
                 audio_clip,
                 time: handle.time(),
             },
-            Tab::Pause { audio_clip, time } => Tab::Pause { audio_clip, time },
+            tab @ (Tab::Pause { .. } | Tab::Load { .. }) => tab,
         };
 
         self.update_cb
@@ -333,19 +1414,23 @@ impl UiState {
 
     #[napi]
     pub fn delete_current_clip(&mut self) -> Result<()> {
+        self.finish_loading();
+        if let Tab::Load { .. } = self.tab {
+            return Err(Error::from_reason("The clip is still loading"));
+        }
         let mut tab = Tab::Record { handle: None };
         std::mem::swap(&mut tab, &mut self.tab);
 
         self.update_cb
             .call((), ThreadsafeFunctionCallMode::NonBlocking);
 
-        if let Tab::Play { mut audio_clip, .. } | Tab::Pause { mut audio_clip, .. } = tab {
+        if let Tab::Play { audio_clip, .. } | Tab::Pause { audio_clip, .. } = tab {
             if let Some(id) = audio_clip.id {
                 self.db
+                    .lock()
+                    .unwrap()
                     .delete_by_id(id)
                     .map_err(|e| Error::from_reason(format!("{:?}", e)))?;
-                audio_clip.id = None;
-                self.deleted_clip = Some(audio_clip);
             } else {
                 return Err(Error::from_reason("Clip is not saved to db"));
             }
@@ -356,50 +1441,272 @@ impl UiState {
         Ok(())
     }
 
+    /// Undo the last rename, delete, restore, notes change or change to a collection's clips
+    /// in the journal. Returns a description of it, or null if there was nothing to undo.
     #[napi]
-    pub fn undelete_current_clip(&mut self) -> Result<()> {
-        if let Some(mut audio_clip) = self.deleted_clip.take() {
-            self.db
-                .save(&mut audio_clip)
-                .map_err(|e| Error::from_reason(format!("{:?}", e)))?;
+    pub fn undo(&mut self) -> Result<Option<String>> {
+        let operation = self
+            .db
+            .lock()
+            .unwrap()
+            .undo()
+            .map_err(|e| Error::from_reason(format!("{:?}", e)))?;
+        self.after_history_step(operation.as_ref(), true)?;
 
-            self.tab = Tab::Pause {
-                audio_clip,
-                time: 0.0, // TODO: remember time?
-            };
+        Ok(operation.map(|operation| operation.to_string()))
+    }
 
-            self.update_cb
-                .call((), ThreadsafeFunctionCallMode::NonBlocking);
-        } else {
-            return Err(Error::from_reason("No clip to undelete"));
+    /// Redo the last undone change. Returns a description of it, or null if there was
+    /// nothing to redo.
+    #[napi]
+    pub fn redo(&mut self) -> Result<Option<String>> {
+        let operation = self
+            .db
+            .lock()
+            .unwrap()
+            .redo()
+            .map_err(|e| Error::from_reason(format!("{:?}", e)))?;
+        self.after_history_step(operation.as_ref(), false)?;
+
+        Ok(operation.map(|operation| operation.to_string()))
+    }
+
+    #[napi(getter)]
+    pub fn get_can_undo(&self) -> Result<bool> {
+        self.db
+            .lock()
+            .unwrap()
+            .can_undo()
+            .map_err(|e| Error::from_reason(format!("{:?}", e)))
+    }
+
+    #[napi(getter)]
+    pub fn get_can_redo(&self) -> Result<bool> {
+        self.db
+            .lock()
+            .unwrap()
+            .can_redo()
+            .map_err(|e| Error::from_reason(format!("{:?}", e)))
+    }
+
+    /// The changes that can be undone or redone, oldest first.
+    #[napi]
+    pub fn get_history(&self) -> Result<Vec<JsHistoryEntry>> {
+        Ok(self
+            .db
+            .lock()
+            .unwrap()
+            .history()
+            .map_err(|e| Error::from_reason(format!("{:?}", e)))?
+            .into_iter()
+            .map(JsHistoryEntry::from)
+            .collect())
+    }
+
+    /// Bring the current clip up to date after an undo or redo. A clip that was brought back
+    /// from the trash is selected, as it was before it was deleted.
+    fn after_history_step(&mut self, operation: Option<&Operation>, undo: bool) -> Result<()> {
+        let operation = match operation {
+            Some(operation) => operation,
+            None => return Ok(()),
+        };
+        self.finish_loading();
+        let id = operation.clip_id();
+        let restored = matches!(
+            (operation, undo),
+            (Operation::Delete { .. }, true) | (Operation::Restore { .. }, false)
+        );
+
+        if restored {
+            if let Some(audio_clip) = self
+                .db
+                .lock()
+                .unwrap()
+                .load_by_id(id)
+                .map_err(|e| Error::from_reason(format!("{:?}", e)))?
+            {
+                self.tab = Tab::Pause {
+                    audio_clip,
+                    time: 0.0,
+                };
+            }
+        } else if self.get_current_clip_id() == Some(id) {
+            match operation {
+                Operation::Delete { .. } | Operation::Restore { .. } => {
+                    self.tab = Tab::Record { handle: None };
+                }
+                Operation::Rename { from, to, .. } => {
+                    let name = if undo { from } else { to }.clone();
+                    match &mut self.tab {
+                        Tab::Play { audio_clip, .. } | Tab::Pause { audio_clip, .. } => {
+                            audio_clip.name = name;
+                        }
+                        Tab::Load { meta, .. } => meta.name = name,
+                        Tab::Record { .. } => {}
+                    }
+                }
+                Operation::EditNotes { from, to, .. } => {
+                    let notes = if undo { from } else { to }.clone();
+                    match &mut self.tab {
+                        Tab::Play { audio_clip, .. } | Tab::Pause { audio_clip, .. } => {
+                            audio_clip.notes = notes;
+                        }
+                        Tab::Load { meta, .. } => meta.notes = notes,
+                        Tab::Record { .. } => {}
+                    }
+                }
+                Operation::AddToCollection { .. } | Operation::RemoveFromCollection { .. } => {}
+            }
         }
+        self.update_cb
+            .call((), ThreadsafeFunctionCallMode::NonBlocking);
 
         Ok(())
     }
 
     #[napi]
     pub fn rename_current_clip(&mut self, new_name: String) -> Result<()> {
-        let clip_id;
+        let id = self
+            .get_current_clip_id()
+            .ok_or_else(|| Error::from_reason("No clip selected"))?;
+        self.db
+            .lock()
+            .unwrap()
+            .rename_by_id(id, &new_name)
+            .map_err(|e| Error::from_reason(format!("{:?}", e)))?;
 
-        if let Tab::Pause {
-            audio_clip: AudioClip { id: Some(id), .. },
-            ..
+        match &mut self.tab {
+            Tab::Play { audio_clip, .. } | Tab::Pause { audio_clip, .. } => {
+                audio_clip.name = new_name;
+            }
+            Tab::Load { meta, .. } => meta.name = new_name,
+            Tab::Record { .. } => {}
         }
-        | Tab::Play {
-            audio_clip: AudioClip { id: Some(id), .. },
-            ..
-        } = &mut self.tab
-        {
-            clip_id = *id;
+        self.update_cb
+            .call((), ThreadsafeFunctionCallMode::NonBlocking);
 
-            self.db
-                .rename_by_id(*id, &new_name)
-                .map_err(|e| Error::from_reason(format!("{:?}", e)))?;
-        } else {
-            return Err(Error::from_reason("No clip selected"));
-        }
+        Ok(())
+    }
+
+    /// Every collection, by name.
+    #[napi]
+    pub fn get_collections(&self) -> Result<Vec<JsCollection>> {
+        Ok(self
+            .db
+            .lock()
+            .unwrap()
+            .collections()
+            .map_err(|e| Error::from_reason(format!("{:?}", e)))?
+            .into_iter()
+            .map(JsCollection::from)
+            .collect())
+    }
+
+    /// The collections the clip with the given ID is in, by name.
+    #[napi]
+    pub fn get_clip_collections(&self, clip_id: u32) -> Result<Vec<JsCollection>> {
+        Ok(self
+            .db
+            .lock()
+            .unwrap()
+            .collections_of(clip_id as usize)
+            .map_err(|e| Error::from_reason(format!("{:?}", e)))?
+            .into_iter()
+            .map(JsCollection::from)
+            .collect())
+    }
+
+    /// The clips in a collection, in its order.
+    #[napi]
+    pub fn get_collection_clips(&self, id: u32) -> Result<Vec<JsClipMeta>> {
+        Ok(self
+            .db
+            .lock()
+            .unwrap()
+            .list_collection(id as usize)
+            .map_err(|e| Error::from_reason(format!("{:?}", e)))?
+            .into_iter()
+            .map(JsClipMeta::from)
+            .collect())
+    }
+
+    /// Create an empty collection, returning its ID.
+    #[napi]
+    pub fn create_collection(&self, name: String) -> Result<u32> {
+        let id = self
+            .db
+            .lock()
+            .unwrap()
+            .create_collection(&name)
+            .map_err(|e| Error::from_reason(format!("{:?}", e)))?;
+        self.update_cb
+            .call((), ThreadsafeFunctionCallMode::NonBlocking);
+
+        Ok(id as u32)
+    }
+
+    #[napi]
+    pub fn rename_collection(&self, id: u32, new_name: String) -> Result<()> {
+        self.db
+            .lock()
+            .unwrap()
+            .rename_collection(id as usize, &new_name)
+            .map_err(|e| Error::from_reason(format!("{:?}", e)))?;
+        self.update_cb
+            .call((), ThreadsafeFunctionCallMode::NonBlocking);
+
+        Ok(())
+    }
 
-        self.set_current_clip_id(clip_id as u32)?;
+    /// Delete a collection, keeping the clips in it.
+    #[napi]
+    pub fn delete_collection(&self, id: u32) -> Result<()> {
+        self.db
+            .lock()
+            .unwrap()
+            .delete_collection(id as usize)
+            .map_err(|e| Error::from_reason(format!("{:?}", e)))?;
+        self.update_cb
+            .call((), ThreadsafeFunctionCallMode::NonBlocking);
+
+        Ok(())
+    }
+
+    /// Add a clip to the end of a collection.
+    #[napi]
+    pub fn add_to_collection(&self, id: u32, clip_id: u32) -> Result<()> {
+        self.db
+            .lock()
+            .unwrap()
+            .add_to_collection(id as usize, clip_id as usize)
+            .map_err(|e| Error::from_reason(format!("{:?}", e)))?;
+        self.update_cb
+            .call((), ThreadsafeFunctionCallMode::NonBlocking);
+
+        Ok(())
+    }
+
+    #[napi]
+    pub fn remove_from_collection(&self, id: u32, clip_id: u32) -> Result<()> {
+        self.db
+            .lock()
+            .unwrap()
+            .remove_from_collection(id as usize, clip_id as usize)
+            .map_err(|e| Error::from_reason(format!("{:?}", e)))?;
+        self.update_cb
+            .call((), ThreadsafeFunctionCallMode::NonBlocking);
+
+        Ok(())
+    }
+
+    /// Move a clip within a collection to `position`, counting from 0.
+    #[napi]
+    pub fn move_in_collection(&self, id: u32, clip_id: u32, position: u32) -> Result<()> {
+        self.db
+            .lock()
+            .unwrap()
+            .move_in_collection(id as usize, clip_id as usize, position as usize)
+            .map_err(|e| Error::from_reason(format!("{:?}", e)))?;
         self.update_cb
             .call((), ThreadsafeFunctionCallMode::NonBlocking);
 
@@ -414,14 +1721,32 @@ impl UiState {
             Tab::Record { handle: None } => None,
             Tab::Play { audio_clip, .. } => Some(audio_clip),
             Tab::Pause { audio_clip, .. } => Some(audio_clip),
+            Tab::Load { audio_clip, .. } => audio_clip
+                .get()
+                .map(|audio_clip| audio_clip as &dyn ClipHandle),
         }
     }
 
     #[napi]
     pub fn draw_current_clip(&mut self, width: u32, height: u32) -> Result<Option<Buffer>> {
-        let width = width as usize;
-        let height = height as usize;
+        Ok(self
+            .render(width as usize, height as usize)?
+            .map(|image| image.buffer.into()))
+    }
+
+    /// Like `draw_current_clip`, but also returns axis ticks.
+    #[napi]
+    pub fn render_current_clip(
+        &mut self,
+        width: u32,
+        height: u32,
+    ) -> Result<Option<JsRenderedImage>> {
+        Ok(self
+            .render(width as usize, height as usize)?
+            .map(JsRenderedImage::from))
+    }
 
+    fn render(&self, width: usize, height: usize) -> Result<Option<RenderedImage>> {
         let clip = match self.clip() {
             Some(clip) => clip,
             None => {
@@ -430,18 +1755,46 @@ impl UiState {
         };
 
         if width == 0 || height == 0 {
-            return Ok(Some(vec![].into()));
+            return Ok(Some(RenderedImage::default()));
         }
 
+        let sample_rate = clip.sample_rate() as f64;
+        let to_samples = |seconds: f64| (seconds * sample_rate).round() as usize;
+        let js_options = &self.render_options;
+        let render_options = RenderOptions {
+            theme: match js_options.theme {
+                RenderTheme::Light => Theme::Light,
+                RenderTheme::Dark => Theme::Dark,
+            },
+            show_rms: js_options.show_rms,
+            show_grid: js_options.show_grid,
+            selection: match (js_options.selection_start, js_options.selection_end) {
+                (Some(start), Some(end)) => Some((to_samples(start), to_samples(end))),
+                _ => None,
+            },
+            playhead: if js_options.show_playhead {
+                Some(to_samples(self.get_time()))
+            } else {
+                None
+            },
+        };
+
         match self.render_mode {
-            RenderMode::Waveform => Ok(Some(
-                clip.render_waveform((self.x1_samples(), self.x2_samples()), width, height)
-                    .into(),
-            )),
+            RenderMode::Waveform => Ok(Some(clip.render_waveform(
+                (self.x1_samples(), self.x2_samples()),
+                width,
+                height,
+                &render_options,
+            ))),
             RenderMode::Spectrogram => Ok(Some(
-                clip.render_spectrogram((self.x1_samples(), self.x2_samples()), width, height)
-                    .map_err(|err| Error::from_reason(format!("{:?}", err)))?
-                    .into(),
+                clip.render_spectrogram(
+                    (self.x1_samples(), self.x2_samples()),
+                    width,
+                    height,
+                    &self.spectrogram_options,
+                    &render_options,
+                )
+                .map_err(|err| Error::from_reason(format!("{:?}", err)))?,
             )),
         }
     }
@@ -464,12 +1817,15 @@ impl UiState {
             } => handle.time(),
             Tab::Play { handle, .. } => handle.time(),
             Tab::Pause { time, .. } => *time,
-            Tab::Record { handle: None } => 0f64,
+            Tab::Record { handle: None } | Tab::Load { .. } => 0f64,
         }
     }
 
     #[napi(getter)]
     pub fn get_duration(&self) -> f64 {
+        if let Tab::Load { duration, .. } = &self.tab {
+            return *duration;
+        }
         match self.clip() {
             Some(clip) => (clip.num_samples() as f64) / (clip.sample_rate() as f64),
             None => 0f64,
@@ -483,6 +1839,9 @@ impl UiState {
 
     #[napi(getter)]
     pub fn get_time_end(&self) -> f32 {
+        if let Tab::Load { duration, .. } = &self.tab {
+            return duration.max(10.0) as f32;
+        }
         self.clip()
             .map(|clip| {
                 (clip.num_samples().max(clip.sample_rate() * 10) as f32) / clip.sample_rate() as f32
@@ -504,15 +1863,43 @@ impl UiState {
             }
             Tab::Play { audio_clip, .. } => audio_clip as &AudioClip,
             Tab::Pause { audio_clip, .. } => audio_clip as &AudioClip,
+            Tab::Load { audio_clip, .. } => match audio_clip.get() {
+                Some(audio_clip) => audio_clip,
+                None => return Ok(None),
+            },
         };
 
         let clip = clip.clone();
-
-        Ok(Some(AsyncTask::new(TranscriptionTask(Some(
-            self.language_processor
-                .transcribe(clip)
+        let turns = match clip.id {
+            Some(id) => self
+                .db
+                .lock()
+                .unwrap()
+                .speaker_turns(id)
                 .map_err(|err| Error::from_reason(format!("{:?}", err)))?,
-        )))))
+            None => vec![],
+        };
+
+        Ok(Some(AsyncTask::new(TranscriptionTask(
+            Some(
+                self.language_processor
+                    .transcribe(clip)
+                    .map_err(|err| Error::from_reason(format!("{:?}", err)))?,
+            ),
+            turns,
+        ))))
+    }
+
+    /// Number of frames dropped by the current recording, to detect glitches.
+    #[napi(getter)]
+    pub fn get_dropped_frames(&self) -> u32 {
+        match &self.tab {
+            Tab::Record {
+                handle: Some(handle),
+            } => handle.dropped_frames() as u32,
+            Tab::Play { handle, .. } => handle.dropped_frames() as u32,
+            Tab::Record { handle: None } | Tab::Pause { .. } | Tab::Load { .. } => 0,
+        }
     }
 
     #[napi(getter)]
@@ -520,35 +1907,154 @@ impl UiState {
         match &self.tab {
             Tab::Record { handle } => handle.is_some(),
             Tab::Play { .. } => true,
-            Tab::Pause { .. } => false,
+            Tab::Pause { .. } | Tab::Load { .. } => false,
         }
     }
 
     #[napi]
     pub fn import(&mut self, path: String) -> Result<()> {
-        let name = Path::new(&path)
-            .file_stem()
-            .ok_or_else(|| Error::from_reason(format!("Invalid path: {}", path)))?
-            .to_str()
-            .ok_or_else(|| Error::from_reason("Path is not utf8"))?
-            .to_string();
-
-        if self
-            .db
-            .load(&name)
+        let db = self.db.lock().unwrap();
+        let outcome = import::import_file(
+            &db,
+            None,
+            Path::new(&path),
+            &ImportOptions::default(),
+            &self.import_cancel,
+            |_| {},
+        )
+        .map_err(|err| Error::from_reason(format!("{:?}", err)))?;
+        let ImportOutcome::Imported(meta) = outcome else {
+            return Err(Error::from_reason("Import was skipped"));
+        };
+        let audio_clip = db
+            .load_by_id(meta.id)
             .map_err(|err| Error::from_reason(format!("{:?}", err)))?
-            .is_some()
-        {
-            return Err(Error::from_reason(format!(
-                "There is already a clip named {}",
-                name
-            )));
-        }
-        let mut audio_clip = AudioClip::import(name, path)
-            .map_err(|err| Error::from_reason(format!("{:?}", err)))?;
+            .ok_or_else(|| Error::from_reason("Imported clip is missing"))?;
+        drop(db);
+
+        self.tab = Tab::Pause {
+            audio_clip,
+            time: 0f64,
+        };
+        self.update_cb
+            .call((), ThreadsafeFunctionCallMode::NonBlocking);
+
+        Ok(())
+    }
+
+    /// Import a file without blocking the UI. Resolves with a `JsImportResult` whose status
+    /// is never `Failed`, since failures reject. The new clip is not selected. It is named
+    /// after the file's title tag, or else the file name. `on_progress` is called with a
+    /// `JsImportProgress` as the file is decoded.
+    #[napi(ts_return_type = "Promise<JsImportResult>")]
+    pub fn import_async(
+        &self,
+        path: String,
+        on_progress: JsFunction,
+        options: Option<JsImportOptions>,
+    ) -> Result<AsyncTask<ImportTask>> {
+        let on_progress = on_progress
+            .create_threadsafe_function(0, |ctx: ThreadSafeCallContext<JsImportProgress>| {
+                Ok(vec![ctx.value])
+            })?;
+
+        Ok(AsyncTask::new(ImportTask {
+            db: self.db.clone(),
+            path,
+            options: options.unwrap_or_default().into(),
+            cancel: self.import_cancel.clone(),
+            on_progress,
+        }))
+    }
+
+    /// Import several files, one after the other, without blocking the UI. Resolves with a
+    /// `JsImportResult` for each file, in order; a file that fails does not stop the others.
+    /// `on_progress` is called with a `JsImportProgress` whose `index` is the file being
+    /// decoded.
+    #[napi(ts_return_type = "Promise<JsImportResult[]>")]
+    pub fn import_many(
+        &self,
+        paths: Vec<String>,
+        on_progress: JsFunction,
+        options: Option<JsImportOptions>,
+    ) -> Result<AsyncTask<BatchImportTask>> {
+        let on_progress = on_progress
+            .create_threadsafe_function(0, |ctx: ThreadSafeCallContext<JsImportProgress>| {
+                Ok(vec![ctx.value])
+            })?;
+
+        Ok(AsyncTask::new(BatchImportTask {
+            db: self.db.clone(),
+            paths,
+            options: options.unwrap_or_default().into(),
+            cancel: self.import_cancel.clone(),
+            on_progress,
+        }))
+    }
+
+    /// Cancel all imports started with `import_async` or `import_many`. Promises from
+    /// `import_async` reject; those from `import_many` resolve with the files imported so
+    /// far, and the one that was cancelled.
+    #[napi]
+    pub fn cancel_imports(&mut self) {
+        self.import_cancel.store(true, Ordering::Relaxed);
+        self.import_cancel = Arc::new(AtomicBool::new(false));
+    }
+
+    /// Back up every clip to an archive at `path`. Resolves with the number of clips.
+    #[napi(ts_return_type = "Promise<number>")]
+    pub fn backup(&self, path: String) -> AsyncTask<BackupTask> {
+        AsyncTask::new(BackupTask {
+            db: self.db.clone(),
+            path,
+        })
+    }
+
+    /// Whether the archive at `path` is a backup of an encrypted journal, which `restore`
+    /// needs the passphrase of.
+    #[napi]
+    pub fn is_backup_encrypted(&self, path: String) -> Result<bool> {
+        backup::is_encrypted(Path::new(&path)).map_err(|e| Error::from_reason(format!("{:?}", e)))
+    }
+
+    /// Restore clips from an archive made by `backup`, opening it with `passphrase` if it is
+    /// encrypted. Nothing changes if the archive is damaged.
+    #[napi(ts_return_type = "Promise<JsRestoreSummary>")]
+    pub fn restore(
+        &self,
+        path: String,
+        mode: JsRestoreMode,
+        passphrase: Option<String>,
+    ) -> AsyncTask<RestoreTask> {
+        AsyncTask::new(RestoreTask {
+            db: self.db.clone(),
+            path,
+            mode: mode.into(),
+            passphrase,
+            update_cb: self.update_cb.clone(),
+        })
+    }
+
+    /// Recordings that were interrupted before they were saved.
+    #[napi]
+    pub fn get_unfinished_recordings(&self) -> Result<Vec<JsUnfinishedRecording>> {
         self.db
-            .save(&mut audio_clip)
-            .map_err(|err| Error::from_reason(format!("{:?}", err)))?;
+            .lock()
+            .unwrap()
+            .unfinished_recordings()
+            .map_err(|e| Error::from_reason(format!("{:?}", e)))
+            .map(|recordings| recordings.into_iter().map(JsUnfinishedRecording).collect())
+    }
+
+    /// Save an interrupted recording as a clip, and select it.
+    #[napi]
+    pub fn recover_recording(&mut self, id: String) -> Result<()> {
+        let audio_clip = self
+            .db
+            .lock()
+            .unwrap()
+            .recover_recording(&id)
+            .map_err(|e| Error::from_reason(format!("{:?}", e)))?;
 
         self.tab = Tab::Pause {
             audio_clip,
@@ -560,26 +2066,30 @@ impl UiState {
         Ok(())
     }
 
+    #[napi]
+    pub fn discard_recording(&mut self, id: String) -> Result<()> {
+        self.db
+            .lock()
+            .unwrap()
+            .discard_recording(&id)
+            .map_err(|e| Error::from_reason(format!("{:?}", e)))?;
+
+        self.update_cb
+            .call((), ThreadsafeFunctionCallMode::NonBlocking);
+
+        Ok(())
+    }
+
     #[napi]
     pub fn export(&mut self, id: u32) -> Result<String> {
         if let Some(clip) = self
             .db
+            .lock()
+            .unwrap()
             .load_by_id(id as usize)
             .map_err(|err| Error::from_reason(format!("{:?}", err)))?
         {
-            let temp_dir = std::env::temp_dir();
-            let safe_name = Path::new(&clip.name)
-                .file_name()
-                .unwrap_or_else(|| OsStr::new("invalid"))
-                .to_str()
-                .ok_or_else(|| Error::from_reason("Path is not valid utf8"))?
-                .to_string();
-            let filename = format!("{}_{}.wav", clip.id.unwrap_or(0), safe_name);
-            let tmp_path = temp_dir.join(Path::new(&filename));
-            let tmp_path = tmp_path
-                .to_str()
-                .ok_or_else(|| Error::from_reason("Path is not utf8"))?
-                .to_string();
+            let tmp_path = export_path(&clip, "wav")?;
 
             clip.export(&tmp_path)
                 .map_err(|err| Error::from_reason(format!("{}", err)))?;
@@ -589,4 +2099,109 @@ impl UiState {
             Err(Error::from_reason(format!("No clip with ID {}", id)))
         }
     }
+
+    /// Transcribe the clip with the given ID and write the transcript to a temporary file,
+    /// resolving to its path.
+    #[napi(ts_return_type = "Promise<string>")]
+    pub fn export_transcript(
+        &self,
+        id: u32,
+        format: JsTranscriptFormat,
+    ) -> Result<AsyncTask<TranscriptExportTask>> {
+        let db = self.db.lock().unwrap();
+        let clip = db
+            .load_by_id(id as usize)
+            .map_err(|err| Error::from_reason(format!("{:?}", err)))?
+            .ok_or_else(|| Error::from_reason(format!("No clip with ID {}", id)))?;
+        let turns = db
+            .speaker_turns(id as usize)
+            .map_err(|err| Error::from_reason(format!("{:?}", err)))?;
+        let speakers = db
+            .speakers(id as usize)
+            .map_err(|err| Error::from_reason(format!("{:?}", err)))?;
+        let format: TranscriptFormat = format.into();
+        let path = export_path(&clip, format.extension())?;
+
+        Ok(AsyncTask::new(TranscriptExportTask {
+            handle: Some(
+                self.language_processor
+                    .transcribe(clip)
+                    .map_err(|err| Error::from_reason(format!("{:?}", err)))?,
+            ),
+            turns,
+            speakers,
+            format,
+            path,
+        }))
+    }
+
+    /// Work out who speaks when in the clip with the given ID, resolving to its speakers.
+    /// The number of speakers is estimated if not given. Names given to speakers before are
+    /// forgotten.
+    #[napi(ts_return_type = "Promise<JsSpeaker[]>")]
+    pub fn diarize(&self, id: u32, num_speakers: Option<u32>) -> AsyncTask<DiarizationTask> {
+        AsyncTask::new(DiarizationTask {
+            db: self.db.clone(),
+            id: id as usize,
+            options: DiarizationOptions {
+                num_speakers: num_speakers.map(|n| n as usize),
+                ..Default::default()
+            },
+        })
+    }
+
+    /// The speakers in the clip with the given ID, or none if it hasn't been diarized.
+    #[napi]
+    pub fn get_speakers(&self, id: u32) -> Result<Vec<JsSpeaker>> {
+        Ok(self
+            .db
+            .lock()
+            .unwrap()
+            .speakers(id as usize)
+            .map_err(|e| Error::from_reason(format!("{:?}", e)))?
+            .into_iter()
+            .map(JsSpeaker)
+            .collect())
+    }
+
+    /// Who speaks when in the clip with the given ID.
+    #[napi]
+    pub fn get_speaker_turns(&self, id: u32) -> Result<Vec<JsSpeakerTurn>> {
+        Ok(self
+            .db
+            .lock()
+            .unwrap()
+            .speaker_turns(id as usize)
+            .map_err(|e| Error::from_reason(format!("{:?}", e)))?
+            .into_iter()
+            .map(JsSpeakerTurn)
+            .collect())
+    }
+
+    /// Name a speaker in the clip with the given ID, or remove their name.
+    #[napi]
+    pub fn name_speaker(&self, id: u32, speaker: u32, name: Option<String>) -> Result<()> {
+        self.db
+            .lock()
+            .unwrap()
+            .name_speaker(id as usize, speaker as usize, name.as_deref())
+            .map_err(|e| Error::from_reason(format!("{:?}", e)))
+    }
+}
+
+/// A path in the temporary directory to export a clip to, named after it.
+fn export_path(clip: &AudioClip, extension: &str) -> Result<String> {
+    let safe_name = Path::new(&clip.name)
+        .file_name()
+        .unwrap_or_else(|| OsStr::new("invalid"))
+        .to_str()
+        .ok_or_else(|| Error::from_reason("Path is not valid utf8"))?
+        .to_string();
+    let filename = format!("{}_{}.{}", clip.id.unwrap_or(0), safe_name, extension);
+    let tmp_path = std::env::temp_dir().join(Path::new(&filename));
+
+    Ok(tmp_path
+        .to_str()
+        .ok_or_else(|| Error::from_reason("Path is not utf8"))?
+        .to_string())
 }
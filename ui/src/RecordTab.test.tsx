@@ -1,6 +1,6 @@
 import React from "react";
 import { render, fireEvent } from "@testing-library/react";
-import { RenderMode } from "oxygen-core";
+import { JsEncodingProfile, RenderMode } from "oxygen-core";
 
 import RecordTab from "./RecordTab";
 
@@ -15,6 +15,8 @@ describe("RecordTab", () => {
     const recordTab = render(
       <RecordTab
         drawCurrentClip={handleDrawCurrentClip}
+        encodingProfile={JsEncodingProfile.Voice}
+        onSetEncodingProfile={() => {}}
         streaming={true}
         onRecord={handleRecord}
         onStop={handleStop}
@@ -43,6 +45,8 @@ describe("RecordTab", () => {
     const recordTab = render(
       <RecordTab
         drawCurrentClip={handleDrawCurrentClip}
+        encodingProfile={JsEncodingProfile.Voice}
+        onSetEncodingProfile={() => {}}
         streaming={false}
         onRecord={handleRecord}
         onStop={handleStop}
@@ -60,4 +64,31 @@ describe("RecordTab", () => {
     expect(handleStop).toHaveBeenCalledTimes(0);
     expect(handleRecord).toHaveBeenCalledTimes(1);
   });
+
+  it("changes the encoding profile", () => {
+    const handleSetEncodingProfile = jest.fn();
+
+    const recordTab = render(
+      <RecordTab
+        drawCurrentClip={() => null}
+        encodingProfile={JsEncodingProfile.Voice}
+        onSetEncodingProfile={handleSetEncodingProfile}
+        streaming={false}
+        onRecord={() => {}}
+        onStop={() => {}}
+        renderMode={RenderMode.Waveform}
+        onSetRenderMode={() => {}}
+        timeStart={0}
+        timeEnd={1}
+      />,
+    );
+
+    fireEvent.change(recordTab.getByTestId("encoding-profile"), {
+      target: { value: String(JsEncodingProfile.Lossless) },
+    });
+
+    expect(handleSetEncodingProfile).toHaveBeenCalledWith(
+      JsEncodingProfile.Lossless,
+    );
+  });
 });
@@ -1,24 +1,28 @@
 import React from "react";
 import cx from "classnames";
-import { RenderMode } from "oxygen-core";
+import { JsEncodingProfile, RenderMode } from "oxygen-core";
 
 import { Record, Stop } from "./icons";
 import AudioView from "./AudioView";
 
 export default function RecordTab({
   drawCurrentClip,
+  encodingProfile,
   streaming,
   onRecord,
   onStop,
+  onSetEncodingProfile,
   onSetRenderMode,
   renderMode,
   timeStart,
   timeEnd,
 }: {
   drawCurrentClip: (width: number, height: number) => Buffer | null;
+  encodingProfile: JsEncodingProfile;
   streaming: boolean;
   onRecord: () => void;
   onStop: () => void;
+  onSetEncodingProfile: (profile: JsEncodingProfile) => void;
   onSetRenderMode: (renderMode: RenderMode) => void;
   renderMode: RenderMode;
   timeStart: number;
@@ -71,7 +75,28 @@ export default function RecordTab({
             </>
           )}
         </button>
-        <div className="flex-grow" />
+        <div className="flex-grow flex items-center justify-end mr-4">
+          <label className="mr-2" htmlFor="encoding-profile">
+            Quality
+          </label>
+          <select
+            id="encoding-profile"
+            data-testid="encoding-profile"
+            className="border-2 rounded-md p-1"
+            disabled={streaming}
+            value={encodingProfile}
+            onChange={(ev) => {
+              onSetEncodingProfile(Number(ev.target.value));
+            }}
+          >
+            <option value={JsEncodingProfile.Voice}>Voice</option>
+            <option value={JsEncodingProfile.Music}>Music</option>
+            <option value={JsEncodingProfile.HighFidelity}>
+              High fidelity
+            </option>
+            <option value={JsEncodingProfile.Lossless}>Lossless</option>
+          </select>
+        </div>
       </div>
     </div>
   );
@@ -1,4 +1,10 @@
-import { RenderMode, UiState } from "oxygen-core";
+import {
+  JsConflictPolicy,
+  JsEncodingProfile,
+  JsImportStatus,
+  RenderMode,
+  UiState,
+} from "oxygen-core";
 import React, { useState, useCallback, useRef, useReducer } from "react";
 import cx from "classnames";
 
@@ -6,6 +12,12 @@ import Toaster, { ToasterInterface } from "./Toaster";
 import ClipList from "./ClipList";
 import RecordTab from "./RecordTab";
 import CurrentClip from "./CurrentClip";
+import Unlock from "./Unlock";
+
+// Video files are accepted too, their audio track is imported.
+function isImportable(item: DataTransferItem) {
+  return item.type.startsWith("audio/") || item.type.startsWith("video/");
+}
 
 function nativeLog(level: string, log: string) {
   if (level === "error") {
@@ -54,13 +66,30 @@ export default function Main({ inMemory }: { inMemory: boolean }) {
     [uiState],
   );
 
+  const handleUndo = useCallback(() => {
+    try {
+      const undone = uiState.undo();
+      if (undone !== null) {
+        toaster.current.info(`Undid: ${undone}`);
+      }
+    } catch (err) {
+      if (err instanceof Error) {
+        toaster.current.error(`Could not undo: ${err.message}`);
+      }
+    }
+  }, [uiState]);
+
   const handleRename = useCallback(
     (name: string) => {
       try {
         const prevName = uiState.currentClip.name;
         if (name !== prevName) {
           uiState.renameCurrentClip(name);
-          toaster.current.info(`Renamed "${prevName}" to "${name}"`);
+          toaster.current.info(
+            `Renamed "${prevName}" to "${name}"`,
+            { text: "Undo", cb: handleUndo },
+            "undoRenameCurrentClip",
+          );
         }
       } catch (err) {
         if (err instanceof Error) {
@@ -75,7 +104,7 @@ export default function Main({ inMemory }: { inMemory: boolean }) {
         }
       }
     },
-    [uiState],
+    [uiState, handleUndo],
   );
 
   const handleDelete = useCallback(() => {
@@ -85,13 +114,11 @@ export default function Main({ inMemory }: { inMemory: boolean }) {
       `Deleted "${prevName}"`,
       {
         text: "Undo",
-        cb: () => {
-          uiState.undeleteCurrentClip();
-        },
+        cb: handleUndo,
       },
       "undoDeleteCurrentClip",
     );
-  }, [uiState]);
+  }, [uiState, handleUndo]);
 
   const handleSetRenderMode = useCallback(
     (renderMode: RenderMode) => {
@@ -100,6 +127,13 @@ export default function Main({ inMemory }: { inMemory: boolean }) {
     [uiState],
   );
 
+  const handleSetEncodingProfile = useCallback(
+    (profile: JsEncodingProfile) => {
+      uiState.setEncodingProfile(profile);
+    },
+    [uiState],
+  );
+
   const handleRecord = useCallback(() => {
     uiState.record();
   }, [uiState]);
@@ -110,7 +144,10 @@ export default function Main({ inMemory }: { inMemory: boolean }) {
 
   const handleSetCurrentClipId = useCallback(
     (clipId: number) => {
-      uiState.setCurrentClipId(clipId);
+      // The clip is shown right away, and drawn once its audio is decoded.
+      uiState.setCurrentClipId(clipId)?.catch((err: Error) => {
+        toaster.current.error(`Could not load this clip: ${err.toString()}`);
+      });
     },
     [uiState],
   );
@@ -122,18 +159,34 @@ export default function Main({ inMemory }: { inMemory: boolean }) {
     [uiState],
   );
 
+  const handleUnlock = useCallback(
+    (passphrase: string) => {
+      try {
+        uiState.unlock(passphrase);
+        return true;
+      } catch (err) {
+        return false;
+      }
+    },
+    [uiState],
+  );
+
   const [dragOver, setDragOver] = useState<boolean | "invalid">(false);
 
+  if (uiState.locked) {
+    return (
+      <div className="w-full h-full flex">
+        <Unlock onUnlock={handleUnlock} />
+      </div>
+    );
+  }
+
   return (
     <div
       className="w-full h-full flex flex-row"
       onDragOver={(ev) => {
         ev.preventDefault();
-        if (
-          [...ev.dataTransfer.items].filter(
-            (item) => item.type === "audio/wav" || item.type === "audio/mpeg",
-          ).length > 0
-        ) {
+        if ([...ev.dataTransfer.items].filter(isImportable).length > 0) {
           setDragOver(true);
         } else {
           setDragOver("invalid");
@@ -144,16 +197,10 @@ export default function Main({ inMemory }: { inMemory: boolean }) {
         setDragOver(false);
       }}
       onDrop={(ev) => {
+        const files = [];
         for (const item of ev.dataTransfer.items) {
-          if (item.type === "audio/wav" || item.type === "audio/mpeg") {
-            try {
-              uiState.import(item.getAsFile().path);
-              toaster.current.info(`Imported ${item.getAsFile().name}.`);
-            } catch (err) {
-              toaster.current.error(
-                `Could not import ${item.getAsFile().name}: ${err.toString()}`,
-              );
-            }
+          if (isImportable(item)) {
+            files.push(item.getAsFile());
           } else {
             toaster.current.error(
               `Count not import ${
@@ -163,6 +210,43 @@ export default function Main({ inMemory }: { inMemory: boolean }) {
           }
         }
         setDragOver(false);
+        if (files.length === 0) {
+          return;
+        }
+
+        const onError = (err: Error) => {
+          toaster.current.error(`Could not import files: ${err.toString()}`);
+        };
+        try {
+          uiState
+            .importMany(
+              files.map((file) => file.path),
+              () => {},
+              {
+                conflict: JsConflictPolicy.Rename,
+                profile: uiState.encodingProfile,
+              },
+            )
+            .then((results) => {
+              results.forEach((result, i) => {
+                if (result.status === JsImportStatus.Imported) {
+                  toaster.current.info(`Imported ${files[i].name}.`);
+                } else {
+                  toaster.current.error(
+                    `Could not import ${files[i].name}: ${result.error}`,
+                  );
+                }
+              });
+              const imported = results.filter(
+                (result) => result.status === JsImportStatus.Imported,
+              );
+              if (imported.length > 0) {
+                handleSetCurrentClipId(imported[imported.length - 1].id);
+              }
+            }, onError);
+        } catch (err) {
+          onError(err);
+        }
       }}
     >
       <ClipList
@@ -196,8 +280,10 @@ export default function Main({ inMemory }: { inMemory: boolean }) {
           streaming={uiState.streaming}
           renderMode={uiState.renderMode}
           drawCurrentClip={drawCurrentClip}
+          encodingProfile={uiState.encodingProfile}
           onRecord={handleRecord}
           onStop={handleStop}
+          onSetEncodingProfile={handleSetEncodingProfile}
           onSetRenderMode={handleSetRenderMode}
           timeStart={Number(uiState.timeStart)}
           timeEnd={Number(uiState.timeEnd)}
@@ -0,0 +1,46 @@
+import React, { useState } from "react";
+
+export default function Unlock({
+  onUnlock,
+}: {
+  onUnlock: (passphrase: string) => boolean;
+}) {
+  const [passphrase, setPassphrase] = useState("");
+  const [wrong, setWrong] = useState(false);
+
+  return (
+    <form
+      className="m-auto flex flex-col w-80"
+      onSubmit={(ev) => {
+        ev.preventDefault();
+        if (!onUnlock(passphrase)) {
+          setWrong(true);
+          setPassphrase("");
+        }
+      }}
+    >
+      <label className="mb-2 text-lg" htmlFor="unlock-passphrase">
+        This journal is encrypted. Enter its passphrase to open it.
+      </label>
+      <input
+        id="unlock-passphrase"
+        data-testid="unlock-passphrase"
+        className="border-2 rounded-md p-2"
+        type="password"
+        autoFocus
+        value={passphrase}
+        onChange={(ev) => {
+          setPassphrase(ev.target.value);
+          setWrong(false);
+        }}
+      />
+      {wrong && <div className="mt-2 text-red-900">Wrong passphrase.</div>}
+      <button
+        className="mt-4 px-4 rounded-md h-10 bg-purple-900 text-white hover:bg-purple-800"
+        type="submit"
+      >
+        Unlock
+      </button>
+    </form>
+  );
+}